@@ -6,49 +6,109 @@ pragma solidity ^0.8.0;
 
 contract Bitacora {
 
-    event NewDevice(string indexed id, bytes32 publicKey);
+    event NewDevice(string indexed id, uint8 keyType, bytes publicKey);
+    event DeviceKeyRotated(string indexed id, uint8 keyType, bytes publicKey);
+    event DeviceRevoked(string indexed id);
     event NewDataset(string indexed id, string indexed deviceId, bytes32 merkleRoot);
+    event DatasetSparseMerkleRoot(string indexed id, string indexed deviceId, bytes32 sparseMerkleRoot);
 
     error DatasetAlreadyRegistered(string);
     error DeviceNotRegistered(string);
     error DeviceAlreadyRegistered(string);
+    error DeviceIsRevoked(string);
     error EmptyStringNotAllowed();
     error EmptyMerkleRootNotAllowed();
+    error UnsupportedKeyType(uint8);
+    error InvalidPublicKeyLength(uint8, uint256);
+
+    // Key types: Ed25519 keys are 32 bytes, secp256k1 and P-256 keys are 33 (compressed) or 65 bytes SEC1 points
+    uint8 constant KEY_TYPE_ED25519 = 0;
+    uint8 constant KEY_TYPE_SECP256K1 = 1;
+    uint8 constant KEY_TYPE_P256 = 2;
 
     struct Device {
         string id;
-        bytes32 pk;
+        uint8 keyType;
+        bytes pk;
+        bool revoked;
         mapping(string => bytes32) datasets;
+        mapping(string => bytes32) datasetsSparseMerkleRoots;
     }
 
     mapping(string => Device) public devices;
 
-    function registerDevice(string calldata _id, bytes32 _pk) external {
+    function registerDevice(string calldata _id, uint8 _keyType, bytes calldata _pk) external {
         if (bytes(_id).length == 0)
             revert EmptyStringNotAllowed();
+        checkPublicKey(_keyType, _pk);
         if (bytes(devices[_id].id).length > 0)
             revert DeviceAlreadyRegistered(_id);
         Device storage device = devices[_id];
         device.id = _id;
+        device.keyType = _keyType;
         device.pk = _pk;
-        emit NewDevice(_id, _pk);
+        emit NewDevice(_id, _keyType, _pk);
     }
 
-    function registerDataset(string calldata _id, string calldata _deviceId, bytes32 _merkleRoot) external {
+    // The device keeps its id, the previous keys stay in the NewDevice and DeviceKeyRotated events
+    function rotateDeviceKey(string calldata _id, uint8 _keyType, bytes calldata _pk) external {
+        checkPublicKey(_keyType, _pk);
+        Device storage device = registeredDevice(_id);
+        if (device.revoked)
+            revert DeviceIsRevoked(_id);
+        device.keyType = _keyType;
+        device.pk = _pk;
+        emit DeviceKeyRotated(_id, _keyType, _pk);
+    }
+
+    // Datasets of a revoked device can still be registered, they hold data signed before its revocation
+    function revokeDevice(string calldata _id) external {
+        Device storage device = registeredDevice(_id);
+        if (device.revoked)
+            revert DeviceIsRevoked(_id);
+        device.revoked = true;
+        emit DeviceRevoked(_id);
+    }
+
+    function registerDataset(string calldata _id, string calldata _deviceId, bytes32 _merkleRoot) public {
         if (bytes(_id).length == 0)
             revert EmptyStringNotAllowed();
         if (_merkleRoot == 0)
             revert EmptyMerkleRootNotAllowed();
-        Device storage device = devices[_deviceId];
-        if (bytes(device.id).length == 0)
-            revert DeviceNotRegistered(_deviceId);
+        Device storage device = registeredDevice(_deviceId);
         if (device.datasets[_id] != 0)
             revert DatasetAlreadyRegistered(_id);
         device.datasets[_id] = _merkleRoot;
         emit NewDataset(_id, _deviceId, _merkleRoot);
     }
 
+    // The Merkle root is registered as registerDataset does, the sparse Merkle root is kept apart from it
+    function registerDatasetWithSparseMerkleRoot(string calldata _id, string calldata _deviceId, bytes32 _merkleRoot, bytes32 _sparseMerkleRoot) external {
+        if (_sparseMerkleRoot == 0)
+            revert EmptyMerkleRootNotAllowed();
+        registerDataset(_id, _deviceId, _merkleRoot);
+        devices[_deviceId].datasetsSparseMerkleRoots[_id] = _sparseMerkleRoot;
+        emit DatasetSparseMerkleRoot(_id, _deviceId, _sparseMerkleRoot);
+    }
+
     function getDataset(string calldata _id, string calldata _deviceId) external view returns(bytes32) {
         return devices[_deviceId].datasets[_id];
     }
+
+    function getDatasetSparseMerkleRoot(string calldata _id, string calldata _deviceId) external view returns(bytes32) {
+        return devices[_deviceId].datasetsSparseMerkleRoots[_id];
+    }
+
+    function checkPublicKey(uint8 _keyType, bytes calldata _pk) internal pure {
+        if (_keyType > KEY_TYPE_P256)
+            revert UnsupportedKeyType(_keyType);
+        if (_keyType == KEY_TYPE_ED25519 ? _pk.length != 32 : _pk.length != 33 && _pk.length != 65)
+            revert InvalidPublicKeyLength(_keyType, _pk.length);
+    }
+
+    function registeredDevice(string calldata _id) internal view returns(Device storage device) {
+        device = devices[_id];
+        if (bytes(device.id).length == 0)
+            revert DeviceNotRegistered(_id);
+    }
 }
\ No newline at end of file
@@ -20,5 +20,76 @@ describe("Bitacora", function () {
 			await loadFixture(deploy);
 		});
   	});
+
+	describe("Devices", function () {
+		it("Should register devices of every key type", async function () {
+			const { bitacora } = await loadFixture(deploy);
+			const ed25519 = "0x" + "11".repeat(32);
+			const secp256k1 = "0x02" + "22".repeat(32);
+			const p256 = "0x04" + "33".repeat(64);
+			await expect(bitacora.registerDevice("ed25519", 0, ed25519)).to.emit(bitacora, "NewDevice").withArgs(anyValue, 0, ed25519);
+			await bitacora.registerDevice("secp256k1", 1, secp256k1);
+			await bitacora.registerDevice("p256", 2, p256);
+			const device = await bitacora.devices("p256");
+			expect(device.keyType).to.equal(2);
+			expect(device.pk).to.equal(p256);
+		});
+
+		it("Should reject keys not matching their type", async function () {
+			const { bitacora } = await loadFixture(deploy);
+			await expect(bitacora.registerDevice("device", 0, "0x02" + "22".repeat(32)))
+				.to.be.revertedWithCustomError(bitacora, "InvalidPublicKeyLength").withArgs(0, 33);
+			await expect(bitacora.registerDevice("device", 1, "0x" + "11".repeat(32)))
+				.to.be.revertedWithCustomError(bitacora, "InvalidPublicKeyLength").withArgs(1, 32);
+			await expect(bitacora.registerDevice("device", 3, "0x" + "11".repeat(32)))
+				.to.be.revertedWithCustomError(bitacora, "UnsupportedKeyType").withArgs(3);
+		});
+
+		it("Should rotate the key of a device keeping its id", async function () {
+			const { bitacora } = await loadFixture(deploy);
+			const p256 = "0x02" + "33".repeat(32);
+			await bitacora.registerDevice("device", 0, "0x" + "11".repeat(32));
+			await expect(bitacora.rotateDeviceKey("device", 2, p256)).to.emit(bitacora, "DeviceKeyRotated").withArgs(anyValue, 2, p256);
+			const device = await bitacora.devices("device");
+			expect(device.id).to.equal("device");
+			expect(device.keyType).to.equal(2);
+			expect(device.pk).to.equal(p256);
+			await expect(bitacora.rotateDeviceKey("other", 2, p256))
+				.to.be.revertedWithCustomError(bitacora, "DeviceNotRegistered").withArgs("other");
+			await expect(bitacora.rotateDeviceKey("device", 1, "0x" + "11".repeat(32)))
+				.to.be.revertedWithCustomError(bitacora, "InvalidPublicKeyLength").withArgs(1, 32);
+		});
+
+		it("Should revoke a device for good", async function () {
+			const { bitacora } = await loadFixture(deploy);
+			await bitacora.registerDevice("device", 0, "0x" + "11".repeat(32));
+			await expect(bitacora.revokeDevice("device")).to.emit(bitacora, "DeviceRevoked");
+			expect((await bitacora.devices("device")).revoked).to.equal(true);
+			await expect(bitacora.revokeDevice("device"))
+				.to.be.revertedWithCustomError(bitacora, "DeviceIsRevoked").withArgs("device");
+			await expect(bitacora.rotateDeviceKey("device", 0, "0x" + "22".repeat(32)))
+				.to.be.revertedWithCustomError(bitacora, "DeviceIsRevoked").withArgs("device");
+			// Datasets signed before the revocation can still be anchored
+			await bitacora.registerDataset("dataset", "device", "0x" + "44".repeat(32));
+		});
+	});
+
+	describe("Datasets", function () {
+		it("Should register the sparse Merkle root apart from the Merkle root", async function () {
+			const { bitacora } = await loadFixture(deploy);
+			const merkleRoot = "0x" + "44".repeat(32);
+			const sparseMerkleRoot = "0x" + "55".repeat(32);
+			await bitacora.registerDevice("device", 0, "0x" + "11".repeat(32));
+			await expect(bitacora.registerDatasetWithSparseMerkleRoot("dataset", "device", merkleRoot, sparseMerkleRoot))
+				.to.emit(bitacora, "NewDataset").withArgs(anyValue, anyValue, merkleRoot)
+				.and.to.emit(bitacora, "DatasetSparseMerkleRoot").withArgs(anyValue, anyValue, sparseMerkleRoot);
+			expect(await bitacora.getDataset("dataset", "device")).to.equal(merkleRoot);
+			expect(await bitacora.getDatasetSparseMerkleRoot("dataset", "device")).to.equal(sparseMerkleRoot);
+			await expect(bitacora.registerDatasetWithSparseMerkleRoot("dataset", "device", merkleRoot, sparseMerkleRoot))
+				.to.be.revertedWithCustomError(bitacora, "DatasetAlreadyRegistered").withArgs("dataset");
+			await expect(bitacora.registerDatasetWithSparseMerkleRoot("other", "device", merkleRoot, "0x" + "00".repeat(32)))
+				.to.be.revertedWithCustomError(bitacora, "EmptyMerkleRootNotAllowed");
+		});
+	});
 	
 });
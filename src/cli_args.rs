@@ -1,17 +1,71 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 
+use crate::common::hash_algorithm::HashAlgorithm;
+use crate::common::merkle::TreeFormat;
+use crate::configuration::{StorageBackend, AUTO_SEAL_DEFAULT_CHECK_INTERVAL_SECS, DEVICE_CHALLENGE_DEFAULT_CAPACITY, DEVICE_CHALLENGE_DEFAULT_TTL_SECS, MERKLE_TREE_CACHE_DEFAULT_SIZE};
 use crate::state::bitacora::DATASET_DEFAULT_LIMIT;
 
 /// Simple program to greet a person
 #[derive(Clone, Debug, Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 pub struct CLIArgs {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     #[arg(short, long, default_value_t = String::from("http://localhost:8545"))]
     pub web3: String,
-    #[arg(short, long)]
-    pub contracts_base: String,
-    #[arg(short, long)]
-    pub private_key: String,
+    #[arg(short, long, required = true)]
+    pub contracts_base: Option<String>,
+    #[arg(short, long, required = true)]
+    pub private_key: Option<String>,
     #[arg(short, long, default_value_t = DATASET_DEFAULT_LIMIT)]
-    pub dataset_count: u32
-}
\ No newline at end of file
+    pub dataset_count: u32,
+    #[arg(long, value_enum, default_value_t = StorageBackend::InMemory)]
+    pub storage: StorageBackend,
+    #[arg(long, default_value_t = String::from("bitacora.db"))]
+    pub sqlite_path: String,
+    #[arg(long, default_value_t = String::from("bitacora-log"))]
+    pub log_dir: String,
+    /// Seal a Dataset after this many seconds without new FlightData
+    #[arg(long)]
+    pub dataset_max_idle: Option<u64>,
+    /// Seal a Dataset once its first FlightData is older than this many seconds
+    #[arg(long)]
+    pub dataset_max_age: Option<u64>,
+    /// Seconds between two searches for Datasets to seal automatically
+    #[arg(long, default_value_t = AUTO_SEAL_DEFAULT_CHECK_INTERVAL_SECS)]
+    pub auto_seal_interval: u64,
+    /// Number of sealed Dataset Merkle trees kept in memory to serve proofs
+    #[arg(long, default_value_t = MERKLE_TREE_CACHE_DEFAULT_SIZE)]
+    pub merkle_tree_cache_size: usize,
+    /// Hash function of the Merkle trees of new Datasets
+    #[arg(long, value_enum, default_value_t = HashAlgorithm::Keccak256)]
+    pub hash_algorithm: HashAlgorithm,
+    /// Hashing of the leaves and nodes of the Merkle trees of new Datasets
+    #[arg(long, value_enum, default_value_t = TreeFormat::V1)]
+    pub tree_format: TreeFormat,
+    /// Seconds a device has to sign its registration challenge
+    #[arg(long, default_value_t = DEVICE_CHALLENGE_DEFAULT_TTL_SECS)]
+    pub device_challenge_ttl: u64,
+    /// Registration challenges kept waiting for their signature, the oldest are dropped beyond it
+    #[arg(long, default_value_t = DEVICE_CHALLENGE_DEFAULT_CAPACITY)]
+    pub device_challenge_capacity: usize,
+    /// PEM file of the manufacturer root CAs accepted to attest device keys
+    #[arg(long)]
+    pub trusted_root_cas: Option<String>
+}
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+    /// Verify offline a FlightData proof, multiproof or sparse proof returned by the service, or a portable proof
+    Verify(VerifyArgs)
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct VerifyArgs {
+    /// Path of the proof file, JSON or the binary encoding of a portable proof
+    pub proof: String,
+    /// Check the proof against the root stored by the Bitacora contract at this address, for the proofs of a Dataset
+    #[arg(long)]
+    pub contract: Option<String>,
+    #[arg(short, long, default_value_t = String::from("http://localhost:8545"))]
+    pub web3: String
+}
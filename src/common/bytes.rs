@@ -1,7 +1,7 @@
 use std::{convert::TryInto, fmt::Debug, fmt::Display};
 
 use hex::FromHexError;
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as DeError};
 use sha2::digest::{generic_array::GenericArray, typenum::U32};
 
 #[derive(Clone, Eq, Hash, PartialEq, PartialOrd)]
@@ -31,12 +31,28 @@ impl Serialize for Bytes32 {
     }
 }
 
+impl<'de> Deserialize<'de> for Bytes32 {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let hex_string = String::deserialize(deserializer)?;
+        Bytes32::try_from(hex_string.as_str()).map_err(D::Error::custom)
+    }
+}
+
 impl AsRef<[u8]> for Bytes32 {
     fn as_ref(&self) -> &[u8] {
         &self.0
     }
 }
 
+impl From<[u8; 32]> for Bytes32 {
+    fn from(value: [u8; 32]) -> Self {
+        Bytes32(value)
+    }
+}
+
 impl From<Bytes32> for [u8; 32] {
     fn from(value: Bytes32) -> Self {
         value.0
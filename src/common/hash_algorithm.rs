@@ -0,0 +1,412 @@
+use std::fmt::Display;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as DeError};
+
+use super::bytes::Bytes32;
+use super::sparse_merkle::{verify_sparse_proof, SparseMerkleProof, SparseMerkleTree};
+use super::merkle::{verify_multiproof, verify_proof, Blake3, EncodingError, Hasher, Keccak256, MerkleAccumulator, MerkleTree, MultiProof, PortableMultiProof, PortableProof, PortableTree, Sha256, TreeFormat};
+
+/// Hash function of a Merkle tree, selected at runtime. Every supported function gives 32 bytes, so that
+/// roots fit the `bytes32` anchored on chain.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize, ValueEnum)]
+pub enum HashAlgorithm {
+    #[default]
+    Keccak256,
+    Sha256,
+    Blake3
+}
+
+impl Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl HashAlgorithm {
+    /// Id of the hash function in the binary encodings of trees and proofs.
+    pub fn id(&self) -> u8 {
+        match self {
+            HashAlgorithm::Keccak256 => 0,
+            HashAlgorithm::Sha256 => 1,
+            HashAlgorithm::Blake3 => 2
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(HashAlgorithm::Keccak256),
+            1 => Some(HashAlgorithm::Sha256),
+            2 => Some(HashAlgorithm::Blake3),
+            _ => None
+        }
+    }
+
+    pub fn hash<T: AsRef<[u8]>>(&self, data: T) -> Bytes32 {
+        match self {
+            HashAlgorithm::Keccak256 => Keccak256::hash(data),
+            HashAlgorithm::Sha256 => Sha256::hash(data),
+            HashAlgorithm::Blake3 => Blake3::hash(data)
+        }
+    }
+
+    /// Leaf of the data in a tree of the given format.
+    pub fn leaf_hash<T: AsRef<[u8]>>(&self, format: TreeFormat, data: T) -> Bytes32 {
+        match self {
+            HashAlgorithm::Keccak256 => format.leaf_hash::<Keccak256, _>(data),
+            HashAlgorithm::Sha256 => format.leaf_hash::<Sha256, _>(data),
+            HashAlgorithm::Blake3 => format.leaf_hash::<Blake3, _>(data)
+        }
+    }
+
+    /// Parent of two nodes in a tree of the given format.
+    pub fn node_hash(&self, format: TreeFormat, v1: &Bytes32, v2: &Bytes32) -> Bytes32 {
+        match self {
+            HashAlgorithm::Keccak256 => format.node_hash::<Keccak256>(v1, v2),
+            HashAlgorithm::Sha256 => format.node_hash::<Sha256>(v1, v2),
+            HashAlgorithm::Blake3 => format.node_hash::<Blake3>(v1, v2)
+        }
+    }
+
+    pub fn verify_sparse_proof(&self, root: &Bytes32, key: &Bytes32, value: Option<&Bytes32>, proof: &SparseMerkleProof<Bytes32>) -> bool {
+        match self {
+            HashAlgorithm::Keccak256 => verify_sparse_proof::<Keccak256>(root, key, value, proof),
+            HashAlgorithm::Sha256 => verify_sparse_proof::<Sha256>(root, key, value, proof),
+            HashAlgorithm::Blake3 => verify_sparse_proof::<Blake3>(root, key, value, proof)
+        }
+    }
+
+    pub fn verify_proof(&self, format: TreeFormat, root: &Bytes32, leaf: &Bytes32, proof: &[Bytes32]) -> bool {
+        match self {
+            HashAlgorithm::Keccak256 => verify_proof::<Keccak256>(format, root, leaf, proof),
+            HashAlgorithm::Sha256 => verify_proof::<Sha256>(format, root, leaf, proof),
+            HashAlgorithm::Blake3 => verify_proof::<Blake3>(format, root, leaf, proof)
+        }
+    }
+
+    pub fn verify_multiproof(&self, format: TreeFormat, root: &Bytes32, leaves: &[Bytes32], multiproof: &MultiProof<Bytes32>) -> bool {
+        match self {
+            HashAlgorithm::Keccak256 => verify_multiproof::<Keccak256>(format, root, leaves, multiproof),
+            HashAlgorithm::Sha256 => verify_multiproof::<Sha256>(format, root, leaves, multiproof),
+            HashAlgorithm::Blake3 => verify_multiproof::<Blake3>(format, root, leaves, multiproof)
+        }
+    }
+}
+
+// Runs the same expression on the inner value whatever its hash function
+macro_rules! dispatch {
+    ($value:expr, $inner:ident => $body:expr) => {
+        match $value {
+            Self::Keccak256($inner) => $body,
+            Self::Sha256($inner) => $body,
+            Self::Blake3($inner) => $body
+        }
+    };
+}
+
+/// `MerkleTree` whose hash function is known only at runtime.
+#[derive(Clone, Debug)]
+pub enum DynMerkleTree {
+    Keccak256(MerkleTree<Keccak256>),
+    Sha256(MerkleTree<Sha256>),
+    Blake3(MerkleTree<Blake3>)
+}
+
+impl DynMerkleTree {
+    pub fn new(algorithm: HashAlgorithm, format: TreeFormat) -> Self {
+        match algorithm {
+            HashAlgorithm::Keccak256 => DynMerkleTree::Keccak256(MerkleTree::with_format(format)),
+            HashAlgorithm::Sha256 => DynMerkleTree::Sha256(MerkleTree::with_format(format)),
+            HashAlgorithm::Blake3 => DynMerkleTree::Blake3(MerkleTree::with_format(format))
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            DynMerkleTree::Keccak256(_) => HashAlgorithm::Keccak256,
+            DynMerkleTree::Sha256(_) => HashAlgorithm::Sha256,
+            DynMerkleTree::Blake3(_) => HashAlgorithm::Blake3
+        }
+    }
+
+    pub fn format(&self) -> TreeFormat {
+        dispatch!(self, mt => mt.format())
+    }
+
+    pub fn append<T: AsRef<[u8]>>(&mut self, element: &T) -> usize {
+        dispatch!(self, mt => mt.append(element))
+    }
+
+    pub fn append_leaves(&mut self, leaves: Vec<Bytes32>) -> usize {
+        dispatch!(self, mt => mt.append_leaves(leaves))
+    }
+
+    pub fn len(&self) -> usize {
+        dispatch!(self, mt => mt.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        dispatch!(self, mt => mt.is_empty())
+    }
+
+    pub fn leaf_index(&self, leaf: &Bytes32) -> Option<usize> {
+        dispatch!(self, mt => mt.leaf_index(leaf))
+    }
+
+    pub fn root(&mut self) -> Option<&Bytes32> {
+        dispatch!(self, mt => mt.root())
+    }
+
+    pub fn proof(&mut self, leaf: &Bytes32) -> Option<Vec<Bytes32>> {
+        dispatch!(self, mt => mt.proof(leaf))
+    }
+
+    pub fn proof_by_index(&mut self, leaf_index: usize) -> Option<Vec<Bytes32>> {
+        dispatch!(self, mt => mt.proof_by_index(leaf_index))
+    }
+
+    pub fn multiproof(&mut self, leaves: &[Bytes32]) -> Option<MultiProof<Bytes32>> {
+        dispatch!(self, mt => mt.multiproof(leaves))
+    }
+
+    pub fn portable_proof(&mut self, leaf_index: usize) -> Option<PortableProof> {
+        dispatch!(self, mt => mt.portable_proof(leaf_index))
+    }
+
+    pub fn portable_multiproof(&mut self, leaves: &[Bytes32]) -> Option<PortableMultiProof> {
+        dispatch!(self, mt => mt.portable_multiproof(leaves))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        dispatch!(self, mt => mt.to_bytes())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EncodingError> {
+        DynMerkleTree::try_from(PortableTree::from_bytes(bytes)?)
+    }
+}
+
+impl TryFrom<PortableTree> for DynMerkleTree {
+
+    type Error = EncodingError;
+
+    fn try_from(value: PortableTree) -> Result<Self, Self::Error> {
+        Ok(match value.hash_algorithm {
+            HashAlgorithm::Keccak256 => DynMerkleTree::Keccak256(MerkleTree::try_from(value)?),
+            HashAlgorithm::Sha256 => DynMerkleTree::Sha256(MerkleTree::try_from(value)?),
+            HashAlgorithm::Blake3 => DynMerkleTree::Blake3(MerkleTree::try_from(value)?)
+        })
+    }
+}
+
+// The portable encoding of a tree records its hash function, so no tag is needed around it
+impl Serialize for DynMerkleTree {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        dispatch!(self, mt => mt.serialize(serializer))
+    }
+}
+
+impl<'de> Deserialize<'de> for DynMerkleTree {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        DynMerkleTree::try_from(PortableTree::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// `SparseMerkleTree` whose hash function is known only at runtime.
+#[derive(Clone, Debug)]
+pub enum DynSparseMerkleTree {
+    Keccak256(SparseMerkleTree<Keccak256>),
+    Sha256(SparseMerkleTree<Sha256>),
+    Blake3(SparseMerkleTree<Blake3>)
+}
+
+impl DynSparseMerkleTree {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Keccak256 => DynSparseMerkleTree::Keccak256(SparseMerkleTree::new()),
+            HashAlgorithm::Sha256 => DynSparseMerkleTree::Sha256(SparseMerkleTree::new()),
+            HashAlgorithm::Blake3 => DynSparseMerkleTree::Blake3(SparseMerkleTree::new())
+        }
+    }
+
+    pub fn insert(&mut self, key: &Bytes32, value: Bytes32) -> Option<Bytes32> {
+        dispatch!(self, smt => smt.insert(key, value))
+    }
+
+    pub fn get(&self, key: &Bytes32) -> Option<&Bytes32> {
+        dispatch!(self, smt => smt.get(key))
+    }
+
+    pub fn len(&self) -> usize {
+        dispatch!(self, smt => smt.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        dispatch!(self, smt => smt.is_empty())
+    }
+
+    pub fn root(&self) -> Option<Bytes32> {
+        dispatch!(self, smt => smt.root())
+    }
+
+    pub fn proof(&self, key: &Bytes32) -> SparseMerkleProof<Bytes32> {
+        dispatch!(self, smt => smt.proof(key))
+    }
+}
+
+/// `MerkleAccumulator` whose hash function is known only at runtime.
+#[derive(Clone, Debug, Serialize)]
+pub enum DynMerkleAccumulator {
+    Keccak256(MerkleAccumulator<Keccak256>),
+    Sha256(MerkleAccumulator<Sha256>),
+    Blake3(MerkleAccumulator<Blake3>)
+}
+
+impl DynMerkleAccumulator {
+    pub fn new(algorithm: HashAlgorithm, format: TreeFormat) -> Self {
+        match algorithm {
+            HashAlgorithm::Keccak256 => DynMerkleAccumulator::Keccak256(MerkleAccumulator::with_format(format)),
+            HashAlgorithm::Sha256 => DynMerkleAccumulator::Sha256(MerkleAccumulator::with_format(format)),
+            HashAlgorithm::Blake3 => DynMerkleAccumulator::Blake3(MerkleAccumulator::with_format(format))
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            DynMerkleAccumulator::Keccak256(_) => HashAlgorithm::Keccak256,
+            DynMerkleAccumulator::Sha256(_) => HashAlgorithm::Sha256,
+            DynMerkleAccumulator::Blake3(_) => HashAlgorithm::Blake3
+        }
+    }
+
+    pub fn format(&self) -> TreeFormat {
+        dispatch!(self, accumulator => accumulator.format())
+    }
+
+    pub fn append<T: AsRef<[u8]>>(&mut self, element: &T) -> usize {
+        dispatch!(self, accumulator => accumulator.append(element))
+    }
+
+    pub fn len(&self) -> usize {
+        dispatch!(self, accumulator => accumulator.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        dispatch!(self, accumulator => accumulator.is_empty())
+    }
+
+    pub fn root(&self) -> Option<Bytes32> {
+        dispatch!(self, accumulator => accumulator.root())
+    }
+}
+
+impl<'de> Deserialize<'de> for DynMerkleAccumulator {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        enum Tagged {
+            Keccak256(MerkleAccumulator<Keccak256>),
+            Sha256(MerkleAccumulator<Sha256>),
+            Blake3(MerkleAccumulator<Blake3>)
+        }
+
+        // Accumulators stored before the hash function was selectable are Keccak-256 ones with no tag
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Tagged(Tagged),
+            Untagged(MerkleAccumulator<Keccak256>)
+        }
+
+        Ok(match Stored::deserialize(deserializer)? {
+            Stored::Tagged(Tagged::Keccak256(accumulator)) | Stored::Untagged(accumulator) => DynMerkleAccumulator::Keccak256(accumulator),
+            Stored::Tagged(Tagged::Sha256(accumulator)) => DynMerkleAccumulator::Sha256(accumulator),
+            Stored::Tagged(Tagged::Blake3(accumulator)) => DynMerkleAccumulator::Blake3(accumulator)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::merkle::{Blake3, Hasher, MerkleAccumulator, Keccak256, Sha256, TreeFormat};
+
+    use super::{DynMerkleAccumulator, DynMerkleTree, HashAlgorithm};
+
+    #[test]
+    fn test_hash_algorithms() {
+        // Reference digests of the empty input
+        assert_eq!(HashAlgorithm::Keccak256.hash("").to_string(), "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470");
+        assert_eq!(HashAlgorithm::Sha256.hash("").to_string(), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(HashAlgorithm::Blake3.hash("").to_string(), "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262");
+        assert_eq!(HashAlgorithm::Sha256.hash("a"), Sha256::hash("a"));
+        assert_eq!(HashAlgorithm::Blake3.hash("a"), Blake3::hash("a"));
+    }
+
+    #[test]
+    fn test_dyn_merkle_tree_and_accumulator_agree() {
+        for format in [TreeFormat::V0, TreeFormat::V1, TreeFormat::OpenZeppelin] {
+            for algorithm in [HashAlgorithm::Keccak256, HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+                let mut mt = DynMerkleTree::new(algorithm, format);
+                let mut accumulator = DynMerkleAccumulator::new(algorithm, format);
+                for value in ["a", "b", "c", "d", "e"] {
+                    mt.append(&value);
+                    accumulator.append(&value);
+                }
+                let root = mt.root().cloned().unwrap();
+                assert_eq!(accumulator.root(), Some(root.clone()));
+                let leaf = algorithm.leaf_hash(format, "c");
+                let proof = mt.proof(&leaf).unwrap();
+                assert!(algorithm.verify_proof(format, &root, &leaf, &proof), "{} {} proof not verified", algorithm, format);
+                for other in [HashAlgorithm::Keccak256, HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+                    if other != algorithm {
+                        assert!(!other.verify_proof(format, &root, &leaf, &proof), "{} proof verified with {}", algorithm, other);
+                    }
+                }
+
+                let restored: DynMerkleAccumulator = serde_json::from_str(&serde_json::to_string(&accumulator).unwrap()).unwrap();
+                assert_eq!(restored.algorithm(), algorithm);
+                assert_eq!(restored.format(), format);
+                assert_eq!(restored.root(), accumulator.root());
+            }
+        }
+    }
+
+    #[test]
+    fn test_dyn_merkle_tree_encodings_keep_the_hash_algorithm() {
+        for algorithm in [HashAlgorithm::Keccak256, HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+            let mut mt = DynMerkleTree::new(algorithm, TreeFormat::V1);
+            for value in ["a", "b", "c"] {
+                mt.append(&value);
+            }
+            let root = mt.root().cloned();
+            let json = serde_json::to_value(&mt).unwrap();
+            assert_eq!(json["hash_algorithm"], serde_json::json!(algorithm.to_string()));
+            for mut restored in [DynMerkleTree::from_bytes(&mt.to_bytes()).unwrap(), serde_json::from_value(json).unwrap()] {
+                assert_eq!(restored.algorithm(), algorithm);
+                assert_eq!(restored.root().cloned(), root);
+            }
+            assert!(mt.portable_proof(1).unwrap().verify());
+        }
+    }
+
+    #[test]
+    fn test_untagged_accumulator_is_keccak256() {
+        let mut accumulator = MerkleAccumulator::<Keccak256>::new();
+        accumulator.append(&"a");
+        // As stored before the hash function and the tree format were recorded
+        let mut stored = serde_json::to_value(&accumulator).unwrap();
+        stored.as_object_mut().unwrap().remove("format");
+        let restored: DynMerkleAccumulator = serde_json::from_value(stored).unwrap();
+        assert_eq!(restored.algorithm(), HashAlgorithm::Keccak256);
+        assert_eq!(restored.format(), TreeFormat::V0);
+        assert_eq!(restored.root(), accumulator.root());
+    }
+}
@@ -1,41 +1,345 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use clap::ValueEnum;
+use ethers::abi::{self, Token};
 use ethers::utils::keccak256;
+use rayon::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as DeError};
+use sha2::Digest;
 
 use super::bytes::Bytes32;
+use super::hash_algorithm::HashAlgorithm;
+
+/// Number of hashes of a tree level, or of leaves, from which they are computed in parallel.
+pub const PARALLEL_HASHING_THRESHOLD: usize = 1024;
 
 pub trait Hasher {
 
-    type ReturnType: AsRef<[u8]> + Clone + Eq + PartialOrd;
+    type ReturnType: AsRef<[u8]> + Clone + Eq + Hash + PartialOrd + Send + Sync;
+
+    /// Id of the hash function in the portable encodings of trees and proofs.
+    const ALGORITHM: HashAlgorithm;
 
     fn hash<T: AsRef<[u8]>>(data: T) -> Self::ReturnType;
 }
 
+#[derive(Clone, Debug)]
 pub struct Keccak256 {}
 
 impl Hasher for Keccak256 {
 
     type ReturnType = Bytes32;
 
+    const ALGORITHM: HashAlgorithm = HashAlgorithm::Keccak256;
+
     fn hash<T: AsRef<[u8]>>(data: T) -> Self::ReturnType {
         keccak256(data).into()
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct Sha256 {}
+
+impl Hasher for Sha256 {
+
+    type ReturnType = Bytes32;
+
+    const ALGORITHM: HashAlgorithm = HashAlgorithm::Sha256;
+
+    fn hash<T: AsRef<[u8]>>(data: T) -> Self::ReturnType {
+        sha2::Sha256::digest(data).into()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Blake3 {}
+
+impl Hasher for Blake3 {
+
+    type ReturnType = Bytes32;
+
+    const ALGORITHM: HashAlgorithm = HashAlgorithm::Blake3;
+
+    fn hash<T: AsRef<[u8]>>(data: T) -> Self::ReturnType {
+        Bytes32(*blake3::hash(data.as_ref()).as_bytes())
+    }
+}
+
 pub type MerkleRoot = Bytes32;
 
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// How the leaves and the internal nodes of a Merkle tree are hashed. A format is never changed once
+/// released, so that the roots anchored with it stay verifiable; changes go in a new version.
+///
+/// Every format builds the same shape: the nodes of each level are hashed in pairs, and an odd node left at
+/// the end of a level is carried up until another level ends with an odd node. The trees built before
+/// proofs were fixed for uneven sizes carried only the first odd node, and left out the others, so their
+/// root did not cover every leaf when the number of leaves has three or more bits set (7, 11, 13 to 15,
+/// 19, ...). For those sizes the roots differ from the ones anchored back then, and proofs of their
+/// `Dataset`s can't be built; for the other sizes the roots are the same.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize, ValueEnum)]
+pub enum TreeFormat {
+    /// Leaves and internal nodes hashed alike with no prefix, so an internal node can be passed off as a
+    /// leaf. It is the `Default` because the records stored before the format was recorded are read as V0,
+    /// while new Datasets use V1 unless another format is configured. It hashes as the trees of the first
+    /// releases but has the current shape, so their roots differ for some sizes, see above.
+    #[default]
+    V0,
+    /// Leaves hashed with a `0x00` prefix and internal nodes with a `0x01` one.
+    V1,
+    /// Leaves of an OpenZeppelin `StandardMerkleTree` of `bytes` values, the ABI encoding of the data
+    /// hashed twice, and internal nodes with no prefix. Proofs can be checked with `StandardMerkleTree.verify`
+    /// and on chain with `MerkleProof.verify`, but the tree keeps its own shape, so its root is not always
+    /// the one `StandardMerkleTree.of` gives for the same values.
+    OpenZeppelin
+}
+
+impl Display for TreeFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl TreeFormat {
+    /// Id of the format in the binary encodings of trees and proofs.
+    pub fn id(&self) -> u8 {
+        match self {
+            TreeFormat::V0 => 0,
+            TreeFormat::V1 => 1,
+            TreeFormat::OpenZeppelin => 2
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(TreeFormat::V0),
+            1 => Some(TreeFormat::V1),
+            2 => Some(TreeFormat::OpenZeppelin),
+            _ => None
+        }
+    }
+
+    pub fn leaf_hash<H: Hasher, T: AsRef<[u8]>>(&self, data: T) -> H::ReturnType {
+        match self {
+            TreeFormat::V0 => H::hash(data),
+            TreeFormat::V1 => {
+                let mut hash_buffer = Vec::with_capacity(data.as_ref().len()+1);
+                hash_buffer.push(LEAF_PREFIX);
+                hash_buffer.extend_from_slice(data.as_ref());
+                H::hash(hash_buffer)
+            },
+            // A 32 bytes preimage can not be confused with the 64 bytes of an internal node
+            TreeFormat::OpenZeppelin => H::hash(H::hash(abi::encode(&[Token::Bytes(data.as_ref().to_vec())])))
+        }
+    }
+
+    /// Hashes two sibling nodes in ascending order, so that proofs need not tell the side of each one.
+    pub fn node_hash<H: Hasher>(&self, v1: &H::ReturnType, v2: &H::ReturnType) -> H::ReturnType {
+        let (first, second) = if v1 < v2 { (v1, v2) } else { (v2, v1) };
+        let mut hash_buffer = Vec::with_capacity(first.as_ref().len()*2+1); // TODO: make it reusable and remove from heap
+        if *self == TreeFormat::V1 {
+            hash_buffer.push(NODE_PREFIX);
+        }
+        hash_buffer.extend_from_slice(first.as_ref());
+        hash_buffer.extend_from_slice(second.as_ref());
+        H::hash(hash_buffer)
+    }
+}
+
+/// Checks that `proof` links `leaf` to `root` without the tree, folding the leaf with each proof
+/// element in order. An empty proof is only valid for a tree made of the leaf alone.
+pub fn verify_proof<H: Hasher>(format: TreeFormat, root: &H::ReturnType, leaf: &H::ReturnType, proof: &[H::ReturnType]) -> bool {
+    let mut accumulator = leaf.clone();
+    for proof_component in proof.iter() {
+        accumulator = format.node_hash::<H>(&accumulator, proof_component);
+    }
+    accumulator == *root
+}
+
+/// Proof of several leaves of the same tree at once. Siblings shared by the paths of the leaves, or
+/// computable from the leaves themselves, are included only once.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MultiProof<T> {
+    /// Number of leaves of the tree, which determines its shape
+    pub n_leaves: usize,
+    /// Position in the tree of each proven leaf
+    pub leaf_indices: Vec<usize>,
+    /// Missing siblings, in the order they are needed while rebuilding the tree bottom up
+    pub proof: Vec<T>
+}
+
+/// Checks that `leaves`, placed at the positions listed in `multiproof`, belong to the tree of `root`.
+pub fn verify_multiproof<H: Hasher>(format: TreeFormat, root: &H::ReturnType, leaves: &[H::ReturnType], multiproof: &MultiProof<H::ReturnType>) -> bool {
+    if leaves.is_empty() || leaves.len() != multiproof.leaf_indices.len() {
+        return false;
+    }
+    let mut known_nodes: HashMap<usize, H::ReturnType> = HashMap::new();
+    for (leaf_index, leaf) in multiproof.leaf_indices.iter().zip(leaves.iter()) {
+        if *leaf_index >= multiproof.n_leaves {
+            return false;
+        }
+        if let Some(known_leaf) = known_nodes.insert(*leaf_index, leaf.clone()) {
+            if known_leaf != *leaf {
+                return false;
+            }
+        }
+    }
+    let mut proof_components = multiproof.proof.iter();
+    for (left, right, parent) in merge_steps(multiproof.n_leaves) {
+        let node = match (known_nodes.get(&left), known_nodes.get(&right)) {
+            (Some(left), Some(right)) => format.node_hash::<H>(left, right),
+            (Some(known), None) | (None, Some(known)) => match proof_components.next() {
+                Some(proof_component) => format.node_hash::<H>(known, proof_component),
+                None => return false
+            },
+            (None, None) => continue
+        };
+        known_nodes.insert(parent, node);
+    }
+    proof_components.next().is_none() && known_nodes.get(&(multiproof.n_leaves*2-2)) == Some(root)
+}
+
+/// Incremental builder of the same root as `MerkleTree`, keeping only O(log n) nodes.
+///
+/// Leaves are paired as they come, so the appended ones are covered by perfect subtrees of decreasing
+/// size, one per bit set in their count. Only the roots of these subtrees (the peaks) are kept, indexed
+/// by height. Hashing the peaks together from the smallest to the largest gives the root, matching how
+/// `MerkleTree` carries an odd node up until it meets another one.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(bound(serialize = "H::ReturnType: Serialize", deserialize = "H::ReturnType: Deserialize<'de>"))]
+pub struct MerkleAccumulator<H: Hasher> {
+    // Accumulators stored before formats were versioned have none
+    #[serde(default)]
+    format: TreeFormat,
+    n_leaves: usize,
+    peaks: Vec<Option<H::ReturnType>>,
+    #[serde(skip)]
+    hasher: PhantomData<H>
+}
+
+impl <H: Hasher> Default for MerkleAccumulator<H> {
+    fn default() -> Self {
+        MerkleAccumulator {
+            format: TreeFormat::default(),
+            n_leaves: 0,
+            peaks: Vec::new(),
+            hasher: PhantomData
+        }
+    }
+}
+
+impl <H: Hasher> MerkleAccumulator<H> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_format(format: TreeFormat) -> Self {
+        MerkleAccumulator {
+            format,
+            ..Self::default()
+        }
+    }
+
+    pub fn format(&self) -> TreeFormat {
+        self.format
+    }
+
+    pub fn append<T: AsRef<[u8]>>(&mut self, element: &T) -> usize {
+        self.append_leaf(self.format.leaf_hash::<H, _>(element))
+    }
+
+    /// Appends an already hashed leaf, returning the number of leaves.
+    pub fn append_leaf(&mut self, leaf: H::ReturnType) -> usize {
+        let mut node = leaf;
+        let mut height = 0;
+        while let Some(Some(peak)) = self.peaks.get_mut(height).map(Option::take) {
+            node = self.format.node_hash::<H>(&peak, &node);
+            height += 1;
+        }
+        if height == self.peaks.len() {
+            self.peaks.push(Some(node));
+        } else {
+            self.peaks[height] = Some(node);
+        }
+        self.n_leaves += 1;
+        self.n_leaves
+    }
+
+    pub fn len(&self) -> usize {
+        self.n_leaves
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n_leaves == 0
+    }
+
+    pub fn root(&self) -> Option<H::ReturnType> {
+        let mut root: Option<H::ReturnType> = None;
+        for peak in self.peaks.iter().flatten() {
+            root = match root {
+                Some(node) => Some(self.format.node_hash::<H>(peak, &node)),
+                None => Some(peak.clone())
+            };
+        }
+        root
+    }
+}
+
+/// Lists the hashes computed to build a tree of `n_leaves` leaves, in order, as the positions of the two
+/// children and of the parent in the nodes of the tree.
+fn merge_steps(n_leaves: usize) -> Vec<(usize, usize, usize)> {
+    let mut steps = Vec::new();
+    if n_leaves == 0 {
+        return steps;
+    }
+    let mut start_index = 0;
+    let mut end_index = n_leaves;
+    let mut odd_item_index: Option<usize> = None;
+    while end_index - start_index > 1 || odd_item_index.is_some() {
+        let mut next_end_index = end_index;
+        for base_index in (start_index..end_index-1).step_by(2) {
+            steps.push((base_index, base_index+1, next_end_index));
+            next_end_index += 1;
+        }
+        if (end_index - start_index) % 2 == 1 {
+            match odd_item_index.take() {
+                None => odd_item_index = Some(end_index-1),
+                Some(odd_index) => {
+                    steps.push((end_index-1, odd_index, next_end_index));
+                    next_end_index += 1;
+                }
+            }
+        }
+        start_index = end_index;
+        end_index = next_end_index;
+    }
+    steps
+}
+
 #[derive(Clone, Debug)]
 pub struct MerkleTree<H>
 where
     H: Hasher
 {
+    format: TreeFormat,
     nodes: Vec<H::ReturnType>,
-    leaves: Vec<H::ReturnType>
+    leaves: Vec<H::ReturnType>,
+    // Position of each leaf hash, to find it without scanning the nodes
+    leaf_indices: HashMap<H::ReturnType, usize>
 }
 
 impl <H: Hasher> Default for MerkleTree<H> {
     fn default() -> Self {
         MerkleTree {
+            format: TreeFormat::default(),
             nodes: Vec::new(),
-            leaves: Vec::new()
+            leaves: Vec::new(),
+            leaf_indices: HashMap::new()
         }
     }
 }
@@ -45,11 +349,45 @@ impl <H: Hasher> MerkleTree<H> {
         Self::default()
     }
 
+    pub fn with_format(format: TreeFormat) -> Self {
+        MerkleTree {
+            format,
+            ..Self::default()
+        }
+    }
+
+    pub fn format(&self) -> TreeFormat {
+        self.format
+    }
+
     pub fn append<T: AsRef<[u8]>>(&mut self, element: &T) -> usize {
-        self.leaves.push(H::hash(element));
+        let leaf = self.format.leaf_hash::<H, _>(element);
+        let leaf_index = self.len();
+        self.leaf_indices.entry(leaf.clone()).or_insert(leaf_index);
+        self.leaves.push(leaf);
+        self.leaves.len()
+    }
+
+    /// Appends leaves already hashed with the format of the tree.
+    pub fn append_leaves(&mut self, leaves: Vec<H::ReturnType>) -> usize {
+        for leaf in leaves {
+            let leaf_index = self.len();
+            self.leaf_indices.entry(leaf.clone()).or_insert(leaf_index);
+            self.leaves.push(leaf);
+        }
         self.leaves.len()
     }
 
+    /// Number of leaves in the tree.
+    pub fn len(&self) -> usize {
+        (self.nodes.len()+1)/2 + self.leaves.len()
+    }
+
+    /// Position of the first occurrence of the leaf in the tree.
+    pub fn leaf_index(&self, leaf: &H::ReturnType) -> Option<usize> {
+        self.leaf_indices.get(leaf).copied()
+    }
+
     pub fn root(&mut self) -> Option<&H::ReturnType> {
         if !self.is_root_valid() {
             self.compute();
@@ -58,59 +396,89 @@ impl <H: Hasher> MerkleTree<H> {
     }
 
     pub fn proof(&mut self, leaf: &H::ReturnType) -> Option<Vec<H::ReturnType>> {
-        if self.is_empty() {
+        match self.leaf_index(leaf) {
+            Some(leaf_index) => self.proof_by_index(leaf_index),
+            None => None
+        }
+    }
+
+    /// Proof of the leaf at the given position, in logarithmic time once the tree is computed.
+    pub fn proof_by_index(&mut self, leaf_index: usize) -> Option<Vec<H::ReturnType>> {
+        if leaf_index >= self.len() {
             return None;
         }
         if !self.is_root_valid() {
             self.compute();
         }
-        let mut item_cursor = match self.nodes.iter().position(|x| x == leaf) {
-            Some(item_index) => item_index,
-            None => return None
-        };
-        let mut proof = Vec::new();
         let n_leaves = (self.nodes.len()+1)/2;
-        let tree_depth = if n_leaves.is_power_of_two() {
-            n_leaves.ilog2()
-        } else {
-            n_leaves.ilog2()+1
-        };
+        let mut item_index = leaf_index;
+        // Walk up the levels exactly as compute() builds them, following the node that includes the leaf
+        let mut proof = Vec::new();
         let mut start_index = 0;
         let mut end_index = n_leaves;
-        let mut odd_levels_count = 0;
-        let mut odd_element_index = 0;
-        let mut odd_element_rebalance = 0;
-        let mut item_index = start_index+item_cursor;
-        for _ in 0..tree_depth {
+        let mut odd_item_index: Option<usize> = None;
+        while end_index - start_index > 1 || odd_item_index.is_some() {
+            let n_pairs = (end_index - start_index)/2;
+            let mut next_end_index = end_index + n_pairs;
+            if item_index >= start_index && item_index < start_index + n_pairs*2 {
+                let item_cursor = item_index - start_index;
+                proof.push(self.nodes[start_index + (item_cursor ^ 1)].clone());
+                item_index = end_index + item_cursor/2;
+            }
             if (end_index - start_index) % 2 == 1 {
-                odd_levels_count += 1;
-                if odd_levels_count % 2 == 0 {
-                    odd_element_rebalance = 1;
-                } else {
-                    odd_element_index = end_index-1;
-                }
-            } 
-            if item_cursor % 2 == 1 {
-                proof.push(self.nodes[item_index-1].clone());
-            } else {
-                if item_index < end_index-1 {
-                    proof.push(self.nodes[item_index+1].clone())
-                } else {
-                    if odd_levels_count % 2 == 0 {
-                        proof.push(self.nodes[odd_element_index].clone());
+                match odd_item_index.take() {
+                    None => odd_item_index = Some(end_index-1),
+                    Some(odd_index) => {
+                        if item_index == end_index-1 {
+                            proof.push(self.nodes[odd_index].clone());
+                            item_index = next_end_index;
+                        } else if item_index == odd_index {
+                            proof.push(self.nodes[end_index-1].clone());
+                            item_index = next_end_index;
+                        }
+                        next_end_index += 1;
                     }
                 }
             }
-            let n_next_level = (end_index-start_index)/2;
             start_index = end_index;
-            end_index += n_next_level + odd_element_rebalance;
-            odd_element_rebalance = 0;
-            item_cursor /= 2;
-            item_index = start_index+item_cursor;
+            end_index = next_end_index;
         }
         Some(proof)
     }
 
+    /// Builds a single proof for all the given leaves, failing if any of them is not in the tree.
+    pub fn multiproof(&mut self, leaves: &[H::ReturnType]) -> Option<MultiProof<H::ReturnType>> {
+        if self.is_empty() || leaves.is_empty() {
+            return None;
+        }
+        if !self.is_root_valid() {
+            self.compute();
+        }
+        let n_leaves = (self.nodes.len()+1)/2;
+        let mut leaf_indices = Vec::with_capacity(leaves.len());
+        for leaf in leaves.iter() {
+            match self.leaf_index(leaf) {
+                Some(leaf_index) => leaf_indices.push(leaf_index),
+                None => return None
+            }
+        }
+        let mut known_nodes = vec![false; self.nodes.len()];
+        for leaf_index in leaf_indices.iter() {
+            known_nodes[*leaf_index] = true;
+        }
+        let mut proof = Vec::new();
+        for (left, right, parent) in merge_steps(n_leaves) {
+            match (known_nodes[left], known_nodes[right]) {
+                (true, true) => {},
+                (true, false) => proof.push(self.nodes[right].clone()),
+                (false, true) => proof.push(self.nodes[left].clone()),
+                (false, false) => continue
+            }
+            known_nodes[parent] = true;
+        }
+        Some(MultiProof { n_leaves, leaf_indices, proof })
+    }
+
     pub fn verify(&mut self, leaf: &H::ReturnType, proof: &Vec<H::ReturnType>) -> bool {
         if self.is_empty() {
             return false;
@@ -118,14 +486,7 @@ impl <H: Hasher> MerkleTree<H> {
         if !self.is_root_valid() {
             self.compute();
         }
-        if proof.is_empty() {
-            return self.nodes.len() == 1 && self.nodes[0] == *leaf;   
-        }
-        let mut accumulator = leaf.clone();
-        for proof_component in proof.iter() {
-            accumulator = Self::pairwise_hash(&accumulator, proof_component);
-        }
-        accumulator == *self.root().unwrap()
+        verify_proof::<H>(self.format, self.root().unwrap(), leaf, proof)
     }
 
     pub fn is_empty(&self) -> bool {
@@ -136,7 +497,30 @@ impl <H: Hasher> MerkleTree<H> {
         self.leaves.len() == 0 && self.nodes.len() > 0
     }
 
+    // Leaves in order, the computed ones first and then those appended since
+    fn all_leaves(&self) -> impl Iterator<Item = &H::ReturnType> {
+        self.nodes[..(self.nodes.len()+1)/2].iter().chain(self.leaves.iter())
+    }
+
+    // Root without computing the tree, which needs it mutable
+    fn current_root(&self) -> Option<H::ReturnType> {
+        if self.is_root_valid() {
+            return self.nodes.last().cloned();
+        }
+        let mut accumulator = MerkleAccumulator::<H>::with_format(self.format);
+        for leaf in self.all_leaves() {
+            accumulator.append_leaf(leaf.clone());
+        }
+        accumulator.root()
+    }
+
     fn compute(&mut self) {
+        self.compute_with_threshold(PARALLEL_HASHING_THRESHOLD)
+    }
+
+    // Levels with at least `parallel_threshold` pairs are hashed in parallel. Each pair is hashed as in the
+    // sequential case and collected in order, so the nodes, and the root, are the same either way.
+    fn compute_with_threshold(&mut self, parallel_threshold: usize) {
         // Reset the current tree
         let total_leaves = (self.nodes.len()+1)/2;
         self.nodes.truncate(total_leaves);
@@ -150,52 +534,417 @@ impl <H: Hasher> MerkleTree<H> {
             return;
         }
 
+        // An odd node left at the end of a level is carried up until another level ends with an odd node,
+        // then the two are hashed together into the next level. This always gives 2n-1 nodes, and every
+        // leaf is under the root, which is not the case of the shape before it, see `TreeFormat`.
         let mut nodes_start_index = 0;
         let mut odd_item_index: Option<usize> = None;
         loop {
             let nodes_end_index = self.nodes.len();
-            for i in 0..(nodes_end_index-nodes_start_index)/2 {
-                let base_index = nodes_start_index+(i*2);
-                self.nodes.push(
-                    Self::pairwise_hash(&self.nodes[base_index], &self.nodes[base_index+1])
-                );
-            }
-            if odd_item_index.is_none() && nodes_end_index % 2 == 1 {
-                odd_item_index = Some(nodes_end_index-1);
+            if nodes_end_index - nodes_start_index == 1 && odd_item_index.is_none() {
+                return;
             }
-            if nodes_end_index - nodes_start_index <= 2 {
-                if odd_item_index.is_some() {
-                    self.nodes.push(
-                        Self::pairwise_hash(
-                            self.nodes.last().unwrap(),
-                            &self.nodes[odd_item_index.unwrap()]
-                        )
-                    );
+            let n_pairs = (nodes_end_index-nodes_start_index)/2;
+            let pairs = &self.nodes[nodes_start_index..nodes_start_index+n_pairs*2];
+            let format = self.format;
+            let level: Vec<H::ReturnType> = if n_pairs >= parallel_threshold {
+                pairs.par_chunks(2).map(|pair| format.node_hash::<H>(&pair[0], &pair[1])).collect()
+            } else {
+                pairs.chunks(2).map(|pair| format.node_hash::<H>(&pair[0], &pair[1])).collect()
+            };
+            self.nodes.extend(level);
+            if (nodes_end_index - nodes_start_index) % 2 == 1 {
+                match odd_item_index.take() {
+                    None => odd_item_index = Some(nodes_end_index-1),
+                    Some(odd_index) => self.nodes.push(
+                        self.format.node_hash::<H>(&self.nodes[nodes_end_index-1], &self.nodes[odd_index])
+                    )
                 }
-                return;
             }
             nodes_start_index = nodes_end_index;
         }
     }
+}
 
-    fn pairwise_hash(v1: &H::ReturnType, v2: &H::ReturnType) -> H::ReturnType {
-        let mut hash_buffer = Vec::new(); // TODO: make it reusable and remove from heap
-        if v1 < v2 {
-            hash_buffer.extend_from_slice(v1.as_ref());
-            hash_buffer.extend_from_slice(v2.as_ref());
-        } else {
-            hash_buffer.extend_from_slice(v2.as_ref());
-            hash_buffer.extend_from_slice(v1.as_ref());
+/// Version of the portable encodings of trees and proofs. It comes first in every encoding, so that a
+/// reader can tell one it does not know from a corrupted one.
+pub const ENCODING_VERSION: u8 = 1;
+
+// Binary encodings start with the version, the kind of item, the hash function and the tree format, one
+// byte each. Counts and positions follow as u64 BE, lists are prefixed by their length
+const ENCODING_HEADER_BYTES: usize = 4;
+const ENCODING_KIND_TREE: u8 = 0;
+const ENCODING_KIND_PROOF: u8 = 1;
+const ENCODING_KIND_MULTIPROOF: u8 = 2;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum EncodingError {
+    UnsupportedVersion(u8),
+    UnexpectedKind(u8),
+    UnknownHashAlgorithm(u8),
+    UnknownTreeFormat(u8),
+    /// The encoded tree uses another hash function than the one it is decoded into
+    HashAlgorithmMismatch(HashAlgorithm),
+    /// The encoded root is not the one of the encoded leaves
+    RootMismatch,
+    Truncated,
+    TrailingBytes(usize)
+}
+
+impl Display for EncodingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodingError::UnsupportedVersion(version) => write!(f, "unsupported encoding version {}", version),
+            EncodingError::UnexpectedKind(kind) => write!(f, "unexpected kind of encoded item {}", kind),
+            EncodingError::UnknownHashAlgorithm(id) => write!(f, "unknown hash algorithm {}", id),
+            EncodingError::UnknownTreeFormat(id) => write!(f, "unknown tree format {}", id),
+            EncodingError::HashAlgorithmMismatch(algorithm) => write!(f, "tree hashed with {}", algorithm),
+            EncodingError::RootMismatch => write!(f, "root does not match the leaves"),
+            EncodingError::Truncated => write!(f, "truncated encoding"),
+            EncodingError::TrailingBytes(n_bytes) => write!(f, "{} bytes after the encoding", n_bytes)
+        }
+    }
+}
+
+fn deserialize_encoding_version<'de, D>(deserializer: D) -> Result<u8, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let version = u8::deserialize(deserializer)?;
+    if version != ENCODING_VERSION {
+        return Err(D::Error::custom(EncodingError::UnsupportedVersion(version)));
+    }
+    Ok(version)
+}
+
+struct EncodingWriter {
+    bytes: Vec<u8>
+}
+
+impl EncodingWriter {
+    fn new(kind: u8, hash_algorithm: HashAlgorithm, tree_format: TreeFormat) -> Self {
+        EncodingWriter { bytes: vec![ENCODING_VERSION, kind, hash_algorithm.id(), tree_format.id()] }
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.bytes.extend_from_slice(&(value as u64).to_be_bytes());
+    }
+
+    fn write_hash(&mut self, hash: &Bytes32) {
+        self.bytes.extend_from_slice(hash.as_ref());
+    }
+
+    fn write_hashes(&mut self, hashes: &[Bytes32]) {
+        self.write_usize(hashes.len());
+        hashes.iter().for_each(|hash| self.write_hash(hash));
+    }
+}
+
+struct EncodingReader<'a> {
+    bytes: &'a [u8],
+    hash_algorithm: HashAlgorithm,
+    tree_format: TreeFormat
+}
+
+impl <'a> EncodingReader<'a> {
+    fn new(bytes: &'a [u8], kind: u8) -> Result<Self, EncodingError> {
+        if bytes.len() < ENCODING_HEADER_BYTES {
+            return Err(EncodingError::Truncated);
+        }
+        if bytes[0] != ENCODING_VERSION {
+            return Err(EncodingError::UnsupportedVersion(bytes[0]));
+        }
+        if bytes[1] != kind {
+            return Err(EncodingError::UnexpectedKind(bytes[1]));
+        }
+        let hash_algorithm = match HashAlgorithm::from_id(bytes[2]) {
+            Some(hash_algorithm) => hash_algorithm,
+            None => return Err(EncodingError::UnknownHashAlgorithm(bytes[2]))
+        };
+        let tree_format = match TreeFormat::from_id(bytes[3]) {
+            Some(tree_format) => tree_format,
+            None => return Err(EncodingError::UnknownTreeFormat(bytes[3]))
+        };
+        Ok(EncodingReader { bytes: &bytes[ENCODING_HEADER_BYTES..], hash_algorithm, tree_format })
+    }
+
+    fn take(&mut self, n_bytes: usize) -> Result<&'a [u8], EncodingError> {
+        if self.bytes.len() < n_bytes {
+            return Err(EncodingError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(n_bytes);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn read_usize(&mut self) -> Result<usize, EncodingError> {
+        let value = u64::from_be_bytes(self.take(8)?.try_into().unwrap());
+        usize::try_from(value).map_err(|_| EncodingError::Truncated)
+    }
+
+    fn read_hash(&mut self) -> Result<Bytes32, EncodingError> {
+        Ok(Bytes32(self.take(32)?.try_into().unwrap()))
+    }
+
+    // Counts are checked against the bytes left before allocating, so a corrupted one fails fast
+    fn read_count(&mut self, item_bytes: usize) -> Result<usize, EncodingError> {
+        let count = self.read_usize()?;
+        if count > self.bytes.len() / item_bytes {
+            return Err(EncodingError::Truncated);
+        }
+        Ok(count)
+    }
+
+    fn read_hashes(&mut self) -> Result<Vec<Bytes32>, EncodingError> {
+        let count = self.read_count(32)?;
+        (0..count).map(|_| self.read_hash()).collect()
+    }
+
+    fn finish(self) -> Result<(), EncodingError> {
+        match self.bytes.len() {
+            0 => Ok(()),
+            n_bytes => Err(EncodingError::TrailingBytes(n_bytes))
+        }
+    }
+}
+
+/// Portable form of a `MerkleTree`: its leaves, already hashed, and its root, so that the tree can be
+/// rebuilt and checked by other systems. Internal nodes are left out as they follow from the leaves.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PortableTree {
+    #[serde(deserialize_with = "deserialize_encoding_version")]
+    pub version: u8,
+    pub hash_algorithm: HashAlgorithm,
+    pub tree_format: TreeFormat,
+    pub leaves: Vec<Bytes32>,
+    /// Absent for an empty tree
+    pub root: Option<MerkleRoot>
+}
+
+impl PortableTree {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = EncodingWriter::new(ENCODING_KIND_TREE, self.hash_algorithm, self.tree_format);
+        writer.write_hashes(&self.leaves);
+        if let Some(root) = &self.root {
+            writer.write_hash(root);
+        }
+        writer.bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EncodingError> {
+        let mut reader = EncodingReader::new(bytes, ENCODING_KIND_TREE)?;
+        let leaves = reader.read_hashes()?;
+        let root = if leaves.is_empty() { None } else { Some(reader.read_hash()?) };
+        let tree = PortableTree {
+            version: ENCODING_VERSION,
+            hash_algorithm: reader.hash_algorithm,
+            tree_format: reader.tree_format,
+            leaves,
+            root
+        };
+        reader.finish()?;
+        Ok(tree)
+    }
+}
+
+/// Portable proof of a single leaf, carrying all that is needed to check it without the tree.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PortableProof {
+    #[serde(deserialize_with = "deserialize_encoding_version")]
+    pub version: u8,
+    pub hash_algorithm: HashAlgorithm,
+    pub tree_format: TreeFormat,
+    pub n_leaves: usize,
+    pub leaf_index: usize,
+    pub leaf: Bytes32,
+    pub proof: Vec<Bytes32>,
+    pub root: MerkleRoot
+}
+
+impl PortableProof {
+    /// Checks the proof along the path of the leaf at `leaf_index`, which takes a sibling exactly at the levels
+    /// where that leaf meets another node, see `verify_multiproof`. Siblings are hashed in ascending order,
+    /// so the position is only bound as far as the shape of its path tells it apart from the other leaves.
+    pub fn verify(&self) -> bool {
+        let multiproof = MultiProof { n_leaves: self.n_leaves, leaf_indices: vec![self.leaf_index], proof: self.proof.clone() };
+        self.hash_algorithm.verify_multiproof(self.tree_format, &self.root, std::slice::from_ref(&self.leaf), &multiproof)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = EncodingWriter::new(ENCODING_KIND_PROOF, self.hash_algorithm, self.tree_format);
+        writer.write_usize(self.n_leaves);
+        writer.write_usize(self.leaf_index);
+        writer.write_hash(&self.leaf);
+        writer.write_hash(&self.root);
+        writer.write_hashes(&self.proof);
+        writer.bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EncodingError> {
+        let mut reader = EncodingReader::new(bytes, ENCODING_KIND_PROOF)?;
+        let proof = PortableProof {
+            version: ENCODING_VERSION,
+            hash_algorithm: reader.hash_algorithm,
+            tree_format: reader.tree_format,
+            n_leaves: reader.read_usize()?,
+            leaf_index: reader.read_usize()?,
+            leaf: reader.read_hash()?,
+            root: reader.read_hash()?,
+            proof: reader.read_hashes()?
+        };
+        reader.finish()?;
+        Ok(proof)
+    }
+}
+
+/// Portable proof of several leaves of the same tree, see `verify_multiproof`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PortableMultiProof {
+    #[serde(deserialize_with = "deserialize_encoding_version")]
+    pub version: u8,
+    pub hash_algorithm: HashAlgorithm,
+    pub tree_format: TreeFormat,
+    /// Proven leaves, in the order of `multiproof.leaf_indices`
+    pub leaves: Vec<Bytes32>,
+    #[serde(flatten)]
+    pub multiproof: MultiProof<Bytes32>,
+    pub root: MerkleRoot
+}
+
+impl PortableMultiProof {
+    pub fn verify(&self) -> bool {
+        self.hash_algorithm.verify_multiproof(self.tree_format, &self.root, &self.leaves, &self.multiproof)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = EncodingWriter::new(ENCODING_KIND_MULTIPROOF, self.hash_algorithm, self.tree_format);
+        writer.write_usize(self.multiproof.n_leaves);
+        writer.write_hash(&self.root);
+        writer.write_usize(self.leaves.len());
+        for (leaf_index, leaf) in self.multiproof.leaf_indices.iter().zip(self.leaves.iter()) {
+            writer.write_usize(*leaf_index);
+            writer.write_hash(leaf);
+        }
+        writer.write_hashes(&self.multiproof.proof);
+        writer.bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EncodingError> {
+        let mut reader = EncodingReader::new(bytes, ENCODING_KIND_MULTIPROOF)?;
+        let n_leaves = reader.read_usize()?;
+        let root = reader.read_hash()?;
+        let n_proven = reader.read_count(8+32)?;
+        let mut leaf_indices = Vec::with_capacity(n_proven);
+        let mut leaves = Vec::with_capacity(n_proven);
+        for _ in 0..n_proven {
+            leaf_indices.push(reader.read_usize()?);
+            leaves.push(reader.read_hash()?);
+        }
+        let proof = reader.read_hashes()?;
+        let multiproof = PortableMultiProof {
+            version: ENCODING_VERSION,
+            hash_algorithm: reader.hash_algorithm,
+            tree_format: reader.tree_format,
+            leaves,
+            multiproof: MultiProof { n_leaves, leaf_indices, proof },
+            root
+        };
+        reader.finish()?;
+        Ok(multiproof)
+    }
+}
+
+impl <H: Hasher<ReturnType = Bytes32>> From<&MerkleTree<H>> for PortableTree {
+    fn from(value: &MerkleTree<H>) -> Self {
+        PortableTree {
+            version: ENCODING_VERSION,
+            hash_algorithm: H::ALGORITHM,
+            tree_format: value.format,
+            leaves: value.all_leaves().cloned().collect(),
+            root: value.current_root()
         }
-        H::hash(hash_buffer)
+    }
+}
+
+impl <H: Hasher<ReturnType = Bytes32>> TryFrom<PortableTree> for MerkleTree<H> {
+
+    type Error = EncodingError;
+
+    fn try_from(value: PortableTree) -> Result<Self, Self::Error> {
+        if value.hash_algorithm != H::ALGORITHM {
+            return Err(EncodingError::HashAlgorithmMismatch(value.hash_algorithm));
+        }
+        let mut mt = MerkleTree::with_format(value.tree_format);
+        mt.append_leaves(value.leaves);
+        if mt.root() != value.root.as_ref() {
+            return Err(EncodingError::RootMismatch);
+        }
+        Ok(mt)
+    }
+}
+
+impl <H: Hasher<ReturnType = Bytes32>> Serialize for MerkleTree<H> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        PortableTree::from(self).serialize(serializer)
+    }
+}
+
+impl <'de, H: Hasher<ReturnType = Bytes32>> Deserialize<'de> for MerkleTree<H> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        MerkleTree::try_from(PortableTree::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+impl <H: Hasher<ReturnType = Bytes32>> MerkleTree<H> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        PortableTree::from(self).to_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EncodingError> {
+        MerkleTree::try_from(PortableTree::from_bytes(bytes)?)
+    }
+
+    /// Proof of the leaf at the given position in its portable form.
+    pub fn portable_proof(&mut self, leaf_index: usize) -> Option<PortableProof> {
+        let proof = self.proof_by_index(leaf_index)?;
+        Some(PortableProof {
+            version: ENCODING_VERSION,
+            hash_algorithm: H::ALGORITHM,
+            tree_format: self.format,
+            n_leaves: self.len(),
+            leaf_index,
+            leaf: self.nodes[leaf_index].clone(),
+            proof,
+            root: self.root()?.clone()
+        })
+    }
+
+    /// Multiproof of the given leaves in its portable form.
+    pub fn portable_multiproof(&mut self, leaves: &[Bytes32]) -> Option<PortableMultiProof> {
+        let multiproof = self.multiproof(leaves)?;
+        Some(PortableMultiProof {
+            version: ENCODING_VERSION,
+            hash_algorithm: H::ALGORITHM,
+            tree_format: self.format,
+            leaves: leaves.to_vec(),
+            multiproof,
+            root: self.root()?.clone()
+        })
     }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::common::hash_algorithm::HashAlgorithm;
     use crate::common::prelude::{Hasher, Bytes32};
 
-    use super::{MerkleTree, Keccak256};
+    use super::{verify_multiproof, verify_proof, EncodingError, MerkleAccumulator, MerkleTree, Keccak256, PortableMultiProof, PortableProof, Sha256, TreeFormat};
 
     #[test]
     fn test_merkle_tree_with_odd_elements() {
@@ -303,6 +1052,257 @@ mod test {
         assert!(mt.verify(&expected_root, &Vec::<Bytes32>::new()));
     }
 
+    #[test]
+    fn test_merkle_proofs_for_every_leaf() {
+        // Sizes with several odd levels (e.g. 7, 11, 13) used to drop nodes
+        for n_leaves in 1..=40u32 {
+            let mut mt = MerkleTree::<Keccak256>::new();
+            for i in 0..n_leaves {
+                mt.append(&i.to_be_bytes());
+            }
+            mt.root();
+            assert_eq!(mt.nodes.len(), n_leaves as usize*2-1, "Merkle Tree of {} leaves has an unexpected number of nodes", n_leaves);
+            for i in 0..n_leaves {
+                let leaf = Keccak256::hash(i.to_be_bytes());
+                let proof = mt.proof(&leaf).unwrap();
+                assert!(mt.verify(&leaf, &proof), "Proof verification failed for leaf {} of {}", i, n_leaves);
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify_proof_without_the_tree() {
+        let mut mt = MerkleTree::<Keccak256>::new();
+        for value in ["a", "b", "c", "d", "e", "f", "g"] {
+            mt.append(&value);
+        }
+        let root = mt.root().unwrap().clone();
+        let leaf = Keccak256::hash("c");
+        let proof = mt.proof(&leaf).unwrap();
+        assert!(verify_proof::<Keccak256>(TreeFormat::V0, &root, &leaf, &proof));
+        assert!(!verify_proof::<Keccak256>(TreeFormat::V0, &root, &Keccak256::hash("z"), &proof));
+        assert!(!verify_proof::<Keccak256>(TreeFormat::V0, &root, &leaf, &proof[1..]));
+        assert!(!verify_proof::<Keccak256>(TreeFormat::V0, &root, &leaf, &[]));
+        assert!(verify_proof::<Keccak256>(TreeFormat::V0, &leaf, &leaf, &[]));
+    }
+
+    #[test]
+    fn test_multiproof_deduplicates_shared_siblings() {
+        for n_leaves in 1..=24u32 {
+            let mut mt = MerkleTree::<Keccak256>::new();
+            for i in 0..n_leaves {
+                mt.append(&i.to_be_bytes());
+            }
+            let root = mt.root().unwrap().clone();
+            for segment_start in 0..n_leaves {
+                let leaves: Vec<Bytes32> = (segment_start..n_leaves).map(|i| Keccak256::hash(i.to_be_bytes())).collect();
+                let multiproof = mt.multiproof(&leaves).unwrap();
+                assert_eq!(multiproof.n_leaves, n_leaves as usize);
+                assert!(verify_multiproof::<Keccak256>(TreeFormat::V0, &root, &leaves, &multiproof), "Multiproof of leaves {}.. of {} failed", segment_start, n_leaves);
+                let single_proofs_len: usize = leaves.iter().map(|leaf| mt.proof(leaf).unwrap().len()).sum();
+                assert!(multiproof.proof.len() <= single_proofs_len);
+            }
+        }
+        let mut mt = MerkleTree::<Keccak256>::new();
+        for value in ["a", "b", "c", "d", "e", "f", "g"] {
+            mt.append(&value);
+        }
+        let root = mt.root().unwrap().clone();
+        let leaves = vec![Keccak256::hash("b"), Keccak256::hash("a"), Keccak256::hash("d")];
+        let multiproof = mt.multiproof(&leaves).unwrap();
+        assert_eq!(multiproof.leaf_indices, vec![1, 0, 3]);
+        // Only "c" and the node of the "e", "f", "g" subtree are missing
+        assert_eq!(multiproof.proof, vec![Keccak256::hash("c"), mt.nodes[11].clone()]);
+        let single_leaf_multiproof = mt.multiproof(&leaves[2..]).unwrap();
+        assert_eq!(single_leaf_multiproof.proof, mt.proof(&leaves[2]).unwrap());
+
+        assert!(!verify_multiproof::<Keccak256>(TreeFormat::V0, &root, &leaves[..2], &multiproof));
+        assert!(!verify_multiproof::<Keccak256>(TreeFormat::V0, &root, &[leaves[0].clone(), leaves[2].clone(), leaves[1].clone()], &multiproof));
+        let mut truncated = multiproof.clone();
+        truncated.proof.pop();
+        assert!(!verify_multiproof::<Keccak256>(TreeFormat::V0, &root, &leaves, &truncated));
+        let mut out_of_range = multiproof.clone();
+        out_of_range.leaf_indices[0] = 7;
+        assert!(!verify_multiproof::<Keccak256>(TreeFormat::V0, &root, &leaves, &out_of_range));
+        assert!(mt.multiproof(&[Keccak256::hash("z")]).is_none());
+    }
+
+    #[test]
+    fn test_leaf_lookup_ignores_internal_nodes() {
+        let mut mt = MerkleTree::<Keccak256>::new();
+        for value in ["a", "b", "c"] {
+            mt.append(&value);
+        }
+        let internal_node = mt.root().unwrap().clone();
+        assert_eq!(mt.len(), 3);
+        assert_eq!(mt.leaf_index(&Keccak256::hash("c")), Some(2));
+        assert!(mt.proof(&internal_node).is_none(), "Proof generated for an internal node");
+        assert!(mt.proof_by_index(3).is_none());
+
+        // Leaves appended after a computation keep counting from the computed ones
+        mt.append(&"d");
+        assert_eq!(mt.len(), 4);
+        assert_eq!(mt.leaf_index(&Keccak256::hash("d")), Some(3));
+        let root = mt.root().unwrap().clone();
+        for leaf_index in 0..4 {
+            let leaf = mt.nodes[leaf_index].clone();
+            let proof = mt.proof_by_index(leaf_index).unwrap();
+            assert_eq!(Some(proof.clone()), mt.proof(&leaf));
+            assert!(verify_proof::<Keccak256>(TreeFormat::V0, &root, &leaf, &proof));
+        }
+    }
+
+    #[test]
+    fn test_accumulator_root_matches_the_tree() {
+        let mut mt = MerkleTree::<Keccak256>::new();
+        let mut accumulator = MerkleAccumulator::<Keccak256>::new();
+        assert!(accumulator.root().is_none());
+        for i in 0..300u32 {
+            mt.append(&i.to_be_bytes());
+            assert_eq!(accumulator.append(&i.to_be_bytes()), i as usize + 1);
+            assert_eq!(accumulator.root().as_ref(), mt.root(), "Different roots with {} leaves", i + 1);
+            assert!(accumulator.peaks.len() <= 32 - (i + 1).leading_zeros() as usize);
+        }
+        let restored: MerkleAccumulator<Keccak256> = serde_json::from_str(&serde_json::to_string(&accumulator).unwrap()).unwrap();
+        assert_eq!(restored.len(), 300);
+        assert_eq!(restored.root(), accumulator.root());
+    }
+
+    #[test]
+    fn test_parallel_computation_matches_the_sequential_one() {
+        for n_leaves in [2usize, 3, 5, 8, 13, 100, 1023, 1025, 2500] {
+            let leaves: Vec<Bytes32> = (0..n_leaves as u32).map(|i| TreeFormat::V1.leaf_hash::<Keccak256, _>(i.to_be_bytes())).collect();
+            let mut sequential_mt = MerkleTree::<Keccak256>::with_format(TreeFormat::V1);
+            let mut parallel_mt = MerkleTree::<Keccak256>::with_format(TreeFormat::V1);
+            assert_eq!(sequential_mt.append_leaves(leaves.clone()), n_leaves);
+            parallel_mt.append_leaves(leaves);
+            sequential_mt.compute_with_threshold(usize::MAX);
+            // Every level is hashed in parallel
+            parallel_mt.compute_with_threshold(1);
+            assert_eq!(parallel_mt.nodes, sequential_mt.nodes, "Different trees with {} leaves", n_leaves);
+            assert_eq!(parallel_mt.root(), sequential_mt.root());
+        }
+    }
+
+    #[test]
+    fn test_tree_formats_separate_leaves_from_internal_nodes() {
+        let values = ["a", "b", "c", "d"];
+        let mut legacy_mt = MerkleTree::<Keccak256>::new();
+        let mut v1_mt = MerkleTree::<Keccak256>::with_format(TreeFormat::V1);
+        let mut oz_mt = MerkleTree::<Keccak256>::with_format(TreeFormat::OpenZeppelin);
+        for value in values {
+            legacy_mt.append(&value);
+            v1_mt.append(&value);
+            oz_mt.append(&value);
+        }
+        // The legacy format is the one of the trees built so far
+        assert_eq!(legacy_mt.format(), TreeFormat::V0);
+        assert_eq!(*legacy_mt.root().unwrap(), Bytes32::try_from("68203f90e9d07dc5859259d7536e87a6ba9d345f2552b5b9de2999ddce9ce1bf").unwrap());
+        assert_ne!(v1_mt.root(), legacy_mt.clone().root());
+        assert_ne!(oz_mt.root(), legacy_mt.clone().root());
+
+        // The node of "a" and "b" with the node of "c" and "d" as proof passes for a leaf in the legacy format only
+        for (mt, expected) in [(&mut legacy_mt, true), (&mut v1_mt, false), (&mut oz_mt, false)] {
+            let format = mt.format();
+            let root = mt.root().unwrap().clone();
+            let internal_node = mt.nodes[4].clone();
+            let sibling = mt.nodes[5].clone();
+            assert_eq!(format.node_hash::<Keccak256>(&internal_node, &sibling), root);
+            let (first, second) = if mt.nodes[0] < mt.nodes[1] { (&mt.nodes[0], &mt.nodes[1]) } else { (&mt.nodes[1], &mt.nodes[0]) };
+            let forged_leaf = format.leaf_hash::<Keccak256, _>([first.as_ref(), second.as_ref()].concat());
+            assert_eq!(forged_leaf == internal_node, expected, "Internal node of a {} tree rebuilt as a leaf", format);
+            assert_eq!(verify_proof::<Keccak256>(format, &root, &forged_leaf, &[sibling]), expected);
+        }
+
+        assert_eq!(TreeFormat::V1.leaf_hash::<Keccak256, _>("a"), Keccak256::hash([&[0x00], "a".as_bytes()].concat()));
+        // OpenZeppelin StandardMerkleTree leaves are keccak256(bytes.concat(keccak256(abi.encode(data)))), a
+        // `bytes` value being encoded as the offset of its content, its length and the content padded to 32 bytes
+        let mut abi_encoded = vec![0u8; 96];
+        abi_encoded[31] = 0x20;
+        abi_encoded[63] = 1;
+        abi_encoded[64] = b'a';
+        assert_eq!(TreeFormat::OpenZeppelin.leaf_hash::<Keccak256, _>("a"), Keccak256::hash(Keccak256::hash(abi_encoded)));
+        let leaf = TreeFormat::OpenZeppelin.leaf_hash::<Keccak256, _>("c");
+        let proof = oz_mt.proof(&leaf).unwrap();
+        assert!(oz_mt.verify(&leaf, &proof));
+        assert!(!verify_proof::<Keccak256>(TreeFormat::V1, oz_mt.root().unwrap(), &leaf, &proof));
+    }
+
+    #[test]
+    fn test_portable_encodings_round_trip() {
+        let mut mt = MerkleTree::<Keccak256>::with_format(TreeFormat::V1);
+        for value in ["a", "b", "c", "d", "e"] {
+            mt.append(&value);
+        }
+        // Encoded before the root is computed, which must not change the encoding
+        let bytes = mt.to_bytes();
+        let json = serde_json::to_string(&mt).unwrap();
+        let root = mt.root().unwrap().clone();
+        assert_eq!(mt.to_bytes(), bytes);
+        assert_eq!(bytes.len(), 4 + 8 + 5*32 + 32);
+        for mut restored in [MerkleTree::<Keccak256>::from_bytes(&bytes).unwrap(), serde_json::from_str(&json).unwrap()] {
+            assert_eq!(restored.format(), TreeFormat::V1);
+            assert_eq!(restored.len(), 5);
+            assert_eq!(restored.root(), Some(&root));
+        }
+
+        let proof = mt.portable_proof(3).unwrap();
+        assert_eq!((proof.leaf_index, proof.n_leaves, &proof.root), (3, 5, &root));
+        assert_eq!(proof.leaf, TreeFormat::V1.leaf_hash::<Keccak256, _>("d"));
+        assert!(proof.verify());
+        assert_eq!(PortableProof::from_bytes(&proof.to_bytes()).unwrap(), proof);
+        assert_eq!(serde_json::from_str::<PortableProof>(&serde_json::to_string(&proof).unwrap()).unwrap(), proof);
+        assert!(mt.portable_proof(5).is_none());
+
+        let leaves = vec![TreeFormat::V1.leaf_hash::<Keccak256, _>("e"), TreeFormat::V1.leaf_hash::<Keccak256, _>("a")];
+        let multiproof = mt.portable_multiproof(&leaves).unwrap();
+        assert!(multiproof.verify());
+        assert_eq!(PortableMultiProof::from_bytes(&multiproof.to_bytes()).unwrap(), multiproof);
+        let multiproof_json = serde_json::to_value(&multiproof).unwrap();
+        assert_eq!(multiproof_json["leaf_indices"], serde_json::json!([4, 0]));
+        assert_eq!(serde_json::from_value::<PortableMultiProof>(multiproof_json).unwrap(), multiproof);
+
+        let empty_mt = MerkleTree::<Keccak256>::from_bytes(&MerkleTree::<Keccak256>::new().to_bytes()).unwrap();
+        assert!(empty_mt.is_empty());
+    }
+
+    #[test]
+    fn test_portable_encodings_reject_invalid_input() {
+        let mut mt = MerkleTree::<Keccak256>::new();
+        for value in ["a", "b", "c"] {
+            mt.append(&value);
+        }
+        let bytes = mt.to_bytes();
+        assert_eq!(MerkleTree::<Sha256>::from_bytes(&bytes).unwrap_err(), EncodingError::HashAlgorithmMismatch(HashAlgorithm::Keccak256));
+        assert_eq!(MerkleTree::<Keccak256>::from_bytes(&bytes[..bytes.len()-1]).unwrap_err(), EncodingError::Truncated);
+        assert_eq!(MerkleTree::<Keccak256>::from_bytes(&[bytes.as_slice(), &[0]].concat()).unwrap_err(), EncodingError::TrailingBytes(1));
+        let mut tampered = bytes.clone();
+        tampered[20] ^= 1;
+        assert_eq!(MerkleTree::<Keccak256>::from_bytes(&tampered).unwrap_err(), EncodingError::RootMismatch);
+        let mut future_version = bytes.clone();
+        future_version[0] = 2;
+        assert_eq!(MerkleTree::<Keccak256>::from_bytes(&future_version).unwrap_err(), EncodingError::UnsupportedVersion(2));
+        // A huge count is not trusted to size an allocation
+        let mut huge_count = bytes.clone();
+        huge_count[4..12].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(MerkleTree::<Keccak256>::from_bytes(&huge_count).is_err());
+        assert_eq!(PortableProof::from_bytes(&bytes).unwrap_err(), EncodingError::UnexpectedKind(0));
+
+        let mut proof = serde_json::to_value(mt.portable_proof(0).unwrap()).unwrap();
+        proof["version"] = serde_json::json!(2);
+        assert!(serde_json::from_value::<PortableProof>(proof).is_err());
+        let mut forged = mt.portable_proof(0).unwrap();
+        forged.leaf = Keccak256::hash("z");
+        assert!(!forged.verify());
+        // "c" is carried up to the root, so its path has a single sibling while the one of "a" has two
+        let mut moved = mt.portable_proof(0).unwrap();
+        moved.leaf_index = 2;
+        assert!(!moved.verify(), "Proof accepted for a leaf index with another path");
+        let mut moved = mt.portable_proof(2).unwrap();
+        moved.leaf_index = 1;
+        assert!(!moved.verify(), "Proof accepted for a leaf index with another path");
+    }
+
     #[test]
     fn test_merkle_root_with_no_elements() {
         let mut mt = MerkleTree::<Keccak256>::new();
@@ -1,3 +1,7 @@
 pub mod bytes;
+pub mod hash_algorithm;
 pub mod merkle;
-pub mod prelude;
\ No newline at end of file
+pub mod prelude;
+pub mod signature;
+pub mod sparse_merkle;
+pub mod x509;
@@ -1,2 +1,2 @@
-pub use super::merkle::{Hasher, MerkleRoot, MerkleTree};
+pub use super::merkle::{verify_multiproof, verify_proof, Hasher, MerkleAccumulator, MultiProof, MerkleRoot, MerkleTree, TreeFormat};
 pub use super::bytes::Bytes32;
\ No newline at end of file
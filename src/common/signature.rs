@@ -0,0 +1,168 @@
+use std::fmt::Display;
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use ed25519_dalek::{Signature, VerifyingKey};
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::ecdsa::signature::Verifier;
+use serde::{Deserialize, Serialize};
+
+/// Signature scheme of a device key.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum KeyType {
+    /// 32 bytes keys. The type of the devices registered before key types were recorded
+    #[default]
+    Ed25519,
+    /// ECDSA over secp256k1 with SHA-256, 33 (compressed) or 65 (uncompressed) bytes SEC1 keys
+    Secp256k1,
+    /// ECDSA over NIST P-256 with SHA-256, 33 (compressed) or 65 (uncompressed) bytes SEC1 keys
+    P256
+}
+
+impl Display for KeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl KeyType {
+    /// Id of the key type, as passed to the contract.
+    pub fn id(&self) -> u8 {
+        match self {
+            KeyType::Ed25519 => 0,
+            KeyType::Secp256k1 => 1,
+            KeyType::P256 => 2
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(KeyType::Ed25519),
+            1 => Some(KeyType::Secp256k1),
+            2 => Some(KeyType::P256),
+            _ => None
+        }
+    }
+
+    /// Checks that the bytes are a valid key of this type and gives its canonical encoding, which is the
+    /// compressed point for the ECDSA keys, so that both SEC1 encodings of a key give the same device.
+    pub fn canonical_key(&self, pk: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            KeyType::Ed25519 => {
+                let pk: &[u8; 32] = pk.try_into().ok()?;
+                VerifyingKey::from_bytes(pk).ok().map(|_| pk.to_vec())
+            },
+            KeyType::Secp256k1 => k256::PublicKey::from_sec1_bytes(pk).ok()
+                .map(|pk| pk.to_encoded_point(true).as_bytes().to_vec()),
+            KeyType::P256 => p256::PublicKey::from_sec1_bytes(pk).ok()
+                .map(|pk| pk.to_encoded_point(true).as_bytes().to_vec())
+        }
+    }
+
+    /// Checks a base64 encoded signature of `message` by the holder of `pk`. ECDSA signatures are the 64
+    /// bytes of `r` and `s`.
+    ///
+    /// Verification is strict: weak Ed25519 keys, non canonical Ed25519 signatures and secp256k1 signatures
+    /// with a high `s` are rejected, so that a valid signature can not be altered into another one that
+    /// still passes. P-256 signatures are accepted with either `s`, as P-256 hardware does not normalize it.
+    pub fn verify(&self, pk: &[u8], message: &[u8], signature: &str) -> bool {
+        let signature = match STANDARD.decode(signature) {
+            Ok(signature) => signature,
+            Err(_) => return false
+        };
+        match self {
+            KeyType::Ed25519 => verify_ed25519(pk, message, &signature),
+            KeyType::Secp256k1 => {
+                let (verifying_key, signature) = match (k256::ecdsa::VerifyingKey::from_sec1_bytes(pk), k256::ecdsa::Signature::from_slice(&signature)) {
+                    (Ok(verifying_key), Ok(signature)) => (verifying_key, signature),
+                    _ => return false
+                };
+                signature.normalize_s().is_none() && verifying_key.verify(message, &signature).is_ok()
+            },
+            KeyType::P256 => {
+                let (verifying_key, signature) = match (p256::ecdsa::VerifyingKey::from_sec1_bytes(pk), p256::ecdsa::Signature::from_slice(&signature)) {
+                    (Ok(verifying_key), Ok(signature)) => (verifying_key, signature),
+                    _ => return false
+                };
+                verifying_key.verify(message, &signature).is_ok()
+            }
+        }
+    }
+}
+
+fn verify_ed25519(pk: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let verifying_key = match pk.try_into().map(VerifyingKey::from_bytes) {
+        Ok(Ok(verifying_key)) => verifying_key,
+        _ => return false
+    };
+    let signature = match Signature::from_slice(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false
+    };
+    verifying_key.verify_strict(message, &signature).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use base64::{Engine as _, engine::general_purpose::STANDARD};
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::KeyType;
+
+    #[test]
+    fn test_verify_ed25519() {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let pk = signing_key.verifying_key().to_bytes();
+        let signature = STANDARD.encode(signing_key.sign(b"message").to_bytes());
+        assert!(KeyType::Ed25519.verify(&pk, b"message", &signature));
+        assert!(!KeyType::Ed25519.verify(&pk, b"other message", &signature));
+        let other_pk = SigningKey::from_bytes(&[8u8; 32]).verifying_key().to_bytes();
+        assert!(!KeyType::Ed25519.verify(&other_pk, b"message", &signature));
+        assert!(!KeyType::Ed25519.verify(&pk, b"message", &signature[4..]));
+        assert!(!KeyType::Ed25519.verify(&pk, b"message", "not base64"));
+        assert!(!KeyType::Ed25519.verify(&pk, b"message", ""));
+        assert!(!KeyType::Ed25519.verify(&pk[1..], b"message", &signature));
+    }
+
+    #[test]
+    fn test_verify_ecdsa() {
+        let k256_key = k256::ecdsa::SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let k256_signature: k256::ecdsa::Signature = k256_key.sign(b"message");
+        let p256_key = p256::ecdsa::SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let p256_signature: p256::ecdsa::Signature = p256_key.sign(b"message");
+        let cases = [
+            (KeyType::Secp256k1, k256_key.verifying_key().to_encoded_point(true).as_bytes().to_vec(), k256_key.verifying_key().to_encoded_point(false).as_bytes().to_vec(), k256_signature.to_bytes().to_vec()),
+            (KeyType::P256, p256_key.verifying_key().to_encoded_point(true).as_bytes().to_vec(), p256_key.verifying_key().to_encoded_point(false).as_bytes().to_vec(), p256_signature.to_bytes().to_vec())
+        ];
+        for (key_type, compressed_pk, uncompressed_pk, signature) in cases {
+            assert_eq!((compressed_pk.len(), uncompressed_pk.len()), (33, 65));
+            assert_eq!(key_type.canonical_key(&uncompressed_pk), Some(compressed_pk.clone()));
+            assert_eq!(key_type.canonical_key(&compressed_pk), Some(compressed_pk.clone()));
+            let encoded_signature = STANDARD.encode(&signature);
+            for pk in [&compressed_pk, &uncompressed_pk] {
+                assert!(key_type.verify(pk, b"message", &encoded_signature), "{} signature not verified", key_type);
+                assert!(!key_type.verify(pk, b"other message", &encoded_signature));
+            }
+            // The same signature with s negated is valid ECDSA, accepted only on P-256
+            let mut negated_s = signature.clone();
+            negated_s.splice(32.., negate_s(key_type, &signature[32..]));
+            assert_eq!(key_type.verify(&compressed_pk, b"message", &STANDARD.encode(&negated_s)), key_type == KeyType::P256);
+        }
+        let ed25519_pk = SigningKey::from_bytes(&[7u8; 32]).verifying_key().to_bytes();
+        assert!(KeyType::Secp256k1.canonical_key(&ed25519_pk).is_none());
+        assert!(KeyType::P256.canonical_key(&[2u8; 12]).is_none());
+        assert!(KeyType::Ed25519.canonical_key(&[2u8; 33]).is_none());
+    }
+
+    fn negate_s(key_type: KeyType, s: &[u8]) -> Vec<u8> {
+        match key_type {
+            KeyType::Secp256k1 => {
+                let s = k256::NonZeroScalar::try_from(s).unwrap();
+                (-s).to_bytes().to_vec()
+            },
+            _ => {
+                let s = p256::NonZeroScalar::try_from(s).unwrap();
+                (-s).to_bytes().to_vec()
+            }
+        }
+    }
+}
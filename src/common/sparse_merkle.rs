@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Serialize};
+
+use super::bytes::Bytes32;
+use super::merkle::{Hasher, PARALLEL_HASHING_THRESHOLD};
+
+/// Number of bits of a key, which is the depth of the leaves.
+pub const SPARSE_MERKLE_TREE_DEPTH: usize = 256;
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+type Entry<'a, T> = (&'a [u8; 32], &'a T);
+type Halves<'a, T> = (&'a [Entry<'a, T>], &'a [Entry<'a, T>]);
+
+/// Proof of the value at a key of a `SparseMerkleTree`, which may be no value at all.
+///
+/// Empty subtrees are left out of the proof, so the depth of each sibling is listed with it.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SparseMerkleProof<T> {
+    /// Depth of each non empty sibling, from the leaf up. The root is at depth 0 and leaves at 256
+    pub sibling_depths: Vec<u16>,
+    pub siblings: Vec<T>
+}
+
+/// Checks that the key holds `value` in the tree of `root`, or that it is empty when `value` is `None`.
+/// An empty tree has no root, so nothing can be proven against it.
+pub fn verify_sparse_proof<H: Hasher>(root: &H::ReturnType, key: &Bytes32, value: Option<&H::ReturnType>, proof: &SparseMerkleProof<H::ReturnType>) -> bool {
+    if proof.sibling_depths.len() != proof.siblings.len() {
+        return false;
+    }
+    let mut node = value.map(|value| leaf_hash::<H>(key, value));
+    let mut siblings = proof.sibling_depths.iter().zip(proof.siblings.iter()).peekable();
+    for depth in (1..=SPARSE_MERKLE_TREE_DEPTH).rev() {
+        let sibling = match siblings.peek() {
+            Some((sibling_depth, sibling)) if **sibling_depth as usize == depth => {
+                let sibling = (*sibling).clone();
+                siblings.next();
+                Some(sibling)
+            },
+            _ => None
+        };
+        node = if key_bit(key, depth-1) {
+            node_hash::<H>(sibling.as_ref(), node.as_ref())
+        } else {
+            node_hash::<H>(node.as_ref(), sibling.as_ref())
+        };
+    }
+    // Depths not in descending order are left behind
+    siblings.next().is_none() && node.as_ref() == Some(root)
+}
+
+/// Merkle tree with a leaf for every possible 256 bits key, almost all of them empty.
+///
+/// Leaves lie at the position given by the bits of their key, so the path to an empty one proves the
+/// key has no value. Leaves hash the key along with the value and are prefixed with `0x00`, internal
+/// nodes with `0x01`. Empty subtrees have no hash, and a node with both children empty is empty too.
+#[derive(Clone, Debug)]
+pub struct SparseMerkleTree<H: Hasher> {
+    leaves: BTreeMap<[u8; 32], H::ReturnType>,
+    hasher: PhantomData<H>
+}
+
+impl <H: Hasher> Default for SparseMerkleTree<H> {
+    fn default() -> Self {
+        SparseMerkleTree {
+            leaves: BTreeMap::new(),
+            hasher: PhantomData
+        }
+    }
+}
+
+impl <H: Hasher> SparseMerkleTree<H> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the value of the key, returning the one it replaces.
+    pub fn insert(&mut self, key: &Bytes32, value: H::ReturnType) -> Option<H::ReturnType> {
+        self.leaves.insert(key.0, value)
+    }
+
+    pub fn get(&self, key: &Bytes32) -> Option<&H::ReturnType> {
+        self.leaves.get(&key.0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn root(&self) -> Option<H::ReturnType> {
+        let leaves: Vec<Entry<H::ReturnType>> = self.leaves.iter().collect();
+        Self::subtree_root(&leaves, 0)
+    }
+
+    /// Proof of the value of the key, or of its absence.
+    pub fn proof(&self, key: &Bytes32) -> SparseMerkleProof<H::ReturnType> {
+        let leaves: Vec<Entry<H::ReturnType>> = self.leaves.iter().collect();
+        let mut subtree = &leaves[..];
+        let mut sibling_depths = Vec::new();
+        let mut siblings = Vec::new();
+        for depth in 0..SPARSE_MERKLE_TREE_DEPTH {
+            if subtree.is_empty() {
+                break;
+            }
+            let (left, right) = Self::split(subtree, depth);
+            let (path, other) = if key_bit(key, depth) { (right, left) } else { (left, right) };
+            if let Some(sibling) = Self::subtree_root(other, depth+1) {
+                sibling_depths.push((depth+1) as u16);
+                siblings.push(sibling);
+            }
+            subtree = path;
+        }
+        sibling_depths.reverse();
+        siblings.reverse();
+        SparseMerkleProof { sibling_depths, siblings }
+    }
+
+    // Leaves are sorted by key, so those under a node are contiguous and split by the bit of its depth
+    fn split<'a>(leaves: &'a [Entry<'a, H::ReturnType>], depth: usize) -> Halves<'a, H::ReturnType> {
+        let split_index = leaves.partition_point(|(key, _)| !bit_at(key, depth));
+        leaves.split_at(split_index)
+    }
+
+    fn subtree_root(leaves: &[Entry<H::ReturnType>], depth: usize) -> Option<H::ReturnType> {
+        match leaves.len() {
+            0 => None,
+            1 if depth == SPARSE_MERKLE_TREE_DEPTH => Some(leaf_hash::<H>(&Bytes32(*leaves[0].0), leaves[0].1)),
+            _ => {
+                let (left, right) = Self::split(leaves, depth);
+                // Both halves of a large subtree are hashed in parallel, which does not change their roots
+                let (left_root, right_root) = if leaves.len() >= PARALLEL_HASHING_THRESHOLD {
+                    rayon::join(|| Self::subtree_root(left, depth+1), || Self::subtree_root(right, depth+1))
+                } else {
+                    (Self::subtree_root(left, depth+1), Self::subtree_root(right, depth+1))
+                };
+                node_hash::<H>(left_root.as_ref(), right_root.as_ref())
+            }
+        }
+    }
+}
+
+fn bit_at(key: &[u8; 32], index: usize) -> bool {
+    (key[index/8] >> (7 - index%8)) & 1 == 1
+}
+
+fn key_bit(key: &Bytes32, index: usize) -> bool {
+    bit_at(&key.0, index)
+}
+
+fn leaf_hash<H: Hasher>(key: &Bytes32, value: &H::ReturnType) -> H::ReturnType {
+    let mut hash_buffer = Vec::with_capacity(1+key.0.len()+value.as_ref().len());
+    hash_buffer.push(LEAF_PREFIX);
+    hash_buffer.extend_from_slice(&key.0);
+    hash_buffer.extend_from_slice(value.as_ref());
+    H::hash(hash_buffer)
+}
+
+// Children are kept in order, their position being given by the key. An empty child counts as zeros
+fn node_hash<H: Hasher>(left: Option<&H::ReturnType>, right: Option<&H::ReturnType>) -> Option<H::ReturnType> {
+    let node_len = match (left, right) {
+        (None, None) => return None,
+        (Some(node), _) | (None, Some(node)) => node.as_ref().len()
+    };
+    let mut hash_buffer = Vec::with_capacity(1+node_len*2);
+    hash_buffer.push(NODE_PREFIX);
+    for child in [left, right] {
+        match child {
+            Some(node) => hash_buffer.extend_from_slice(node.as_ref()),
+            None => hash_buffer.resize(hash_buffer.len()+node_len, 0)
+        }
+    }
+    Some(H::hash(hash_buffer))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::merkle::{Hasher, Keccak256};
+    use crate::common::prelude::Bytes32;
+
+    use super::{verify_sparse_proof, SparseMerkleTree};
+
+    fn key(value: &str) -> Bytes32 {
+        Keccak256::hash(value)
+    }
+
+    #[test]
+    fn test_sparse_merkle_inclusion_and_exclusion_proofs() {
+        let mut smt = SparseMerkleTree::<Keccak256>::new();
+        assert!(smt.root().is_none());
+        let keys: Vec<Bytes32> = (0..8).map(|i| key(&i.to_string())).collect();
+        for (i, k) in keys.iter().enumerate() {
+            assert!(smt.insert(k, Keccak256::hash(format!("value {}", i))).is_none());
+        }
+        let root = smt.root().unwrap();
+
+        for (i, k) in keys.iter().enumerate() {
+            let value = Keccak256::hash(format!("value {}", i));
+            let proof = smt.proof(k);
+            assert!(verify_sparse_proof::<Keccak256>(&root, k, Some(&value), &proof), "Inclusion of key {} not verified", i);
+            assert!(!verify_sparse_proof::<Keccak256>(&root, k, None, &proof), "Key {} proven absent", i);
+            assert!(!verify_sparse_proof::<Keccak256>(&root, k, Some(&Keccak256::hash("other")), &proof));
+        }
+        for absent in ["a", "b", "c"] {
+            let proof = smt.proof(&key(absent));
+            assert!(verify_sparse_proof::<Keccak256>(&root, &key(absent), None, &proof), "Exclusion of {} not verified", absent);
+            assert!(!verify_sparse_proof::<Keccak256>(&root, &key(absent), Some(&Keccak256::hash("value 0")), &proof));
+            // A proof is bound to its key
+            assert!(!verify_sparse_proof::<Keccak256>(&root, &keys[0], None, &proof));
+        }
+
+        let mut truncated = smt.proof(&keys[3]);
+        truncated.siblings.pop();
+        truncated.sibling_depths.pop();
+        assert!(!verify_sparse_proof::<Keccak256>(&root, &keys[3], Some(&Keccak256::hash("value 3")), &truncated));
+    }
+
+    #[test]
+    fn test_sparse_merkle_root_ignores_insertion_order() {
+        let mut smt = SparseMerkleTree::<Keccak256>::new();
+        let mut reversed_smt = SparseMerkleTree::<Keccak256>::new();
+        for i in 0..10 {
+            smt.insert(&key(&i.to_string()), key("value"));
+            reversed_smt.insert(&key(&(9 - i).to_string()), key("value"));
+        }
+        assert_eq!(smt.root(), reversed_smt.root());
+        assert_eq!(smt.insert(&key("0"), key("new value")), Some(key("value")));
+        assert_ne!(smt.root(), reversed_smt.root());
+        assert_eq!(smt.len(), 10);
+
+        // Keys sharing all the bits but the last one are siblings at the bottom of the tree
+        let mut smt = SparseMerkleTree::<Keccak256>::new();
+        let (mut left, mut right) = (Bytes32([0xab; 32]), Bytes32([0xab; 32]));
+        left.0[31] = 0xfe;
+        right.0[31] = 0xff;
+        smt.insert(&left, key("left"));
+        smt.insert(&right, key("right"));
+        let proof = smt.proof(&left);
+        assert_eq!(proof.sibling_depths, vec![256]);
+        assert!(verify_sparse_proof::<Keccak256>(&smt.root().unwrap(), &left, Some(&key("left")), &proof));
+    }
+}
@@ -0,0 +1,375 @@
+use std::fmt::Display;
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use p256::ecdsa::signature::hazmat::PrehashVerifier;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha384};
+use x509_cert::Certificate;
+use x509_cert::der::{Encode, oid::ObjectIdentifier};
+use x509_cert::ext::pkix::{BasicConstraints, KeyUsage};
+use x509_cert::spki::SubjectPublicKeyInfoOwned;
+
+use super::signature::KeyType;
+
+/// Longest accepted chain, from the device certificate to the one issued by a trusted root.
+pub const MAX_CHAIN_LENGTH: usize = 8;
+
+pub const ED25519_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+pub const EC_PUBLIC_KEY_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+pub const SECP256R1_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.3.1.7");
+pub const SECP256K1_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.132.0.10");
+pub const ECDSA_WITH_SHA256_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.2");
+pub const ECDSA_WITH_SHA384_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.3");
+
+/// What the manufacturer certificate of a device tells about it. Validity bounds are in milliseconds
+/// since the Unix epoch.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Attestation {
+    pub issuer: String,
+    pub serial: String,
+    pub not_before: u64,
+    pub not_after: u64
+}
+
+/// Device key certified by a chain of certificates, in the encoding given by `KeyType::canonical_key`.
+#[derive(Clone, Debug)]
+pub struct CertifiedKey {
+    pub key_type: KeyType,
+    pub pk: Vec<u8>,
+    pub attestation: Attestation
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AttestationError {
+    NoTrustedRoots,
+    BadPem,
+    EmptyChain,
+    ChainTooLong(usize),
+    NotValid(String),
+    IssuerMismatch(String),
+    NotACertificateAuthority(String),
+    UntrustedRoot(String),
+    UnsupportedAlgorithm(String),
+    UnsupportedKey(String),
+    BadSignature(String),
+    /// The certified key is not the one being registered
+    KeyMismatch
+}
+
+impl Display for AttestationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttestationError::NoTrustedRoots => write!(f, "no trusted root CAs configured"),
+            AttestationError::BadPem => write!(f, "not a PEM certificate chain"),
+            AttestationError::EmptyChain => write!(f, "no certificate in the chain"),
+            AttestationError::ChainTooLong(length) => write!(f, "{} certificates in the chain, at most {} accepted", length, MAX_CHAIN_LENGTH),
+            AttestationError::NotValid(subject) => write!(f, "certificate of {} expired or not yet valid", subject),
+            AttestationError::IssuerMismatch(subject) => write!(f, "certificate of {} not followed by the one of its issuer", subject),
+            AttestationError::NotACertificateAuthority(subject) => write!(f, "{} can't issue certificates", subject),
+            AttestationError::UntrustedRoot(issuer) => write!(f, "{} is not a trusted root CA", issuer),
+            AttestationError::UnsupportedAlgorithm(oid) => write!(f, "unsupported signature algorithm {}", oid),
+            AttestationError::UnsupportedKey(subject) => write!(f, "unsupported public key of {}", subject),
+            AttestationError::BadSignature(subject) => write!(f, "invalid signature of the certificate of {}", subject),
+            AttestationError::KeyMismatch => write!(f, "the certified key is not the one of the device")
+        }
+    }
+}
+
+/// Root CAs whose chains are accepted to certify device keys.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedRoots {
+    roots: Vec<Certificate>
+}
+
+impl TrustedRoots {
+    /// Roots given as a list of PEM certificates.
+    pub fn from_pem(pem: &str) -> Result<Self, AttestationError> {
+        Ok(TrustedRoots { roots: load_pem_chain(pem)? })
+    }
+
+    /// Validates a PEM chain starting with the device certificate, each one followed by the certificate of
+    /// its issuer, up to one issued by a trusted root. The root itself can be left out. Every certificate,
+    /// the root included, must be valid at `now` (milliseconds since the Unix epoch).
+    pub fn verify_chain(&self, pem_chain: &str, now: u64) -> Result<CertifiedKey, AttestationError> {
+        if self.roots.is_empty() {
+            return Err(AttestationError::NoTrustedRoots);
+        }
+        let chain = load_pem_chain(pem_chain)?;
+        let leaf = match chain.first() {
+            Some(leaf) => leaf,
+            None => return Err(AttestationError::EmptyChain)
+        };
+        if chain.len() > MAX_CHAIN_LENGTH {
+            return Err(AttestationError::ChainTooLong(chain.len()));
+        }
+        let mut certificate = leaf;
+        let mut issuers = chain[1..].iter();
+        loop {
+            check_validity(certificate, now)?;
+            let issuer = match issuers.next() {
+                Some(issuer) => issuer,
+                None => match self.roots.iter().find(|root| root.tbs_certificate.subject == certificate.tbs_certificate.issuer) {
+                    Some(root) => root,
+                    None => return Err(AttestationError::UntrustedRoot(certificate.tbs_certificate.issuer.to_string()))
+                }
+            };
+            if issuer.tbs_certificate.subject != certificate.tbs_certificate.issuer {
+                return Err(AttestationError::IssuerMismatch(subject(certificate)));
+            }
+            let trusted = self.roots.contains(issuer);
+            // Trust anchors are trusted as configured, older ones may not have the extensions
+            if !trusted {
+                check_certificate_authority(issuer)?;
+            }
+            verify_signature(issuer, certificate)?;
+            if trusted {
+                check_validity(issuer, now)?;
+                break;
+            }
+            certificate = issuer;
+        }
+        let (key_type, pk) = spki_key(&leaf.tbs_certificate.subject_public_key_info, leaf)?;
+        Ok(CertifiedKey {
+            key_type,
+            pk,
+            attestation: Attestation {
+                issuer: leaf.tbs_certificate.issuer.to_string(),
+                serial: leaf.tbs_certificate.serial_number.to_string(),
+                not_before: leaf.tbs_certificate.validity.not_before.to_unix_duration().as_millis() as u64,
+                not_after: leaf.tbs_certificate.validity.not_after.to_unix_duration().as_millis() as u64
+            }
+        })
+    }
+}
+
+fn load_pem_chain(pem: &str) -> Result<Vec<Certificate>, AttestationError> {
+    // x509-cert does not expect an empty input
+    if pem.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    Certificate::load_pem_chain(pem.as_bytes()).map_err(|_| AttestationError::BadPem)
+}
+
+fn subject(certificate: &Certificate) -> String {
+    certificate.tbs_certificate.subject.to_string()
+}
+
+fn check_validity(certificate: &Certificate, now: u64) -> Result<(), AttestationError> {
+    let validity = &certificate.tbs_certificate.validity;
+    let not_before = validity.not_before.to_unix_duration().as_millis() as u64;
+    let not_after = validity.not_after.to_unix_duration().as_millis() as u64;
+    if now < not_before || now > not_after {
+        return Err(AttestationError::NotValid(subject(certificate)));
+    }
+    Ok(())
+}
+
+fn check_certificate_authority(certificate: &Certificate) -> Result<(), AttestationError> {
+    let is_ca = matches!(certificate.tbs_certificate.get::<BasicConstraints>(), Ok(Some((_, basic_constraints))) if basic_constraints.ca);
+    let can_sign_certificates = match certificate.tbs_certificate.get::<KeyUsage>() {
+        Ok(Some((_, key_usage))) => key_usage.key_cert_sign(),
+        Ok(None) => true,
+        Err(_) => false
+    };
+    if !is_ca || !can_sign_certificates {
+        return Err(AttestationError::NotACertificateAuthority(subject(certificate)));
+    }
+    Ok(())
+}
+
+/// Type and canonical encoding of a public key of a certificate.
+fn spki_key(spki: &SubjectPublicKeyInfoOwned, certificate: &Certificate) -> Result<(KeyType, Vec<u8>), AttestationError> {
+    let key_type = match spki.algorithm.oid {
+        ED25519_OID => Some(KeyType::Ed25519),
+        EC_PUBLIC_KEY_OID => match spki.algorithm.parameters.as_ref().map(|curve| curve.decode_as::<ObjectIdentifier>()) {
+            Some(Ok(SECP256R1_OID)) => Some(KeyType::P256),
+            Some(Ok(SECP256K1_OID)) => Some(KeyType::Secp256k1),
+            _ => None
+        },
+        _ => None
+    };
+    let canonical_key = match (key_type, spki.subject_public_key.as_bytes()) {
+        (Some(key_type), Some(pk)) => key_type.canonical_key(pk).map(|pk| (key_type, pk)),
+        _ => None
+    };
+    canonical_key.ok_or_else(|| AttestationError::UnsupportedKey(subject(certificate)))
+}
+
+fn verify_signature(issuer: &Certificate, certificate: &Certificate) -> Result<(), AttestationError> {
+    let algorithm = &certificate.signature_algorithm;
+    if *algorithm != certificate.tbs_certificate.signature {
+        return Err(AttestationError::BadSignature(subject(certificate)));
+    }
+    let (key_type, pk) = spki_key(&issuer.tbs_certificate.subject_public_key_info, issuer)?;
+    let (tbs_certificate, signature) = match (certificate.tbs_certificate.to_der(), certificate.signature.as_bytes()) {
+        (Ok(tbs_certificate), Some(signature)) => (tbs_certificate, signature),
+        _ => return Err(AttestationError::BadSignature(subject(certificate)))
+    };
+    let prehash = match algorithm.oid {
+        ED25519_OID => None,
+        ECDSA_WITH_SHA256_OID => Some(Sha256::digest(&tbs_certificate).to_vec()),
+        ECDSA_WITH_SHA384_OID => Some(Sha384::digest(&tbs_certificate).to_vec()),
+        oid => return Err(AttestationError::UnsupportedAlgorithm(oid.to_string()))
+    };
+    let valid = match (key_type, prehash) {
+        (KeyType::Ed25519, None) => match (pk.as_slice().try_into().map(VerifyingKey::from_bytes), Signature::from_slice(signature)) {
+            (Ok(Ok(verifying_key)), Ok(signature)) => verifying_key.verify_strict(&tbs_certificate, &signature).is_ok(),
+            _ => false
+        },
+        (KeyType::P256, Some(prehash)) => match (p256::ecdsa::VerifyingKey::from_sec1_bytes(&pk), p256::ecdsa::Signature::from_der(signature)) {
+            (Ok(verifying_key), Ok(signature)) => verifying_key.verify_prehash(&prehash, &signature).is_ok(),
+            _ => false
+        },
+        // Certificate signatures are not required to have a low s, which k256 would insist on
+        (KeyType::Secp256k1, Some(prehash)) => match (k256::ecdsa::VerifyingKey::from_sec1_bytes(&pk), k256::ecdsa::Signature::from_der(signature)) {
+            (Ok(verifying_key), Ok(signature)) => verifying_key.verify_prehash(&prehash, &signature.normalize_s().unwrap_or(signature)).is_ok(),
+            _ => false
+        },
+        // The algorithm is not the one of the issuer key
+        _ => false
+    };
+    if !valid {
+        return Err(AttestationError::BadSignature(subject(certificate)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use p256::ecdsa::{DerSignature, SigningKey, signature::Signer};
+    use x509_cert::{Certificate, TbsCertificate, Version};
+    use x509_cert::der::{Any, Encode, EncodePem, pem::LineEnding, asn1::{BitString, OctetString, UtcTime}, oid::AssociatedOid};
+    use x509_cert::ext::{Extension, pkix::BasicConstraints};
+    use x509_cert::name::Name;
+    use x509_cert::serial_number::SerialNumber;
+    use x509_cert::spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned};
+    use x509_cert::time::{Time, Validity};
+
+    use super::{AttestationError, TrustedRoots, ECDSA_WITH_SHA256_OID, EC_PUBLIC_KEY_OID, ED25519_OID, SECP256R1_OID};
+    use crate::common::signature::KeyType;
+
+    // Validity of the test certificates, in seconds since the Unix epoch, and a time within it in milliseconds
+    pub(crate) const NOT_BEFORE: u64 = 1_700_000_000;
+    pub(crate) const NOT_AFTER: u64 = 2_000_000_000;
+    pub(crate) const NOW: u64 = 1_800_000_000_000;
+
+    pub(crate) fn p256_spki(key: &SigningKey) -> SubjectPublicKeyInfoOwned {
+        SubjectPublicKeyInfoOwned {
+            algorithm: AlgorithmIdentifierOwned { oid: EC_PUBLIC_KEY_OID, parameters: Some(Any::encode_from(&SECP256R1_OID).unwrap()) },
+            subject_public_key: BitString::from_bytes(key.verifying_key().to_encoded_point(false).as_bytes()).unwrap()
+        }
+    }
+
+    pub(crate) fn ed25519_spki(pk: &[u8; 32]) -> SubjectPublicKeyInfoOwned {
+        SubjectPublicKeyInfoOwned {
+            algorithm: AlgorithmIdentifierOwned { oid: ED25519_OID, parameters: None },
+            subject_public_key: BitString::from_bytes(pk).unwrap()
+        }
+    }
+
+    /// Certificate of `spki` for `subject`, signed with ECDSA P-256 by `issuer_key`.
+    pub(crate) fn issue(serial: u8, subject: &str, spki: SubjectPublicKeyInfoOwned, issuer: &str, issuer_key: &SigningKey, ca: bool, not_after: u64) -> Certificate {
+        let basic_constraints = BasicConstraints { ca, path_len_constraint: None };
+        let algorithm = AlgorithmIdentifierOwned { oid: ECDSA_WITH_SHA256_OID, parameters: None };
+        let tbs_certificate = TbsCertificate {
+            version: Version::V3,
+            serial_number: SerialNumber::new(&[serial]).unwrap(),
+            signature: algorithm.clone(),
+            issuer: Name::from_str(issuer).unwrap(),
+            validity: Validity {
+                not_before: Time::UtcTime(UtcTime::from_unix_duration(Duration::from_secs(NOT_BEFORE)).unwrap()),
+                not_after: Time::UtcTime(UtcTime::from_unix_duration(Duration::from_secs(not_after)).unwrap())
+            },
+            subject: Name::from_str(subject).unwrap(),
+            subject_public_key_info: spki,
+            issuer_unique_id: None,
+            subject_unique_id: None,
+            extensions: Some(vec![Extension {
+                extn_id: BasicConstraints::OID,
+                critical: true,
+                extn_value: OctetString::new(basic_constraints.to_der().unwrap()).unwrap()
+            }])
+        };
+        let signature: DerSignature = issuer_key.sign(&tbs_certificate.to_der().unwrap());
+        Certificate {
+            tbs_certificate,
+            signature_algorithm: algorithm,
+            signature: BitString::from_bytes(signature.as_bytes()).unwrap()
+        }
+    }
+
+    pub(crate) fn to_pem(certificates: &[&Certificate]) -> String {
+        certificates.iter().map(|certificate| certificate.to_pem(LineEnding::LF).unwrap()).collect()
+    }
+
+    /// Root and intermediate CAs of a manufacturer with their keys.
+    pub(crate) fn manufacturer_cas() -> ((Certificate, SigningKey), (Certificate, SigningKey)) {
+        let root_key = SigningKey::from_slice(&[1u8; 32]).unwrap();
+        let root = issue(1, "CN=Root CA,O=Manufacturer", p256_spki(&root_key), "CN=Root CA,O=Manufacturer", &root_key, true, NOT_AFTER);
+        let intermediate_key = SigningKey::from_slice(&[2u8; 32]).unwrap();
+        let intermediate = issue(2, "CN=Devices CA,O=Manufacturer", p256_spki(&intermediate_key), "CN=Root CA,O=Manufacturer", &root_key, true, NOT_AFTER);
+        ((root, root_key), (intermediate, intermediate_key))
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let ((root, _), (intermediate, intermediate_key)) = manufacturer_cas();
+        let trusted_roots = TrustedRoots::from_pem(&to_pem(&[&root])).unwrap();
+        let device_pk = [3u8; 32];
+        let device_pk = ed25519_dalek::SigningKey::from_bytes(&device_pk).verifying_key().to_bytes();
+        let device = issue(42, "CN=Device 42,O=Manufacturer", ed25519_spki(&device_pk), "CN=Devices CA,O=Manufacturer", &intermediate_key, false, NOT_AFTER);
+
+        // With or without the root at the end
+        for pem_chain in [to_pem(&[&device, &intermediate]), to_pem(&[&device, &intermediate, &root])] {
+            let certified_key = trusted_roots.verify_chain(&pem_chain, NOW).unwrap();
+            assert_eq!(certified_key.key_type, KeyType::Ed25519);
+            assert_eq!(certified_key.pk, device_pk.to_vec());
+            assert_eq!(certified_key.attestation.issuer, "CN=Devices CA,O=Manufacturer");
+            assert_eq!(certified_key.attestation.serial, "2A");
+            assert_eq!((certified_key.attestation.not_before, certified_key.attestation.not_after), (NOT_BEFORE * 1000, NOT_AFTER * 1000));
+        }
+
+        // A P-256 device key is certified compressed
+        let device_key = SigningKey::from_slice(&[4u8; 32]).unwrap();
+        let p256_device = issue(43, "CN=Device 43", p256_spki(&device_key), "CN=Devices CA,O=Manufacturer", &intermediate_key, false, NOT_AFTER);
+        let certified_key = trusted_roots.verify_chain(&to_pem(&[&p256_device, &intermediate]), NOW).unwrap();
+        assert_eq!(certified_key.key_type, KeyType::P256);
+        assert_eq!(certified_key.pk, device_key.verifying_key().to_encoded_point(true).as_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_verify_chain_rejects_invalid_chains() {
+        let ((root, root_key), (intermediate, intermediate_key)) = manufacturer_cas();
+        let trusted_roots = TrustedRoots::from_pem(&to_pem(&[&root])).unwrap();
+        let device_spki = ed25519_spki(&ed25519_dalek::SigningKey::from_bytes(&[3u8; 32]).verifying_key().to_bytes());
+        let device = issue(42, "CN=Device 42", device_spki.clone(), "CN=Devices CA,O=Manufacturer", &intermediate_key, false, NOT_AFTER);
+
+        assert_eq!(TrustedRoots::default().verify_chain(&to_pem(&[&device, &intermediate]), NOW).unwrap_err(), AttestationError::NoTrustedRoots);
+        assert_eq!(trusted_roots.verify_chain(" \n", NOW).unwrap_err(), AttestationError::EmptyChain);
+        assert_eq!(trusted_roots.verify_chain("not a certificate", NOW).unwrap_err(), AttestationError::BadPem);
+        assert_eq!(trusted_roots.verify_chain("-----BEGIN CERTIFICATE-----\nnot a certificate\n-----END CERTIFICATE-----\n", NOW).unwrap_err(), AttestationError::BadPem);
+        assert!(matches!(trusted_roots.verify_chain(&to_pem(&[&device, &intermediate]), NOT_AFTER * 1000 + 1), Err(AttestationError::NotValid(_))));
+        assert!(matches!(trusted_roots.verify_chain(&to_pem(&[&device]), NOW), Err(AttestationError::UntrustedRoot(_))));
+        assert!(matches!(trusted_roots.verify_chain(&to_pem(&[&intermediate, &device]), NOW), Err(AttestationError::IssuerMismatch(_))));
+
+        // Issued by a CA of another manufacturer with the same name
+        let other_root_key = SigningKey::from_slice(&[5u8; 32]).unwrap();
+        let other_root = issue(1, "CN=Root CA,O=Manufacturer", p256_spki(&other_root_key), "CN=Root CA,O=Manufacturer", &other_root_key, true, NOT_AFTER);
+        let forged_intermediate = issue(2, "CN=Devices CA,O=Manufacturer", p256_spki(&intermediate_key), "CN=Root CA,O=Manufacturer", &other_root_key, true, NOT_AFTER);
+        assert!(matches!(trusted_roots.verify_chain(&to_pem(&[&device, &forged_intermediate]), NOW), Err(AttestationError::BadSignature(_))));
+        assert!(matches!(trusted_roots.verify_chain(&to_pem(&[&device, &forged_intermediate, &other_root]), NOW), Err(AttestationError::BadSignature(_))));
+
+        // Issued by an expired intermediate
+        let expired_intermediate = issue(2, "CN=Devices CA,O=Manufacturer", p256_spki(&intermediate_key), "CN=Root CA,O=Manufacturer", &root_key, true, NOT_BEFORE + 1);
+        assert!(matches!(trusted_roots.verify_chain(&to_pem(&[&device, &expired_intermediate]), NOW), Err(AttestationError::NotValid(_))));
+
+        // Issued by a device, which is not a CA
+        let device_key = SigningKey::from_slice(&[4u8; 32]).unwrap();
+        let signing_device = issue(43, "CN=Device 43", p256_spki(&device_key), "CN=Devices CA,O=Manufacturer", &intermediate_key, false, NOT_AFTER);
+        let sub_device = issue(44, "CN=Device 44", device_spki, "CN=Device 43", &device_key, false, NOT_AFTER);
+        assert!(matches!(trusted_roots.verify_chain(&to_pem(&[&sub_device, &signing_device, &intermediate]), NOW), Err(AttestationError::NotACertificateAuthority(_))));
+    }
+}
@@ -1,7 +1,17 @@
+use clap::ValueEnum;
 use once_cell::sync::Lazy;
 use std::sync::RwLock;
+use std::time::Duration;
 
 use crate::cli_args::CLIArgs;
+use crate::common::hash_algorithm::HashAlgorithm;
+use crate::common::merkle::TreeFormat;
+use crate::state::bitacora::DATASET_DEFAULT_LIMIT;
+
+pub const AUTO_SEAL_DEFAULT_CHECK_INTERVAL_SECS: u64 = 60;
+pub const MERKLE_TREE_CACHE_DEFAULT_SIZE: usize = 64;
+pub const DEVICE_CHALLENGE_DEFAULT_TTL_SECS: u64 = 300;
+pub const DEVICE_CHALLENGE_DEFAULT_CAPACITY: usize = 65536;
 
 pub struct Web3Configuration {
     pub url: String,
@@ -10,9 +20,38 @@ pub struct Web3Configuration {
     pub contracts_base_dir: String
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum StorageBackend {
+    InMemory,
+    Sqlite,
+    AppendLog
+}
+
+pub struct StorageConfiguration {
+    pub backend: StorageBackend,
+    pub sqlite_path: String,
+    pub log_dir: String
+}
+
+/// Datasets are sealed automatically when idle for `max_idle` or when their first FlightData is older
+/// than `max_age`. Automatic sealing is disabled when neither is set.
+pub struct AutoSealConfiguration {
+    pub check_interval: Duration,
+    pub max_idle: Option<Duration>,
+    pub max_age: Option<Duration>
+}
+
 pub struct BitacoraConfiguration {
     pub web3: Web3Configuration,
-    pub dataset_default_count: u32
+    pub storage: StorageConfiguration,
+    pub auto_seal: AutoSealConfiguration,
+    pub dataset_default_count: u32,
+    pub merkle_tree_cache_size: usize,
+    pub hash_algorithm: HashAlgorithm,
+    pub tree_format: TreeFormat,
+    pub device_challenge_ttl: Duration,
+    pub device_challenge_capacity: usize,
+    pub trusted_root_cas: Option<String>
 }
 
 impl BitacoraConfiguration {
@@ -33,6 +72,30 @@ impl BitacoraConfiguration {
         BitacoraConfiguration::instance().read().unwrap().dataset_default_count
     }
 
+    pub fn get_merkle_tree_cache_size() -> usize {
+        BitacoraConfiguration::instance().read().unwrap().merkle_tree_cache_size
+    }
+
+    pub fn get_hash_algorithm() -> HashAlgorithm {
+        BitacoraConfiguration::instance().read().unwrap().hash_algorithm
+    }
+
+    pub fn get_tree_format() -> TreeFormat {
+        BitacoraConfiguration::instance().read().unwrap().tree_format
+    }
+
+    pub fn get_device_challenge_ttl() -> Duration {
+        BitacoraConfiguration::instance().read().unwrap().device_challenge_ttl
+    }
+
+    pub fn get_device_challenge_capacity() -> usize {
+        BitacoraConfiguration::instance().read().unwrap().device_challenge_capacity
+    }
+
+    pub fn get_trusted_root_cas() -> Option<String> {
+        BitacoraConfiguration::instance().read().unwrap().trusted_root_cas.clone()
+    }
+
     pub fn get_web3_contract_base_dir() -> String {
         BitacoraConfiguration::instance().read().unwrap().web3.contracts_base_dir.clone()
     }
@@ -40,6 +103,30 @@ impl BitacoraConfiguration {
     pub fn get_web3_signer() -> Option<String> {
         BitacoraConfiguration::instance().read().unwrap().web3.signer.clone()
     }
+
+    pub fn get_storage_backend() -> StorageBackend {
+        BitacoraConfiguration::instance().read().unwrap().storage.backend
+    }
+
+    pub fn get_sqlite_path() -> String {
+        BitacoraConfiguration::instance().read().unwrap().storage.sqlite_path.clone()
+    }
+
+    pub fn get_log_dir() -> String {
+        BitacoraConfiguration::instance().read().unwrap().storage.log_dir.clone()
+    }
+
+    pub fn get_auto_seal_check_interval() -> Duration {
+        BitacoraConfiguration::instance().read().unwrap().auto_seal.check_interval
+    }
+
+    pub fn get_dataset_max_idle() -> Option<Duration> {
+        BitacoraConfiguration::instance().read().unwrap().auto_seal.max_idle
+    }
+
+    pub fn get_dataset_max_age() -> Option<Duration> {
+        BitacoraConfiguration::instance().read().unwrap().auto_seal.max_age
+    }
 }
 
 impl Default for BitacoraConfiguration {
@@ -51,7 +138,23 @@ impl Default for BitacoraConfiguration {
                 signer: None,
                 contracts_base_dir: String::from(".")
             },
-            dataset_default_count: 0
+            storage: StorageConfiguration {
+                backend: StorageBackend::InMemory,
+                sqlite_path: String::from("bitacora.db"),
+                log_dir: String::from("bitacora-log")
+            },
+            auto_seal: AutoSealConfiguration {
+                check_interval: Duration::from_secs(AUTO_SEAL_DEFAULT_CHECK_INTERVAL_SECS),
+                max_idle: None,
+                max_age: None
+            },
+            dataset_default_count: DATASET_DEFAULT_LIMIT,
+            merkle_tree_cache_size: MERKLE_TREE_CACHE_DEFAULT_SIZE,
+            hash_algorithm: HashAlgorithm::default(),
+            tree_format: TreeFormat::V1,
+            device_challenge_ttl: Duration::from_secs(DEVICE_CHALLENGE_DEFAULT_TTL_SECS),
+            device_challenge_capacity: DEVICE_CHALLENGE_DEFAULT_CAPACITY,
+            trusted_root_cas: None
         }
     
     }
@@ -63,10 +166,26 @@ impl From<CLIArgs> for BitacoraConfiguration {
             web3: Web3Configuration {
                 url: args.web3,
                 address: None,
-                signer: Some(args.private_key),
-                contracts_base_dir: args.contracts_base
+                signer: args.private_key,
+                contracts_base_dir: args.contracts_base.unwrap_or_else(|| String::from("."))
+            },
+            storage: StorageConfiguration {
+                backend: args.storage,
+                sqlite_path: args.sqlite_path,
+                log_dir: args.log_dir
+            },
+            auto_seal: AutoSealConfiguration {
+                check_interval: Duration::from_secs(args.auto_seal_interval),
+                max_idle: args.dataset_max_idle.map(Duration::from_secs),
+                max_age: args.dataset_max_age.map(Duration::from_secs)
             },
-            dataset_default_count: args.dataset_count
+            dataset_default_count: args.dataset_count,
+            merkle_tree_cache_size: args.merkle_tree_cache_size,
+            hash_algorithm: args.hash_algorithm,
+            tree_format: args.tree_format,
+            device_challenge_ttl: Duration::from_secs(args.device_challenge_ttl),
+            device_challenge_capacity: args.device_challenge_capacity,
+            trusted_root_cas: args.trusted_root_cas
         }
     }
 }
\ No newline at end of file
@@ -1,7 +1,8 @@
 use axum::{http::StatusCode, Json, response::IntoResponse};
 use serde::Serialize;
 
-use crate::state::{errors::BitacoraError, entities::Entity};
+use crate::common::x509::AttestationError;
+use crate::state::{errors::BitacoraError, entities::{DatasetStatus, Entity}};
 
 
 #[derive(Debug)]
@@ -76,6 +77,83 @@ impl ErrorResponse {
             } 
         }
     }
+
+    pub fn invalid_status_transition(from: DatasetStatus, to: DatasetStatus) -> Self {
+        ErrorResponse {
+            status: StatusCode::CONFLICT,
+            body: ErrorResponseBody {
+                code: 1005,
+                message: String::from("Operation not allowed in the current Dataset status"),
+                description: format!("Dataset can not go from {} to {}", from, to)
+            }
+        }
+    }
+
+    pub fn not_anchored(dataset_id: String) -> Self {
+        ErrorResponse {
+            status: StatusCode::CONFLICT,
+            body: ErrorResponseBody {
+                code: 1006,
+                message: String::from("Dataset not anchored yet"),
+                description: format!("Dataset {} must be anchored on the blockchain first", dataset_id)
+            }
+        }
+    }
+
+    pub fn no_sparse_merkle_tree(dataset_id: String) -> Self {
+        ErrorResponse {
+            status: StatusCode::CONFLICT,
+            body: ErrorResponseBody {
+                code: 1007,
+                message: String::from("Dataset has no sparse Merkle tree"),
+                description: format!("Dataset {} was sealed before sparse Merkle trees were kept", dataset_id)
+            }
+        }
+    }
+
+    pub fn invalid_signature() -> Self {
+        ErrorResponse {
+            status: StatusCode::UNAUTHORIZED,
+            body: ErrorResponseBody {
+                code: 1008,
+                message: String::from("Invalid FlightData signature"),
+                description: String::from("The signature does not match the FlightData and the registered Device key")
+            }
+        }
+    }
+
+    pub fn invalid_challenge() -> Self {
+        ErrorResponse {
+            status: StatusCode::UNAUTHORIZED,
+            body: ErrorResponseBody {
+                code: 1009,
+                message: String::from("Invalid Device registration challenge"),
+                description: String::from("The nonce was not issued for the Device, expired or is not signed with its key")
+            }
+        }
+    }
+
+    pub fn invalid_attestation(error: AttestationError) -> Self {
+        ErrorResponse {
+            status: StatusCode::UNAUTHORIZED,
+            body: ErrorResponseBody {
+                code: 1010,
+                message: String::from("Invalid Device certificate chain"),
+                description: format!("The certificate chain does not attest the Device key: {}", error)
+            }
+        }
+    }
+
+    pub fn device_revoked(device_id: String) -> Self {
+        ErrorResponse {
+            status: StatusCode::CONFLICT,
+            body: ErrorResponseBody {
+                code: 1011,
+                message: String::from("Device revoked"),
+                description: format!("Device {} was revoked and its key can't change anymore", device_id)
+            }
+        }
+    }
 }
 
 impl IntoResponse for ErrorResponse {
@@ -91,7 +169,19 @@ impl From<BitacoraError> for ErrorResponse {
             BitacoraError::Web3Error => ErrorResponse::web3_error(),
             BitacoraError::NotFound => ErrorResponse::not_found(&String::from("CHANGE ME")),
             BitacoraError::StorageError(_) => ErrorResponse::storage_error(),
-            BitacoraError::BadIdFormat => ErrorResponse::bad_input("id", None)
+            BitacoraError::BadIdFormat => ErrorResponse::bad_input("id", None),
+            BitacoraError::InvalidStatusTransition(from, to) => ErrorResponse::invalid_status_transition(from, to),
+            BitacoraError::NotAnchored(dataset_id) => ErrorResponse::not_anchored(dataset_id),
+            BitacoraError::DifferentDatasets(first, other) => ErrorResponse::bad_input(
+                "flight_data_ids",
+                Some(format!("FlightData belong to different Datasets: {}, {}", first, other).as_str())
+            ),
+            BitacoraError::NoSparseMerkleTree(dataset_id) => ErrorResponse::no_sparse_merkle_tree(dataset_id),
+            BitacoraError::InvalidSignature => ErrorResponse::invalid_signature(),
+            BitacoraError::FullDataMismatch(field) => ErrorResponse::bad_input(field, Some("It differs from the live FlightData")),
+            BitacoraError::InvalidChallenge => ErrorResponse::invalid_challenge(),
+            BitacoraError::InvalidAttestation(error) => ErrorResponse::invalid_attestation(error),
+            BitacoraError::DeviceRevoked(device_id) => ErrorResponse::device_revoked(device_id)
         }
     }
 }
\ No newline at end of file
@@ -9,7 +9,7 @@ pub async fn handler<S: FullStorage, T: Timestamper>(
     Path(id): Path<String>,
     State(state): State<SharedBitacora<S, T>>
 ) -> Response {
-    match state.get_dataset(&id) {
+    match state.get_dataset(&id).await {
         Ok(query_result) => {
             match query_result {
                 Some(dataset) => (StatusCode::OK, Json(dataset)).into_response(),
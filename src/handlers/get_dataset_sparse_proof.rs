@@ -0,0 +1,38 @@
+use axum::{extract::{Path, Query, State}, http::StatusCode, Json, response::{IntoResponse, Response}};
+use serde::Deserialize;
+
+use crate::{state::{entities::FlightDataId, errors::BitacoraError}, storage::storage::{DatasetStorage, FullStorage}, web3::traits::Timestamper};
+use crate::SharedBitacora;
+
+use super::errors::ErrorResponse;
+
+/// The FlightData is given by its id, or by its timestamp as a live FlightData of the Dataset device.
+#[derive(Debug, Deserialize)]
+pub struct GETDatasetSparseProofOptions {
+    flight_data_id: Option<String>,
+    timestamp: Option<u64>
+}
+
+pub async fn handler<S: FullStorage, T: Timestamper>(
+    Path(id): Path<String>,
+    Query(options): Query<GETDatasetSparseProofOptions>,
+    State(state): State<SharedBitacora<S, T>>
+) -> Response {
+    let f_id = match (options.flight_data_id, options.timestamp) {
+        (Some(flight_data_id), None) => match FlightDataId::try_from(flight_data_id) {
+            Ok(f_id) => f_id,
+            Err(_) => return ErrorResponse::bad_input("flight_data_id", Some("Can't decode Id")).into_response()
+        },
+        (None, Some(timestamp)) => match state.get_dataset_device(&id).await {
+            Ok(Some(device_id)) => FlightDataId::new(timestamp, &device_id),
+            Ok(None) => return ErrorResponse::not_found("Dataset").into_response(),
+            Err(_) => return ErrorResponse::storage_error().into_response()
+        },
+        _ => return ErrorResponse::bad_input("flight_data_id", Some("Either flight_data_id or timestamp is required")).into_response()
+    };
+    match state.flight_data_sparse_proof(&id, &f_id).await {
+        Ok(proof) => (StatusCode::OK, Json(proof)).into_response(),
+        Err(BitacoraError::NotFound) => ErrorResponse::not_found("Dataset").into_response(),
+        Err(error) => ErrorResponse::from(error).into_response()
+    }
+}
@@ -9,7 +9,7 @@ pub async fn handler<S: FullStorage, T: Timestamper>(
     Path(id): Path<String>,
     State(state): State<SharedBitacora<S, T>>
 ) -> Response {
-    match state.get_device(&id) {
+    match state.get_device(&id).await {
         Ok(query_result) => {
             match query_result {
                 Some(device) => (StatusCode::OK, Json(device)).into_response(),
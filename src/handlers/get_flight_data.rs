@@ -10,7 +10,7 @@ pub async fn handler<S: FullStorage, T: Timestamper>(
     State(state): State<SharedBitacora<S, T>>
 ) -> Response {
     match FlightDataId::try_from(id) {
-        Ok(f_id) => match state.get_flight_data(&f_id) {
+        Ok(f_id) => match state.get_flight_data(&f_id).await {
             Ok(query_result) => {
                 match query_result {
                     Some(fd) => (StatusCode::OK, Json(fd)).into_response(),
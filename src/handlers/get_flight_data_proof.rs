@@ -0,0 +1,21 @@
+use axum::{extract::{State, Path}, http::StatusCode, Json, response::{IntoResponse, Response}};
+
+use crate::{state::{entities::FlightDataId, errors::BitacoraError}, storage::storage::FullStorage, web3::traits::Timestamper};
+use crate::SharedBitacora;
+
+use super::errors::ErrorResponse;
+
+pub async fn handler<S: FullStorage, T: Timestamper>(
+    Path(id): Path<String>,
+    State(state): State<SharedBitacora<S, T>>
+) -> Response {
+    let f_id = match FlightDataId::try_from(id) {
+        Ok(f_id) => f_id,
+        Err(_) => return ErrorResponse::bad_input("id", Some("Can't decode Id")).into_response()
+    };
+    match state.flight_data_proof(&f_id).await {
+        Ok(proof) => (StatusCode::OK, Json(proof)).into_response(),
+        Err(BitacoraError::NotFound) => ErrorResponse::not_found("FlightData").into_response(),
+        Err(error) => ErrorResponse::from(error).into_response()
+    }
+}
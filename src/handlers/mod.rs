@@ -1,6 +1,15 @@
 pub mod get_dataset;
+pub mod get_dataset_sparse_proof;
 pub mod get_device;
 pub mod get_flight_data;
+pub mod get_flight_data_proof;
 pub mod errors;
+pub mod patch_flight_data;
+pub mod post_dataset;
+pub mod post_dataset_seal;
 pub mod post_device;
+pub mod post_device_challenge;
+pub mod post_device_revoke;
+pub mod post_device_rotate;
 pub mod post_flight_data;
+pub mod post_flight_data_multiproof;
@@ -0,0 +1,52 @@
+use axum::{
+    extract::{Path, State},
+    Json, response::{IntoResponse, Response}
+};
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use serde::{ Deserialize, Serialize };
+
+use crate::{ SharedBitacora, state::{errors::BitacoraError, entities::{FlightDataId, LocalizationPoint}}, storage::storage::FullStorage, web3::traits::Timestamper};
+
+use super::errors::ErrorResponse;
+
+#[derive(Debug, Deserialize)]
+pub struct PATCHFlightDataRequest {
+    device_id: String,
+    /// Checked against the live FlightData when given
+    timestamp: Option<u64>,
+    /// Checked against the live FlightData when given
+    localization: Option<LocalizationPoint>,
+    payload: String,
+    signature_full: String
+}
+
+#[derive(Serialize)]
+pub struct PATCHFlightDataResponse {
+    pub id: String,
+    pub dataset_id: String
+}
+
+pub async fn handler<S: FullStorage, T: Timestamper>(
+    Path(id): Path<String>,
+    State(state): State<SharedBitacora<S, T>>,
+    Json(request): Json<PATCHFlightDataRequest>
+) -> Response {
+    tracing::debug!("received full flight data for {}", id);
+    let live_id = match FlightDataId::try_from(id) {
+        Ok(live_id) => live_id,
+        Err(_) => return ErrorResponse::bad_input("id", Some("Can't decode Id")).into_response()
+    };
+    let payload = match STANDARD.decode(request.payload) {
+        Ok(payload) => payload,
+        Err(err) => return ErrorResponse::bad_input("payload", Some(&err.to_string())).into_response()
+    };
+    match state.complete_flight_data(&live_id, &request.device_id, request.timestamp, request.localization, payload, request.signature_full).await {
+        Ok((full_fd, dataset)) => Json(PATCHFlightDataResponse {
+            id: full_fd.id.into(),
+            dataset_id: dataset.id
+        }).into_response(),
+        Err(BitacoraError::NotFound) => ErrorResponse::not_found("FlightData").into_response(),
+        Err(error) => ErrorResponse::from(error).into_response()
+    }
+}
@@ -0,0 +1,42 @@
+use axum::{
+    http::StatusCode,
+    extract::{Query, State},
+    Json, response::{IntoResponse, Response}
+};
+
+use serde::Deserialize;
+
+use crate::{common::{hash_algorithm::HashAlgorithm, merkle::TreeFormat}, state::errors::BitacoraError, SharedBitacora, storage::storage::FullStorage, web3::traits::Timestamper};
+
+use super::errors::ErrorResponse;
+
+#[derive(Debug, Deserialize)]
+pub struct POSTDatasetRequest {
+    device_id: String,
+    limit: u32,
+    #[serde(default)]
+    hash_algorithm: Option<HashAlgorithm>,
+    #[serde(default)]
+    tree_format: Option<TreeFormat>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct POSTDatasetOptions {
+    #[serde(default)]
+    close_current: bool
+}
+
+pub async fn handler<S: FullStorage, T: Timestamper>(
+    State(state): State<SharedBitacora<S, T>>,
+    Query(options): Query<POSTDatasetOptions>,
+    Json(payload): Json<POSTDatasetRequest>
+) -> Response {
+    if payload.limit == 0 {
+        return ErrorResponse::bad_input("limit", Some("Must be greater than zero")).into_response();
+    }
+    match state.open_dataset(payload.limit, &payload.device_id, options.close_current, payload.hash_algorithm, payload.tree_format).await {
+        Ok(dataset) => (StatusCode::CREATED, Json(dataset)).into_response(),
+        Err(BitacoraError::NotFound) => ErrorResponse::not_found("Device").into_response(),
+        Err(error) => ErrorResponse::from(error).into_response()
+    }
+}
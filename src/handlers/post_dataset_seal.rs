@@ -0,0 +1,17 @@
+use axum::{extract::{State, Path}, http::StatusCode, Json, response::{IntoResponse, Response}};
+
+use crate::{state::errors::BitacoraError, storage::storage::FullStorage, web3::traits::Timestamper};
+use crate::SharedBitacora;
+
+use super::errors::ErrorResponse;
+
+pub async fn handler<S: FullStorage, T: Timestamper>(
+    Path(id): Path<String>,
+    State(state): State<SharedBitacora<S, T>>
+) -> Response {
+    match state.seal(&id).await {
+        Ok(dataset) => (StatusCode::OK, Json(dataset)).into_response(),
+        Err(BitacoraError::NotFound) => ErrorResponse::not_found("Dataset").into_response(),
+        Err(error) => ErrorResponse::from(error).into_response()
+    }
+}
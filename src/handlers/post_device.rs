@@ -6,29 +6,59 @@ use axum::{
 
 use serde::Deserialize;
 
-use crate::{state::entities::{Device, PublicKey}, SharedBitacora, storage::storage::FullStorage, web3::traits::Timestamper};
+use crate::{common::{prelude::Bytes32, signature::KeyType}, state::entities::{Device, PublicKey}, SharedBitacora, storage::storage::FullStorage, web3::traits::Timestamper};
 
 use super::errors::ErrorResponse;
 
+/// Key of the Device, as given to request its registration challenge and to register it.
+#[derive(Deserialize)]
+pub struct POSTDeviceKey {
+    pk: String,
+    #[serde(default)]
+    key_type: KeyType
+}
+
+/// Registration of a Device, answering the challenge issued by `POST /device/challenge` with the
+/// base64 signature of the Device key. The manufacturer PEM certificate chain of the key can be given
+/// to attest the Device.
 #[derive(Deserialize)]
 pub struct POSTDeviceRequest {
-    pk: String
+    #[serde(flatten)]
+    key: POSTDeviceKey,
+    nonce: String,
+    signature: String,
+    certificate_chain: Option<String>
 }
 
 pub enum POSTDeviceRequestError {
-    FailedPKDecoding
+    FailedPKDecoding,
+    InvalidPK(KeyType),
+    FailedNonceDecoding
+}
+
+impl From<POSTDeviceRequestError> for ErrorResponse {
+    fn from(value: POSTDeviceRequestError) -> Self {
+        match value {
+            POSTDeviceRequestError::FailedPKDecoding => ErrorResponse::bad_input("pk", Some("Failed to decode")),
+            POSTDeviceRequestError::InvalidPK(key_type) => ErrorResponse::bad_input("pk", Some(&format!("Not a valid {} public key", key_type))),
+            POSTDeviceRequestError::FailedNonceDecoding => ErrorResponse::bad_input("nonce", Some("Failed to decode"))
+        }
+    }
 }
 
-impl TryFrom<POSTDeviceRequest> for Device {
+impl TryFrom<POSTDeviceKey> for Device {
 
     type Error = POSTDeviceRequestError;
 
-    fn try_from(value: POSTDeviceRequest) -> Result<Self, Self::Error> {
+    fn try_from(value: POSTDeviceKey) -> Result<Self, Self::Error> {
         let pk: PublicKey = match value.pk.try_into() {
             Ok(pk) => pk,
             Err(_) => return Err(Self::Error::FailedPKDecoding)
         };
-        Ok(Device::from(pk))
+        match Device::new(value.key_type, pk) {
+            Some(device) => Ok(device),
+            None => Err(Self::Error::InvalidPK(value.key_type))
+        }
     }
 }
 
@@ -36,14 +66,21 @@ pub async fn handler<S: FullStorage, T: Timestamper>(
     State(state): State<SharedBitacora<S, T>>,
     Json(payload): Json<POSTDeviceRequest>
 ) -> Response {
-    let mut device = match Device::try_from(payload) {
+    let mut device = match Device::try_from(payload.key) {
         Ok(device) => device,
-        Err(error) => match error {
-            POSTDeviceRequestError::FailedPKDecoding => return ErrorResponse::bad_input("pk", Some("Failed to decode")).into_response()
-        }
+        Err(error) => return ErrorResponse::from(error).into_response()
+    };
+    let nonce = match Bytes32::try_from(payload.nonce) {
+        Ok(nonce) => nonce,
+        Err(_) => return ErrorResponse::from(POSTDeviceRequestError::FailedNonceDecoding).into_response()
     };
-    match state.new_device(&mut device).await {
+    if let Some(certificate_chain) = &payload.certificate_chain {
+        if let Err(error) = state.attest_device(&mut device, certificate_chain) {
+            return ErrorResponse::from(error).into_response();
+        }
+    }
+    match state.new_device(&mut device, &nonce, &payload.signature).await {
         Ok(()) => (StatusCode::CREATED, Json(device)).into_response(),
         Err(error) => ErrorResponse::from(error).into_response()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,21 @@
+use axum::{extract::State, http::StatusCode, Json, response::{IntoResponse, Response}};
+
+use crate::{state::entities::Device, storage::storage::FullStorage, web3::traits::Timestamper};
+use crate::SharedBitacora;
+
+use super::errors::ErrorResponse;
+use super::post_device::POSTDeviceKey;
+
+pub async fn handler<S: FullStorage, T: Timestamper>(
+    State(state): State<SharedBitacora<S, T>>,
+    Json(payload): Json<POSTDeviceKey>
+) -> Response {
+    let device = match Device::try_from(payload) {
+        Ok(device) => device,
+        Err(error) => return ErrorResponse::from(error).into_response()
+    };
+    match state.new_device_challenge(&device).await {
+        Ok(challenge) => (StatusCode::CREATED, Json(challenge)).into_response(),
+        Err(error) => ErrorResponse::from(error).into_response()
+    }
+}
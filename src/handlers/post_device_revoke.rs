@@ -0,0 +1,25 @@
+use axum::{extract::{State, Path}, http::StatusCode, Json, response::{IntoResponse, Response}};
+use serde::Deserialize;
+
+use crate::{state::errors::BitacoraError, storage::storage::FullStorage, web3::traits::Timestamper};
+use crate::SharedBitacora;
+
+use super::errors::ErrorResponse;
+
+/// Base64 signature of `Device::revocation_signing_bytes` by the current key of the Device.
+#[derive(Deserialize)]
+pub struct POSTDeviceRevokeRequest {
+    signature: String
+}
+
+pub async fn handler<S: FullStorage, T: Timestamper>(
+    Path(id): Path<String>,
+    State(state): State<SharedBitacora<S, T>>,
+    Json(payload): Json<POSTDeviceRevokeRequest>
+) -> Response {
+    match state.revoke_device(&id, &payload.signature).await {
+        Ok(device) => (StatusCode::OK, Json(device)).into_response(),
+        Err(BitacoraError::NotFound) => ErrorResponse::not_found("Device").into_response(),
+        Err(error) => ErrorResponse::from(error).into_response()
+    }
+}
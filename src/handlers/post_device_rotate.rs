@@ -0,0 +1,42 @@
+use axum::{extract::{State, Path}, http::StatusCode, Json, response::{IntoResponse, Response}};
+use serde::Deserialize;
+
+use crate::{state::{errors::BitacoraError, entities::Device}, storage::storage::FullStorage, web3::traits::Timestamper};
+use crate::SharedBitacora;
+
+use super::errors::ErrorResponse;
+use super::post_device::POSTDeviceKey;
+
+/// New key of a Device with the base64 signatures of `Device::key_rotation_signing_bytes` by the current
+/// key (`signature`) and the new one (`key_signature`). The manufacturer PEM certificate chain of the new
+/// key can be given to attest it.
+#[derive(Deserialize)]
+pub struct POSTDeviceRotateRequest {
+    #[serde(flatten)]
+    key: POSTDeviceKey,
+    signature: String,
+    key_signature: String,
+    certificate_chain: Option<String>
+}
+
+pub async fn handler<S: FullStorage, T: Timestamper>(
+    Path(id): Path<String>,
+    State(state): State<SharedBitacora<S, T>>,
+    Json(payload): Json<POSTDeviceRotateRequest>
+) -> Response {
+    // The Device the new key would register, only used to validate and attest the key
+    let mut key_holder = match Device::try_from(payload.key) {
+        Ok(device) => device,
+        Err(error) => return ErrorResponse::from(error).into_response()
+    };
+    if let Some(certificate_chain) = &payload.certificate_chain {
+        if let Err(error) = state.attest_device(&mut key_holder, certificate_chain) {
+            return ErrorResponse::from(error).into_response();
+        }
+    }
+    match state.rotate_device_key(&id, key_holder.current_key(), &payload.signature, &payload.key_signature).await {
+        Ok(device) => (StatusCode::OK, Json(device)).into_response(),
+        Err(BitacoraError::NotFound) => ErrorResponse::not_found("Device").into_response(),
+        Err(error) => ErrorResponse::from(error).into_response()
+    }
+}
@@ -21,7 +21,8 @@ pub struct POSTFlightDataRequest {
 }
 
 pub enum InputFlightDataError {
-    BadPayloadData(DecodeError)
+    BadPayloadData(DecodeError),
+    BadLocalization
 }
 
 impl TryFrom<POSTFlightDataRequest> for FlightData {
@@ -33,16 +34,17 @@ impl TryFrom<POSTFlightDataRequest> for FlightData {
             Ok(payload) => payload,
             Err(err) => return Err(InputFlightDataError::BadPayloadData(err))
         };
+        if !value.localization.is_valid() {
+            return Err(InputFlightDataError::BadLocalization);
+        }
         Ok(FlightData {
             id: FlightDataId::new(value.timestamp, &value.device_id),
             signature: value.signature,
             timestamp: value.timestamp,
             localization: value.localization,
             payload,
+            signature_full: None
         })
-        // TODO: add parameters validation
-        // TODO: add FlightData validation
-   
     }
 }
 
@@ -61,7 +63,8 @@ pub async fn handler<S: FullStorage, T: Timestamper>(
     let flight_data = match FlightData::try_from(payload) {
         Ok(fd) => fd,
         Err(err) => match err {
-            InputFlightDataError::BadPayloadData(err) => return ErrorResponse::bad_input("payload", Some(&err.to_string())).into_response()
+            InputFlightDataError::BadPayloadData(err) => return ErrorResponse::bad_input("payload", Some(&err.to_string())).into_response(),
+            InputFlightDataError::BadLocalization => return ErrorResponse::bad_input("localization", Some("Latitude and longitude must be in degrees")).into_response()
         }
     };
     match state.new_flight_data(&flight_data, &device_id).await {
@@ -74,7 +77,8 @@ pub async fn handler<S: FullStorage, T: Timestamper>(
             BitacoraError::NotFound => ErrorResponse::not_found("Device").into_response(),
             BitacoraError::StorageError(_) => ErrorResponse::storage_error().into_response(),
             BitacoraError::Web3Error => ErrorResponse::web3_error().into_response(),
-            BitacoraError::BadIdFormat => ErrorResponse::bad_input("device_id", Some("Bad Device Id")).into_response() //this should be unreachable
+            BitacoraError::BadIdFormat => ErrorResponse::bad_input("device_id", Some("Bad Device Id")).into_response(), //this should be unreachable
+            error => ErrorResponse::from(error).into_response()
         }
     }
 }
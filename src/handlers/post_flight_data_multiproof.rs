@@ -0,0 +1,33 @@
+use axum::{extract::State, http::StatusCode, Json, response::{IntoResponse, Response}};
+use serde::Deserialize;
+
+use crate::{state::{entities::FlightDataId, errors::BitacoraError}, storage::storage::FullStorage, web3::traits::Timestamper};
+use crate::SharedBitacora;
+
+use super::errors::ErrorResponse;
+
+#[derive(Debug, Deserialize)]
+pub struct POSTFlightDataMultiProofRequest {
+    flight_data_ids: Vec<String>
+}
+
+pub async fn handler<S: FullStorage, T: Timestamper>(
+    State(state): State<SharedBitacora<S, T>>,
+    Json(payload): Json<POSTFlightDataMultiProofRequest>
+) -> Response {
+    if payload.flight_data_ids.is_empty() {
+        return ErrorResponse::bad_input("flight_data_ids", Some("At least one FlightData is required")).into_response();
+    }
+    let mut ids = Vec::with_capacity(payload.flight_data_ids.len());
+    for id in payload.flight_data_ids {
+        match FlightDataId::try_from(id) {
+            Ok(f_id) => ids.push(f_id),
+            Err(_) => return ErrorResponse::bad_input("flight_data_ids", Some("Can't decode Id")).into_response()
+        }
+    }
+    match state.flight_data_multiproof(&ids).await {
+        Ok(multiproof) => (StatusCode::OK, Json(multiproof)).into_response(),
+        Err(BitacoraError::NotFound) => ErrorResponse::not_found("FlightData").into_response(),
+        Err(error) => ErrorResponse::from(error).into_response()
+    }
+}
@@ -3,6 +3,8 @@ use axum::{
     Router
 };
 use clap::Parser;
+use common::x509::TrustedRoots;
+use configuration::StorageBackend;
 use state::bitacora::Bitacora;
 use web3::{ethereum::new_ethereum_timestamper_from_url_with_sk, traits::Timestamper};
 
@@ -15,10 +17,11 @@ pub mod configuration;
 pub mod handlers;
 pub mod state;
 pub mod storage;
+pub mod verify;
 pub mod web3;
 
-use handlers::{ get_dataset, get_device, get_flight_data, post_device, post_flight_data };
-use storage::{in_memory::InMemoryStorage, storage::FullStorage};
+use handlers::{ get_dataset, get_dataset_sparse_proof, get_device, get_flight_data, get_flight_data_proof, patch_flight_data, post_dataset, post_dataset_seal, post_device, post_device_challenge, post_device_revoke, post_device_rotate, post_flight_data, post_flight_data_multiproof };
+use storage::{append_log::AppendLogStorage, in_memory::InMemoryStorage, sqlite::SqliteStorage, storage::FullStorage};
 
 type SharedBitacora<S, T> = Arc<Bitacora<S, T>>;
 
@@ -29,27 +32,90 @@ async fn main() {
 
     // cli params
     let args = cli_args::CLIArgs::parse();
+    if let Some(cli_args::Command::Verify(verify_args)) = &args.command {
+        match verify::verify(verify_args).await {
+            Ok(true) => println!("Valid proof"),
+            Ok(false) => {
+                println!("Invalid proof");
+                std::process::exit(1);
+            },
+            Err(error) => {
+                eprintln!("Verification failed: {}", error);
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
     configuration::BitacoraConfiguration::from_cli_args(&args);
 
-    let timestamper = new_ethereum_timestamper_from_url_with_sk(&args.web3, &args.private_key).await.unwrap();
+    let private_key = args.private_key.clone().expect("The private key is required");
+    let timestamper = new_ethereum_timestamper_from_url_with_sk(&args.web3, &private_key).await.unwrap();
 
-    let shared_bitacora = Arc::new(
-        Bitacora::new(
-            InMemoryStorage::default(),
-            timestamper
-        )
-    );
+    match args.storage {
+        StorageBackend::InMemory => serve(Bitacora::new(InMemoryStorage::default(), timestamper)).await,
+        StorageBackend::Sqlite => {
+            let storage = SqliteStorage::open(&args.sqlite_path).expect("Failed opening the SQLite database");
+            tracing::info!("using SQLite storage at {}", args.sqlite_path);
+            serve(Bitacora::new(storage, timestamper)).await
+        },
+        StorageBackend::AppendLog => {
+            let storage = AppendLogStorage::open(&args.log_dir).await.expect("Failed replaying the storage log");
+            tracing::info!("using append-only log storage in {}", args.log_dir);
+            serve(Bitacora::new(storage, timestamper)).await
+        }
+    }
+}
+
+async fn serve<S, T>(bitacora: Bitacora<S, T>)
+where
+    S: FullStorage + 'static,
+    T: Timestamper + 'static
+{
+    let bitacora = match configuration::BitacoraConfiguration::get_trusted_root_cas() {
+        Some(path) => {
+            let pem = std::fs::read_to_string(&path).expect("Failed reading the trusted root CAs");
+            let trusted_roots = TrustedRoots::from_pem(&pem).expect("Failed parsing the trusted root CAs");
+            tracing::info!("attesting device keys with the root CAs in {}", path);
+            bitacora.with_trusted_roots(trusted_roots)
+        },
+        None => bitacora
+    };
+    let shared_bitacora = Arc::new(bitacora);
+
+    match shared_bitacora.recover_interrupted_anchoring().await {
+        Ok(anchored) if !anchored.is_empty() => tracing::info!("anchored {} datasets interrupted by the previous run", anchored.len()),
+        Ok(_) => (),
+        Err(error) => tracing::warn!("failed recovering the interrupted dataset anchoring: {:?}", error)
+    }
+
+    tokio::spawn(shared_bitacora.clone().run_challenge_expiry(configuration::BitacoraConfiguration::get_device_challenge_ttl()));
+
+    let max_idle = configuration::BitacoraConfiguration::get_dataset_max_idle();
+    let max_age = configuration::BitacoraConfiguration::get_dataset_max_age();
+    if max_idle.is_some() || max_age.is_some() {
+        let check_interval = configuration::BitacoraConfiguration::get_auto_seal_check_interval();
+        tracing::info!("sealing datasets automatically (max idle {:?}, max age {:?})", max_idle, max_age);
+        tokio::spawn(shared_bitacora.clone().run_auto_sealing(check_interval, max_idle, max_age));
+    }
 
     // build our application with a route
     let app = Router::new()
         // `GET /` goes to `root`
         .route("/", get(root))
         .route("/device", post(post_device::handler))
+        .route("/device/challenge", post(post_device_challenge::handler))
         .route("/device/:id", get(get_device::handler))
+        .route("/device/:id/rotate", post(post_device_rotate::handler))
+        .route("/device/:id/revoke", post(post_device_revoke::handler))
         // `POST /users` goes to `create_user`
         .route("/flight_data", post(post_flight_data::handler))
-        .route("/flight_data/:id", get(get_flight_data::handler))
+        .route("/flight_data/multiproof", post(post_flight_data_multiproof::handler))
+        .route("/flight_data/:id", get(get_flight_data::handler).patch(patch_flight_data::handler))
+        .route("/flight_data/:id/proof", get(get_flight_data_proof::handler))
+        .route("/dataset", post(post_dataset::handler))
         .route("/dataset/:id", get(get_dataset::handler))
+        .route("/dataset/:id/seal", post(post_dataset_seal::handler))
+        .route("/dataset/:id/sparse_proof", get(get_dataset_sparse_proof::handler))
         .with_state(shared_bitacora);
 
     // run our app with hyper
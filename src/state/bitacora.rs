@@ -1,28 +1,101 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use tracing::{warn, info, debug, trace};
+use async_trait::async_trait;
+use rayon::prelude::*;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use tracing::{error, warn, info, debug, trace};
 
-use crate::common::merkle::Keccak256;
-use crate::common::prelude::MerkleTree;
+use crate::common::hash_algorithm::{DynMerkleTree, DynSparseMerkleTree, HashAlgorithm};
+use crate::common::merkle::PARALLEL_HASHING_THRESHOLD;
+use crate::common::prelude::{Bytes32, MerkleRoot, TreeFormat};
+use crate::common::x509::{AttestationError, TrustedRoots};
 use crate::configuration::BitacoraConfiguration as Conf;
 use crate::storage::errors::Error as StorageError;
 use crate::storage::storage::{FullStorage, FlightDataStorage, DeviceStorage, DatasetStorage};
+use crate::storage::transaction::Transaction;
 use crate::web3::traits::Timestamper;
 
-use super::entities::{FlightData, Device, DeviceId, Dataset, Entity, FlightDataId};
+use super::challenges::Challenges;
+use super::entities::{FlightData, FlightDataProof, FlightDataMultiProof, FlightDataSparseProof, Device, DeviceChallenge, DeviceId, DeviceKey, DeviceRevocation, Dataset, DatasetAccumulator, DatasetId, DatasetKind, DatasetStatus, Entity, FlightDataId, LocalizationPoint};
 use super::errors::BitacoraError;
+use super::tree_cache::TreeCache;
 
 pub const DATASET_DEFAULT_LIMIT: u32 = 10;  //TODO: refactor with configuration management
 
 type SharedBitacora<S, T> = Arc<Bitacora<S, T>>;
 
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_millis() as u64).unwrap_or_default()
+}
+
+/// Leaves of the FlightData in the Merkle trees of a Dataset, hashed in parallel when there are many.
+fn flight_data_leaves(hash_algorithm: HashAlgorithm, tree_format: TreeFormat, fds: &[FlightData]) -> Vec<MerkleRoot> {
+    let leaf = |fd: &FlightData| hash_algorithm.leaf_hash(tree_format, fd.to_bytes());
+    if fds.len() >= PARALLEL_HASHING_THRESHOLD {
+        fds.par_iter().map(leaf).collect()
+    } else {
+        fds.iter().map(leaf).collect()
+    }
+}
+
+/// Sparse Merkle tree of the FlightData of a Dataset, keyed by their id with their leaf as value.
+fn dataset_sparse_tree(hash_algorithm: HashAlgorithm, leaves: &[(FlightDataId, MerkleRoot)]) -> DynSparseMerkleTree {
+    let mut fd_smt = DynSparseMerkleTree::new(hash_algorithm);
+    for (fd_id, leaf) in leaves.iter() {
+        fd_smt.insert(&Bytes32::from(fd_id), leaf.clone());
+    }
+    fd_smt
+}
+
+/// Runs the hashing of a whole Dataset on the blocking threads, so that the async task serving the
+/// request, and the others sharing its worker, are not held up by large Datasets.
+async fn hash_blocking<R, F>(f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(join_error) => std::panic::resume_unwind(join_error.into_panic())
+    }
+}
+
 pub struct Bitacora<S, T> 
 where
     S: FullStorage,
     T: Timestamper
 {
     storage: S,
-    timestamper: T
+    timestamper: T,
+    // Serializes the FlightData ingestion of each device, so that a Dataset is filled exactly up to its limit
+    pub(super) device_locks: Mutex<HashMap<DeviceId, Arc<AsyncMutex<()>>>>,
+    // Merkle trees of the recently proven Datasets, which can't change once sealed
+    tree_cache: Mutex<TreeCache>,
+    // Registration challenges waiting for the signature of their device
+    challenges: Mutex<Challenges>,
+    // Root CAs of the manufacturers whose certificates attest device keys
+    trusted_roots: TrustedRoots
+}
+
+/// Exclusive access to the ingestion of a device. Its lock is removed from the map once the last holder
+/// releases it, so that the map only keeps the devices being served.
+struct DeviceGuard<'a> {
+    device_locks: &'a Mutex<HashMap<DeviceId, Arc<AsyncMutex<()>>>>,
+    device_id: DeviceId,
+    guard: Option<OwnedMutexGuard<()>>
+}
+
+impl Drop for DeviceGuard<'_> {
+    fn drop(&mut self) {
+        // Locks are only cloned with the map locked, so nobody else holds or waits for it when the map has the only reference
+        let mut device_locks = self.device_locks.lock().unwrap();
+        drop(self.guard.take());
+        if device_locks.get(&self.device_id).is_some_and(|device_lock| Arc::strong_count(device_lock) == 1) {
+            device_locks.remove(&self.device_id);
+        }
+    }
 }
 
 impl <S, T> Bitacora<S, T>
@@ -31,35 +104,122 @@ where
     T: Timestamper
 {
     pub fn new(storage: S, timestamper: T) -> Bitacora<S, T> {
-        Bitacora { storage, timestamper }
+        Bitacora {
+            storage,
+            timestamper,
+            device_locks: Mutex::new(HashMap::new()),
+            tree_cache: Mutex::new(TreeCache::new(Conf::get_merkle_tree_cache_size())),
+            challenges: Mutex::new(Challenges::new(Conf::get_device_challenge_ttl().as_millis() as u64, Conf::get_device_challenge_capacity())),
+            trusted_roots: TrustedRoots::default()
+        }
+    }
+
+    /// Accepts the certificate chains up to these roots in `attest_device`, none are by default.
+    pub fn with_trusted_roots(mut self, trusted_roots: TrustedRoots) -> Self {
+        self.trusted_roots = trusted_roots;
+        self
+    }
+
+    async fn device_guard(&self, device_id: &DeviceId) -> DeviceGuard<'_> {
+        let device_lock = self.device_locks.lock().unwrap().entry(device_id.clone()).or_default().clone();
+        DeviceGuard {
+            device_locks: &self.device_locks,
+            device_id: device_id.clone(),
+            guard: Some(device_lock.lock_owned().await)
+        }
+    }
+
+    /// Locks an existing device, returning it as read once the lock is held. Unknown devices are rejected
+    /// before a lock is created for them.
+    async fn lock_device(&self, device_id: &DeviceId) -> Result<(DeviceGuard<'_>, Device), BitacoraError> {
+        self.read_device(device_id).await?;
+        let guard = self.device_guard(device_id).await;
+        let device = self.read_device(device_id).await?;
+        Ok((guard, device))
+    }
+
+    async fn read_device(&self, device_id: &DeviceId) -> Result<Device, BitacoraError> {
+        match self.storage.get_device(device_id).await {
+            Ok(Some(device)) => Ok(device),
+            Ok(None) => Err(BitacoraError::NotFound),
+            Err(storage_error) => Err(BitacoraError::StorageError(storage_error))
+        }
     }
 
     pub async fn new_flight_data(&self, fd: &FlightData, device_id: &DeviceId) -> Result<Dataset, BitacoraError> {
         info!("Creating a new FlightData");
-        trace!(device_id = device_id, "Searching the supplied device");
-        let _ = match self.storage.get_device(device_id) {
-            Ok(maybe_device) => match maybe_device {
-                Some(device) => device,
-                None => return Err(BitacoraError::NotFound)
-            },
+        self.ingest_flight_data(fd, device_id, DatasetKind::Live).await
+    }
+
+    /// Completes an anchored live FlightData with the full payload downloaded after the flight.
+    ///
+    /// The live record is left untouched, so that it stays provable against its anchored Dataset,
+    /// while the full record gets its own id and is collected in a Full Dataset of the device.
+    /// The full record takes the timestamp and the localization of the live one, which `signature_full`
+    /// covers along with the full payload. When the device supplies them too, they must be the same.
+    pub async fn complete_flight_data(&self, id: &FlightDataId, device_id: &DeviceId, timestamp: Option<u64>, localization: Option<LocalizationPoint>, payload: Vec<u8>, signature_full: String) -> Result<(FlightData, Dataset), BitacoraError> {
+        info!(flight_data_id = id.to_string(), "Completing a FlightData with full data");
+        let live_fd = match self.storage.get_flight_data(id).await {
+            Ok(Some(fd)) => fd,
+            Ok(None) => return Err(BitacoraError::NotFound),
             Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
         };
-        trace!(flight_data_id = fd.id.to_string(), "Storing the FlightData");
-        match self.storage.set_flight_data(fd) {
-            Ok(already_existing) => {
-                if already_existing {
-                    warn!(flight_data_id=fd.id.to_string(), "Supplied FlightData already exists");
-                    return Err(BitacoraError::AlreadyExists(Entity::FlightData, fd.id.clone().into()))
-                }
-            }
+        if FlightDataId::new(live_fd.timestamp, device_id) != *id {
+            warn!(flight_data_id = id.to_string(), device_id = device_id, "FlightData does not belong to the supplied device");
+            return Err(BitacoraError::NotFound);
+        }
+        if timestamp.is_some_and(|timestamp| timestamp != live_fd.timestamp) {
+            return Err(BitacoraError::FullDataMismatch("timestamp"));
+        }
+        if localization.is_some_and(|localization| localization != live_fd.localization) {
+            return Err(BitacoraError::FullDataMismatch("localization"));
+        }
+        let live_ds_id = match self.storage.get_flight_data_dataset(id).await {
+            Ok(Some(ds_id)) => ds_id,
+            Ok(None) => return Err(BitacoraError::NotFound),
             Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
         };
-        trace!(device_id = device_id, "Getting the latest dataset");
-        let dataset = match self.storage.get_latest_dataset(device_id) {
+        match self.storage.get_dataset(&live_ds_id).await {
+            Ok(Some(live_ds)) if live_ds.kind == DatasetKind::Live && live_ds.status == DatasetStatus::Anchored => (),
+            Ok(Some(_)) => return Err(BitacoraError::NotAnchored(live_ds_id)),
+            Ok(None) => return Err(BitacoraError::NotFound),
+            Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+        };
+        let full_fd = FlightData {
+            id: FlightDataId::new_full(live_fd.timestamp, device_id),
+            signature: live_fd.signature,
+            timestamp: live_fd.timestamp,
+            localization: live_fd.localization,
+            payload,
+            signature_full: Some(signature_full)
+        };
+        let dataset = self.ingest_flight_data(&full_fd, device_id, DatasetKind::Full).await?;
+        Ok((full_fd, dataset))
+    }
+
+    async fn ingest_flight_data(&self, fd: &FlightData, device_id: &DeviceId, kind: DatasetKind) -> Result<Dataset, BitacoraError> {
+        // The device is read with its lock held, so that its key can't be rotated or revoked meanwhile
+        trace!(device_id = device_id, "Searching the supplied device");
+        let (ingestion_guard, device) = self.lock_device(device_id).await?;
+        if !fd.validate(&device) {
+            warn!(flight_data_id = fd.id.to_string(), device_id = device_id, "FlightData signature not valid for the device");
+            return Err(BitacoraError::InvalidSignature);
+        }
+        trace!(flight_data_id = fd.id.to_string(), "Checking the FlightData is new");
+        match self.storage.get_flight_data(&fd.id).await {
+            Ok(Some(_)) => {
+                warn!(flight_data_id=fd.id.to_string(), "Supplied FlightData already exists");
+                return Err(BitacoraError::AlreadyExists(Entity::FlightData, fd.id.clone().into()))
+            },
+            Ok(None) => (),
+            Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+        };
+        trace!(device_id = device_id, kind = %kind, "Getting the latest dataset");
+        let dataset = match self.storage.get_latest_dataset(device_id, kind).await {
             Ok(maybe_dataset) => match maybe_dataset {
                 Some(dataset) => {
-                    if dataset.limit == dataset.count {
-                        debug!(device_id = device_id, dataset_id = dataset.id, "Latest dataset is full");
+                    if !dataset.status.accepts_flight_data() || dataset.limit == dataset.count {
+                        debug!(device_id = device_id, dataset_id = dataset.id, status = %dataset.status, "Latest dataset is full");
                         None
                     } else {
                         Some(dataset)
@@ -72,67 +232,517 @@ where
             },
             Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
         };
+        // FlightData, its Dataset (if a new one is needed) and their relation are stored all together or not at all
+        let mut transaction = Transaction::new();
+        transaction.new_flight_data(fd);
         let mut dataset = match dataset {
             Some(ds) => ds,
             None => {
-                let dataset_default_count = Conf::get_dataset_default_count();
-                match self.new_dataset(dataset_default_count, device_id) {
-                    Ok(new_dataset) => new_dataset,
-                    //TODO handle failure here (FlightData has no Dataset)
-                    Err(bitacora_error) => return Err(bitacora_error)
-                }
+                let dataset = self.prototype_dataset(Conf::get_dataset_default_count(), kind, Conf::get_hash_algorithm(), Conf::get_tree_format()).await?;
+                transaction.add_dataset(&dataset, device_id);
+                dataset
             }
         };
-        trace!(dataset_id = dataset.id, flight_data_id = fd.id.to_string(), "Adding FlightData to the Dataset");
-        match self.storage.add_flight_data(&dataset.id, fd) {
+        let mut accumulator = match self.storage.get_dataset_accumulator(&dataset.id).await {
+            Ok(Some(accumulator)) => Some(accumulator),
+            Ok(None) if dataset.count == 0 => Some(DatasetAccumulator::new(dataset.hash_algorithm, dataset.tree_format)),
+            Ok(None) => None,
+            Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+        };
+        let received_at = now_millis();
+        let leaf = dataset.hash_algorithm.leaf_hash(dataset.tree_format, fd.to_bytes());
+        transaction.add_flight_data(&dataset.id, fd)
+            .touch_dataset(&dataset.id, received_at)
+            .add_dataset_sparse_leaf(&dataset.id, &fd.id, &leaf);
+        // Datasets stored before accumulators were introduced have none, their root is computed from all the FlightData
+        if let Some(accumulator) = accumulator.as_mut() {
+            accumulator.append(&fd.to_bytes());
+            transaction.set_dataset_accumulator(&dataset.id, accumulator);
+        }
+        trace!(dataset_id = dataset.id, flight_data_id = fd.id.to_string(), "Storing the FlightData into the Dataset");
+        match self.storage.commit(transaction).await {
             Ok(_) => {
                 trace!(flight_data_id=fd.id.to_string(), "Created FlightData");
                 dataset.count += 1; // to avoid reading it again
+                dataset.first_flight_data_at = dataset.first_flight_data_at.or(Some(received_at));
+                dataset.last_flight_data_at = Some(received_at);
+                if dataset.status == DatasetStatus::Initialized {
+                    Self::transition(&mut dataset, DatasetStatus::Active)?;
+                }
+            },
+            Err(StorageError::AlreadyExists) => {
+                warn!(flight_data_id=fd.id.to_string(), "Supplied FlightData already exists");
+                return Err(BitacoraError::AlreadyExists(Entity::FlightData, fd.id.clone().into()))
             },
             Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
         }
-        if dataset.count == dataset.limit {
-            let fds = match self.storage.get_dataset_flight_data(&dataset.id) {
-                Ok(fds) => fds,
-                Err(err) => return Err(BitacoraError::StorageError(err))
-            };
-            let mut fd_mt = MerkleTree::<Keccak256>::new();
-            for fd in fds {
-                fd_mt.append(&fd.to_bytes());
+        let sealed = dataset.count == dataset.limit;
+        if sealed {
+            self.seal_dataset(&mut dataset).await?;
+            Self::transition(&mut dataset, DatasetStatus::Anchoring)?;
+            if let Err(storage_error) = self.storage.set_dataset(&dataset).await {
+                return Err(BitacoraError::StorageError(storage_error));
             }
-            dataset.merkle_root = fd_mt.root().cloned();
+        }
+        // The Dataset state is persisted, next FlightData of the device can proceed while this one is being anchored
+        drop(ingestion_guard);
+        if sealed {
             self.timestamp_dataset(&mut dataset, device_id).await?;
         }
         Ok(dataset)
     }
 
-    pub fn new_dataset(&self, limit: u32, device_id: &DeviceId) -> Result<Dataset, BitacoraError> {
-        trace!(device_id=device_id, "Creating new Dataset");
-        let new_id = match self.storage.new_dataset_id() {
+    /// Computes the Merkle root and the sparse Merkle root over the FlightData currently in the Dataset
+    /// so that it can not accept more of them. The sealed Dataset is not stored.
+    async fn seal_dataset(&self, dataset: &mut Dataset) -> Result<(), BitacoraError> {
+        Self::transition(dataset, DatasetStatus::Sealed)?;
+        let accumulator = match self.storage.get_dataset_accumulator(&dataset.id).await {
+            Ok(accumulator) => accumulator,
+            Err(err) => return Err(BitacoraError::StorageError(err))
+        };
+        // The sparse Merkle tree needs every leaf with its FlightData id, while the Merkle root can come from the accumulator
+        let leaves = self.dataset_leaves(dataset).await?;
+        let accumulator_root = match accumulator {
+            Some(accumulator) if accumulator.algorithm() == dataset.hash_algorithm
+                && accumulator.format() == dataset.tree_format
+                && accumulator.len() == dataset.count as usize => accumulator.root(),
+            _ => {
+                debug!(dataset_id = dataset.id, "Computing the Merkle root from the Dataset FlightData");
+                None
+            }
+        };
+        let (hash_algorithm, tree_format) = (dataset.hash_algorithm, dataset.tree_format);
+        let (merkle_root, sparse_merkle_root) = hash_blocking(move || {
+            let sparse_merkle_root = dataset_sparse_tree(hash_algorithm, &leaves).root();
+            let merkle_root = accumulator_root.or_else(|| {
+                let mut fd_mt = DynMerkleTree::new(hash_algorithm, tree_format);
+                fd_mt.append_leaves(leaves.into_iter().map(|(_, leaf)| leaf).collect());
+                fd_mt.root().cloned()
+            });
+            (merkle_root, sparse_merkle_root)
+        }).await;
+        dataset.merkle_root = merkle_root;
+        dataset.sparse_merkle_root = sparse_merkle_root;
+        dataset.limit = dataset.count;
+        debug!(dataset_id = dataset.id, count = dataset.count, "Sealed Dataset");
+        Ok(())
+    }
+
+    /// Leaves of the FlightData of the Dataset along with their ids, in the order they were added. They are
+    /// kept at ingestion, only the Datasets filled before that are hashed again from all their FlightData.
+    async fn dataset_leaves(&self, dataset: &Dataset) -> Result<Vec<(FlightDataId, MerkleRoot)>, BitacoraError> {
+        match self.storage.get_dataset_sparse_leaves(&dataset.id).await {
+            Ok(leaves) if leaves.len() == dataset.count as usize => return Ok(leaves),
+            Ok(_) => debug!(dataset_id = dataset.id, "Hashing the leaves of the Dataset FlightData"),
+            Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+        }
+        let fds = match self.storage.get_dataset_flight_data(&dataset.id).await {
+            Ok(fds) => fds,
+            Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+        };
+        let (hash_algorithm, tree_format) = (dataset.hash_algorithm, dataset.tree_format);
+        Ok(hash_blocking(move || {
+            let leaves = flight_data_leaves(hash_algorithm, tree_format, &fds);
+            fds.into_iter().map(|fd| fd.id).zip(leaves).collect()
+        }).await)
+    }
+
+    fn transition(dataset: &mut Dataset, next: DatasetStatus) -> Result<(), BitacoraError> {
+        if !dataset.status.can_transition_to(next) {
+            warn!(dataset_id = dataset.id, from = %dataset.status, to = %next, "Illegal Dataset status transition");
+            return Err(BitacoraError::InvalidStatusTransition(dataset.status, next));
+        }
+        trace!(dataset_id = dataset.id, from = %dataset.status, to = %next, "Dataset status transition");
+        dataset.status = next;
+        Ok(())
+    }
+
+    /// Opens a new Dataset for the device, which receives its next FlightData. With `close_current`
+    /// the currently active Dataset is sealed and anchored, otherwise it is left as it is. The Merkle
+    /// tree uses the configured hash function and format unless `hash_algorithm` or `tree_format` are given.
+    pub async fn open_dataset(&self, limit: u32, device_id: &DeviceId, close_current: bool, hash_algorithm: Option<HashAlgorithm>, tree_format: Option<TreeFormat>) -> Result<Dataset, BitacoraError> {
+        info!(device_id = device_id, limit = limit, close_current = close_current, "Opening a new Dataset");
+        let (ingestion_guard, _) = self.lock_device(device_id).await?;
+        // Sealing the current Dataset and opening the new one is stored all together or not at all
+        let mut transaction = Transaction::new();
+        let mut closed_dataset = None;
+        if close_current {
+            match self.storage.get_latest_dataset(device_id, DatasetKind::Live).await {
+                Ok(Some(mut current)) if current.status == DatasetStatus::Active => {
+                    self.seal_dataset(&mut current).await?;
+                    Self::transition(&mut current, DatasetStatus::Anchoring)?;
+                    transaction.set_dataset(&current);
+                    closed_dataset = Some(current);
+                },
+                Ok(_) => debug!(device_id = device_id, "Device has no active Dataset to close"),
+                Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+            }
+        }
+        let dataset = self.prototype_dataset(limit, DatasetKind::Live, hash_algorithm.unwrap_or_else(Conf::get_hash_algorithm), tree_format.unwrap_or_else(Conf::get_tree_format)).await?;
+        transaction.add_dataset(&dataset, device_id);
+        match self.storage.commit(transaction).await {
+            Ok(_) => trace!(dataset_id=dataset.id, device_id=device_id, "Created Dataset"),
+            Err(StorageError::AlreadyExists) => return Err(BitacoraError::AlreadyExists(Entity::Dataset, dataset.id)),
+            Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+        }
+        drop(ingestion_guard);
+        if let Some(mut closed_dataset) = closed_dataset {
+            // The new Dataset is already open, a failed anchoring is left to be retried
+            if let Err(error) = self.timestamp_dataset(&mut closed_dataset, device_id).await {
+                warn!(dataset_id = closed_dataset.id, error = ?error, "Failed anchoring the closed Dataset");
+            }
+        }
+        Ok(dataset)
+    }
+
+    /// Builds the inclusion proof of a FlightData in the Merkle tree of its sealed Dataset.
+    pub async fn flight_data_proof(&self, id: &FlightDataId) -> Result<FlightDataProof, BitacoraError> {
+        let (fd, ds_id) = self.get_flight_data_with_dataset(id).await?;
+        let (dataset, device_id, merkle_root) = self.get_anchored_dataset(&ds_id).await?;
+        let leaf = dataset.hash_algorithm.leaf_hash(dataset.tree_format, fd.to_bytes());
+        let proof = match self.with_dataset_tree(&dataset, &merkle_root, |fd_mt| fd_mt.proof(&leaf)).await? {
+            Some(proof) => proof,
+            None => return Err(BitacoraError::NotFound)
+        };
+        Ok(FlightDataProof {
+            flight_data_id: id.to_string(),
+            dataset_id: dataset.id,
+            device_id,
+            hash_algorithm: dataset.hash_algorithm,
+            tree_format: dataset.tree_format,
+            leaf,
+            proof,
+            merkle_root,
+            web3: dataset.web3
+        })
+    }
+
+    /// Builds a single proof for several FlightData of the same sealed Dataset.
+    pub async fn flight_data_multiproof(&self, ids: &[FlightDataId]) -> Result<FlightDataMultiProof, BitacoraError> {
+        let mut ds_id: Option<DatasetId> = None;
+        let mut fds = Vec::with_capacity(ids.len());
+        for id in ids.iter() {
+            let (fd, fd_ds_id) = self.get_flight_data_with_dataset(id).await?;
+            match &ds_id {
+                Some(ds_id) if *ds_id != fd_ds_id => return Err(BitacoraError::DifferentDatasets(ds_id.clone(), fd_ds_id)),
+                Some(_) => {},
+                None => ds_id = Some(fd_ds_id)
+            }
+            fds.push(fd);
+        }
+        let ds_id = match ds_id {
+            Some(ds_id) => ds_id,
+            None => return Err(BitacoraError::NotFound)
+        };
+        let (dataset, device_id, merkle_root) = self.get_anchored_dataset(&ds_id).await?;
+        let leaves: Vec<MerkleRoot> = fds.iter().map(|fd| dataset.hash_algorithm.leaf_hash(dataset.tree_format, fd.to_bytes())).collect();
+        let multiproof = match self.with_dataset_tree(&dataset, &merkle_root, |fd_mt| fd_mt.multiproof(&leaves)).await? {
+            Some(multiproof) => multiproof,
+            None => return Err(BitacoraError::NotFound)
+        };
+        Ok(FlightDataMultiProof {
+            flight_data_ids: ids.iter().map(|id| id.to_string()).collect(),
+            dataset_id: dataset.id,
+            device_id,
+            hash_algorithm: dataset.hash_algorithm,
+            tree_format: dataset.tree_format,
+            leaves,
+            n_leaves: multiproof.n_leaves,
+            leaf_indices: multiproof.leaf_indices,
+            proof: multiproof.proof,
+            merkle_root,
+            web3: dataset.web3
+        })
+    }
+
+    /// Builds the proof that the FlightData id is, or is not, in the sparse Merkle tree of an anchored
+    /// Dataset. The FlightData does not need to exist.
+    pub async fn flight_data_sparse_proof(&self, ds_id: &DatasetId, id: &FlightDataId) -> Result<FlightDataSparseProof, BitacoraError> {
+        let (dataset, device_id, merkle_root) = self.get_anchored_dataset(ds_id).await?;
+        let sparse_merkle_root = match &dataset.sparse_merkle_root {
+            Some(sparse_merkle_root) => sparse_merkle_root.clone(),
+            None => return Err(BitacoraError::NoSparseMerkleTree(dataset.id))
+        };
+        let leaves = self.dataset_leaves(&dataset).await?;
+        let hash_algorithm = dataset.hash_algorithm;
+        let key = Bytes32::from(id);
+        let (root, value, proof) = hash_blocking(move || {
+            let fd_smt = dataset_sparse_tree(hash_algorithm, &leaves);
+            (fd_smt.root(), fd_smt.get(&key).cloned(), fd_smt.proof(&key))
+        }).await;
+        if root.as_ref() != Some(&sparse_merkle_root) {
+            error!(dataset_id = dataset.id, "Stored sparse Merkle root does not match the Dataset FlightData");
+            return Err(BitacoraError::StorageError(StorageError::InconsistentRelatedData(String::from("Dataset"), String::from("FlightData"))));
+        }
+        Ok(FlightDataSparseProof {
+            flight_data_id: id.to_string(),
+            dataset_id: dataset.id,
+            device_id,
+            hash_algorithm: dataset.hash_algorithm,
+            tree_format: dataset.tree_format,
+            value,
+            sibling_depths: proof.sibling_depths,
+            siblings: proof.siblings,
+            sparse_merkle_root,
+            merkle_root,
+            web3: dataset.web3
+        })
+    }
+
+    async fn get_flight_data_with_dataset(&self, id: &FlightDataId) -> Result<(FlightData, DatasetId), BitacoraError> {
+        let fd = match self.storage.get_flight_data(id).await {
+            Ok(Some(fd)) => fd,
+            Ok(None) => return Err(BitacoraError::NotFound),
+            Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+        };
+        match self.storage.get_flight_data_dataset(id).await {
+            Ok(Some(ds_id)) => Ok((fd, ds_id)),
+            Ok(None) => Err(BitacoraError::NotFound),
+            Err(storage_error) => Err(BitacoraError::StorageError(storage_error))
+        }
+    }
+
+    async fn get_anchored_dataset(&self, ds_id: &DatasetId) -> Result<(Dataset, DeviceId, MerkleRoot), BitacoraError> {
+        let dataset = match self.storage.get_dataset(ds_id).await {
+            Ok(Some(dataset)) => dataset,
+            Ok(None) => return Err(BitacoraError::NotFound),
+            Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+        };
+        let merkle_root = match &dataset.merkle_root {
+            Some(merkle_root) => merkle_root.clone(),
+            None => return Err(BitacoraError::NotAnchored(dataset.id))
+        };
+        let device_id = match self.storage.get_dataset_device(&dataset.id).await {
+            Ok(Some(device_id)) => device_id,
+            Ok(None) => return Err(BitacoraError::StorageError(StorageError::InconsistentRelatedData(String::from("Dataset"), String::from("Device")))),
+            Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+        };
+        Ok((dataset, device_id, merkle_root))
+    }
+
+    /// Runs `f` on the Merkle tree of an anchored Dataset. The tree is taken from the cache when it matches
+    /// the stored root, otherwise it is rebuilt from storage, checked and cached.
+    async fn with_dataset_tree<R, F>(&self, dataset: &Dataset, merkle_root: &MerkleRoot, f: F) -> Result<R, BitacoraError>
+    where
+        F: FnOnce(&mut DynMerkleTree) -> R
+    {
+        {
+            let mut tree_cache = self.tree_cache.lock().unwrap();
+            if let Some(fd_mt) = tree_cache.get_mut(&dataset.id) {
+                if fd_mt.root() == Some(merkle_root) {
+                    return Ok(f(fd_mt));
+                }
+            }
+        }
+        let fds = match self.storage.get_dataset_flight_data(&dataset.id).await {
+            Ok(fds) => fds,
+            Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+        };
+        let (hash_algorithm, tree_format) = (dataset.hash_algorithm, dataset.tree_format);
+        let mut fd_mt = hash_blocking(move || {
+            let mut fd_mt = DynMerkleTree::new(hash_algorithm, tree_format);
+            fd_mt.append_leaves(flight_data_leaves(hash_algorithm, tree_format, &fds));
+            fd_mt.root();
+            fd_mt
+        }).await;
+        if fd_mt.root() != Some(merkle_root) {
+            error!(dataset_id = dataset.id, "Stored Merkle root does not match the Dataset FlightData");
+            return Err(BitacoraError::StorageError(StorageError::InconsistentRelatedData(String::from("Dataset"), String::from("FlightData"))));
+        }
+        trace!(dataset_id = dataset.id, "Caching the Dataset Merkle tree");
+        let mut tree_cache = self.tree_cache.lock().unwrap();
+        Ok(f(tree_cache.insert(dataset.id.clone(), fd_mt)))
+    }
+
+    /// Seals a Dataset with the FlightData it currently has and anchors its Merkle root. A Dataset
+    /// whose anchoring failed is submitted again.
+    pub async fn seal(&self, ds_id: &DatasetId) -> Result<Dataset, BitacoraError> {
+        info!(dataset_id = ds_id, "Sealing Dataset");
+        match self.seal_if(ds_id, |_| true).await? {
+            Some(dataset) => Ok(dataset),
+            None => unreachable!()
+        }
+    }
+
+    /// Seals and anchors the active Datasets that did not receive FlightData for `max_idle` or
+    /// whose first FlightData was received more than `max_age` ago, returning them.
+    pub async fn seal_expired_datasets(&self, now: u64, max_idle: Option<Duration>, max_age: Option<Duration>) -> Result<Vec<Dataset>, BitacoraError> {
+        let is_expired = |dataset: &Dataset| {
+            let idle = match (max_idle, dataset.last_flight_data_at) {
+                (Some(max_idle), Some(last)) => now.saturating_sub(last) >= max_idle.as_millis() as u64,
+                _ => false
+            };
+            let aged = match (max_age, dataset.first_flight_data_at) {
+                (Some(max_age), Some(first)) => now.saturating_sub(first) >= max_age.as_millis() as u64,
+                _ => false
+            };
+            dataset.status == DatasetStatus::Active && (idle || aged)
+        };
+        let candidates = match self.storage.get_datasets_by_status(DatasetStatus::Active).await {
+            Ok(datasets) => datasets,
+            Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+        };
+        let mut sealed = Vec::new();
+        for candidate in candidates.iter().filter(|dataset| is_expired(dataset)) {
+            info!(dataset_id = candidate.id, "Sealing expired Dataset");
+            match self.seal_if(&candidate.id, is_expired).await {
+                Ok(Some(dataset)) => sealed.push(dataset),
+                Ok(None) => debug!(dataset_id = candidate.id, "Dataset changed before being sealed"),
+                Err(error) => warn!(dataset_id = candidate.id, error = ?error, "Failed sealing expired Dataset")
+            }
+        }
+        Ok(sealed)
+    }
+
+    /// Periodically seals the expired Datasets, see [`Bitacora::seal_expired_datasets`].
+    pub async fn run_auto_sealing(self: Arc<Self>, check_interval: Duration, max_idle: Option<Duration>, max_age: Option<Duration>) {
+        let mut interval = tokio::time::interval(check_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            match self.seal_expired_datasets(now_millis(), max_idle, max_age).await {
+                Ok(sealed) if !sealed.is_empty() => info!(count = sealed.len(), "Sealed expired Datasets"),
+                Ok(_) => trace!("No expired Dataset to seal"),
+                Err(error) => warn!(error = ?error, "Failed searching expired Datasets")
+            }
+        }
+    }
+
+    /// Periodically drops the registration challenges that expired without being answered.
+    pub async fn run_challenge_expiry(self: Arc<Self>, check_interval: Duration) {
+        let mut interval = tokio::time::interval(check_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.challenges.lock().unwrap().expire(now_millis());
+        }
+    }
+
+    /// Resubmits the Datasets left `Anchoring` by a previous run, which stopped before knowing the outcome of
+    /// their submission, and submits those it left `Sealed` before starting their submission, returning the
+    /// ones anchored. It must run before any Dataset is sealed or anchored by this run. Those that fail are
+    /// left `AnchorFailed`, to be retried by `seal`.
+    pub async fn recover_interrupted_anchoring(&self) -> Result<Vec<Dataset>, BitacoraError> {
+        let mut interrupted = Vec::new();
+        for status in [DatasetStatus::Anchoring, DatasetStatus::Sealed] {
+            match self.storage.get_datasets_by_status(status).await {
+                Ok(datasets) => interrupted.extend(datasets),
+                Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+            };
+        }
+        let mut anchored = Vec::new();
+        for mut dataset in interrupted {
+            if dataset.status == DatasetStatus::Anchoring {
+                warn!(dataset_id = dataset.id, "Dataset anchoring was interrupted, submitting it again");
+                Self::transition(&mut dataset, DatasetStatus::AnchorFailed)?;
+                if let Err(storage_error) = self.storage.set_dataset(&dataset).await {
+                    return Err(BitacoraError::StorageError(storage_error));
+                }
+            } else {
+                warn!(dataset_id = dataset.id, "Dataset was sealed but never submitted, submitting it");
+            }
+            let status = dataset.status;
+            match self.seal_if(&dataset.id, |dataset| dataset.status == status).await {
+                Ok(Some(dataset)) => anchored.push(dataset),
+                Ok(None) => debug!(dataset_id = dataset.id, "Dataset changed before being submitted again"),
+                Err(error) => warn!(dataset_id = dataset.id, error = ?error, "Failed anchoring the interrupted Dataset")
+            }
+        }
+        Ok(anchored)
+    }
+
+    /// Seals the Dataset if it satisfies the condition once the device is locked, returning None otherwise.
+    /// Datasets already sealed, whose submission failed or never started, are only submitted.
+    async fn seal_if<F: Fn(&Dataset) -> bool>(&self, ds_id: &DatasetId, condition: F) -> Result<Option<Dataset>, BitacoraError> {
+        let device_id = match self.storage.get_dataset_device(ds_id).await {
+            Ok(Some(device_id)) => device_id,
+            Ok(None) => return Err(BitacoraError::NotFound),
+            Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+        };
+        let ingestion_guard = self.device_guard(&device_id).await;
+        let mut dataset = match self.storage.get_dataset(ds_id).await {
+            Ok(Some(dataset)) => dataset,
+            Ok(None) => return Err(BitacoraError::NotFound),
+            Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
+        };
+        if !condition(&dataset) {
+            return Ok(None);
+        }
+        if !matches!(dataset.status, DatasetStatus::Sealed | DatasetStatus::AnchorFailed) {
+            self.seal_dataset(&mut dataset).await?;
+        }
+        // Stored as being submitted before the device is released, so that a concurrent seal finds it
+        // `Anchoring` and does not submit it a second time
+        Self::transition(&mut dataset, DatasetStatus::Anchoring)?;
+        if let Err(storage_error) = self.storage.set_dataset(&dataset).await {
+            return Err(BitacoraError::StorageError(storage_error));
+        }
+        drop(ingestion_guard);
+        self.timestamp_dataset(&mut dataset, &device_id).await?;
+        Ok(Some(dataset))
+    }
+
+    /// Builds an empty Dataset with a fresh id, without storing it.
+    async fn prototype_dataset(&self, limit: u32, kind: DatasetKind, hash_algorithm: HashAlgorithm, tree_format: TreeFormat) -> Result<Dataset, BitacoraError> {
+        let new_id = match self.storage.new_dataset_id().await {
             Ok(id) => id,
             Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
         };
-        let dataset = Dataset {
-            id: new_id.clone(),
+        Ok(Dataset {
+            id: new_id,
+            kind,
+            hash_algorithm,
+            tree_format,
             limit,
             count: 0,
+            status: DatasetStatus::Initialized,
+            first_flight_data_at: None,
+            last_flight_data_at: None,
             merkle_root: None,
+            sparse_merkle_root: None,
             web3: None
+        })
+    }
+
+    /// Issues the challenge the device signs to be registered, see `new_device`.
+    pub async fn new_device_challenge(&self, device: &Device) -> Result<DeviceChallenge, BitacoraError> {
+        match self.storage.get_device(&device.id).await {
+            Ok(Some(_)) => return Err(BitacoraError::AlreadyExists(Entity::Device, device.id.clone())),
+            Ok(None) => (),
+            Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
         };
-        match self.storage.add_dataset(&dataset, device_id) {
-            Ok(_) => { //TODO: manage clashes on Ids
-                trace!(dataset_id=new_id, device_id=device_id, "Created Dataset");
-                Ok(dataset)
-            }, 
-            Err(storage_error) => match storage_error {
-                StorageError::AlreadyExists => return Err(BitacoraError::AlreadyExists(Entity::Dataset, dataset.id)),
-                _ => return Err(BitacoraError::StorageError(storage_error))
+        info!(device_id = device.id, "Issuing a Device registration challenge");
+        Ok(self.challenges.lock().unwrap().issue(&device.id, now_millis()))
+    }
+
+    /// Attests the device with the manufacturer certificate chain of its key, see `TrustedRoots::verify_chain`.
+    pub fn attest_device(&self, device: &mut Device, pem_chain: &str) -> Result<(), BitacoraError> {
+        let certified_key = match self.trusted_roots.verify_chain(pem_chain, now_millis()) {
+            Ok(certified_key) => certified_key,
+            Err(attestation_error) => {
+                warn!(device_id = device.id, error = attestation_error.to_string(), "Device certificate chain rejected");
+                return Err(BitacoraError::InvalidAttestation(attestation_error));
             }
+        };
+        if certified_key.key_type != device.key_type || certified_key.pk != device.pk.0 {
+            return Err(BitacoraError::InvalidAttestation(AttestationError::KeyMismatch));
         }
+        device.attestation = Some(certified_key.attestation);
+        Ok(())
     }
 
-    pub async fn new_device(&self, device: &mut Device) -> Result<(), BitacoraError> {
-        match self.storage.new_device(&device) {
+    /// Registers the device, once it proved to hold its key by signing the challenge with the given
+    /// nonce. The challenge can't be answered again, even when the signature is not valid.
+    pub async fn new_device(&self, device: &mut Device, nonce: &Bytes32, signature: &str) -> Result<(), BitacoraError> {
+        let challenge = self.challenges.lock().unwrap().take(&device.id, nonce, now_millis());
+        match challenge {
+            Some(challenge) if device.verify(&challenge.signing_bytes(), signature) => (),
+            _ => {
+                warn!(device_id = device.id, "Device registration challenge not answered");
+                return Err(BitacoraError::InvalidChallenge);
+            }
+        };
+        match self.storage.new_device(&device).await {
             Ok(_) => (),
             Err(storage_error) => match storage_error {
                 StorageError::AlreadyExists => return Err(BitacoraError::AlreadyExists(Entity::Device, device.id.clone())),
@@ -142,12 +752,92 @@ where
         self.timestamp_device(device).await
     }
 
+    /// Replaces the key of the device with `key` from now on, keeping its id. Both the current and the new
+    /// key sign `Device::key_rotation_signing_bytes`, the first to authorize the rotation and the second to
+    /// prove it is held. FlightData keep being verified with the key the device held at their timestamp.
+    pub async fn rotate_device_key(&self, device_id: &DeviceId, mut key: DeviceKey, signature: &str, key_signature: &str) -> Result<Device, BitacoraError> {
+        let (_ingestion_guard, mut device) = self.lock_device(device_id).await?;
+        if device.is_revoked() {
+            return Err(BitacoraError::DeviceRevoked(device_id.clone()));
+        }
+        let message = device.key_rotation_signing_bytes(key.key_type, &key.pk);
+        if !device.verify(&message, signature) || !key.key_type.verify(key.pk.as_ref(), &message, key_signature) {
+            warn!(device_id = device_id, "Device key rotation not signed by both keys");
+            return Err(BitacoraError::InvalidSignature);
+        }
+        // Intervals stay ordered even if the clock went back
+        key.valid_from = now_millis().max(device.key_valid_from);
+        key.valid_until = None;
+        key.web3 = None;
+        // Submitted before being stored, so that the key is never replaced without the chain knowing it: on
+        // a web3 failure nothing changed and the device can retry the same rotation
+        let mut rotated_device = device.clone();
+        rotated_device.rotate_key(key.clone());
+        match self.timestamper.rotate_device_key(&rotated_device).await {
+            Ok(web3_info) => {
+                info!(device=device.id, tx_hash=web3_info.tx.hash.to_string(), "Device key rotation submitted to blockchain");
+                key.web3 = Some(web3_info);
+            },
+            Err(web3_error) => {
+                warn!(device_id = device_id, error = ?web3_error, "Device key rotation not submitted, the key is kept");
+                return Err(BitacoraError::Web3Error);
+            }
+        };
+        if let Err(storage_error) = self.storage.rotate_device_key(device_id, &key).await {
+            return Err(Self::device_storage_error(device_id, storage_error));
+        }
+        device.rotate_key(key);
+        info!(device_id = device_id, key_type = %device.key_type, "Device key rotated");
+        Ok(device)
+    }
+
+    /// Revokes the device from now on: FlightData timestamped later are rejected and its key can't be
+    /// rotated anymore. The current key signs `Device::revocation_signing_bytes`.
+    pub async fn revoke_device(&self, device_id: &DeviceId, signature: &str) -> Result<Device, BitacoraError> {
+        let (_ingestion_guard, mut device) = self.lock_device(device_id).await?;
+        if device.is_revoked() {
+            return Err(BitacoraError::DeviceRevoked(device_id.clone()));
+        }
+        if !device.verify(&device.revocation_signing_bytes(), signature) {
+            warn!(device_id = device_id, "Device revocation not signed by its key");
+            return Err(BitacoraError::InvalidSignature);
+        }
+        let revoked_at = now_millis().max(device.key_valid_from);
+        // Submitted before being stored, as key rotations are
+        let web3_info = match self.timestamper.revoke_device(&device).await {
+            Ok(web3_info) => {
+                info!(device=device.id, tx_hash=web3_info.tx.hash.to_string(), "Device revocation submitted to blockchain");
+                web3_info
+            },
+            Err(web3_error) => {
+                warn!(device_id = device_id, error = ?web3_error, "Device revocation not submitted, the device is kept");
+                return Err(BitacoraError::Web3Error);
+            }
+        };
+        device.revocation = Some(DeviceRevocation { revoked_at, web3: Some(web3_info) });
+        let mut transaction = Transaction::new();
+        transaction.revoke_device(device_id, revoked_at).set_device(&device);
+        if let Err(storage_error) = self.storage.commit(transaction).await {
+            return Err(Self::device_storage_error(device_id, storage_error));
+        }
+        info!(device_id = device_id, "Device revoked");
+        Ok(device)
+    }
+
+    fn device_storage_error(device_id: &DeviceId, storage_error: StorageError) -> BitacoraError {
+        match storage_error {
+            StorageError::DeviceRevoked => BitacoraError::DeviceRevoked(device_id.clone()),
+            StorageError::NotFound(_) => BitacoraError::NotFound,
+            _ => BitacoraError::StorageError(storage_error)
+        }
+    }
+
     async fn timestamp_device(&self, device: &mut Device) -> Result<(), BitacoraError> {
         match self.timestamper.register_device(device).await {
             Ok(web3_info) => {
                 info!(device=device.id, tx_hash=web3_info.tx.hash.to_string(), "Device submitted to blockchain");
                 device.web3 = Some(web3_info);
-                match self.storage.set_device(device) {
+                match self.storage.set_device(device).await {
                     Ok(_) => Ok(()),
                     Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
                 }
@@ -156,71 +846,109 @@ where
         }
     }
 
+    /// Submits the Dataset, which the caller stored `Anchoring` while holding its device, and stores the outcome.
     async fn timestamp_dataset(&self, dataset: &mut Dataset, device_id: &String) -> Result<(), BitacoraError> {
-        match self.timestamper.register_dataset(dataset, device_id).await {
+        let result = match self.timestamper.register_dataset(dataset, device_id).await {
             Ok(web3_info) => {
                 info!(dataset=dataset.id, tx_hash=web3_info.tx.hash.to_string(), "Dataset submitted to blockchain");
                 dataset.web3 = Some(web3_info);
-                match self.storage.set_dataset(dataset) {
-                    Ok(_) => Ok(()),
-                    Err(storage_error) => return Err(BitacoraError::StorageError(storage_error))
-                }
+                Self::transition(dataset, DatasetStatus::Anchored)?;
+                Ok(())
             },
-            Err(_) => Err(BitacoraError::Web3Error)
+            Err(_) => {
+                warn!(dataset=dataset.id, "Failed submitting Dataset to blockchain");
+                Self::transition(dataset, DatasetStatus::AnchorFailed)?;
+                Err(BitacoraError::Web3Error)
+            }
+        };
+        match self.storage.set_dataset(dataset).await {
+            Ok(_) => result,
+            Err(storage_error) => Err(BitacoraError::StorageError(storage_error))
         }
     }
 }
 
+#[async_trait]
 impl <S: FullStorage, T: Timestamper> FlightDataStorage for SharedBitacora<S, T> {
-    fn get_flight_data(&self, id: &FlightDataId) -> Result<Option<FlightData>, crate::storage::errors::Error> {
-        self.storage.get_flight_data(id)
+    async fn get_flight_data(&self, id: &FlightDataId) -> Result<Option<FlightData>, crate::storage::errors::Error> {
+        self.storage.get_flight_data(id).await
     }
 
-    fn set_flight_data(&self, fd: &FlightData) -> Result<bool, crate::storage::errors::Error> {
-        self.storage.set_flight_data(fd)
+    async fn set_flight_data(&self, fd: &FlightData) -> Result<bool, crate::storage::errors::Error> {
+        self.storage.set_flight_data(fd).await
     }
 }
 
+#[async_trait]
 impl <S: FullStorage, T: Timestamper> DeviceStorage for SharedBitacora<S, T> {
-    fn new_device(&self, device: &Device) -> Result<(), StorageError> {
-        self.storage.new_device(device)
+    async fn new_device(&self, device: &Device) -> Result<(), StorageError> {
+        self.storage.new_device(device).await
+    }
+
+    async fn get_device(&self, id: &DeviceId) -> Result<Option<super::entities::Device>, crate::storage::errors::Error> {
+        self.storage.get_device(id).await
     }
 
-    fn get_device(&self, id: &DeviceId) -> Result<Option<super::entities::Device>, crate::storage::errors::Error> {
-        self.storage.get_device(id)
+    async fn set_device(&self, device: &super::entities::Device) -> Result<bool, crate::storage::errors::Error> {
+        self.storage.set_device(device).await
     }
 
-    fn set_device(&self, device: &super::entities::Device) -> Result<bool, crate::storage::errors::Error> {
-        self.storage.set_device(device)
+    async fn rotate_device_key(&self, device_id: &DeviceId, key: &DeviceKey) -> Result<(), StorageError> {
+        self.storage.rotate_device_key(device_id, key).await
+    }
+
+    async fn revoke_device(&self, device_id: &DeviceId, at: u64) -> Result<(), StorageError> {
+        self.storage.revoke_device(device_id, at).await
     }
 }
 
+#[async_trait]
 impl <S: FullStorage, T: Timestamper> DatasetStorage for SharedBitacora<S, T> {
-    fn add_flight_data(&self, ds_id: &super::entities::DatasetId, fd: &FlightData) -> Result<(), crate::storage::errors::Error> {
-        self.storage.add_flight_data(ds_id, fd)
+    async fn add_flight_data(&self, ds_id: &super::entities::DatasetId, fd: &FlightData) -> Result<(), crate::storage::errors::Error> {
+        self.storage.add_flight_data(ds_id, fd).await
+    }
+
+    async fn get_dataset_flight_data(&self, ds_id: &super::entities::DatasetId) -> Result<Vec<FlightData>, StorageError> {
+        self.storage.get_dataset_flight_data(ds_id).await
+    }
+
+    async fn add_dataset(&self, ds: &Dataset, device_id: &DeviceId) -> Result<(), crate::storage::errors::Error> {
+        self.storage.add_dataset(ds, device_id).await
+    }
+
+    async fn set_dataset(&self, ds: &Dataset) -> Result<bool, crate::storage::errors::Error> {
+        self.storage.set_dataset(ds).await
+    }
+
+    async fn get_dataset(&self, id: &super::entities::DatasetId) -> Result<Option<Dataset>, crate::storage::errors::Error> {
+        self.storage.get_dataset(id).await
+    }
+
+    async fn get_latest_dataset(&self, device_id: &DeviceId, kind: DatasetKind) -> Result<Option<Dataset>, crate::storage::errors::Error> {
+        self.storage.get_latest_dataset(device_id, kind).await
     }
 
-    fn get_dataset_flight_data(&self, ds_id: &super::entities::DatasetId) -> Result<Vec<FlightData>, StorageError> {
-        self.storage.get_dataset_flight_data(ds_id)
+    async fn get_datasets_by_status(&self, status: DatasetStatus) -> Result<Vec<Dataset>, crate::storage::errors::Error> {
+        self.storage.get_datasets_by_status(status).await
     }
 
-    fn add_dataset(&self, ds: &Dataset, device_id: &DeviceId) -> Result<(), crate::storage::errors::Error> {
-        self.storage.add_dataset(ds, device_id)
+    async fn get_flight_data_dataset(&self, fd_id: &FlightDataId) -> Result<Option<super::entities::DatasetId>, crate::storage::errors::Error> {
+        self.storage.get_flight_data_dataset(fd_id).await
     }
 
-    fn set_dataset(&self, ds: &Dataset) -> Result<bool, crate::storage::errors::Error> {
-        self.storage.set_dataset(ds)
+    async fn get_dataset_device(&self, ds_id: &super::entities::DatasetId) -> Result<Option<DeviceId>, crate::storage::errors::Error> {
+        self.storage.get_dataset_device(ds_id).await
     }
 
-    fn get_dataset(&self, id: &super::entities::DatasetId) -> Result<Option<Dataset>, crate::storage::errors::Error> {
-        self.storage.get_dataset(id)
+    async fn get_dataset_accumulator(&self, ds_id: &super::entities::DatasetId) -> Result<Option<DatasetAccumulator>, crate::storage::errors::Error> {
+        self.storage.get_dataset_accumulator(ds_id).await
     }
 
-    fn get_latest_dataset(&self, device_id: &DeviceId) -> Result<Option<Dataset>, crate::storage::errors::Error> {
-        self.storage.get_latest_dataset(device_id)
+    async fn get_dataset_sparse_leaves(&self, ds_id: &super::entities::DatasetId) -> Result<Vec<(FlightDataId, MerkleRoot)>, crate::storage::errors::Error> {
+        self.storage.get_dataset_sparse_leaves(ds_id).await
     }
 
-    fn new_dataset_id(&self) -> Result<super::entities::DatasetId, crate::storage::errors::Error> {
-        self.storage.new_dataset_id()
+    async fn new_dataset_id(&self) -> Result<super::entities::DatasetId, crate::storage::errors::Error> {
+        self.storage.new_dataset_id().await
     }
 }
\ No newline at end of file
@@ -0,0 +1,81 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::common::prelude::Bytes32;
+
+use super::entities::{DeviceChallenge, DeviceId};
+
+/// Challenges a device can have outstanding at once, the oldest are dropped beyond it
+pub const MAX_CHALLENGES_PER_DEVICE: usize = 8;
+
+/// Registration challenges issued and not answered yet. A device can have several outstanding, so that
+/// requesting new ones for it does not void the one it is signing. A challenge is answered at most once:
+/// it is dropped as soon as it is taken, whether its signature is then valid or not.
+pub struct Challenges {
+    ttl_millis: u64,
+    capacity: usize,
+    pending: HashMap<DeviceId, VecDeque<DeviceChallenge>>,
+    // Every challenge issued and not expired yet, in the order they were issued and so expire. The ones
+    // already taken or dropped are left here until they expire, and count towards the capacity.
+    issued: VecDeque<(u64, DeviceId, Bytes32)>
+}
+
+impl Challenges {
+    pub fn new(ttl_millis: u64, capacity: usize) -> Self {
+        Challenges { ttl_millis, capacity, pending: HashMap::new(), issued: VecDeque::new() }
+    }
+
+    /// Issues a new challenge for the device. When the device or the whole set is full, their oldest
+    /// challenge is dropped.
+    pub fn issue(&mut self, device_id: &DeviceId, now: u64) -> DeviceChallenge {
+        while self.issued.len() >= self.capacity.max(1) {
+            if let Some((_, oldest_device_id, oldest_nonce)) = self.issued.pop_front() {
+                self.remove(&oldest_device_id, &oldest_nonce);
+            }
+        }
+        let challenge = DeviceChallenge {
+            device_id: device_id.clone(),
+            nonce: Bytes32(rand::random()),
+            expires_at: now + self.ttl_millis
+        };
+        let device_challenges = self.pending.entry(device_id.clone()).or_default();
+        if device_challenges.len() >= MAX_CHALLENGES_PER_DEVICE {
+            device_challenges.pop_front();
+        }
+        device_challenges.push_back(challenge.clone());
+        self.issued.push_back((challenge.expires_at, device_id.clone(), challenge.nonce.clone()));
+        challenge
+    }
+
+    /// Takes the challenge of the device when `nonce` is one issued and it did not expire.
+    pub fn take(&mut self, device_id: &DeviceId, nonce: &Bytes32, now: u64) -> Option<DeviceChallenge> {
+        self.remove(device_id, nonce).filter(|challenge| challenge.expires_at > now)
+    }
+
+    /// Drops the expired challenges, meant to be called periodically: it only looks at the expired ones.
+    pub fn expire(&mut self, now: u64) {
+        while self.issued.front().is_some_and(|(expires_at, _, _)| *expires_at <= now) {
+            if let Some((_, device_id, nonce)) = self.issued.pop_front() {
+                self.remove(&device_id, &nonce);
+            }
+        }
+    }
+
+    /// Number of challenges waiting for their signature.
+    pub fn len(&self) -> usize {
+        self.pending.values().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn remove(&mut self, device_id: &DeviceId, nonce: &Bytes32) -> Option<DeviceChallenge> {
+        let device_challenges = self.pending.get_mut(device_id)?;
+        let position = device_challenges.iter().position(|challenge| challenge.nonce == *nonce)?;
+        let challenge = device_challenges.remove(position);
+        if device_challenges.is_empty() {
+            self.pending.remove(device_id);
+        }
+        challenge
+    }
+}
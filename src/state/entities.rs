@@ -2,17 +2,29 @@ use std::{fmt::Display, hash};
 
 use ethers::utils::keccak256;
 use hex::FromHexError;
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as DeError};
 use sha2::{ Digest, Sha256 };
 
 use crate::{web3::traits::Web3Info};
 
+use crate::common::hash_algorithm::{DynMerkleAccumulator, HashAlgorithm};
+use crate::common::signature::KeyType;
+use crate::common::x509::Attestation;
 use crate::common::prelude::*;
 
 use super::errors::BitacoraError;
 
 pub const ID_BYTE_LENGTH: u8 = 16;
 pub const FLIGHT_DATA_ID_PREFIX: u8 = 1;
+pub const FLIGHT_DATA_FULL_ID_PREFIX: u8 = 2;
+/// Prepended to the signed bytes of a FlightData, so that its signature can't be passed off for another message.
+pub const FLIGHT_DATA_SIGNING_DOMAIN: &[u8] = b"Bitacora FlightData v1";
+/// Prepended to the signed bytes of a device registration challenge.
+pub const DEVICE_CHALLENGE_SIGNING_DOMAIN: &[u8] = b"Bitacora Device registration v1";
+/// Prepended to the signed bytes of a device key rotation.
+pub const DEVICE_KEY_ROTATION_SIGNING_DOMAIN: &[u8] = b"Bitacora Device key rotation v1";
+/// Prepended to the signed bytes of a device revocation.
+pub const DEVICE_REVOCATION_SIGNING_DOMAIN: &[u8] = b"Bitacora Device revocation v1";
 
 #[derive(Clone, Debug)]
 pub enum Entity {
@@ -37,59 +49,290 @@ impl From<Entity> for String {
     }
 }
 
-pub type PublicKey = Bytes32;
+/// Public key of a device, whose encoding depends on its `KeyType`. Serialized as a 0x prefixed hex string.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct PublicKey(pub Vec<u8>);
+
+impl Serialize for PublicKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        Bytes32::serialize_as_hex(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let hex_string = String::deserialize(deserializer)?;
+        PublicKey::try_from(hex_string.as_str()).map_err(D::Error::custom)
+    }
+}
+
+impl AsRef<[u8]> for PublicKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
 
 impl From<[u8; 32]> for PublicKey {
     fn from(value: [u8; 32]) -> Self {
-        Bytes32(value)
+        PublicKey(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for PublicKey {
+    fn from(value: Vec<u8>) -> Self {
+        PublicKey(value)
+    }
+}
+
+impl TryFrom<&str> for PublicKey {
+
+    type Error = FromHexError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let value = value.strip_prefix("0x").unwrap_or(value);
+        Ok(PublicKey(hex::decode(value)?))
     }
 }
 
-// impl From<PublicKey> for [u8; 32] {
-//     fn from(value: PublicKey) -> Self {
-//         let bytes = hex::decode(value.0).map_err(|_| "Invalid Hex String").unwrap();
+impl TryFrom<String> for PublicKey {
+
+    type Error = FromHexError;
 
-//         let mut array = [0u8; 32];
-//         array.copy_from_slice(&bytes);
-//         array
-//     }
-// }
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        PublicKey::try_from(value.as_str())
+    }
+}
 
 pub type DeviceId = String;
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Device {
     pub id: DeviceId,
-    #[serde(serialize_with = "Bytes32::serialize_as_hex")]
+    /// Signature scheme of `pk`, `Ed25519` for the devices registered before it was recorded
+    #[serde(default)]
+    pub key_type: KeyType,
     pub pk: PublicKey,
+    /// Manufacturer certificate of `pk`, when the device was registered with its chain
+    #[serde(default)]
+    pub attestation: Option<Attestation>,
+    /// Since when `pk` is the key of the device (milliseconds since the Unix epoch), 0 until it is rotated
+    #[serde(default)]
+    pub key_valid_from: u64,
+    /// Keys rotated out, oldest first
+    #[serde(default)]
+    pub previous_keys: Vec<DeviceKey>,
+    #[serde(default)]
+    pub revocation: Option<DeviceRevocation>,
+    /// Transaction registering `pk` on chain
+    pub web3: Option<Web3Info>
+}
+
+/// A key of a device, valid from `valid_from` until `valid_until` excluded (milliseconds since the Unix
+/// epoch). Only the current key has no end.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeviceKey {
+    pub key_type: KeyType,
+    pub pk: PublicKey,
+    pub valid_from: u64,
+    pub valid_until: Option<u64>,
+    pub attestation: Option<Attestation>,
+    pub web3: Option<Web3Info>
+}
+
+impl DeviceKey {
+    pub fn is_valid_at(&self, timestamp: u64) -> bool {
+        match self.valid_until {
+            Some(valid_until) => self.valid_from <= timestamp && timestamp < valid_until,
+            None => self.valid_from <= timestamp
+        }
+    }
+}
+
+/// A revoked device has no valid key from `revoked_at` (milliseconds since the Unix epoch) on, and its key
+/// can't be rotated anymore.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeviceRevocation {
+    pub revoked_at: u64,
     pub web3: Option<Web3Info>
 }
 
+impl Device {
+    /// Device holding the given key, `None` if the key is not a valid one of its type. ECDSA keys are
+    /// kept compressed, so both of their SEC1 encodings give the same device.
+    ///
+    /// The id is the base58 SHA-256 of the key. For key types other than Ed25519 the key is prefixed
+    /// by the id of its type, so that the same bytes registered with different types give different ids.
+    pub fn new(key_type: KeyType, pk: PublicKey) -> Option<Self> {
+        let pk = PublicKey(key_type.canonical_key(pk.as_ref())?);
+        let mut hasher = Sha256::new();
+        if key_type != KeyType::Ed25519 {
+            hasher.update([key_type.id()]);
+        }
+        hasher.update(&pk.0);
+        Some(Device {
+            id: bs58::encode(hasher.finalize()).into_string(),
+            key_type,
+            pk,
+            attestation: None,
+            key_valid_from: 0,
+            previous_keys: Vec::new(),
+            revocation: None,
+            web3: None
+        })
+    }
+
+    /// Checks that `signature` is the one of the current key of the device over `message`.
+    pub fn verify(&self, message: &[u8], signature: &str) -> bool {
+        self.key_type.verify(self.pk.as_ref(), message, signature)
+    }
+
+    /// Checks that `signature` is the one over `message` of the key the device held at `timestamp`.
+    pub fn verify_at(&self, timestamp: u64, message: &[u8], signature: &str) -> bool {
+        match self.key_at(timestamp) {
+            Some(key) => key.key_type.verify(key.pk.as_ref(), message, signature),
+            None => false
+        }
+    }
+
+    pub fn current_key(&self) -> DeviceKey {
+        DeviceKey {
+            key_type: self.key_type,
+            pk: self.pk.clone(),
+            valid_from: self.key_valid_from,
+            valid_until: self.revocation.as_ref().map(|revocation| revocation.revoked_at),
+            attestation: self.attestation.clone(),
+            web3: self.web3.clone()
+        }
+    }
+
+    /// Key of the device at `timestamp` (milliseconds since the Unix epoch), none once it is revoked.
+    pub fn key_at(&self, timestamp: u64) -> Option<DeviceKey> {
+        let current_key = self.current_key();
+        if current_key.is_valid_at(timestamp) {
+            return Some(current_key);
+        }
+        self.previous_keys.iter().find(|key| key.is_valid_at(timestamp)).cloned()
+    }
+
+    pub fn is_revoked(&self) -> bool {
+        self.revocation.is_some()
+    }
+
+    /// Makes `key` the current key of the device from its `valid_from`, the one it replaces is kept
+    /// among the previous keys. The id of the device does not change.
+    pub fn rotate_key(&mut self, key: DeviceKey) {
+        let mut previous_key = self.current_key();
+        previous_key.valid_until = Some(key.valid_from);
+        self.previous_keys.push(previous_key);
+        self.key_type = key.key_type;
+        self.pk = key.pk;
+        self.key_valid_from = key.valid_from;
+        self.attestation = key.attestation;
+        self.web3 = key.web3;
+    }
+
+    /// Bytes both the current and the new key sign to rotate the key of the device: the signing domain, the
+    /// device id prefixed by its length (u32 BE), the number of previous keys (u32 BE), so that a rotation
+    /// can't be replayed, the id of the new key type and the new key prefixed by its length (u32 BE).
+    pub fn key_rotation_signing_bytes(&self, key_type: KeyType, pk: &PublicKey) -> Vec<u8> {
+        let mut accumulator = self.lifecycle_signing_bytes(DEVICE_KEY_ROTATION_SIGNING_DOMAIN);
+        accumulator.push(key_type.id());
+        accumulator.extend_from_slice((pk.0.len() as u32).to_be_bytes().as_slice());
+        accumulator.extend_from_slice(pk.as_ref());
+        accumulator
+    }
+
+    /// Bytes the current key signs to revoke the device: the signing domain, the device id prefixed by its
+    /// length (u32 BE) and the number of previous keys (u32 BE).
+    pub fn revocation_signing_bytes(&self) -> Vec<u8> {
+        self.lifecycle_signing_bytes(DEVICE_REVOCATION_SIGNING_DOMAIN)
+    }
+
+    fn lifecycle_signing_bytes(&self, domain: &[u8]) -> Vec<u8> {
+        let mut accumulator = Vec::with_capacity(domain.len() + 4 + self.id.len() + 4);
+        accumulator.extend_from_slice(domain);
+        accumulator.extend_from_slice((self.id.len() as u32).to_be_bytes().as_slice());
+        accumulator.extend_from_slice(self.id.as_bytes());
+        accumulator.extend_from_slice((self.previous_keys.len() as u32).to_be_bytes().as_slice());
+        accumulator
+    }
+}
+
+/// Nonce a device signs to prove it holds the key it is being registered with. It can be answered once,
+/// until `expires_at` (milliseconds since the Unix epoch).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeviceChallenge {
+    pub device_id: DeviceId,
+    pub nonce: Bytes32,
+    pub expires_at: u64
+}
+
+impl DeviceChallenge {
+    /// Bytes the device signs to answer the challenge: the signing domain, the device id prefixed by its
+    /// length (u32 BE) and then the nonce.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut accumulator = Vec::with_capacity(DEVICE_CHALLENGE_SIGNING_DOMAIN.len() + 4 + self.device_id.len() + 32);
+        accumulator.extend_from_slice(DEVICE_CHALLENGE_SIGNING_DOMAIN);
+        accumulator.extend_from_slice((self.device_id.len() as u32).to_be_bytes().as_slice());
+        accumulator.extend_from_slice(self.device_id.as_bytes());
+        accumulator.extend_from_slice(self.nonce.as_ref());
+        accumulator
+    }
+}
+
+/// Ed25519 device. The key is not validated, see `Device::new`.
 impl From<PublicKey> for Device {
     fn from(value: PublicKey) -> Self {
         let mut hasher = Sha256::new();
-        hasher.update(value.0);
+        hasher.update(&value.0);
         Device {
             id: bs58::encode(hasher.finalize()).into_string(),
-            pk: value.clone(),
+            key_type: KeyType::Ed25519,
+            pk: value,
+            attestation: None,
+            key_valid_from: 0,
+            previous_keys: Vec::new(),
+            revocation: None,
             web3: None
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 pub struct LocalizationPoint {
     pub longitude: f64,
     pub latitude: f64,
 }
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize)]
+impl LocalizationPoint {
+    /// Whether the point is a position on Earth, in degrees
+    pub fn is_valid(&self) -> bool {
+        (-90.0..=90.0).contains(&self.latitude) && (-180.0..=180.0).contains(&self.longitude)
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
 pub struct FlightDataId(Bytes32);
 
 impl FlightDataId {
     pub fn new(timestamp: u64, device_id: &str) -> Self {
+        Self::with_prefix(FLIGHT_DATA_ID_PREFIX, timestamp, device_id)
+    }
+
+    /// Id of the full FlightData completing the live one with the same timestamp.
+    pub fn new_full(timestamp: u64, device_id: &str) -> Self {
+        Self::with_prefix(FLIGHT_DATA_FULL_ID_PREFIX, timestamp, device_id)
+    }
+
+    fn with_prefix(prefix: u8, timestamp: u64, device_id: &str) -> Self {
         let mut hasher = Sha256::new();
-        hasher.update(FLIGHT_DATA_ID_PREFIX.to_be_bytes());
+        hasher.update(prefix.to_be_bytes());
         hasher.update(timestamp.to_be_bytes());
         hasher.update(device_id);
         FlightDataId(hasher.finalize().into())
@@ -110,6 +353,18 @@ impl Default for FlightDataId {
     }
 }
 
+impl From<Bytes32> for FlightDataId {
+    fn from(value: Bytes32) -> Self {
+        FlightDataId(value)
+    }
+}
+
+impl From<&FlightDataId> for Bytes32 {
+    fn from(value: &FlightDataId) -> Self {
+        value.0.clone()
+    }
+}
+
 impl TryFrom<String> for FlightDataId {
     type Error = BitacoraError;
 
@@ -138,13 +393,14 @@ impl AsRef<[u8]> for FlightDataId {
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct FlightData {
     pub id: FlightDataId,
     pub signature: String,
     pub timestamp: u64,
     pub localization: LocalizationPoint,
-    pub payload: Vec<u8>
+    pub payload: Vec<u8>,
+    pub signature_full: Option<String>
 }
 
 impl FlightData {
@@ -157,15 +413,168 @@ impl FlightData {
         accumulator.extend(&self.payload);
         accumulator
     }
+
+    /// Bytes the device signs for the FlightData: the signing domain, the device id prefixed by its
+    /// length (u32 BE), the timestamp (u64 BE), latitude and longitude (f64 BE) and then the payload.
+    pub fn signing_bytes(&self, device_id: &str) -> Vec<u8> {
+        let mut accumulator = Vec::with_capacity(FLIGHT_DATA_SIGNING_DOMAIN.len() + 4 + device_id.len() + 24 + self.payload.len());
+        accumulator.extend_from_slice(FLIGHT_DATA_SIGNING_DOMAIN);
+        accumulator.extend_from_slice((device_id.len() as u32).to_be_bytes().as_slice());
+        accumulator.extend_from_slice(device_id.as_bytes());
+        accumulator.extend_from_slice(self.timestamp.to_be_bytes().as_slice());
+        accumulator.extend_from_slice(self.localization.latitude.to_be_bytes().as_slice());
+        accumulator.extend_from_slice(self.localization.longitude.to_be_bytes().as_slice());
+        accumulator.extend(&self.payload);
+        accumulator
+    }
+
+    /// Checks that the device signed the FlightData with the key it held at its timestamp. Full FlightData
+    /// are checked against `signature_full`, as `signature` covers the live payload they replace.
+    pub fn validate(&self, device: &Device) -> bool {
+        let signature = self.signature_full.as_ref().unwrap_or(&self.signature);
+        device.verify_at(self.timestamp, &self.signing_bytes(&device.id), signature)
+    }
 }
 
 pub type DatasetId = String;
 
-#[derive(Clone, Debug, Serialize)]
+/// Inclusion claim of a FlightData in the Merkle tree of its Dataset. Folding `leaf` with each
+/// element of `proof`, hashing every pair in ascending order with `hash_algorithm` as `tree_format`
+/// tells, gives `merkle_root`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FlightDataProof {
+    pub flight_data_id: String,
+    pub dataset_id: DatasetId,
+    pub device_id: DeviceId,
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+    #[serde(default)]
+    pub tree_format: TreeFormat,
+    pub leaf: Bytes32,
+    pub proof: Vec<Bytes32>,
+    pub merkle_root: MerkleRoot,
+    pub web3: Option<Web3Info>
+}
+
+/// Inclusion claim of several FlightData of the same Dataset, see `common::merkle::verify_multiproof`.
+/// `leaves` and `leaf_indices` follow the order of `flight_data_ids`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FlightDataMultiProof {
+    pub flight_data_ids: Vec<String>,
+    pub dataset_id: DatasetId,
+    pub device_id: DeviceId,
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+    #[serde(default)]
+    pub tree_format: TreeFormat,
+    pub leaves: Vec<Bytes32>,
+    pub n_leaves: usize,
+    pub leaf_indices: Vec<usize>,
+    pub proof: Vec<Bytes32>,
+    pub merkle_root: MerkleRoot,
+    pub web3: Option<Web3Info>
+}
+
+/// Claim that a FlightData id is, or is not, in a Dataset, see `common::sparse_merkle::verify_sparse_proof`.
+/// `value` is the leaf of the FlightData in the Merkle tree of the Dataset, absent when it is not there.
+/// `sparse_merkle_root` is registered on chain next to `merkle_root`, the root of the Merkle tree of the Dataset.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FlightDataSparseProof {
+    pub flight_data_id: String,
+    pub dataset_id: DatasetId,
+    pub device_id: DeviceId,
+    pub hash_algorithm: HashAlgorithm,
+    pub tree_format: TreeFormat,
+    pub value: Option<Bytes32>,
+    pub sibling_depths: Vec<u16>,
+    pub siblings: Vec<Bytes32>,
+    pub sparse_merkle_root: MerkleRoot,
+    pub merkle_root: MerkleRoot,
+    pub web3: Option<Web3Info>
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum DatasetStatus {
+    /// No FlightData assigned yet
+    #[default]
+    Initialized,
+    /// At least one FlightData assigned, more can be accepted
+    Active,
+    /// Merkle root computed, no more FlightData can be accepted
+    Sealed,
+    /// Merkle root submitted to the blockchain, waiting for the outcome
+    Anchoring,
+    Anchored,
+    /// Submission to the blockchain failed, it can be retried
+    AnchorFailed
+}
+
+impl DatasetStatus {
+    pub fn accepts_flight_data(&self) -> bool {
+        matches!(self, DatasetStatus::Initialized | DatasetStatus::Active)
+    }
+
+    pub fn can_transition_to(&self, next: DatasetStatus) -> bool {
+        matches!(
+            (self, next),
+            (DatasetStatus::Initialized, DatasetStatus::Active) |
+            (DatasetStatus::Active, DatasetStatus::Sealed) |
+            (DatasetStatus::Sealed, DatasetStatus::Anchoring) |
+            (DatasetStatus::AnchorFailed, DatasetStatus::Anchoring) |
+            (DatasetStatus::Anchoring, DatasetStatus::Anchored) |
+            (DatasetStatus::Anchoring, DatasetStatus::AnchorFailed)
+        )
+    }
+}
+
+impl Display for DatasetStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Live Datasets collect the FlightData submitted during the flight, Full ones the FlightData
+/// completed with the data downloaded after it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum DatasetKind {
+    #[default]
+    Live,
+    Full
+}
+
+impl Display for DatasetKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Running Merkle root of the FlightData added to a Dataset, so that sealing does not need to read them.
+pub type DatasetAccumulator = DynMerkleAccumulator;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Dataset {
     pub id: DatasetId,
+    #[serde(default)]
+    pub kind: DatasetKind,
+    /// Hash function of the Merkle tree of the Dataset
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+    /// How leaves and nodes of the Merkle tree are hashed, `V0` for the Datasets created before it was recorded
+    #[serde(default)]
+    pub tree_format: TreeFormat,
     pub limit: u32,
     pub count: u32,
+    #[serde(default)]
+    pub status: DatasetStatus,
+    /// When the first FlightData was received, in milliseconds since the Unix epoch
+    #[serde(default)]
+    pub first_flight_data_at: Option<u64>,
+    /// When the latest FlightData was received, in milliseconds since the Unix epoch
+    #[serde(default)]
+    pub last_flight_data_at: Option<u64>,
     pub merkle_root: Option<MerkleRoot>,
+    /// Root of the sparse Merkle tree of the FlightData ids, absent for Datasets sealed before it was kept
+    #[serde(default)]
+    pub sparse_merkle_root: Option<MerkleRoot>,
     pub web3: Option<Web3Info>
-}
\ No newline at end of file
+}
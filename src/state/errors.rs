@@ -1,6 +1,7 @@
+use crate::common::x509::AttestationError;
 use crate::storage::errors::Error;
 
-use super::entities::Entity;
+use super::entities::{DatasetId, DatasetStatus, DeviceId, Entity};
 
 #[derive(Debug)]
 pub enum BitacoraError {
@@ -8,5 +9,19 @@ pub enum BitacoraError {
     AlreadyExists(Entity, String),
     StorageError(Error),
     Web3Error,
-    BadIdFormat
+    BadIdFormat,
+    InvalidStatusTransition(DatasetStatus, DatasetStatus),
+    NotAnchored(DatasetId),
+    DifferentDatasets(DatasetId, DatasetId),
+    NoSparseMerkleTree(DatasetId),
+    /// The FlightData signature is not the one of its device
+    InvalidSignature,
+    /// The given field of the full FlightData differs from the live one it completes
+    FullDataMismatch(&'static str),
+    /// The registration challenge is unknown, expired or not signed with the key of the device
+    InvalidChallenge,
+    /// The certificate chain does not certify the key of the device
+    InvalidAttestation(AttestationError),
+    /// The device was revoked, its key can't change anymore
+    DeviceRevoked(DeviceId)
 }
\ No newline at end of file
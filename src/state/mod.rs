@@ -1,4 +1,6 @@
 pub mod bitacora;
+pub mod challenges;
 pub mod entities;
 pub mod errors;
-pub mod tests;
\ No newline at end of file
+pub mod tests;
+pub mod tree_cache;
\ No newline at end of file
@@ -1,5 +1,11 @@
+#[cfg(test)]
 mod tests {
-    use crate::{state::{entities::{Device, PublicKey, FlightData, LocalizationPoint, FlightDataId, Dataset}, bitacora::{Bitacora, DATASET_DEFAULT_LIMIT}}, storage::in_memory::InMemoryStorage, web3::stub::EthereumStub};
+    use std::{collections::HashSet, sync::Arc, time::Duration};
+
+    use base64::{Engine as _, engine::general_purpose::STANDARD};
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use crate::{cli_args::VerifyArgs, verify::{verify, VerifyError}, common::{prelude::Bytes32, signature::KeyType, x509::{self, AttestationError, TrustedRoots}, sparse_merkle::SparseMerkleProof, hash_algorithm::{DynMerkleTree, DynSparseMerkleTree, HashAlgorithm}, merkle::{verify_multiproof, verify_proof, Hasher, Keccak256, MultiProof, Sha256, TreeFormat}, prelude::MerkleTree}, state::{errors::BitacoraError, entities::{Device, DeviceChallenge, PublicKey, FlightData, LocalizationPoint, FlightDataId, Dataset, DatasetKind, DatasetStatus}, bitacora::{Bitacora, DATASET_DEFAULT_LIMIT}, challenges::{Challenges, MAX_CHALLENGES_PER_DEVICE}, tree_cache::TreeCache}, storage::{append_log::AppendLogStorage, in_memory::InMemoryStorage, tests::tests::test_dataset, sqlite::SqliteStorage, storage::{FullStorage, DatasetStorage, DeviceStorage, FlightDataStorage}}, web3::stub::EthereumStub};
 
     fn new_bitacora_from_stubs() -> Bitacora<InMemoryStorage, EthereumStub> {
         let storage_in_memory = InMemoryStorage::default();
@@ -8,11 +14,37 @@ mod tests {
         Bitacora::new(storage_in_memory, timestamper_stub)
     }
 
-    #[tokio::test]
-    async fn test_basic_flow_on_in_memory_storage() { //TODO: Why it panics with two equal FlightData ?
-        let device_pk: PublicKey = "0x1234567890123456789012345678901234567890123456789012345678901234".try_into().unwrap();
-        let mut device = Device::from(device_pk);
+    fn device_signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7u8; 32])
+    }
+
+    fn new_device() -> Device {
+        let device_pk = PublicKey::from(device_signing_key().verifying_key().to_bytes());
+        Device::from(device_pk)
+    }
+
+    // Registers the device answering its challenge with the signatures of `sign`
+    async fn register_device_with<S: FullStorage>(bitacora: &Bitacora<S, EthereumStub>, device: &mut Device, sign: impl Fn(&[u8]) -> String) -> Result<(), BitacoraError> {
+        let challenge = bitacora.new_device_challenge(device).await?;
+        bitacora.new_device(device, &challenge.nonce, &sign(&challenge.signing_bytes())).await
+    }
+
+    async fn register_device<S: FullStorage>(bitacora: &Bitacora<S, EthereumStub>, device: &mut Device) -> Result<(), BitacoraError> {
+        register_device_with(bitacora, device, |message| STANDARD.encode(device_signing_key().sign(message).to_bytes())).await
+    }
+
+    fn sign(signing_key: &SigningKey, fd: &FlightData, device: &Device) -> String {
+        STANDARD.encode(signing_key.sign(&fd.signing_bytes(&device.id)).to_bytes())
+    }
+
+    // Signature of the full FlightData completing a live one with the given payload
+    fn sign_full(fd: &FlightData, device: &Device, payload: &[u8]) -> String {
+        let mut full_fd = fd.clone();
+        full_fd.payload = payload.to_vec();
+        sign(&device_signing_key(), &full_fd, device)
+    }
 
+    fn new_flight_datas(device: &Device, n: u32) -> Vec<FlightData> {
         let flight_data_prototype = FlightData {
             id: FlightDataId::default(),
             signature: String::new(),
@@ -21,23 +53,29 @@ mod tests {
                 longitude: 14.425681,
                 latitude: 40.820948
             },
-            payload: Vec::new()
+            payload: Vec::new(),
+            signature_full: None
         };
         let mut flight_datas: Vec<FlightData> = Vec::new();
 
-        for i in 0..DATASET_DEFAULT_LIMIT*2 {
+        for i in 0..n {
             let mut fd = flight_data_prototype.clone();
             fd.timestamp += 1000u64 * i as u64; // assume a FlightData object each second
             fd.localization.latitude += 0.01 * i as f64; // just to change data
             fd.localization.latitude += 0.01 * i as f64;
             fd.id = FlightDataId::new(fd.timestamp, &device.id);
+            fd.signature = sign(&device_signing_key(), &fd, device);
             flight_datas.push(fd);
         }
+        flight_datas
+    }
+
+    async fn basic_flow<S: FullStorage>(bitacora: &Bitacora<S, EthereumStub>) -> (Device, Vec<FlightData>) {
+        let mut device = new_device();
+        let flight_datas = new_flight_datas(&device, DATASET_DEFAULT_LIMIT*2);
 
-        let bitacora = new_bitacora_from_stubs();
-        
         // Create the device
-        if bitacora.new_device(&mut device).await.is_err() {
+        if register_device(bitacora, &mut device).await.is_err() {
             panic!("Failed adding a new Device");
         }
 
@@ -52,8 +90,10 @@ mod tests {
 
             if ds.limit > ds.count {
                 assert!(ds.web3.is_none());
+                assert_eq!(ds.status, DatasetStatus::Active);
             } else if ds.limit == ds.count {
                 assert!(ds.web3.is_some());
+                assert_eq!(ds.status, DatasetStatus::Anchored);
             } else {
                 panic!("Dataset limit exceeded by the FlightData count");
             }
@@ -63,7 +103,938 @@ mod tests {
             }
             previous_dataset = Some(ds);
         }
+        (device, flight_datas)
+    }
+
+    #[tokio::test]
+    async fn test_basic_flow_on_in_memory_storage() { //TODO: Why it panics with two equal FlightData ?
+        let bitacora = new_bitacora_from_stubs();
+        basic_flow(&bitacora).await;
+    }
+
+    #[tokio::test]
+    async fn test_basic_flow_on_sqlite_storage() {
+        let bitacora = Bitacora::new(SqliteStorage::open_in_memory().unwrap(), EthereumStub::default());
+        basic_flow(&bitacora).await;
+    }
+
+    #[tokio::test]
+    async fn test_basic_flow_on_append_log_storage() {
+        let log_dir = std::env::temp_dir().join(format!("bitacora-log-test-{}", rand::random::<u64>()));
+        let bitacora = Bitacora::new(AppendLogStorage::open(&log_dir).await.unwrap(), EthereumStub::default());
+        basic_flow(&bitacora).await;
+        let _ = std::fs::remove_dir_all(&log_dir);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_storage_survives_reopen() {
+        let db_path = std::env::temp_dir().join(format!("bitacora-test-{}.db", rand::random::<u64>()));
+
+        let (device, flight_datas) = {
+            let bitacora = Bitacora::new(SqliteStorage::open(&db_path).unwrap(), EthereumStub::default());
+            basic_flow(&bitacora).await
+        };
+
+        let storage = SqliteStorage::open(&db_path).unwrap();
+        let stored_device = storage.get_device(&device.id).await.unwrap().expect("Device lost after reopening");
+        assert!(stored_device.web3.is_some(), "Device Web3Info lost after reopening");
+        for fd in flight_datas.iter() {
+            let stored_fd = storage.get_flight_data(&fd.id).await.unwrap().expect("FlightData lost after reopening");
+            assert_eq!(stored_fd.to_bytes(), fd.to_bytes(), "FlightData changed after reopening");
+        }
+        let latest_dataset = storage.get_latest_dataset(&device.id, DatasetKind::Live).await.unwrap().expect("Dataset lost after reopening");
+        assert_eq!(latest_dataset.count, DATASET_DEFAULT_LIMIT);
+        assert!(latest_dataset.merkle_root.is_some() && latest_dataset.web3.is_some(), "Dataset anchoring lost after reopening");
+        let dataset_fds = storage.get_dataset_flight_data(&latest_dataset.id).await.unwrap();
+        assert_eq!(
+            dataset_fds.iter().map(|fd| fd.id.clone()).collect::<Vec<FlightDataId>>(),
+            flight_datas[DATASET_DEFAULT_LIMIT as usize..].iter().map(|fd| fd.id.clone()).collect::<Vec<FlightDataId>>(),
+            "Dataset relations changed after reopening"
+        );
+
+        let _ = std::fs::remove_file(&db_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_concurrent_flight_data_fill_datasets_exactly() {
+        const DATASETS: u32 = 20;
+        let bitacora = new_bitacora_from_stubs();
+        let mut device = new_device();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+
+        let mut handles = Vec::new();
+        for fd in new_flight_datas(&device, DATASET_DEFAULT_LIMIT * DATASETS) {
+            let bitacora = bitacora.clone();
+            let device_id = device.id.clone();
+            handles.push(tokio::spawn(async move {
+                bitacora.new_flight_data(&fd, &device_id).await.expect("Failed adding a new FlightData")
+            }));
+        }
+        let mut dataset_ids = HashSet::new();
+        for handle in handles {
+            let ds = handle.await.unwrap();
+            assert!(ds.count <= ds.limit, "Dataset limit exceeded by the FlightData count");
+            dataset_ids.insert(ds.id);
+        }
+
+        assert_eq!(dataset_ids.len(), DATASETS as usize, "FlightData spread on an unexpected number of Datasets");
+        for ds_id in dataset_ids.iter() {
+            let ds = bitacora.get_dataset(ds_id).await.unwrap().unwrap();
+            assert_eq!(ds.count, ds.limit, "Dataset was not filled exactly up to its limit");
+            assert_eq!(ds.status, DatasetStatus::Anchored);
+            assert_eq!(bitacora.get_dataset_flight_data(ds_id).await.unwrap().len(), ds.limit as usize);
+            assert!(ds.merkle_root.is_some() && ds.web3.is_some(), "Full Dataset was not sealed");
+        }
+        assert!(bitacora.device_locks.lock().unwrap().is_empty(), "Device lock kept after the ingestion");
+
+        // Unknown devices don't get a lock
+        let unknown_device = Device::from(PublicKey::from([9u8; 32]));
+        let fd = new_flight_datas(&unknown_device, 1).remove(0);
+        assert!(matches!(bitacora.new_flight_data(&fd, &unknown_device.id).await, Err(BitacoraError::NotFound)));
+        assert!(bitacora.device_locks.lock().unwrap().is_empty(), "Device lock created for an unknown device");
+    }
+
+    #[tokio::test]
+    async fn test_open_dataset_closing_the_current_one() {
+        let bitacora = new_bitacora_from_stubs();
+        let mut device = new_device();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+        let flight_datas = new_flight_datas(&device, 4);
+
+        let first_ds = bitacora.new_flight_data(&flight_datas[0], &device.id).await.unwrap();
+        let kept_open_ds = bitacora.open_dataset(5, &device.id, false, None, None).await.unwrap();
+        assert_eq!(bitacora.get_dataset(&first_ds.id).await.unwrap().unwrap().status, DatasetStatus::Active, "Dataset closed without being requested");
+        assert_eq!(bitacora.new_flight_data(&flight_datas[1], &device.id).await.unwrap().id, kept_open_ds.id);
+        assert_eq!(bitacora.new_flight_data(&flight_datas[2], &device.id).await.unwrap().id, kept_open_ds.id);
+
+        let new_ds = bitacora.open_dataset(3, &device.id, true, None, None).await.unwrap();
+        assert_eq!(new_ds.limit, 3);
+        assert_eq!(new_ds.status, DatasetStatus::Initialized);
+        let closed_ds = bitacora.get_dataset(&kept_open_ds.id).await.unwrap().unwrap();
+        assert_eq!(closed_ds.status, DatasetStatus::Anchored);
+        assert_eq!(closed_ds.limit, 2, "Closed Dataset limit does not match its FlightData");
+        assert!(closed_ds.merkle_root.is_some() && closed_ds.web3.is_some());
+
+        assert_eq!(bitacora.new_flight_data(&flight_datas[3], &device.id).await.unwrap().id, new_ds.id);
+        assert!(matches!(bitacora.open_dataset(3, &String::from("unknown"), true, None, None).await, Err(BitacoraError::NotFound)));
+    }
+
+    async fn complete_flow<S: FullStorage>(bitacora: Bitacora<S, EthereumStub>) {
+        let (device, flight_datas) = basic_flow(&bitacora).await;
+        let bitacora = Arc::new(bitacora);
+        let live_fd = &flight_datas[0];
+        let live_ds_id = bitacora.get_flight_data_dataset(&live_fd.id).await.unwrap().unwrap();
+
+        let signature_full = sign_full(live_fd, &device, &[7u8; 64]);
+        assert!(matches!(
+            bitacora.complete_flight_data(&live_fd.id, &device.id, None, None, vec![8u8; 64], signature_full.clone()).await,
+            Err(BitacoraError::InvalidSignature)
+        ), "Full FlightData accepted with the signature of another payload");
+        let mut moved_localization = live_fd.localization;
+        moved_localization.latitude += 1.0;
+        assert!(matches!(
+            bitacora.complete_flight_data(&live_fd.id, &device.id, None, Some(moved_localization), vec![7u8; 64], signature_full.clone()).await,
+            Err(BitacoraError::FullDataMismatch("localization"))
+        ), "Full FlightData accepted with another localization");
+        assert!(matches!(
+            bitacora.complete_flight_data(&live_fd.id, &device.id, Some(live_fd.timestamp + 1), None, vec![7u8; 64], signature_full.clone()).await,
+            Err(BitacoraError::FullDataMismatch("timestamp"))
+        ), "Full FlightData accepted with another timestamp");
+        let (full_fd, full_ds) = bitacora.complete_flight_data(&live_fd.id, &device.id, Some(live_fd.timestamp), Some(live_fd.localization), vec![7u8; 64], signature_full.clone()).await.unwrap();
+        assert_eq!(full_fd.id, FlightDataId::new_full(live_fd.timestamp, &device.id));
+        assert_eq!(full_ds.kind, DatasetKind::Full);
+        assert_ne!(full_ds.id, live_ds_id);
+        let stored_full_fd = bitacora.get_flight_data(&full_fd.id).await.unwrap().unwrap();
+        assert_eq!(stored_full_fd.payload, vec![7u8; 64]);
+        assert_eq!(stored_full_fd.signature_full, Some(signature_full.clone()));
+        let stored_live_fd = bitacora.get_flight_data(&live_fd.id).await.unwrap().unwrap();
+        assert_eq!(stored_live_fd.to_bytes(), live_fd.to_bytes(), "Anchored live FlightData was modified");
+        assert_eq!(bitacora.get_flight_data_dataset(&live_fd.id).await.unwrap().unwrap(), live_ds_id);
+        assert_eq!(bitacora.get_latest_dataset(&device.id, DatasetKind::Full).await.unwrap().unwrap().id, full_ds.id);
+        assert_ne!(bitacora.get_latest_dataset(&device.id, DatasetKind::Live).await.unwrap().unwrap().id, full_ds.id);
+
+        assert!(matches!(
+            bitacora.complete_flight_data(&live_fd.id, &device.id, None, None, vec![7u8; 64], signature_full).await,
+            Err(BitacoraError::AlreadyExists(_, _))
+        ), "FlightData completed twice");
+        assert!(matches!(
+            bitacora.complete_flight_data(&flight_datas[1].id, &String::from("other"), None, None, vec![], String::new()).await,
+            Err(BitacoraError::NotFound)
+        ), "FlightData completed for another device");
+
+        // A live FlightData in a Dataset still being filled can't be completed yet
+        let pending_fd = &new_flight_datas(&device, DATASET_DEFAULT_LIMIT * 2 + 1)[DATASET_DEFAULT_LIMIT as usize * 2];
+        bitacora.new_flight_data(pending_fd, &device.id).await.unwrap();
+        assert!(matches!(
+            bitacora.complete_flight_data(&pending_fd.id, &device.id, None, None, vec![], String::new()).await,
+            Err(BitacoraError::NotAnchored(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_complete_flight_data_on_in_memory_storage() {
+        complete_flow(new_bitacora_from_stubs()).await;
+    }
+
+    #[tokio::test]
+    async fn test_complete_flight_data_on_sqlite_storage() {
+        complete_flow(Bitacora::new(SqliteStorage::open_in_memory().unwrap(), EthereumStub::default())).await;
+    }
+
+    #[tokio::test]
+    async fn test_forged_flight_data_rejected() {
+        let bitacora = new_bitacora_from_stubs();
+        let mut device = new_device();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+        let flight_datas = new_flight_datas(&device, 4);
+
+        let mut moved_fd = flight_datas[0].clone();
+        moved_fd.localization.longitude += 1.0;
+        let mut other_key_fd = flight_datas[1].clone();
+        other_key_fd.signature = sign(&SigningKey::from_bytes(&[8u8; 32]), &other_key_fd, &device);
+        let mut unsigned_fd = flight_datas[2].clone();
+        unsigned_fd.signature = String::from("Fg6tt7UKb==");
+        for forged_fd in [moved_fd, other_key_fd, unsigned_fd] {
+            assert!(matches!(bitacora.new_flight_data(&forged_fd, &device.id).await, Err(BitacoraError::InvalidSignature)));
+            assert!(bitacora.get_flight_data(&forged_fd.id).await.unwrap().is_none(), "Forged FlightData stored");
+        }
+
+        // The signature binds the FlightData to its device
+        let mut other_device = Device::from(PublicKey::from(SigningKey::from_bytes(&[8u8; 32]).verifying_key().to_bytes()));
+        register_device_with(&bitacora, &mut other_device, |message| STANDARD.encode(SigningKey::from_bytes(&[8u8; 32]).sign(message).to_bytes())).await.unwrap();
+        assert!(matches!(bitacora.new_flight_data(&flight_datas[3], &other_device.id).await, Err(BitacoraError::InvalidSignature)));
+        assert!(bitacora.new_flight_data(&flight_datas[3], &device.id).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_mixed_key_type_devices() {
+        use p256::ecdsa::signature::Signer as _;
+
+        let bitacora = new_bitacora_from_stubs();
+        let k256_key = k256::ecdsa::SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let p256_key = p256::ecdsa::SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let k256_pk = PublicKey::from(k256_key.verifying_key().to_encoded_point(false).as_bytes().to_vec());
+        let p256_pk = PublicKey::from(p256_key.verifying_key().to_encoded_point(false).as_bytes().to_vec());
+        let mut k256_device = Device::new(KeyType::Secp256k1, k256_pk.clone()).unwrap();
+        let mut p256_device = Device::new(KeyType::P256, p256_pk).unwrap();
+        let mut ed25519_device = new_device();
+        assert!(Device::new(KeyType::P256, k256_pk.clone()).is_none(), "secp256k1 key accepted as a P-256 one");
+        assert!(Device::new(KeyType::Ed25519, k256_pk).is_none());
+        assert_eq!(k256_device.pk.0.len(), 33, "ECDSA keys are not kept compressed");
+        assert_eq!(Device::new(KeyType::Secp256k1, k256_device.pk.clone()).unwrap().id, k256_device.id);
+        assert_eq!(Device::new(KeyType::Ed25519, ed25519_device.pk.clone()).unwrap().id, ed25519_device.id);
+        register_device_with(&bitacora, &mut k256_device, |message| {
+            let signature: k256::ecdsa::Signature = k256_key.sign(message);
+            STANDARD.encode(signature.to_bytes())
+        }).await.unwrap();
+        register_device_with(&bitacora, &mut p256_device, |message| {
+            let signature: p256::ecdsa::Signature = p256_key.sign(message);
+            STANDARD.encode(signature.to_bytes())
+        }).await.unwrap();
+        register_device(&bitacora, &mut ed25519_device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+
+        let mut k256_fds = new_flight_datas(&k256_device, 2);
+        for fd in k256_fds.iter_mut() {
+            let signature: k256::ecdsa::Signature = k256_key.sign(&fd.signing_bytes(&k256_device.id));
+            fd.signature = STANDARD.encode(signature.to_bytes());
+        }
+        let mut p256_fds = new_flight_datas(&p256_device, 2);
+        for fd in p256_fds.iter_mut() {
+            let signature: p256::ecdsa::Signature = p256_key.sign(&fd.signing_bytes(&p256_device.id));
+            fd.signature = STANDARD.encode(signature.to_bytes());
+        }
+        let ed25519_fds = new_flight_datas(&ed25519_device, 2);
+
+        assert!(bitacora.new_flight_data(&k256_fds[0], &k256_device.id).await.is_ok());
+        assert!(bitacora.new_flight_data(&p256_fds[0], &p256_device.id).await.is_ok());
+        assert!(bitacora.new_flight_data(&ed25519_fds[0], &ed25519_device.id).await.is_ok());
+        assert_eq!(bitacora.get_device(&p256_device.id).await.unwrap().unwrap().key_type, KeyType::P256);
+
+        // A signature of a key of another type does not pass for the device
+        let mut swapped_fd = p256_fds[1].clone();
+        swapped_fd.id = k256_fds[1].id.clone();
+        let signature: p256::ecdsa::Signature = p256_key.sign(&k256_fds[1].signing_bytes(&k256_device.id));
+        swapped_fd.signature = STANDARD.encode(signature.to_bytes());
+        assert!(matches!(bitacora.new_flight_data(&swapped_fd, &k256_device.id).await, Err(BitacoraError::InvalidSignature)));
+        assert!(matches!(bitacora.new_flight_data(&k256_fds[1], &p256_device.id).await, Err(BitacoraError::InvalidSignature)));
+    }
+
+    #[tokio::test]
+    async fn test_seal_partially_filled_dataset() {
+        let bitacora = new_bitacora_from_stubs();
+        let mut device = new_device();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+        let flight_datas = new_flight_datas(&device, 4);
+        let mut ds = None;
+        for fd in flight_datas[..3].iter() {
+            ds = Some(bitacora.new_flight_data(fd, &device.id).await.unwrap());
+        }
+        let ds = ds.unwrap();
+
+        let sealed_ds = bitacora.seal(&ds.id).await.unwrap();
+        assert_eq!(sealed_ds.status, DatasetStatus::Anchored);
+        assert_eq!(sealed_ds.limit, 3);
+        let mut expected_mt = MerkleTree::<Keccak256>::with_format(TreeFormat::V1);
+        for fd in flight_datas[..3].iter() {
+            expected_mt.append(&fd.to_bytes());
+        }
+        assert_eq!(sealed_ds.merkle_root, expected_mt.root().cloned());
+        assert!(sealed_ds.web3.is_some());
+        assert_eq!(bitacora.get_dataset(&ds.id).await.unwrap().unwrap().status, DatasetStatus::Anchored);
+
+        assert_ne!(bitacora.new_flight_data(&flight_datas[3], &device.id).await.unwrap().id, ds.id, "FlightData added to a sealed Dataset");
+        assert!(matches!(bitacora.seal(&ds.id).await, Err(BitacoraError::InvalidStatusTransition(DatasetStatus::Anchored, DatasetStatus::Sealed))));
+        let empty_ds = bitacora.open_dataset(5, &device.id, false, None, None).await.unwrap();
+        assert!(matches!(bitacora.seal(&empty_ds.id).await, Err(BitacoraError::InvalidStatusTransition(DatasetStatus::Initialized, DatasetStatus::Sealed))));
+        assert!(matches!(bitacora.seal(&String::from("unknown")).await, Err(BitacoraError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_flight_data_proof() {
+        let bitacora = new_bitacora_from_stubs();
+        let mut device = new_device();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+        let flight_datas = new_flight_datas(&device, 8);
+        let mut ds = None;
+        for fd in flight_datas[..7].iter() {
+            ds = Some(bitacora.new_flight_data(fd, &device.id).await.unwrap());
+        }
+        let ds = ds.unwrap();
+        assert!(matches!(bitacora.flight_data_proof(&flight_datas[0].id).await, Err(BitacoraError::NotAnchored(_))));
+
+        let sealed_ds = bitacora.seal(&ds.id).await.unwrap();
+        let mut expected_mt = MerkleTree::<Keccak256>::with_format(TreeFormat::V1);
+        for fd in flight_datas[..7].iter() {
+            expected_mt.append(&fd.to_bytes());
+        }
+        for fd in flight_datas[..7].iter() {
+            let fd_proof = bitacora.flight_data_proof(&fd.id).await.unwrap();
+            assert_eq!(fd_proof.dataset_id, ds.id);
+            assert_eq!(fd_proof.device_id, device.id);
+            assert_eq!(fd_proof.flight_data_id, fd.id.to_string());
+            assert_eq!(fd_proof.leaf, TreeFormat::V1.leaf_hash::<Keccak256, _>(fd.to_bytes()));
+            assert_eq!(Some(fd_proof.merkle_root.clone()), sealed_ds.merkle_root);
+            assert!(fd_proof.web3.is_some());
+            assert!(expected_mt.verify(&fd_proof.leaf, &fd_proof.proof), "Invalid proof for a FlightData of the Dataset");
+            assert!(verify_proof::<Keccak256>(TreeFormat::V1, &fd_proof.merkle_root, &fd_proof.leaf, &fd_proof.proof));
+        }
+        assert!(matches!(bitacora.flight_data_proof(&flight_datas[7].id).await, Err(BitacoraError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_flight_data_sparse_proof() {
+        let bitacora = new_bitacora_from_stubs();
+        let mut device = new_device();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+        let flight_datas = new_flight_datas(&device, 5);
+        let mut ds = None;
+        for fd in flight_datas[..4].iter() {
+            ds = Some(bitacora.new_flight_data(fd, &device.id).await.unwrap());
+        }
+        let ds = ds.unwrap();
+        assert!(matches!(bitacora.flight_data_sparse_proof(&ds.id, &flight_datas[0].id).await, Err(BitacoraError::NotAnchored(_))));
+        // Sealing reads the leaves kept at ingestion rather than the FlightData
+        assert_eq!(bitacora.get_dataset_sparse_leaves(&ds.id).await.unwrap().len(), 4);
+        let sealed_ds = bitacora.seal(&ds.id).await.unwrap();
+
+        let included = bitacora.flight_data_sparse_proof(&ds.id, &flight_datas[1].id).await.unwrap();
+        assert_eq!(included.value, Some(TreeFormat::V1.leaf_hash::<Keccak256, _>(flight_datas[1].to_bytes())));
+        assert_eq!(Some(included.merkle_root.clone()), sealed_ds.merkle_root);
+        assert_eq!(Some(included.sparse_merkle_root.clone()), sealed_ds.sparse_merkle_root);
+        let proof = SparseMerkleProof { sibling_depths: included.sibling_depths.clone(), siblings: included.siblings.clone() };
+        let key = Bytes32::from(&flight_datas[1].id);
+        assert!(included.hash_algorithm.verify_sparse_proof(&included.sparse_merkle_root, &key, included.value.as_ref(), &proof));
+
+        // Nothing was reported at the timestamp of the FlightData left out, nor half a second after the first one
+        for timestamp in [flight_datas[4].timestamp, flight_datas[0].timestamp + 500] {
+            let absent_id = FlightDataId::new(timestamp, &device.id);
+            let excluded = bitacora.flight_data_sparse_proof(&ds.id, &absent_id).await.unwrap();
+            assert!(excluded.value.is_none());
+            let proof = SparseMerkleProof { sibling_depths: excluded.sibling_depths, siblings: excluded.siblings };
+            let key = Bytes32::from(&absent_id);
+            assert!(excluded.hash_algorithm.verify_sparse_proof(&excluded.sparse_merkle_root, &key, None, &proof));
+            assert!(!excluded.hash_algorithm.verify_sparse_proof(&excluded.sparse_merkle_root, &key, included.value.as_ref(), &proof));
+        }
+        assert!(matches!(bitacora.flight_data_sparse_proof(&String::from("unknown"), &flight_datas[0].id).await, Err(BitacoraError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_dataset_with_sha256_merkle_tree() {
+        let bitacora = new_bitacora_from_stubs();
+        let mut device = new_device();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+        let ds = bitacora.open_dataset(3, &device.id, false, Some(HashAlgorithm::Sha256), Some(TreeFormat::V1)).await.unwrap();
+        assert_eq!(ds.hash_algorithm, HashAlgorithm::Sha256);
+        assert_eq!(ds.tree_format, TreeFormat::V1);
+        let flight_datas = new_flight_datas(&device, 3);
+        for fd in flight_datas.iter() {
+            assert_eq!(bitacora.new_flight_data(fd, &device.id).await.unwrap().id, ds.id);
+        }
+
+        let sealed_ds = bitacora.get_dataset(&ds.id).await.unwrap().unwrap();
+        assert_eq!(sealed_ds.status, DatasetStatus::Anchored);
+        let mut expected_mt = MerkleTree::<Sha256>::with_format(TreeFormat::V1);
+        let mut keccak_mt = MerkleTree::<Keccak256>::with_format(TreeFormat::V1);
+        for fd in flight_datas.iter() {
+            expected_mt.append(&fd.to_bytes());
+            keccak_mt.append(&fd.to_bytes());
+        }
+        assert_eq!(sealed_ds.merkle_root, expected_mt.root().cloned());
+        assert_ne!(sealed_ds.merkle_root, keccak_mt.root().cloned());
+        for fd in flight_datas.iter() {
+            let fd_proof = bitacora.flight_data_proof(&fd.id).await.unwrap();
+            assert_eq!(fd_proof.hash_algorithm, HashAlgorithm::Sha256);
+            assert_eq!(fd_proof.leaf, TreeFormat::V1.leaf_hash::<Sha256, _>(fd.to_bytes()));
+            assert!(fd_proof.hash_algorithm.verify_proof(fd_proof.tree_format, &fd_proof.merkle_root, &fd_proof.leaf, &fd_proof.proof));
+            assert!(!HashAlgorithm::Keccak256.verify_proof(fd_proof.tree_format, &fd_proof.merkle_root, &fd_proof.leaf, &fd_proof.proof));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flight_data_multiproof() {
+        let bitacora = new_bitacora_from_stubs();
+        let mut device = new_device();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+        let flight_datas = new_flight_datas(&device, 9);
+        let mut ds = None;
+        for fd in flight_datas[..7].iter() {
+            ds = Some(bitacora.new_flight_data(fd, &device.id).await.unwrap());
+        }
+        let sealed_ds = bitacora.seal(&ds.unwrap().id).await.unwrap();
+        let mut next_ds = None;
+        for fd in flight_datas[7..].iter() {
+            next_ds = Some(bitacora.new_flight_data(fd, &device.id).await.unwrap());
+        }
+        let next_ds = bitacora.seal(&next_ds.unwrap().id).await.unwrap();
+
+        let segment: Vec<FlightDataId> = flight_datas[2..6].iter().map(|fd| fd.id.clone()).collect();
+        let fd_multiproof = bitacora.flight_data_multiproof(&segment).await.unwrap();
+        assert_eq!(fd_multiproof.dataset_id, sealed_ds.id);
+        assert_eq!(fd_multiproof.device_id, device.id);
+        assert_eq!(Some(fd_multiproof.merkle_root.clone()), sealed_ds.merkle_root);
+        let multiproof = MultiProof { n_leaves: fd_multiproof.n_leaves, leaf_indices: fd_multiproof.leaf_indices.clone(), proof: fd_multiproof.proof.clone() };
+        assert_eq!(multiproof.leaf_indices, vec![2, 3, 4, 5]);
+        assert!(verify_multiproof::<Keccak256>(fd_multiproof.tree_format, &fd_multiproof.merkle_root, &fd_multiproof.leaves, &multiproof));
+
+        let mixed = vec![flight_datas[0].id.clone(), flight_datas[8].id.clone()];
+        assert!(matches!(bitacora.flight_data_multiproof(&mixed).await, Err(BitacoraError::DifferentDatasets(first, other)) if first == sealed_ds.id && other == next_ds.id));
+        assert!(matches!(bitacora.flight_data_multiproof(&[]).await, Err(BitacoraError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_device_registration_requires_challenge() {
+        let bitacora = new_bitacora_from_stubs();
+        let mut device = new_device();
+        let sign_challenge = |signing_key: SigningKey, challenge: &DeviceChallenge| STANDARD.encode(signing_key.sign(&challenge.signing_bytes()).to_bytes());
+
+        // A nonce not issued by the server
+        let forged_challenge = DeviceChallenge { device_id: device.id.clone(), nonce: Bytes32([1u8; 32]), expires_at: u64::MAX };
+        let signature = sign_challenge(device_signing_key(), &forged_challenge);
+        assert!(matches!(bitacora.new_device(&mut device, &forged_challenge.nonce, &signature).await, Err(BitacoraError::InvalidChallenge)));
+
+        // A challenge signed with another key is used up
+        let challenge = bitacora.new_device_challenge(&device).await.unwrap();
+        assert_eq!(challenge.device_id, device.id);
+        let signature = sign_challenge(SigningKey::from_bytes(&[8u8; 32]), &challenge);
+        assert!(matches!(bitacora.new_device(&mut device, &challenge.nonce, &signature).await, Err(BitacoraError::InvalidChallenge)));
+        let signature = sign_challenge(device_signing_key(), &challenge);
+        assert!(matches!(bitacora.new_device(&mut device, &challenge.nonce, &signature).await, Err(BitacoraError::InvalidChallenge)));
+        let bitacora = Arc::new(bitacora);
+        assert!(bitacora.get_device(&device.id).await.unwrap().is_none(), "Device stored without proving to hold its key");
+
+        // Challenges requested later for the device do not void the one it is signing
+        let challenge = bitacora.new_device_challenge(&device).await.unwrap();
+        let next_challenge = bitacora.new_device_challenge(&device).await.unwrap();
+        assert_ne!(challenge.nonce, next_challenge.nonce);
+        let signature = sign_challenge(device_signing_key(), &challenge);
+        assert!(bitacora.as_ref().new_device(&mut device, &challenge.nonce, &signature).await.is_ok());
+        assert!(bitacora.get_device(&device.id).await.unwrap().unwrap().web3.is_some());
+        assert!(matches!(bitacora.new_device_challenge(&device).await, Err(BitacoraError::AlreadyExists(_, _))));
+    }
+
+    // FlightData of the device at the given timestamp signed by `signing_key`
+    fn flight_data_at(device: &Device, timestamp: u64, signing_key: &SigningKey) -> FlightData {
+        let mut fd = new_flight_datas(device, 1).remove(0);
+        fd.timestamp = timestamp;
+        fd.id = FlightDataId::new(timestamp, &device.id);
+        fd.signature = sign(signing_key, &fd, device);
+        fd
+    }
+
+    #[tokio::test]
+    async fn test_device_key_rotation() {
+        use p256::ecdsa::signature::Signer as _;
+
+        let bitacora = new_bitacora_from_stubs();
+        let mut device = new_device();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let registration_web3 = device.web3.clone().unwrap();
+        let bitacora = Arc::new(bitacora);
+        let new_signing_key = SigningKey::from_bytes(&[8u8; 32]);
+        let new_key = Device::from(PublicKey::from(new_signing_key.verifying_key().to_bytes())).current_key();
+        let message = device.key_rotation_signing_bytes(new_key.key_type, &new_key.pk);
+        let signature = STANDARD.encode(device_signing_key().sign(&message).to_bytes());
+        let key_signature = STANDARD.encode(new_signing_key.sign(&message).to_bytes());
+
+        // Both keys must sign the rotation
+        assert!(matches!(bitacora.as_ref().rotate_device_key(&device.id, new_key.clone(), &key_signature, &key_signature).await, Err(BitacoraError::InvalidSignature)));
+        assert!(matches!(bitacora.as_ref().rotate_device_key(&device.id, new_key.clone(), &signature, &signature).await, Err(BitacoraError::InvalidSignature)));
+        assert!(matches!(bitacora.as_ref().rotate_device_key(&String::from("unknown"), new_key.clone(), &signature, &key_signature).await, Err(BitacoraError::NotFound)));
+
+        let rotated_device = bitacora.as_ref().rotate_device_key(&device.id, new_key.clone(), &signature, &key_signature).await.unwrap();
+        let stored_device = bitacora.get_device(&device.id).await.unwrap().unwrap();
+        for rotated_device in [&rotated_device, &stored_device] {
+            assert_eq!(rotated_device.id, device.id, "The device id changed with its key");
+            assert_eq!(rotated_device.pk, new_key.pk);
+            assert_eq!(rotated_device.previous_keys.len(), 1);
+            assert_eq!(rotated_device.previous_keys[0].pk, device.pk);
+            assert_eq!(rotated_device.previous_keys[0].valid_until, Some(rotated_device.key_valid_from));
+            assert_eq!(rotated_device.previous_keys[0].web3.as_ref().unwrap().tx.hash, registration_web3.tx.hash);
+            assert_ne!(rotated_device.web3.as_ref().unwrap().tx.hash, registration_web3.tx.hash);
+        }
+        // The same rotation can't be replayed
+        assert!(matches!(bitacora.as_ref().rotate_device_key(&device.id, new_key.clone(), &signature, &key_signature).await, Err(BitacoraError::InvalidSignature)));
+
+        // FlightData are verified with the key valid at their timestamp
+        let future = rotated_device.key_valid_from + 3_600_000;
+        assert!(bitacora.new_flight_data(&flight_data_at(&device, 1701305636123, &device_signing_key()), &device.id).await.is_ok());
+        assert!(matches!(bitacora.new_flight_data(&flight_data_at(&device, 1701305637123, &new_signing_key), &device.id).await, Err(BitacoraError::InvalidSignature)));
+        assert!(matches!(bitacora.new_flight_data(&flight_data_at(&device, future, &device_signing_key()), &device.id).await, Err(BitacoraError::InvalidSignature)));
+        assert!(bitacora.new_flight_data(&flight_data_at(&device, future, &new_signing_key), &device.id).await.is_ok());
+
+        // Rotating again, to a key of another type
+        let p256_key = p256::ecdsa::SigningKey::from_slice(&[9u8; 32]).unwrap();
+        let p256_key_holder = Device::new(KeyType::P256, PublicKey::from(p256_key.verifying_key().to_encoded_point(true).as_bytes().to_vec())).unwrap();
+        let message = rotated_device.key_rotation_signing_bytes(KeyType::P256, &p256_key_holder.pk);
+        let p256_signature: p256::ecdsa::Signature = p256_key.sign(&message);
+        let device = bitacora.as_ref().rotate_device_key(
+            &device.id,
+            p256_key_holder.current_key(),
+            &STANDARD.encode(new_signing_key.sign(&message).to_bytes()),
+            &STANDARD.encode(p256_signature.to_bytes())
+        ).await.unwrap();
+        assert_eq!(device.key_type, KeyType::P256);
+        assert_eq!(device.previous_keys.iter().map(|key| key.key_type).collect::<Vec<_>>(), vec![KeyType::Ed25519, KeyType::Ed25519]);
+        assert_eq!(device.key_at(1701305636123).unwrap().pk, new_device().pk);
+        assert_eq!(device.key_at(future).unwrap().pk, p256_key_holder.pk);
+    }
+
+    #[tokio::test]
+    async fn test_device_revocation() {
+        let bitacora = new_bitacora_from_stubs();
+        let mut device = new_device();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+
+        let other_signature = STANDARD.encode(SigningKey::from_bytes(&[8u8; 32]).sign(&device.revocation_signing_bytes()).to_bytes());
+        assert!(matches!(bitacora.as_ref().revoke_device(&device.id, &other_signature).await, Err(BitacoraError::InvalidSignature)));
+        let signature = STANDARD.encode(device_signing_key().sign(&device.revocation_signing_bytes()).to_bytes());
+        let revoked_device = bitacora.as_ref().revoke_device(&device.id, &signature).await.unwrap();
+        let revocation = revoked_device.revocation.as_ref().unwrap();
+        assert!(revocation.web3.is_some());
+        let stored_device = bitacora.get_device(&device.id).await.unwrap().unwrap();
+        assert_eq!(stored_device.revocation.unwrap().revoked_at, revocation.revoked_at);
+
+        // FlightData signed before the revocation are still accepted
+        assert!(bitacora.new_flight_data(&flight_data_at(&device, revocation.revoked_at - 1, &device_signing_key()), &device.id).await.is_ok());
+        assert!(matches!(bitacora.new_flight_data(&flight_data_at(&device, revocation.revoked_at, &device_signing_key()), &device.id).await, Err(BitacoraError::InvalidSignature)));
+
+        assert!(matches!(bitacora.as_ref().revoke_device(&device.id, &signature).await, Err(BitacoraError::DeviceRevoked(_))));
+        let new_key = Device::from(PublicKey::from(SigningKey::from_bytes(&[8u8; 32]).verifying_key().to_bytes())).current_key();
+        assert!(matches!(bitacora.as_ref().rotate_device_key(&device.id, new_key, &signature, &signature).await, Err(BitacoraError::DeviceRevoked(_))));
+    }
+
+    #[tokio::test]
+    async fn test_device_lifecycle_waits_for_the_chain() {
+        let timestamper = EthereumStub::default();
+        let chain_failing = timestamper.failure_switch();
+        let bitacora = Bitacora::new(InMemoryStorage::default(), timestamper);
+        let mut device = new_device();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+        let new_signing_key = SigningKey::from_bytes(&[8u8; 32]);
+        let new_key = Device::from(PublicKey::from(new_signing_key.verifying_key().to_bytes())).current_key();
+        let message = device.key_rotation_signing_bytes(new_key.key_type, &new_key.pk);
+        let signature = STANDARD.encode(device_signing_key().sign(&message).to_bytes());
+        let key_signature = STANDARD.encode(new_signing_key.sign(&message).to_bytes());
+        let revocation_signature = STANDARD.encode(device_signing_key().sign(&device.revocation_signing_bytes()).to_bytes());
+
+        // Nothing changes when the chain can't be updated
+        chain_failing.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert!(matches!(bitacora.as_ref().rotate_device_key(&device.id, new_key.clone(), &signature, &key_signature).await, Err(BitacoraError::Web3Error)));
+        assert!(matches!(bitacora.as_ref().revoke_device(&device.id, &revocation_signature).await, Err(BitacoraError::Web3Error)));
+        let stored_device = bitacora.get_device(&device.id).await.unwrap().unwrap();
+        assert_eq!(stored_device.pk, device.pk, "Key rotated without updating the chain");
+        assert!(stored_device.previous_keys.is_empty());
+        assert!(!stored_device.is_revoked(), "Device revoked without updating the chain");
+
+        // The same requests go through once the chain is back
+        chain_failing.store(false, std::sync::atomic::Ordering::SeqCst);
+        let rotated_device = bitacora.as_ref().rotate_device_key(&device.id, new_key.clone(), &signature, &key_signature).await.unwrap();
+        assert_eq!(rotated_device.pk, new_key.pk);
+        assert!(rotated_device.web3.is_some());
+        let revocation_signature = STANDARD.encode(new_signing_key.sign(&rotated_device.revocation_signing_bytes()).to_bytes());
+        bitacora.as_ref().revoke_device(&device.id, &revocation_signature).await.unwrap();
+        let stored_device = bitacora.get_device(&device.id).await.unwrap().unwrap();
+        assert_eq!(stored_device.pk, new_key.pk);
+        assert!(stored_device.revocation.unwrap().web3.is_some(), "Revocation stored without its transaction");
+    }
+
+    #[tokio::test]
+    async fn test_device_attestation() {
+        let ((root, _), (intermediate, intermediate_key)) = x509::test::manufacturer_cas();
+        let device_pk = device_signing_key().verifying_key().to_bytes();
+        let device_certificate = x509::test::issue(42, "CN=Device 42", x509::test::ed25519_spki(&device_pk), "CN=Devices CA,O=Manufacturer", &intermediate_key, false, x509::test::NOT_AFTER);
+        let pem_chain = x509::test::to_pem(&[&device_certificate, &intermediate]);
+
+        // No root is trusted by default
+        let mut device = new_device();
+        assert!(matches!(new_bitacora_from_stubs().attest_device(&mut device, &pem_chain), Err(BitacoraError::InvalidAttestation(AttestationError::NoTrustedRoots))));
+
+        let trusted_roots = TrustedRoots::from_pem(&x509::test::to_pem(&[&root])).unwrap();
+        let bitacora = Bitacora::new(SqliteStorage::open_in_memory().unwrap(), EthereumStub::default()).with_trusted_roots(trusted_roots);
+
+        // The chain certifies the key of another device
+        let mut other_device = Device::from(PublicKey::from(SigningKey::from_bytes(&[8u8; 32]).verifying_key().to_bytes()));
+        assert!(matches!(bitacora.attest_device(&mut other_device, &pem_chain), Err(BitacoraError::InvalidAttestation(AttestationError::KeyMismatch))));
+        assert!(other_device.attestation.is_none());
+
+        bitacora.attest_device(&mut device, &pem_chain).unwrap();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+        let attestation = bitacora.get_device(&device.id).await.unwrap().unwrap().attestation.expect("Attestation not stored");
+        assert_eq!(attestation.issuer, "CN=Devices CA,O=Manufacturer");
+        assert_eq!(attestation.serial, "2A");
+        assert_eq!(attestation.not_after, x509::test::NOT_AFTER * 1000);
+    }
+
+    #[test]
+    fn test_challenges_expire() {
+        let mut challenges = Challenges::new(1000, 16);
+        let device_id = new_device().id;
+        let challenge = challenges.issue(&device_id, 5000);
+        assert_eq!(challenge.expires_at, 6000);
+        assert!(challenges.take(&String::from("other device"), &challenge.nonce, 5500).is_none());
+        assert!(challenges.take(&device_id, &challenge.nonce, 6000).is_none(), "Expired challenge taken");
+        let challenge = challenges.issue(&device_id, 7000);
+        assert!(challenges.take(&device_id, &challenge.nonce, 7999).is_some());
+        assert!(challenges.take(&device_id, &challenge.nonce, 7999).is_none(), "Challenge taken twice");
+
+        challenges.issue(&device_id, 8000);
+        challenges.issue(&String::from("other device"), 8500);
+        challenges.expire(8999);
+        assert_eq!(challenges.len(), 2);
+        challenges.expire(9000);
+        assert_eq!(challenges.len(), 1, "Expired challenge kept");
+        challenges.expire(9500);
+        assert!(challenges.is_empty());
+    }
+
+    #[test]
+    fn test_challenges_are_not_replaced() {
+        let mut challenges = Challenges::new(1000, 16);
+        let device_id = new_device().id;
+        let challenge = challenges.issue(&device_id, 5000);
+        // Requesting more challenges for the device does not void the one it is signing
+        for _ in 1..MAX_CHALLENGES_PER_DEVICE {
+            challenges.issue(&device_id, 5000);
+        }
+        assert!(challenges.take(&device_id, &challenge.nonce, 5500).is_some(), "Outstanding challenge replaced");
+
+        let challenge = challenges.issue(&device_id, 5000);
+        for _ in 0..MAX_CHALLENGES_PER_DEVICE {
+            challenges.issue(&device_id, 5000);
+        }
+        assert_eq!(challenges.len(), MAX_CHALLENGES_PER_DEVICE);
+        assert!(challenges.take(&device_id, &challenge.nonce, 5500).is_none(), "Challenges of a device not bounded");
+
+        // Beyond the capacity the oldest challenges are dropped, whatever their device
+        let other_challenge = challenges.issue(&String::from("other device"), 5000);
+        for i in 0..16 {
+            challenges.issue(&format!("device {}", i), 5000);
+        }
+        assert_eq!(challenges.len(), 16);
+        assert!(challenges.take(&String::from("other device"), &other_challenge.nonce, 5500).is_none(), "Challenges not bounded");
+    }
+
+    #[test]
+    fn test_tree_cache_drops_least_recently_used() {
+        let mut tree_cache = TreeCache::new(2);
+        for ds_id in ["a", "b"] {
+            let mut tree = DynMerkleTree::new(HashAlgorithm::Keccak256, TreeFormat::V0);
+            tree.append(&ds_id);
+            tree_cache.insert(String::from(ds_id), tree);
+        }
+        assert!(tree_cache.get_mut(&String::from("a")).is_some());
+        tree_cache.insert(String::from("c"), DynMerkleTree::new(HashAlgorithm::Keccak256, TreeFormat::V0));
+        assert_eq!(tree_cache.len(), 2);
+        assert!(tree_cache.get_mut(&String::from("b")).is_none(), "Least recently used tree not dropped");
+        let tree = tree_cache.get_mut(&String::from("a")).unwrap();
+        assert_eq!(tree.leaf_index(&Keccak256::hash("a")), Some(0));
+        assert!(tree_cache.get_mut(&String::from("c")).is_some());
+    }
 
-        
+    #[tokio::test]
+    async fn test_verify_proof_file_offline() {
+        let bitacora = new_bitacora_from_stubs();
+        let mut device = new_device();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+        let flight_datas = new_flight_datas(&device, 3);
+        let mut ds = None;
+        for fd in flight_datas.iter() {
+            ds = Some(bitacora.new_flight_data(fd, &device.id).await.unwrap());
+        }
+        bitacora.seal(&ds.unwrap().id).await.unwrap();
+
+        let mut fd_proof = bitacora.flight_data_proof(&flight_datas[2].id).await.unwrap();
+        let proof_path = std::env::temp_dir().join(format!("bitacora-proof-test-{}.json", rand::random::<u64>()));
+        let verify_args = VerifyArgs { proof: proof_path.to_string_lossy().into_owned(), contract: None, web3: String::new() };
+        std::fs::write(&proof_path, serde_json::to_string(&fd_proof).unwrap()).unwrap();
+        assert!(verify(&verify_args).await.unwrap(), "Valid proof file rejected");
+
+        fd_proof.leaf = TreeFormat::V1.leaf_hash::<Keccak256, _>(flight_datas[0].to_bytes());
+        std::fs::write(&proof_path, serde_json::to_string(&fd_proof).unwrap()).unwrap();
+        assert!(!verify(&verify_args).await.unwrap(), "Proof file accepted for another leaf");
+
+        std::fs::write(&proof_path, "{}").unwrap();
+        assert!(matches!(verify(&verify_args).await, Err(VerifyError::BadProofFormat(_))));
+        std::fs::remove_file(&proof_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_verify_other_proof_files_offline() {
+        let bitacora = new_bitacora_from_stubs();
+        let mut device = new_device();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+        let flight_datas = new_flight_datas(&device, 3);
+        let mut ds = None;
+        for fd in flight_datas.iter() {
+            ds = Some(bitacora.new_flight_data(fd, &device.id).await.unwrap());
+        }
+        let ds = bitacora.seal(&ds.unwrap().id).await.unwrap();
+
+        let proof_path = std::env::temp_dir().join(format!("bitacora-proof-test-{}", rand::random::<u64>()));
+        let mut verify_args = VerifyArgs { proof: proof_path.to_string_lossy().into_owned(), contract: None, web3: String::new() };
+
+        let ids: Vec<FlightDataId> = flight_datas[1..].iter().map(|fd| fd.id.clone()).collect();
+        let mut fd_multiproof = bitacora.flight_data_multiproof(&ids).await.unwrap();
+        std::fs::write(&proof_path, serde_json::to_string(&fd_multiproof).unwrap()).unwrap();
+        assert!(verify(&verify_args).await.unwrap(), "Valid multiproof file rejected");
+        fd_multiproof.leaves.swap(0, 1);
+        std::fs::write(&proof_path, serde_json::to_string(&fd_multiproof).unwrap()).unwrap();
+        assert!(!verify(&verify_args).await.unwrap(), "Multiproof file accepted with swapped leaves");
+
+        let mut sparse_proof = bitacora.flight_data_sparse_proof(&ds.id, &flight_datas[0].id).await.unwrap();
+        std::fs::write(&proof_path, serde_json::to_string(&sparse_proof).unwrap()).unwrap();
+        assert!(verify(&verify_args).await.unwrap(), "Valid sparse proof file rejected");
+        sparse_proof.value = None;
+        std::fs::write(&proof_path, serde_json::to_string(&sparse_proof).unwrap()).unwrap();
+        assert!(!verify(&verify_args).await.unwrap(), "Sparse proof file accepted for a missing FlightData");
+
+        let mut mt = MerkleTree::<Keccak256>::with_format(TreeFormat::V1);
+        mt.append_leaves(flight_datas.iter().map(|fd| TreeFormat::V1.leaf_hash::<Keccak256, _>(fd.to_bytes())).collect());
+        let proof = mt.portable_proof(1).unwrap();
+        assert_eq!(Some(proof.root.clone()), ds.merkle_root);
+        std::fs::write(&proof_path, proof.to_bytes()).unwrap();
+        assert!(verify(&verify_args).await.unwrap(), "Valid binary portable proof rejected");
+        std::fs::write(&proof_path, serde_json::to_string(&proof).unwrap()).unwrap();
+        assert!(verify(&verify_args).await.unwrap(), "Valid JSON portable proof rejected");
+        let multiproof = mt.portable_multiproof(std::slice::from_ref(&proof.leaf)).unwrap();
+        std::fs::write(&proof_path, multiproof.to_bytes()).unwrap();
+        assert!(verify(&verify_args).await.unwrap(), "Valid binary portable multiproof rejected");
+
+        let mut bytes = proof.to_bytes();
+        bytes[1] = 0;
+        std::fs::write(&proof_path, bytes).unwrap();
+        assert!(matches!(verify(&verify_args).await, Err(VerifyError::BadProofFormat(_))));
+
+        // A portable proof does not tell its Dataset, the contract has no root to check it against
+        verify_args.contract = Some(String::from("0x0000000000000000000000000000000000000000"));
+        std::fs::write(&proof_path, proof.to_bytes()).unwrap();
+        assert!(matches!(verify(&verify_args).await, Err(VerifyError::UnknownDataset)));
+        std::fs::remove_file(&proof_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_seal_dataset_stored_without_accumulator() {
+        let storage = InMemoryStorage::default();
+        let device = new_device();
+        storage.new_device(&device).await.unwrap();
+        let flight_datas = new_flight_datas(&device, 5);
+        let dataset = test_dataset(storage.new_dataset_id().await.unwrap(), 10);
+        storage.add_dataset(&dataset, &device.id).await.unwrap();
+        for fd in flight_datas[..3].iter() {
+            storage.set_flight_data(fd).await.unwrap();
+            storage.add_flight_data(&dataset.id, fd).await.unwrap();
+        }
+        let bitacora = Arc::new(Bitacora::new(storage, EthereumStub::default()));
+
+        // FlightData keep going to the Dataset without an accumulator, which is then sealed from all of them
+        for fd in flight_datas[3..].iter() {
+            assert_eq!(bitacora.new_flight_data(fd, &device.id).await.unwrap().id, dataset.id);
+        }
+        assert!(bitacora.get_dataset_accumulator(&dataset.id).await.unwrap().is_none());
+        let sealed_ds = bitacora.seal(&dataset.id).await.unwrap();
+        // Still with the format the Dataset was created with
+        let mut expected_mt = MerkleTree::<Keccak256>::new();
+        for fd in flight_datas.iter() {
+            expected_mt.append(&fd.to_bytes());
+        }
+        assert_eq!(sealed_ds.merkle_root, expected_mt.root().cloned());
+        // The sparse leaves of the FlightData stored before are missing too, they are all hashed again
+        assert_eq!(bitacora.get_dataset_sparse_leaves(&dataset.id).await.unwrap().len(), 2);
+        let mut expected_smt = DynSparseMerkleTree::new(HashAlgorithm::Keccak256);
+        for fd in flight_datas.iter() {
+            expected_smt.insert(&Bytes32::from(&fd.id), TreeFormat::V0.leaf_hash::<Keccak256, _>(fd.to_bytes()));
+        }
+        assert_eq!(sealed_ds.sparse_merkle_root, expected_smt.root());
+        let fd_proof = bitacora.flight_data_proof(&flight_datas[4].id).await.unwrap();
+        assert_eq!(fd_proof.tree_format, TreeFormat::V0);
+        assert!(verify_proof::<Keccak256>(TreeFormat::V0, &fd_proof.merkle_root, &fd_proof.leaf, &fd_proof.proof));
+
+        // New Datasets are sealed from their accumulator
+        let mut next_ds = None;
+        for fd in new_flight_datas(&device, 8)[5..].iter() {
+            next_ds = Some(bitacora.new_flight_data(fd, &device.id).await.unwrap());
+        }
+        let next_ds = next_ds.unwrap();
+        assert_eq!(bitacora.get_dataset_accumulator(&next_ds.id).await.unwrap().unwrap().len(), 3);
+        let mut expected_mt = MerkleTree::<Keccak256>::with_format(TreeFormat::V1);
+        for fd in new_flight_datas(&device, 8)[5..].iter() {
+            expected_mt.append(&fd.to_bytes());
+        }
+        assert_eq!(bitacora.seal(&next_ds.id).await.unwrap().merkle_root, expected_mt.root().cloned());
+    }
+
+    #[tokio::test]
+    async fn test_recover_interrupted_anchoring() {
+        let storage = InMemoryStorage::default();
+        let device = new_device();
+        storage.new_device(&device).await.unwrap();
+        // Left by a run stopped while submitting it
+        let interrupted_ds = Dataset {
+            count: 10,
+            status: DatasetStatus::Anchoring,
+            merkle_root: Some(Bytes32([1u8; 32])),
+            ..test_dataset(storage.new_dataset_id().await.unwrap(), 10)
+        };
+        storage.add_dataset(&interrupted_ds, &device.id).await.unwrap();
+        let bitacora = Arc::new(Bitacora::new(storage, EthereumStub::default()));
+
+        let anchored = bitacora.recover_interrupted_anchoring().await.unwrap();
+        assert_eq!(anchored.iter().map(|dataset| dataset.id.clone()).collect::<Vec<_>>(), vec![interrupted_ds.id.clone()]);
+        let recovered_ds = bitacora.get_dataset(&interrupted_ds.id).await.unwrap().unwrap();
+        assert_eq!(recovered_ds.status, DatasetStatus::Anchored, "Interrupted Dataset not anchored");
+        assert!(recovered_ds.web3.is_some());
+        assert_eq!(recovered_ds.merkle_root, interrupted_ds.merkle_root, "Interrupted Dataset sealed again");
+        assert!(bitacora.recover_interrupted_anchoring().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_seals_submit_once() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        let device = new_device();
+        storage.new_device(&device).await.unwrap();
+        let failed_ds = Dataset {
+            count: 10,
+            status: DatasetStatus::AnchorFailed,
+            merkle_root: Some(Bytes32([1u8; 32])),
+            ..test_dataset(storage.new_dataset_id().await.unwrap(), 10)
+        };
+        storage.add_dataset(&failed_ds, &device.id).await.unwrap();
+        let bitacora = Bitacora::new(storage, EthereumStub::default());
+
+        // Whichever seal comes second finds the Dataset already being submitted, or submitted
+        let (first, second) = tokio::join!(bitacora.seal(&failed_ds.id), bitacora.seal(&failed_ds.id));
+        let (anchored, rejected): (Vec<_>, Vec<_>) = [first, second].into_iter().partition(Result::is_ok);
+        assert_eq!(anchored.len(), 1, "Dataset submitted twice");
+        assert_eq!(anchored[0].as_ref().unwrap().status, DatasetStatus::Anchored);
+        assert!(matches!(rejected[0], Err(BitacoraError::InvalidStatusTransition(DatasetStatus::Anchoring | DatasetStatus::Anchored, DatasetStatus::Sealed))));
+    }
+
+    #[tokio::test]
+    async fn test_recover_sealed_dataset() {
+        let storage = InMemoryStorage::default();
+        let device = new_device();
+        storage.new_device(&device).await.unwrap();
+        // Left by runs stopped after sealing them, before their submission started
+        let mut sealed_datasets = Vec::new();
+        for _ in 0..2 {
+            let sealed_ds = Dataset {
+                count: 10,
+                status: DatasetStatus::Sealed,
+                merkle_root: Some(Bytes32([1u8; 32])),
+                ..test_dataset(storage.new_dataset_id().await.unwrap(), 10)
+            };
+            storage.add_dataset(&sealed_ds, &device.id).await.unwrap();
+            sealed_datasets.push(sealed_ds);
+        }
+        let bitacora = Arc::new(Bitacora::new(storage, EthereumStub::default()));
+
+        // Sealing it again only submits it
+        let anchored_ds = bitacora.seal(&sealed_datasets[0].id).await.unwrap();
+        assert_eq!(anchored_ds.status, DatasetStatus::Anchored);
+        assert_eq!(anchored_ds.merkle_root, sealed_datasets[0].merkle_root, "Sealed Dataset sealed again");
+
+        let anchored = bitacora.recover_interrupted_anchoring().await.unwrap();
+        assert_eq!(anchored.iter().map(|dataset| dataset.id.clone()).collect::<Vec<_>>(), vec![sealed_datasets[1].id.clone()]);
+        let recovered_ds = bitacora.get_dataset(&sealed_datasets[1].id).await.unwrap().unwrap();
+        assert_eq!(recovered_ds.status, DatasetStatus::Anchored, "Sealed Dataset not anchored");
+        assert!(recovered_ds.web3.is_some());
+        assert_eq!(recovered_ds.merkle_root, sealed_datasets[1].merkle_root, "Sealed Dataset sealed again");
+        assert!(bitacora.recover_interrupted_anchoring().await.unwrap().is_empty());
+    }
+
+    async fn seal_expired_flow<S: FullStorage>(bitacora: Bitacora<S, EthereumStub>) {
+        let mut device = new_device();
+        register_device(&bitacora, &mut device).await.unwrap();
+        let bitacora = Arc::new(bitacora);
+        let flight_datas = new_flight_datas(&device, 3);
+        let max_idle = Some(Duration::from_secs(10));
+        let max_age = Some(Duration::from_secs(60));
+
+        bitacora.new_flight_data(&flight_datas[0], &device.id).await.unwrap();
+        let idle_ds = bitacora.new_flight_data(&flight_datas[1], &device.id).await.unwrap();
+        let stored_ds = bitacora.get_dataset(&idle_ds.id).await.unwrap().unwrap();
+        let (first, last) = (stored_ds.first_flight_data_at.unwrap(), stored_ds.last_flight_data_at.unwrap());
+        assert!(first <= last);
+
+        assert!(bitacora.seal_expired_datasets(last + 9_999, max_idle, max_age).await.unwrap().is_empty(), "Dataset sealed before being idle");
+        let sealed = bitacora.seal_expired_datasets(last + 10_000, max_idle, max_age).await.unwrap();
+        assert_eq!(sealed.iter().map(|ds| ds.id.clone()).collect::<Vec<_>>(), vec![idle_ds.id.clone()]);
+        let stored_ds = bitacora.get_dataset(&idle_ds.id).await.unwrap().unwrap();
+        assert_eq!(stored_ds.status, DatasetStatus::Anchored);
+        assert_eq!(stored_ds.limit, 2);
+
+        // Receiving FlightData does not prevent an aged Dataset from being sealed
+        let aged_ds = bitacora.new_flight_data(&flight_datas[2], &device.id).await.unwrap();
+        assert_ne!(aged_ds.id, idle_ds.id);
+        let first = aged_ds.first_flight_data_at.unwrap();
+        assert!(bitacora.seal_expired_datasets(first + 59_999, None, max_age).await.unwrap().is_empty());
+        assert_eq!(bitacora.seal_expired_datasets(first + 60_000, None, max_age).await.unwrap().len(), 1);
+        assert_eq!(bitacora.get_dataset(&aged_ds.id).await.unwrap().unwrap().status, DatasetStatus::Anchored);
+
+        assert!(bitacora.seal_expired_datasets(u64::MAX, None, None).await.unwrap().is_empty(), "Datasets sealed with auto sealing disabled");
+    }
+
+    #[tokio::test]
+    async fn test_seal_expired_datasets_on_in_memory_storage() {
+        seal_expired_flow(new_bitacora_from_stubs()).await;
+    }
+
+    #[tokio::test]
+    async fn test_seal_expired_datasets_on_sqlite_storage() {
+        seal_expired_flow(Bitacora::new(SqliteStorage::open_in_memory().unwrap(), EthereumStub::default())).await;
     }
-}
\ No newline at end of file
+}
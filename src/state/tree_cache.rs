@@ -0,0 +1,61 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::common::hash_algorithm::DynMerkleTree;
+
+use super::entities::DatasetId;
+
+/// Computed Merkle trees of at most `capacity` Datasets. The least recently used one is dropped to make
+/// room for a new one.
+pub struct TreeCache {
+    capacity: usize,
+    trees: HashMap<DatasetId, DynMerkleTree>,
+    // Least recently used first
+    usage: VecDeque<DatasetId>
+}
+
+impl TreeCache {
+    pub fn new(capacity: usize) -> Self {
+        TreeCache { capacity, trees: HashMap::new(), usage: VecDeque::new() }
+    }
+
+    pub fn get_mut(&mut self, ds_id: &DatasetId) -> Option<&mut DynMerkleTree> {
+        if !self.trees.contains_key(ds_id) {
+            return None;
+        }
+        self.touch(ds_id);
+        self.trees.get_mut(ds_id)
+    }
+
+    /// Stores the tree of the Dataset, replacing the previous one, and returns it. With no capacity the
+    /// tree is only kept until the next insertion.
+    pub fn insert(&mut self, ds_id: DatasetId, tree: DynMerkleTree) -> &mut DynMerkleTree {
+        if self.trees.contains_key(&ds_id) {
+            self.touch(&ds_id);
+        } else {
+            while !self.usage.is_empty() && self.usage.len() >= self.capacity.max(1) {
+                if let Some(evicted) = self.usage.pop_front() {
+                    self.trees.remove(&evicted);
+                }
+            }
+            self.usage.push_back(ds_id.clone());
+        }
+        self.trees.insert(ds_id.clone(), tree);
+        self.trees.get_mut(&ds_id).unwrap()
+    }
+
+    pub fn len(&self) -> usize {
+        self.trees.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trees.is_empty()
+    }
+
+    fn touch(&mut self, ds_id: &DatasetId) {
+        if let Some(position) = self.usage.iter().position(|id| id == ds_id) {
+            if let Some(id) = self.usage.remove(position) {
+                self.usage.push_back(id);
+            }
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex};
+use tracing::{info, warn};
+
+use crate::common::prelude::MerkleRoot;
+use crate::state::entities::{Device, DeviceKey, FlightData, Dataset, DatasetAccumulator, DatasetKind, DatasetStatus, DeviceId, FlightDataId, DatasetId};
+
+use super::errors::Error;
+use super::in_memory::InMemoryStorage;
+use super::storage::{FullStorage, DatasetStorage, DeviceStorage, FlightDataStorage, TransactionStorage};
+use super::transaction::{Operation, Transaction};
+
+pub const SEGMENT_DEFAULT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+const SEGMENT_EXTENSION: &str = "log";
+// Each record is framed as: payload length (u32 BE) | CRC32 of the payload (u32 BE) | payload
+// The payload is a whole Transaction, so that a torn write drops it entirely
+const RECORD_HEADER_BYTES: usize = 8;
+
+struct SegmentWriter {
+    dir: PathBuf,
+    file: File,
+    sequence: u64,
+    size: u64,
+    max_bytes: u64
+}
+
+impl SegmentWriter {
+    fn open(dir: &Path, sequence: u64, max_bytes: u64) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(dir, sequence))?;
+        let size = file.metadata()?.len();
+        Ok(SegmentWriter { dir: dir.to_path_buf(), file: File::from_std(file), sequence, size, max_bytes })
+    }
+
+    async fn append(&mut self, record: &Transaction) -> Result<(), Error> {
+        let payload = match serde_json::to_vec(record) {
+            Ok(payload) => payload,
+            Err(err) => return Err(Error::BackendFailure(err.to_string()))
+        };
+        let mut frame = Vec::with_capacity(RECORD_HEADER_BYTES + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        if self.size > 0 && self.size + frame.len() as u64 > self.max_bytes {
+            *self = SegmentWriter::open(&self.dir, self.sequence + 1, self.max_bytes)?;
+            info!(segment = self.sequence, "Rotated log segment");
+        }
+        if let Err(err) = self.write_frame(&frame).await {
+            // Drop what may have been written of the frame, so that the next records don't follow a torn one
+            if let Err(truncate_err) = self.file.set_len(self.size).await {
+                warn!(segment = self.sequence, error = %truncate_err, "Failed to truncate a partially written record");
+            }
+            return Err(err);
+        }
+        self.size += frame.len() as u64;
+        Ok(())
+    }
+
+    async fn write_frame(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.file.write_all(frame).await?;
+        self.file.flush().await?;
+        self.file.sync_data().await?;
+        Ok(())
+    }
+}
+
+/// Durable storage writing every mutation to an append-only, checksummed log.
+///
+/// The log is split in numbered segment files inside a directory. On startup the
+/// segments are replayed in order to rebuild an in-memory index, which then serves
+/// every read. A record torn by a crash at the tail of the last segment is detected
+/// through its length and checksum and truncated away.
+pub struct AppendLogStorage {
+    index: InMemoryStorage,
+    writer: Mutex<SegmentWriter>
+}
+
+impl AppendLogStorage {
+    pub async fn open<P: AsRef<Path>>(dir: P) -> Result<Self, Error> {
+        Self::open_with_segment_size(dir, SEGMENT_DEFAULT_MAX_BYTES).await
+    }
+
+    pub async fn open_with_segment_size<P: AsRef<Path>>(dir: P, max_segment_bytes: u64) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let segments = list_segments(dir)?;
+        let index = InMemoryStorage::default();
+        let mut replayed = 0usize;
+        for (i, (_, path)) in segments.iter().enumerate() {
+            replayed += replay_segment(&index, path, i == segments.len() - 1).await?;
+        }
+        info!(segments = segments.len(), records = replayed, "Replayed storage log");
+        let last_sequence = segments.last().map(|(sequence, _)| *sequence).unwrap_or(0);
+        Ok(AppendLogStorage {
+            index,
+            writer: Mutex::new(SegmentWriter::open(dir, last_sequence, max_segment_bytes)?)
+        })
+    }
+
+    async fn write(&self, operation: Operation) -> Result<bool, Error> {
+        self.write_transaction(Transaction::from(operation)).await
+    }
+
+    /// Applies the transaction to the index and appends it to the log. Readers see it only once it is
+    /// durable: the index is rolled back if the record can't be written.
+    async fn write_transaction(&self, transaction: Transaction) -> Result<bool, Error> {
+        let mut writer = self.writer.lock().await;
+        let staged = self.index.stage(transaction.clone()).await?;
+        if let Err(err) = writer.append(&transaction).await {
+            staged.rollback();
+            return Err(err);
+        }
+        Ok(staged.replaced_existing())
+    }
+}
+
+#[async_trait]
+impl DeviceStorage for AppendLogStorage {
+    async fn new_device(&self, device: &Device) -> Result<(), Error> {
+        self.write(Operation::NewDevice(device.clone())).await.map(|_| ())
+    }
+
+    async fn set_device(&self, device: &Device) -> Result<bool, Error> {
+        self.write(Operation::SetDevice(device.clone())).await
+    }
+
+    async fn get_device(&self, id: &DeviceId) -> Result<Option<Device>, Error> {
+        self.index.get_device(id).await
+    }
+
+    async fn rotate_device_key(&self, device_id: &DeviceId, key: &DeviceKey) -> Result<(), Error> {
+        self.write(Operation::RotateDeviceKey(device_id.clone(), key.clone())).await.map(|_| ())
+    }
+
+    async fn revoke_device(&self, device_id: &DeviceId, at: u64) -> Result<(), Error> {
+        self.write(Operation::RevokeDevice(device_id.clone(), at)).await.map(|_| ())
+    }
+}
+
+#[async_trait]
+impl FlightDataStorage for AppendLogStorage {
+    async fn set_flight_data(&self, fd: &FlightData) -> Result<bool, Error> {
+        self.write(Operation::SetFlightData(fd.clone())).await
+    }
+
+    async fn get_flight_data(&self, id: &FlightDataId) -> Result<Option<FlightData>, Error> {
+        self.index.get_flight_data(id).await
+    }
+}
+
+#[async_trait]
+impl DatasetStorage for AppendLogStorage {
+    async fn add_flight_data(&self, ds_id: &DatasetId, fd: &FlightData) -> Result<(), Error> {
+        self.write(Operation::AddFlightData(ds_id.clone(), fd.clone())).await.map(|_| ())
+    }
+
+    async fn get_dataset_flight_data(&self, ds_id: &DatasetId) -> Result<Vec<FlightData>, Error> {
+        self.index.get_dataset_flight_data(ds_id).await
+    }
+
+    async fn get_dataset(&self, id: &DatasetId) -> Result<Option<Dataset>, Error> {
+        self.index.get_dataset(id).await
+    }
+
+    async fn set_dataset(&self, ds: &Dataset) -> Result<bool, Error> {
+        self.write(Operation::SetDataset(ds.clone())).await
+    }
+
+    async fn add_dataset(&self, ds: &Dataset, device_id: &DeviceId) -> Result<(), Error> {
+        self.write(Operation::AddDataset(ds.clone(), device_id.clone())).await.map(|_| ())
+    }
+
+    async fn get_latest_dataset(&self, device_id: &DeviceId, kind: DatasetKind) -> Result<Option<Dataset>, Error> {
+        self.index.get_latest_dataset(device_id, kind).await
+    }
+
+    async fn get_datasets_by_status(&self, status: DatasetStatus) -> Result<Vec<Dataset>, Error> {
+        self.index.get_datasets_by_status(status).await
+    }
+
+    async fn get_flight_data_dataset(&self, fd_id: &FlightDataId) -> Result<Option<DatasetId>, Error> {
+        self.index.get_flight_data_dataset(fd_id).await
+    }
+
+    async fn get_dataset_device(&self, ds_id: &DatasetId) -> Result<Option<DeviceId>, Error> {
+        self.index.get_dataset_device(ds_id).await
+    }
+
+    async fn get_dataset_accumulator(&self, ds_id: &DatasetId) -> Result<Option<DatasetAccumulator>, Error> {
+        self.index.get_dataset_accumulator(ds_id).await
+    }
+
+    async fn get_dataset_sparse_leaves(&self, ds_id: &DatasetId) -> Result<Vec<(FlightDataId, MerkleRoot)>, Error> {
+        self.index.get_dataset_sparse_leaves(ds_id).await
+    }
+
+    async fn new_dataset_id(&self) -> Result<DatasetId, Error> {
+        self.index.new_dataset_id().await
+    }
+}
+
+#[async_trait]
+impl TransactionStorage for AppendLogStorage {
+    async fn commit(&self, transaction: Transaction) -> Result<(), Error> {
+        self.write_transaction(transaction).await.map(|_| ())
+    }
+}
+
+impl FullStorage for AppendLogStorage {}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::BackendFailure(value.to_string())
+    }
+}
+
+fn segment_path(dir: &Path, sequence: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", sequence, SEGMENT_EXTENSION))
+}
+
+fn list_segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>, Error> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(sequence) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok()) {
+            segments.push((sequence, path));
+        }
+    }
+    segments.sort_by_key(|(sequence, _)| *sequence);
+    Ok(segments)
+}
+
+/// Replays all the records of a segment into the index returning how many were applied.
+/// A damaged record is tolerated only at the tail of the last segment, where it runs to the end
+/// of the file with no valid record after it, as the result of an interrupted write, and it is
+/// truncated away. Any other damaged record fails the replay, so that the valid records after it are
+/// never dropped.
+async fn replay_segment(index: &InMemoryStorage, path: &Path, is_last: bool) -> Result<usize, Error> {
+    let content = fs::read(path)?;
+    let mut offset = 0usize;
+    let mut replayed = 0usize;
+    while offset < content.len() {
+        let payload = match read_record(&content[offset..]) {
+            Record::Valid(payload) => payload,
+            Record::Torn if is_last => {
+                warn!(segment = %path.display(), offset = offset, "Truncating torn record at the tail of the log");
+                OpenOptions::new().write(true).open(path)?.set_len(offset as u64)?;
+                break;
+            },
+            Record::Torn | Record::Corrupted => {
+                return Err(Error::BackendFailure(format!("Corrupted record in {} at offset {}", path.display(), offset)));
+            }
+        };
+        let record: Transaction = match serde_json::from_slice(payload) {
+            Ok(record) => record,
+            Err(err) => return Err(Error::BackendFailure(format!("Undecodable record in {} at offset {}: {}", path.display(), offset, err)))
+        };
+        index.commit(record).await?;
+        offset += RECORD_HEADER_BYTES + payload.len();
+        replayed += 1;
+    }
+    Ok(replayed)
+}
+
+enum Record<'a> {
+    Valid(&'a [u8]),
+    /// The record runs to the end of the buffer, does not verify and no record verifies after it
+    Torn,
+    /// The record does not verify and is followed by more bytes, or by a record that does verify
+    Corrupted
+}
+
+fn read_record(buffer: &[u8]) -> Record<'_> {
+    match read_frame(buffer) {
+        Some((payload, true)) => Record::Valid(payload),
+        Some((payload, false)) if RECORD_HEADER_BYTES + payload.len() < buffer.len() => Record::Corrupted,
+        _ => torn_or_corrupted(buffer)
+    }
+}
+
+/// An interrupted write is the last one of the log, so a record running to the end of the buffer is only
+/// torn when no record verifies after it. Otherwise its length was damaged, and the records it spans
+/// must not be truncated with it.
+fn torn_or_corrupted(buffer: &[u8]) -> Record<'_> {
+    let followed_by_record = (1..buffer.len())
+        .any(|start| matches!(read_frame(&buffer[start..]), Some((payload, true)) if !payload.is_empty()));
+    match followed_by_record {
+        true => Record::Corrupted,
+        false => Record::Torn
+    }
+}
+
+/// Payload of the frame at the start of the buffer and whether it matches its checksum, or none when the
+/// buffer ends before the frame does.
+fn read_frame(buffer: &[u8]) -> Option<(&[u8], bool)> {
+    let header = buffer.get(..RECORD_HEADER_BYTES)?;
+    let length = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    let payload = buffer[RECORD_HEADER_BYTES..].get(..length)?;
+    Some((payload, crc32fast::hash(payload) == checksum))
+}
@@ -3,5 +3,9 @@ pub enum Error {
     FailedRelatingData(String, String),
     InconsistentRelatedData(String, String),
     NotFound(String),
-    AlreadyExists
+    AlreadyExists,
+    DatasetFull,
+    DatasetSealed,
+    DeviceRevoked,
+    BackendFailure(String)
 }
\ No newline at end of file
@@ -1,159 +1,351 @@
 use std::collections::HashMap;
-use std::sync::RwLock;
 
-use sha2::{Digest, Sha256};
+use async_trait::async_trait;
+use tokio::sync::{RwLock, RwLockWriteGuard};
 
-use crate::state::entities::{Device, FlightData, Dataset, DeviceId, FlightDataId, DatasetId};
+use crate::common::prelude::MerkleRoot;
+use crate::state::entities::{Device, DeviceKey, DeviceRevocation, FlightData, Dataset, DatasetAccumulator, DatasetKind, DatasetStatus, DeviceId, FlightDataId, DatasetId};
 
 use super::errors::Error;
-use super::storage::{FullStorage, DatasetStorage, DeviceStorage, FlightDataStorage};
+use super::storage::{random_dataset_id, FullStorage, DatasetStorage, DeviceStorage, FlightDataStorage, TransactionStorage};
+use super::transaction::{Operation, Transaction};
 
 #[derive(Default)]
-pub struct InMemoryStorage {
-    devices: RwLock<HashMap<DeviceId, Device>>,
-    fligth_data: RwLock<HashMap<FlightDataId, FlightData>>,
-    datasets: RwLock<HashMap<DatasetId, Dataset>>,
-    datasets_flight_data: RwLock<HashMap<DatasetId, Vec<FlightDataId>>>,
-    devices_datasets: RwLock<HashMap<DeviceId, Vec<DatasetId>>>
+struct InMemoryData {
+    devices: HashMap<DeviceId, Device>,
+    fligth_data: HashMap<FlightDataId, FlightData>,
+    datasets: HashMap<DatasetId, Dataset>,
+    datasets_flight_data: HashMap<DatasetId, Vec<FlightDataId>>,
+    flight_data_datasets: HashMap<FlightDataId, DatasetId>,
+    datasets_devices: HashMap<DatasetId, DeviceId>,
+    devices_datasets: HashMap<DeviceId, Vec<DatasetId>>,
+    datasets_accumulators: HashMap<DatasetId, DatasetAccumulator>,
+    datasets_sparse_leaves: HashMap<DatasetId, Vec<(FlightDataId, MerkleRoot)>>
 }
 
-impl DeviceStorage for InMemoryStorage {
-    fn new_device(&self, device: &Device) -> Result<(), Error> {
-        let mut write_lock = self.devices.write().unwrap();
-        if write_lock.contains_key(&device.id) {
-            return Err(Error::AlreadyExists);
+/// What is needed to revert an applied Operation
+enum Undo {
+    Device(DeviceId, Option<Device>),
+    FlightData(FlightDataId, Option<FlightData>),
+    Dataset(DatasetId, Option<Dataset>),
+    AddedDataset(DatasetId, DeviceId),
+    AddedFlightData(DatasetId, DatasetStatus),
+    DatasetAccumulator(DatasetId, Option<DatasetAccumulator>),
+    AddedDatasetSparseLeaf(DatasetId)
+}
+
+impl Undo {
+    fn replaced_existing(&self) -> bool {
+        match self {
+            Undo::Device(_, previous) => previous.is_some(),
+            Undo::FlightData(_, previous) => previous.is_some(),
+            Undo::Dataset(_, previous) => previous.is_some(),
+            Undo::DatasetAccumulator(_, previous) => previous.is_some(),
+            _ => false
         }
-        write_lock.insert(device.id.clone(), device.clone());
-        self.devices_datasets.write().unwrap().insert(device.id.clone(), vec![]);
-        Ok(())
     }
+}
 
-    fn set_device(&self, device: &Device) -> Result<bool, Error> {
-        match self.devices.write().unwrap().insert(device.id.clone(), device.clone()) {
-            Some(_) => Ok(true),
-            None => {
-                self.devices_datasets.write().unwrap().insert(device.id.clone(), vec![]);
-                Ok(false)
+impl InMemoryData {
+    fn apply(&mut self, operation: Operation) -> Result<Undo, Error> {
+        match operation {
+            Operation::NewDevice(device) | Operation::SetDevice(device) if !self.devices.contains_key(&device.id) => {
+                self.devices_datasets.insert(device.id.clone(), vec![]);
+                Ok(Undo::Device(device.id.clone(), self.devices.insert(device.id.clone(), device)))
+            },
+            Operation::NewDevice(_) => Err(Error::AlreadyExists),
+            Operation::SetDevice(device) => {
+                Ok(Undo::Device(device.id.clone(), self.devices.insert(device.id.clone(), device)))
+            },
+            Operation::RotateDeviceKey(device_id, key) => {
+                let device = match self.devices.get_mut(&device_id) {
+                    Some(device) if device.is_revoked() => return Err(Error::DeviceRevoked),
+                    Some(device) => device,
+                    None => return Err(Error::NotFound(String::from("Device")))
+                };
+                let previous = device.clone();
+                device.rotate_key(key);
+                Ok(Undo::Device(device_id, Some(previous)))
+            },
+            Operation::RevokeDevice(device_id, at) => {
+                let device = match self.devices.get_mut(&device_id) {
+                    Some(device) if device.is_revoked() => return Err(Error::DeviceRevoked),
+                    Some(device) => device,
+                    None => return Err(Error::NotFound(String::from("Device")))
+                };
+                let previous = device.clone();
+                device.revocation = Some(DeviceRevocation { revoked_at: at, web3: None });
+                Ok(Undo::Device(device_id, Some(previous)))
+            },
+            Operation::NewFlightData(fd) if self.fligth_data.contains_key(&fd.id) => Err(Error::AlreadyExists),
+            Operation::NewFlightData(fd) | Operation::SetFlightData(fd) => {
+                Ok(Undo::FlightData(fd.id.clone(), self.fligth_data.insert(fd.id.clone(), fd)))
+            },
+            Operation::SetDataset(ds) => {
+                Ok(Undo::Dataset(ds.id.clone(), self.datasets.insert(ds.id.clone(), ds)))
+            },
+            Operation::AddDataset(ds, device_id) => {
+                if self.datasets.contains_key(&ds.id) {
+                    return Err(Error::AlreadyExists);
+                }
+                match self.devices_datasets.get_mut(&device_id) {
+                    Some(dataset_list) => dataset_list.push(ds.id.clone()),
+                    None => return Err(Error::FailedRelatingData(String::from("Dataset"), String::from("Device")))
+                }
+                let ds_id = ds.id.clone();
+                self.datasets_devices.insert(ds_id.clone(), device_id.clone());
+                self.datasets_flight_data.insert(ds_id.clone(), vec![]);
+                self.datasets.insert(ds_id.clone(), ds);
+                Ok(Undo::AddedDataset(ds_id, device_id))
+            },
+            Operation::AddFlightData(ds_id, fd) => {
+                let dataset = match self.datasets.get_mut(&ds_id) {
+                    Some(dataset) => dataset,
+                    None => return Err(Error::InconsistentRelatedData(String::from("Dataset"), String::from("FlightData")))
+                };
+                if !dataset.status.accepts_flight_data() {
+                    return Err(Error::DatasetSealed);
+                }
+                if dataset.count >= dataset.limit {
+                    return Err(Error::DatasetFull);
+                }
+                let previous_status = dataset.status;
+                dataset.count += 1;
+                dataset.status = DatasetStatus::Active;
+                match self.datasets_flight_data.get_mut(&ds_id) {
+                    Some(vector) => vector.push(fd.id.clone()),
+                    None => unreachable!()
+                };
+                self.flight_data_datasets.insert(fd.id, ds_id.clone());
+                Ok(Undo::AddedFlightData(ds_id, previous_status))
+            },
+            Operation::TouchDataset(ds_id, at) => {
+                let dataset = match self.datasets.get_mut(&ds_id) {
+                    Some(dataset) => dataset,
+                    None => return Err(Error::NotFound(String::from("Dataset")))
+                };
+                let previous = dataset.clone();
+                dataset.first_flight_data_at = dataset.first_flight_data_at.or(Some(at));
+                dataset.last_flight_data_at = Some(at);
+                Ok(Undo::Dataset(ds_id, Some(previous)))
+            },
+            Operation::SetDatasetAccumulator(ds_id, accumulator) => {
+                if !self.datasets.contains_key(&ds_id) {
+                    return Err(Error::NotFound(String::from("Dataset")));
+                }
+                Ok(Undo::DatasetAccumulator(ds_id.clone(), self.datasets_accumulators.insert(ds_id, accumulator)))
+            },
+            Operation::AddDatasetSparseLeaf(ds_id, fd_id, leaf) => {
+                if !self.datasets.contains_key(&ds_id) {
+                    return Err(Error::NotFound(String::from("Dataset")));
+                }
+                self.datasets_sparse_leaves.entry(ds_id.clone()).or_default().push((fd_id, leaf));
+                Ok(Undo::AddedDatasetSparseLeaf(ds_id))
             }
         }
     }
 
-    fn get_device(&self, id: &DeviceId) -> Result<Option<Device>, Error> {
-        let acquire_read = self.devices.read().unwrap();
-        let result = acquire_read.get(id);
-        if result.is_some() {
-            Ok(Some(result.unwrap().clone()))
-        } else {
-            Ok(Option::None)
+    fn revert(&mut self, undo: Undo) {
+        match undo {
+            Undo::Device(id, Some(previous)) => { self.devices.insert(id, previous); },
+            Undo::Device(id, None) => {
+                self.devices.remove(&id);
+                self.devices_datasets.remove(&id);
+            },
+            Undo::FlightData(id, Some(previous)) => { self.fligth_data.insert(id, previous); },
+            Undo::FlightData(id, None) => { self.fligth_data.remove(&id); },
+            Undo::Dataset(id, Some(previous)) => { self.datasets.insert(id, previous); },
+            Undo::Dataset(id, None) => { self.datasets.remove(&id); },
+            Undo::AddedDataset(ds_id, device_id) => {
+                self.datasets.remove(&ds_id);
+                self.datasets_flight_data.remove(&ds_id);
+                self.datasets_devices.remove(&ds_id);
+                if let Some(dataset_list) = self.devices_datasets.get_mut(&device_id) {
+                    dataset_list.pop();
+                }
+            },
+            Undo::AddedFlightData(ds_id, previous_status) => {
+                if let Some(fd_id) = self.datasets_flight_data.get_mut(&ds_id).and_then(|vector| vector.pop()) {
+                    self.flight_data_datasets.remove(&fd_id);
+                }
+                if let Some(dataset) = self.datasets.get_mut(&ds_id) {
+                    dataset.count -= 1;
+                    dataset.status = previous_status;
+                }
+            },
+            Undo::DatasetAccumulator(ds_id, Some(previous)) => { self.datasets_accumulators.insert(ds_id, previous); },
+            Undo::DatasetAccumulator(ds_id, None) => { self.datasets_accumulators.remove(&ds_id); },
+            Undo::AddedDatasetSparseLeaf(ds_id) => {
+                if let Some(leaves) = self.datasets_sparse_leaves.get_mut(&ds_id) {
+                    leaves.pop();
+                }
+            }
+        }
+    }
+}
+
+/// Volatile storage keeping everything in memory behind a single lock, so that
+/// transactions are isolated from concurrent readers.
+#[derive(Default)]
+pub struct InMemoryStorage {
+    data: RwLock<InMemoryData>
+}
+
+/// A transaction applied to an `InMemoryStorage` that still holds its lock, so that readers can't see it
+/// until it is dropped. It can be rolled back until then.
+pub struct StagedTransaction<'a> {
+    data: RwLockWriteGuard<'a, InMemoryData>,
+    applied: Vec<Undo>
+}
+
+impl StagedTransaction<'_> {
+    /// Whether the transaction replaced an existing entity
+    pub fn replaced_existing(&self) -> bool {
+        self.applied.iter().any(Undo::replaced_existing)
+    }
+
+    pub fn rollback(mut self) {
+        while let Some(undo) = self.applied.pop() {
+            self.data.revert(undo);
         }
     }
 }
 
+impl InMemoryStorage {
+    /// Applies a single operation returning whether it replaced an existing entity.
+    pub async fn apply(&self, operation: Operation) -> Result<bool, Error> {
+        self.data.write().await.apply(operation).map(|undo| undo.replaced_existing())
+    }
+
+    /// Applies all the operations of the transaction, or none of them if one fails, keeping the lock
+    /// until the returned transaction is dropped.
+    pub async fn stage(&self, transaction: Transaction) -> Result<StagedTransaction<'_>, Error> {
+        let mut staged = StagedTransaction { data: self.data.write().await, applied: Vec::new() };
+        for operation in transaction.into_operations() {
+            match staged.data.apply(operation) {
+                Ok(undo) => staged.applied.push(undo),
+                Err(err) => {
+                    staged.rollback();
+                    return Err(err);
+                }
+            }
+        }
+        Ok(staged)
+    }
+}
+
+#[async_trait]
+impl DeviceStorage for InMemoryStorage {
+    async fn new_device(&self, device: &Device) -> Result<(), Error> {
+        self.apply(Operation::NewDevice(device.clone())).await.map(|_| ())
+    }
+
+    async fn set_device(&self, device: &Device) -> Result<bool, Error> {
+        self.apply(Operation::SetDevice(device.clone())).await
+    }
+
+    async fn get_device(&self, id: &DeviceId) -> Result<Option<Device>, Error> {
+        Ok(self.data.read().await.devices.get(id).cloned())
+    }
+
+    async fn rotate_device_key(&self, device_id: &DeviceId, key: &DeviceKey) -> Result<(), Error> {
+        self.apply(Operation::RotateDeviceKey(device_id.clone(), key.clone())).await.map(|_| ())
+    }
+
+    async fn revoke_device(&self, device_id: &DeviceId, at: u64) -> Result<(), Error> {
+        self.apply(Operation::RevokeDevice(device_id.clone(), at)).await.map(|_| ())
+    }
+}
+
+#[async_trait]
 impl FlightDataStorage for InMemoryStorage {
-    fn set_flight_data(&self, fd: &FlightData) -> Result<bool, Error> {
-        match self.fligth_data.write().unwrap().insert(fd.id.clone(), fd.clone()) {
-            Some(_) => Ok(true),
-            None => Ok(false)
-        }   
-    }
-
-    fn get_flight_data(&self, id: &FlightDataId) -> Result<Option<FlightData>, Error> {
-        let acquire_read = self.fligth_data.read().unwrap();
-        let result = acquire_read.get(id);
-        if result.is_some() {
-            Ok(Some(result.unwrap().clone()))
-        } else {
-            Ok(Option::None)
-        }   
+    async fn set_flight_data(&self, fd: &FlightData) -> Result<bool, Error> {
+        self.apply(Operation::SetFlightData(fd.clone())).await
+    }
+
+    async fn get_flight_data(&self, id: &FlightDataId) -> Result<Option<FlightData>, Error> {
+        Ok(self.data.read().await.fligth_data.get(id).cloned())
     }
 }
 
+#[async_trait]
 impl DatasetStorage for InMemoryStorage {
-    fn add_flight_data(&self, ds_id: &DatasetId, fd: &FlightData) -> Result<(), Error> {
-        let mut write_access = self.datasets_flight_data.write().unwrap();
-        match write_access.get_mut(ds_id) {
-            Some(vector) => {
-                vector.push(fd.id.clone());
-            },
-            None => return Err(Error::InconsistentRelatedData(String::from("Dataset"), String::from("FlightData")))
-        };
-        let mut write_access = self.datasets.write().unwrap();
-        match write_access.get_mut(ds_id) {
-            Some(dataset) => dataset.count += 1,
-            None => unreachable!()
-        }
-        Ok(())
+    async fn add_flight_data(&self, ds_id: &DatasetId, fd: &FlightData) -> Result<(), Error> {
+        self.apply(Operation::AddFlightData(ds_id.clone(), fd.clone())).await.map(|_| ())
     }
 
-    fn get_dataset_flight_data(&self, ds_id: &DatasetId) -> Result<Vec<FlightData>, Error> {
-        let read_access_cross_ds_fd = self.datasets_flight_data.read().unwrap();
-        let read_access_fds = self.fligth_data.read().unwrap();
-        let fd_ids = match read_access_cross_ds_fd.get(ds_id) {
+    async fn get_dataset_flight_data(&self, ds_id: &DatasetId) -> Result<Vec<FlightData>, Error> {
+        let data = self.data.read().await;
+        let fd_ids = match data.datasets_flight_data.get(ds_id) {
             Some(fd_ids) => fd_ids,
             None => return Err(Error::NotFound(String::from("Dataset")))
         };
         let mut fds = Vec::new();
         for fd_id in fd_ids {
-            fds.push(read_access_fds.get(fd_id).unwrap().clone());
+            fds.push(data.fligth_data.get(fd_id).unwrap().clone());
         }
         Ok(fds)
     }
 
-    fn get_dataset(&self, id: &DatasetId) -> Result<Option<Dataset>, Error> {
-        match self.datasets.read().unwrap().get(id) {
-            Some(dataset) => Ok(Some(dataset.clone())),
-            None => Ok(Option::None)
-        }
+    async fn get_dataset(&self, id: &DatasetId) -> Result<Option<Dataset>, Error> {
+        Ok(self.data.read().await.datasets.get(id).cloned())
     }
 
-    fn set_dataset(&self, ds: &Dataset) -> Result<bool, Error> {
-        match self.datasets.write().unwrap().insert(ds.id.clone(), ds.clone()) {
-            Some(_) => Ok(true),
-            None => Ok(false)
-        }
+    async fn set_dataset(&self, ds: &Dataset) -> Result<bool, Error> {
+        self.apply(Operation::SetDataset(ds.clone())).await
     }
 
-    fn add_dataset(&self, ds: &Dataset, device_id: &DeviceId) -> Result<(), Error> {
-        let mut write_lock = self.datasets.write().unwrap();
-        match write_lock.insert(ds.id.clone(), ds.clone()) {
-            Some(old_dataset) => { //a dataset already exists with such ID this is an error
-                write_lock.insert(old_dataset.id.clone(), old_dataset); // restore the old dataset
-                Err(Error::AlreadyExists)
-            },
-            None => {
-                let mut write_access = self.devices_datasets.write().unwrap();
-                match write_access.get_mut(device_id) {
-                    Some(dataset_list) => dataset_list.push(ds.id.clone()),
-                    None => return Err(Error::FailedRelatingData(String::from("Dataset"), String::from("Device")))
-                }
-                self.datasets_flight_data.write().unwrap().insert(ds.id.clone(), vec![]);
-                Ok(())
-            }
-        }
+    async fn add_dataset(&self, ds: &Dataset, device_id: &DeviceId) -> Result<(), Error> {
+        self.apply(Operation::AddDataset(ds.clone(), device_id.clone())).await.map(|_| ())
     }
 
-    fn get_latest_dataset(&self, device_id: &DeviceId) -> Result<Option<Dataset>, Error> {
-        match self.devices_datasets.read().unwrap().get(device_id) {
+    async fn get_latest_dataset(&self, device_id: &DeviceId, kind: DatasetKind) -> Result<Option<Dataset>, Error> {
+        let data = self.data.read().await;
+        match data.devices_datasets.get(device_id) {
             Some(dataset_list) => {
-                match dataset_list.last() {
-                    Some(dataset_id) => match self.datasets.read().unwrap().get(dataset_id) {
-                        Some(dataset) => Ok(Some(dataset.clone())),
-                        None => Err(Error::NotFound(String::from("Latest Dataset Id does not have corresponding data")))
-                    },
-                    None => Ok(Option::None)
+                for dataset_id in dataset_list.iter().rev() {
+                    match data.datasets.get(dataset_id) {
+                        Some(dataset) if dataset.kind == kind => return Ok(Some(dataset.clone())),
+                        Some(_) => continue,
+                        None => return Err(Error::NotFound(String::from("Latest Dataset Id does not have corresponding data")))
+                    }
                 }
-                
+                Ok(Option::None)
             },
             None => Err(Error::NotFound(String::from("Device not found")))
         }
     }
 
-    fn new_dataset_id(&self) -> Result<DatasetId, Error> {
-        let mut hasher = Sha256::new();
-        hasher.update(rand::random::<u64>().to_be_bytes());
-        hasher.update(rand::random::<u64>().to_be_bytes());
-        Ok(bs58::encode(hasher.finalize()).into_string())
+    async fn get_datasets_by_status(&self, status: DatasetStatus) -> Result<Vec<Dataset>, Error> {
+        Ok(self.data.read().await.datasets.values().filter(|dataset| dataset.status == status).cloned().collect())
+    }
+
+    async fn get_flight_data_dataset(&self, fd_id: &FlightDataId) -> Result<Option<DatasetId>, Error> {
+        Ok(self.data.read().await.flight_data_datasets.get(fd_id).cloned())
+    }
+
+    async fn get_dataset_device(&self, ds_id: &DatasetId) -> Result<Option<DeviceId>, Error> {
+        Ok(self.data.read().await.datasets_devices.get(ds_id).cloned())
+    }
+
+    async fn get_dataset_accumulator(&self, ds_id: &DatasetId) -> Result<Option<DatasetAccumulator>, Error> {
+        Ok(self.data.read().await.datasets_accumulators.get(ds_id).cloned())
+    }
+
+    async fn get_dataset_sparse_leaves(&self, ds_id: &DatasetId) -> Result<Vec<(FlightDataId, MerkleRoot)>, Error> {
+        Ok(self.data.read().await.datasets_sparse_leaves.get(ds_id).cloned().unwrap_or_default())
+    }
+
+    async fn new_dataset_id(&self) -> Result<DatasetId, Error> {
+        Ok(random_dataset_id())
+    }
+}
+
+#[async_trait]
+impl TransactionStorage for InMemoryStorage {
+    async fn commit(&self, transaction: Transaction) -> Result<(), Error> {
+        self.stage(transaction).await.map(|_| ())
     }
 }
 
-impl FullStorage for InMemoryStorage {}
\ No newline at end of file
+impl FullStorage for InMemoryStorage {}
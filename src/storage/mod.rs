@@ -1,3 +1,7 @@
+pub mod append_log;
 pub mod errors;
 pub mod in_memory;
-pub mod storage;
\ No newline at end of file
+pub mod sqlite;
+pub mod storage;
+pub mod tests;
+pub mod transaction;
\ No newline at end of file
@@ -0,0 +1,672 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::common::prelude::*;
+use crate::state::entities::{Device, DeviceKey, DeviceRevocation, FlightData, Dataset, DatasetAccumulator, DatasetKind, DatasetStatus, DeviceId, FlightDataId, DatasetId, LocalizationPoint, PublicKey};
+
+use super::errors::Error;
+use super::storage::{random_dataset_id, FullStorage, DatasetStorage, DeviceStorage, FlightDataStorage, TransactionStorage};
+use super::transaction::{Operation, Transaction};
+
+const SCHEMA_VERSION: u32 = 12;
+
+const DEVICE_COLUMNS: &str = "id, key_type, pk, attestation, key_valid_from, previous_keys, revocation, web3";
+
+const DATASET_COLUMNS: &str = "id, kind, hash_algorithm, tree_format, ds_limit, count, status, first_flight_data_at, last_flight_data_at, merkle_root, sparse_merkle_root, web3";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS devices (
+        id TEXT PRIMARY KEY,
+        pk BLOB NOT NULL,
+        web3 TEXT
+    );
+    CREATE TABLE IF NOT EXISTS flight_data (
+        id BLOB PRIMARY KEY,
+        signature TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        latitude REAL NOT NULL,
+        longitude REAL NOT NULL,
+        payload BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS datasets (
+        id TEXT PRIMARY KEY,
+        ds_limit INTEGER NOT NULL,
+        count INTEGER NOT NULL,
+        merkle_root BLOB,
+        web3 TEXT
+    );
+    CREATE TABLE IF NOT EXISTS devices_datasets (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        device_id TEXT NOT NULL REFERENCES devices(id),
+        dataset_id TEXT NOT NULL UNIQUE REFERENCES datasets(id)
+    );
+    CREATE TABLE IF NOT EXISTS datasets_flight_data (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        dataset_id TEXT NOT NULL REFERENCES datasets(id),
+        flight_data_id BLOB NOT NULL REFERENCES flight_data(id)
+    );
+    CREATE INDEX IF NOT EXISTS devices_datasets_device ON devices_datasets(device_id);
+    CREATE INDEX IF NOT EXISTS datasets_flight_data_dataset ON datasets_flight_data(dataset_id);
+";
+
+// MIGRATIONS[i] brings the schema from version i + 1 to version i + 2
+const MIGRATIONS: &[&str] = &[
+    "
+    ALTER TABLE datasets ADD COLUMN status TEXT NOT NULL DEFAULT 'Initialized';
+    UPDATE datasets SET status = CASE
+        WHEN web3 IS NOT NULL THEN 'Anchored'
+        WHEN merkle_root IS NOT NULL THEN 'Sealed'
+        WHEN count > 0 THEN 'Active'
+        ELSE 'Initialized'
+    END;
+    ",
+    "
+    ALTER TABLE flight_data ADD COLUMN signature_full TEXT;
+    ALTER TABLE datasets ADD COLUMN kind TEXT NOT NULL DEFAULT 'Live';
+    CREATE INDEX IF NOT EXISTS datasets_flight_data_flight_data ON datasets_flight_data(flight_data_id);
+    ",
+    "
+    ALTER TABLE datasets ADD COLUMN first_flight_data_at INTEGER;
+    ALTER TABLE datasets ADD COLUMN last_flight_data_at INTEGER;
+    CREATE INDEX IF NOT EXISTS datasets_status ON datasets(status);
+    ",
+    "
+    CREATE TABLE IF NOT EXISTS datasets_accumulators (
+        dataset_id TEXT PRIMARY KEY REFERENCES datasets(id),
+        accumulator TEXT NOT NULL
+    );
+    ",
+    "
+    ALTER TABLE datasets ADD COLUMN hash_algorithm TEXT NOT NULL DEFAULT 'Keccak256';
+    ",
+    "
+    ALTER TABLE datasets ADD COLUMN tree_format TEXT NOT NULL DEFAULT 'V0';
+    ",
+    "
+    ALTER TABLE datasets ADD COLUMN sparse_merkle_root BLOB;
+    ",
+    "
+    ALTER TABLE devices ADD COLUMN key_type TEXT NOT NULL DEFAULT 'Ed25519';
+    ",
+    "
+    ALTER TABLE devices ADD COLUMN attestation TEXT;
+    ",
+    "
+    ALTER TABLE devices ADD COLUMN key_valid_from INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE devices ADD COLUMN previous_keys TEXT;
+    ALTER TABLE devices ADD COLUMN revocation TEXT;
+    ",
+    "
+    CREATE TABLE IF NOT EXISTS datasets_sparse_leaves (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        dataset_id TEXT NOT NULL REFERENCES datasets(id),
+        flight_data_id BLOB NOT NULL,
+        leaf BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS datasets_sparse_leaves_dataset ON datasets_sparse_leaves(dataset_id);
+    "
+];
+
+/// Durable storage keeping every entity and their relations in a SQLite database.
+///
+/// Queries are blocking, so they are run on the tokio blocking thread pool.
+pub struct SqliteStorage {
+    connection: Arc<Mutex<Connection>>
+}
+
+impl SqliteStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::initialize(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, Error> {
+        Self::initialize(Connection::open_in_memory()?)
+    }
+
+    fn initialize(mut connection: Connection) -> Result<Self, Error> {
+        let version: u32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            return Err(Error::BackendFailure(format!("Unsupported database schema version {}", version)));
+        }
+        let tx = connection.transaction()?;
+        tx.execute_batch(SCHEMA)?;
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            if version < i as u32 + 2 {
+                tx.execute_batch(migration)?;
+            }
+        }
+        tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        tx.commit()?;
+        Ok(SqliteStorage { connection: Arc::new(Mutex::new(connection)) })
+    }
+
+    async fn run<R, F>(&self, operation: F) -> Result<R, Error>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<R, Error> + Send + 'static
+    {
+        let connection = self.connection.clone();
+        match tokio::task::spawn_blocking(move || operation(&mut connection.lock().unwrap())).await {
+            Ok(result) => result,
+            Err(join_error) => Err(Error::BackendFailure(join_error.to_string()))
+        }
+    }
+
+    /// Applies a single operation returning whether it replaced an existing entity.
+    async fn apply(&self, operation: Operation) -> Result<bool, Error> {
+        self.run(move |connection| {
+            let tx = connection.transaction()?;
+            let replaced_existing = Self::apply_operation(&tx, &operation)?;
+            tx.commit()?;
+            Ok(replaced_existing)
+        }).await
+    }
+
+    fn apply_operation(connection: &Connection, operation: &Operation) -> Result<bool, Error> {
+        match operation {
+            Operation::NewDevice(device) => {
+                if Self::exists(connection, "SELECT 1 FROM devices WHERE id = ?1", &device.id)? {
+                    return Err(Error::AlreadyExists);
+                }
+                Self::upsert_device(connection, device)
+            },
+            Operation::SetDevice(device) => Self::upsert_device(connection, device),
+            Operation::RotateDeviceKey(device_id, key) => {
+                let mut device = match Self::select_device(connection, device_id)? {
+                    Some(device) if device.is_revoked() => return Err(Error::DeviceRevoked),
+                    Some(device) => device,
+                    None => return Err(Error::NotFound(String::from("Device")))
+                };
+                device.rotate_key(key.clone());
+                Self::upsert_device(connection, &device)
+            },
+            Operation::RevokeDevice(device_id, at) => {
+                let mut device = match Self::select_device(connection, device_id)? {
+                    Some(device) if device.is_revoked() => return Err(Error::DeviceRevoked),
+                    Some(device) => device,
+                    None => return Err(Error::NotFound(String::from("Device")))
+                };
+                device.revocation = Some(DeviceRevocation { revoked_at: *at, web3: None });
+                Self::upsert_device(connection, &device)
+            },
+            Operation::NewFlightData(fd) => {
+                if Self::exists(connection, "SELECT 1 FROM flight_data WHERE id = ?1", &fd.id.as_ref())? {
+                    return Err(Error::AlreadyExists);
+                }
+                Self::upsert_flight_data(connection, fd)
+            },
+            Operation::SetFlightData(fd) => Self::upsert_flight_data(connection, fd),
+            Operation::SetDataset(ds) => Self::upsert_dataset(connection, ds),
+            Operation::AddDataset(ds, device_id) => {
+                if Self::exists(connection, "SELECT 1 FROM datasets WHERE id = ?1", &ds.id)? {
+                    return Err(Error::AlreadyExists);
+                }
+                if !Self::exists(connection, "SELECT 1 FROM devices WHERE id = ?1", device_id)? {
+                    return Err(Error::FailedRelatingData(String::from("Dataset"), String::from("Device")));
+                }
+                Self::upsert_dataset(connection, ds)?;
+                connection.execute(
+                    "INSERT INTO devices_datasets (device_id, dataset_id) VALUES (?1, ?2)",
+                    params![device_id, ds.id]
+                )?;
+                Ok(false)
+            },
+            Operation::AddFlightData(ds_id, fd) => {
+                let dataset = match Self::select_dataset(connection, ds_id)? {
+                    Some(dataset) => dataset,
+                    None => return Err(Error::InconsistentRelatedData(String::from("Dataset"), String::from("FlightData")))
+                };
+                if !dataset.status.accepts_flight_data() {
+                    return Err(Error::DatasetSealed);
+                }
+                if dataset.count >= dataset.limit {
+                    return Err(Error::DatasetFull);
+                }
+                connection.execute(
+                    "INSERT INTO datasets_flight_data (dataset_id, flight_data_id) VALUES (?1, ?2)",
+                    params![ds_id, fd.id.as_ref()]
+                )?;
+                connection.execute(
+                    "UPDATE datasets SET count = count + 1, status = ?2 WHERE id = ?1",
+                    params![ds_id, DatasetStatus::Active.to_string()]
+                )?;
+                Ok(false)
+            },
+            Operation::TouchDataset(ds_id, at) => {
+                let updated = connection.execute(
+                    "UPDATE datasets SET
+                        first_flight_data_at = COALESCE(first_flight_data_at, ?2),
+                        last_flight_data_at = ?2
+                        WHERE id = ?1",
+                    params![ds_id, *at as i64]
+                )?;
+                if updated == 0 {
+                    return Err(Error::NotFound(String::from("Dataset")));
+                }
+                Ok(true)
+            },
+            Operation::SetDatasetAccumulator(ds_id, accumulator) => {
+                if !Self::exists(connection, "SELECT 1 FROM datasets WHERE id = ?1", ds_id)? {
+                    return Err(Error::NotFound(String::from("Dataset")));
+                }
+                let already_existing = Self::exists(connection, "SELECT 1 FROM datasets_accumulators WHERE dataset_id = ?1", ds_id)?;
+                let accumulator = match serde_json::to_string(accumulator) {
+                    Ok(json) => json,
+                    Err(err) => return Err(Error::BackendFailure(err.to_string()))
+                };
+                connection.execute(
+                    "INSERT INTO datasets_accumulators (dataset_id, accumulator) VALUES (?1, ?2)
+                        ON CONFLICT(dataset_id) DO UPDATE SET accumulator = excluded.accumulator",
+                    params![ds_id, accumulator]
+                )?;
+                Ok(already_existing)
+            },
+            Operation::AddDatasetSparseLeaf(ds_id, fd_id, leaf) => {
+                if !Self::exists(connection, "SELECT 1 FROM datasets WHERE id = ?1", ds_id)? {
+                    return Err(Error::NotFound(String::from("Dataset")));
+                }
+                connection.execute(
+                    "INSERT INTO datasets_sparse_leaves (dataset_id, flight_data_id, leaf) VALUES (?1, ?2, ?3)",
+                    params![ds_id, fd_id.as_ref(), leaf.as_ref()]
+                )?;
+                Ok(false)
+            }
+        }
+    }
+
+    fn upsert_device(connection: &Connection, device: &Device) -> Result<bool, Error> {
+        let already_existing = Self::exists(connection, "SELECT 1 FROM devices WHERE id = ?1", &device.id)?;
+        connection.execute(
+            "INSERT INTO devices (id, key_type, pk, attestation, key_valid_from, previous_keys, revocation, web3) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                ON CONFLICT(id) DO UPDATE SET
+                    key_type = excluded.key_type,
+                    pk = excluded.pk,
+                    attestation = excluded.attestation,
+                    key_valid_from = excluded.key_valid_from,
+                    previous_keys = excluded.previous_keys,
+                    revocation = excluded.revocation,
+                    web3 = excluded.web3",
+            params![
+                device.id,
+                device.key_type.to_string(),
+                device.pk.as_ref(),
+                json_to_column(&device.attestation)?,
+                device.key_valid_from as i64,
+                json_to_column(&Some(&device.previous_keys))?,
+                json_to_column(&device.revocation)?,
+                json_to_column(&device.web3)?
+            ]
+        )?;
+        Ok(already_existing)
+    }
+
+    fn upsert_flight_data(connection: &Connection, fd: &FlightData) -> Result<bool, Error> {
+        let already_existing = Self::exists(connection, "SELECT 1 FROM flight_data WHERE id = ?1", &fd.id.as_ref())?;
+        connection.execute(
+            "INSERT INTO flight_data (id, signature, timestamp, latitude, longitude, payload, signature_full) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ON CONFLICT(id) DO UPDATE SET
+                    signature = excluded.signature,
+                    timestamp = excluded.timestamp,
+                    latitude = excluded.latitude,
+                    longitude = excluded.longitude,
+                    payload = excluded.payload,
+                    signature_full = excluded.signature_full",
+            params![
+                fd.id.as_ref(),
+                fd.signature,
+                fd.timestamp as i64,
+                fd.localization.latitude,
+                fd.localization.longitude,
+                fd.payload,
+                fd.signature_full
+            ]
+        )?;
+        Ok(already_existing)
+    }
+
+    fn upsert_dataset(connection: &Connection, ds: &Dataset) -> Result<bool, Error> {
+        let already_existing = Self::exists(connection, "SELECT 1 FROM datasets WHERE id = ?1", &ds.id)?;
+        connection.execute(
+            "INSERT INTO datasets (id, ds_limit, count, status, merkle_root, web3, kind, first_flight_data_at, last_flight_data_at, hash_algorithm, tree_format, sparse_merkle_root)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+                ON CONFLICT(id) DO UPDATE SET
+                    kind = excluded.kind,
+                    hash_algorithm = excluded.hash_algorithm,
+                    tree_format = excluded.tree_format,
+                    first_flight_data_at = excluded.first_flight_data_at,
+                    last_flight_data_at = excluded.last_flight_data_at,
+                    ds_limit = excluded.ds_limit,
+                    count = excluded.count,
+                    status = excluded.status,
+                    merkle_root = excluded.merkle_root,
+                    sparse_merkle_root = excluded.sparse_merkle_root,
+                    web3 = excluded.web3",
+            params![
+                ds.id,
+                ds.limit,
+                ds.count,
+                ds.status.to_string(),
+                ds.merkle_root.as_ref().map(|root| root.as_ref().to_vec()),
+                json_to_column(&ds.web3)?,
+                ds.kind.to_string(),
+                ds.first_flight_data_at.map(|at| at as i64),
+                ds.last_flight_data_at.map(|at| at as i64),
+                ds.hash_algorithm.to_string(),
+                ds.tree_format.to_string(),
+                ds.sparse_merkle_root.as_ref().map(|root| root.as_ref().to_vec())
+            ]
+        )?;
+        Ok(already_existing)
+    }
+
+    fn device_from_row(row: &Row) -> rusqlite::Result<Device> {
+        let pk: Vec<u8> = row.get("pk")?;
+        let key_valid_from: i64 = row.get("key_valid_from")?;
+        Ok(Device {
+            id: row.get("id")?,
+            key_type: enum_from_column(row.get("key_type")?, "key_type")?,
+            pk: PublicKey(pk),
+            attestation: json_from_column(row.get("attestation")?, "attestation")?,
+            key_valid_from: key_valid_from as u64,
+            previous_keys: json_from_column(row.get("previous_keys")?, "previous_keys")?.unwrap_or_default(),
+            revocation: json_from_column(row.get("revocation")?, "revocation")?,
+            web3: json_from_column(row.get("web3")?, "web3")?
+        })
+    }
+
+    fn flight_data_from_row(row: &Row) -> rusqlite::Result<FlightData> {
+        let id: Vec<u8> = row.get("id")?;
+        let timestamp: i64 = row.get("timestamp")?;
+        Ok(FlightData {
+            id: FlightDataId::from(blob_to_bytes32(id, "id")?),
+            signature: row.get("signature")?,
+            timestamp: timestamp as u64,
+            localization: LocalizationPoint {
+                longitude: row.get("longitude")?,
+                latitude: row.get("latitude")?
+            },
+            payload: row.get("payload")?,
+            signature_full: row.get("signature_full")?
+        })
+    }
+
+    fn dataset_from_row(row: &Row) -> rusqlite::Result<Dataset> {
+        let merkle_root: Option<Vec<u8>> = row.get("merkle_root")?;
+        let sparse_merkle_root: Option<Vec<u8>> = row.get("sparse_merkle_root")?;
+        Ok(Dataset {
+            id: row.get("id")?,
+            kind: enum_from_column(row.get("kind")?, "kind")?,
+            hash_algorithm: enum_from_column(row.get("hash_algorithm")?, "hash_algorithm")?,
+            tree_format: enum_from_column(row.get("tree_format")?, "tree_format")?,
+            limit: row.get("ds_limit")?,
+            count: row.get("count")?,
+            status: enum_from_column(row.get("status")?, "status")?,
+            first_flight_data_at: row.get::<_, Option<i64>>("first_flight_data_at")?.map(|at| at as u64),
+            last_flight_data_at: row.get::<_, Option<i64>>("last_flight_data_at")?.map(|at| at as u64),
+            merkle_root: match merkle_root {
+                Some(root) => Some(blob_to_bytes32(root, "merkle_root")?),
+                None => None
+            },
+            sparse_merkle_root: match sparse_merkle_root {
+                Some(root) => Some(blob_to_bytes32(root, "sparse_merkle_root")?),
+                None => None
+            },
+            web3: json_from_column(row.get("web3")?, "web3")?
+        })
+    }
+
+    fn select_device(connection: &Connection, id: &DeviceId) -> Result<Option<Device>, Error> {
+        Ok(connection.query_row(
+            &format!("SELECT {} FROM devices WHERE id = ?1", DEVICE_COLUMNS),
+            params![id],
+            Self::device_from_row
+        ).optional()?)
+    }
+
+    fn select_dataset(connection: &Connection, id: &DatasetId) -> Result<Option<Dataset>, Error> {
+        Ok(connection.query_row(
+            &format!("SELECT {} FROM datasets WHERE id = ?1", DATASET_COLUMNS),
+            params![id],
+            Self::dataset_from_row
+        ).optional()?)
+    }
+
+    fn exists(connection: &Connection, query: &str, id: &dyn rusqlite::ToSql) -> Result<bool, Error> {
+        Ok(connection.query_row(query, [id], |_| Ok(())).optional()?.is_some())
+    }
+}
+
+#[async_trait]
+impl DeviceStorage for SqliteStorage {
+    async fn new_device(&self, device: &Device) -> Result<(), Error> {
+        self.apply(Operation::NewDevice(device.clone())).await.map(|_| ())
+    }
+
+    async fn set_device(&self, device: &Device) -> Result<bool, Error> {
+        self.apply(Operation::SetDevice(device.clone())).await
+    }
+
+    async fn get_device(&self, id: &DeviceId) -> Result<Option<Device>, Error> {
+        let id = id.clone();
+        self.run(move |connection| Self::select_device(connection, &id)).await
+    }
+
+    async fn rotate_device_key(&self, device_id: &DeviceId, key: &DeviceKey) -> Result<(), Error> {
+        self.apply(Operation::RotateDeviceKey(device_id.clone(), key.clone())).await.map(|_| ())
+    }
+
+    async fn revoke_device(&self, device_id: &DeviceId, at: u64) -> Result<(), Error> {
+        self.apply(Operation::RevokeDevice(device_id.clone(), at)).await.map(|_| ())
+    }
+}
+
+#[async_trait]
+impl FlightDataStorage for SqliteStorage {
+    async fn set_flight_data(&self, fd: &FlightData) -> Result<bool, Error> {
+        self.apply(Operation::SetFlightData(fd.clone())).await
+    }
+
+    async fn get_flight_data(&self, id: &FlightDataId) -> Result<Option<FlightData>, Error> {
+        let id = id.clone();
+        self.run(move |connection| {
+            Ok(connection.query_row(
+                "SELECT id, signature, timestamp, latitude, longitude, payload, signature_full FROM flight_data WHERE id = ?1",
+                params![id.as_ref()],
+                Self::flight_data_from_row
+            ).optional()?)
+        }).await
+    }
+}
+
+#[async_trait]
+impl DatasetStorage for SqliteStorage {
+    async fn add_flight_data(&self, ds_id: &DatasetId, fd: &FlightData) -> Result<(), Error> {
+        self.apply(Operation::AddFlightData(ds_id.clone(), fd.clone())).await.map(|_| ())
+    }
+
+    async fn get_dataset_flight_data(&self, ds_id: &DatasetId) -> Result<Vec<FlightData>, Error> {
+        let ds_id = ds_id.clone();
+        self.run(move |connection| {
+            if !Self::exists(connection, "SELECT 1 FROM datasets WHERE id = ?1", &ds_id)? {
+                return Err(Error::NotFound(String::from("Dataset")));
+            }
+            let mut statement = connection.prepare(
+                "SELECT fd.id, fd.signature, fd.timestamp, fd.latitude, fd.longitude, fd.payload, fd.signature_full
+                    FROM datasets_flight_data AS dsfd JOIN flight_data AS fd ON fd.id = dsfd.flight_data_id
+                    WHERE dsfd.dataset_id = ?1
+                    ORDER BY dsfd.seq"
+            )?;
+            let fds = statement
+                .query_map(params![ds_id], Self::flight_data_from_row)?
+                .collect::<rusqlite::Result<Vec<FlightData>>>()?;
+            Ok(fds)
+        }).await
+    }
+
+    async fn get_dataset(&self, id: &DatasetId) -> Result<Option<Dataset>, Error> {
+        let id = id.clone();
+        self.run(move |connection| Self::select_dataset(connection, &id)).await
+    }
+
+    async fn set_dataset(&self, ds: &Dataset) -> Result<bool, Error> {
+        self.apply(Operation::SetDataset(ds.clone())).await
+    }
+
+    async fn add_dataset(&self, ds: &Dataset, device_id: &DeviceId) -> Result<(), Error> {
+        self.apply(Operation::AddDataset(ds.clone(), device_id.clone())).await.map(|_| ())
+    }
+
+    async fn get_latest_dataset(&self, device_id: &DeviceId, kind: DatasetKind) -> Result<Option<Dataset>, Error> {
+        let device_id = device_id.clone();
+        self.run(move |connection| {
+            if !Self::exists(connection, "SELECT 1 FROM devices WHERE id = ?1", &device_id)? {
+                return Err(Error::NotFound(String::from("Device not found")));
+            }
+            let latest_id: Option<DatasetId> = connection.query_row(
+                "SELECT dsd.dataset_id FROM devices_datasets AS dsd JOIN datasets AS ds ON ds.id = dsd.dataset_id
+                    WHERE dsd.device_id = ?1 AND ds.kind = ?2
+                    ORDER BY dsd.seq DESC LIMIT 1",
+                params![device_id, kind.to_string()],
+                |row| row.get(0)
+            ).optional()?;
+            match latest_id {
+                Some(dataset_id) => match Self::select_dataset(connection, &dataset_id)? {
+                    Some(dataset) => Ok(Some(dataset)),
+                    None => Err(Error::NotFound(String::from("Latest Dataset Id does not have corresponding data")))
+                },
+                None => Ok(Option::None)
+            }
+        }).await
+    }
+
+    async fn get_datasets_by_status(&self, status: DatasetStatus) -> Result<Vec<Dataset>, Error> {
+        self.run(move |connection| {
+            let mut statement = connection.prepare(&format!("SELECT {} FROM datasets WHERE status = ?1", DATASET_COLUMNS))?;
+            let datasets = statement
+                .query_map(params![status.to_string()], Self::dataset_from_row)?
+                .collect::<rusqlite::Result<Vec<Dataset>>>()?;
+            Ok(datasets)
+        }).await
+    }
+
+    async fn get_flight_data_dataset(&self, fd_id: &FlightDataId) -> Result<Option<DatasetId>, Error> {
+        let fd_id = fd_id.clone();
+        self.run(move |connection| {
+            Ok(connection.query_row(
+                "SELECT dataset_id FROM datasets_flight_data WHERE flight_data_id = ?1",
+                params![fd_id.as_ref()],
+                |row| row.get(0)
+            ).optional()?)
+        }).await
+    }
+
+    async fn get_dataset_device(&self, ds_id: &DatasetId) -> Result<Option<DeviceId>, Error> {
+        let ds_id = ds_id.clone();
+        self.run(move |connection| {
+            Ok(connection.query_row(
+                "SELECT device_id FROM devices_datasets WHERE dataset_id = ?1",
+                params![ds_id],
+                |row| row.get(0)
+            ).optional()?)
+        }).await
+    }
+
+    async fn get_dataset_accumulator(&self, ds_id: &DatasetId) -> Result<Option<DatasetAccumulator>, Error> {
+        let ds_id = ds_id.clone();
+        self.run(move |connection| {
+            let accumulator: Option<String> = connection.query_row(
+                "SELECT accumulator FROM datasets_accumulators WHERE dataset_id = ?1",
+                params![ds_id],
+                |row| row.get(0)
+            ).optional()?;
+            match accumulator {
+                Some(json) => match serde_json::from_str(&json) {
+                    Ok(accumulator) => Ok(Some(accumulator)),
+                    Err(err) => Err(Error::BackendFailure(err.to_string()))
+                },
+                None => Ok(None)
+            }
+        }).await
+    }
+
+    async fn get_dataset_sparse_leaves(&self, ds_id: &DatasetId) -> Result<Vec<(FlightDataId, MerkleRoot)>, Error> {
+        let ds_id = ds_id.clone();
+        self.run(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT flight_data_id, leaf FROM datasets_sparse_leaves WHERE dataset_id = ?1 ORDER BY seq"
+            )?;
+            let leaves = statement
+                .query_map(params![ds_id], |row| Ok((
+                    FlightDataId::from(blob_to_bytes32(row.get("flight_data_id")?, "flight_data_id")?),
+                    blob_to_bytes32(row.get("leaf")?, "leaf")?
+                )))?
+                .collect::<rusqlite::Result<Vec<(FlightDataId, MerkleRoot)>>>()?;
+            Ok(leaves)
+        }).await
+    }
+
+    async fn new_dataset_id(&self) -> Result<DatasetId, Error> {
+        Ok(random_dataset_id())
+    }
+}
+
+#[async_trait]
+impl TransactionStorage for SqliteStorage {
+    async fn commit(&self, transaction: Transaction) -> Result<(), Error> {
+        self.run(move |connection| {
+            // Dropping the SQL transaction on error rolls it back
+            let tx = connection.transaction()?;
+            for operation in transaction.operations() {
+                Self::apply_operation(&tx, operation)?;
+            }
+            tx.commit()?;
+            Ok(())
+        }).await
+    }
+}
+
+impl FullStorage for SqliteStorage {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(value: rusqlite::Error) -> Self {
+        Error::BackendFailure(value.to_string())
+    }
+}
+
+fn blob_to_bytes32(blob: Vec<u8>, column: &str) -> rusqlite::Result<Bytes32> {
+    Bytes32::try_from(blob).map_err(|_| rusqlite::Error::InvalidColumnType(
+        0,
+        String::from(column),
+        rusqlite::types::Type::Blob
+    ))
+}
+
+/// Stores an optional value as JSON.
+fn json_to_column<T: Serialize>(value: &Option<T>) -> Result<Option<String>, Error> {
+    match value {
+        Some(value) => match serde_json::to_string(value) {
+            Ok(json) => Ok(Some(json)),
+            Err(err) => Err(Error::BackendFailure(err.to_string()))
+        },
+        None => Ok(None)
+    }
+}
+
+fn json_from_column<T: DeserializeOwned>(column: Option<String>, name: &str) -> rusqlite::Result<Option<T>> {
+    match column {
+        Some(json) => serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|_| rusqlite::Error::InvalidColumnType(0, String::from(name), rusqlite::types::Type::Text)),
+        None => Ok(None)
+    }
+}
+
+/// Decodes a unit enum variant stored as its name.
+fn enum_from_column<T: DeserializeOwned>(column: String, name: &str) -> rusqlite::Result<T> {
+    serde_json::from_value(serde_json::Value::String(column))
+        .map_err(|_| rusqlite::Error::InvalidColumnType(0, String::from(name), rusqlite::types::Type::Text))
+}
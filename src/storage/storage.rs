@@ -1,31 +1,66 @@
 // use std::sync::{Arc, RwLock};
 
-use crate::state::entities::{Device, FlightData, Dataset, DatasetId, DeviceId, FlightDataId};
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+
+use crate::common::prelude::MerkleRoot;
+use crate::state::entities::{Device, DeviceKey, FlightData, Dataset, DatasetAccumulator, DatasetId, DatasetKind, DatasetStatus, DeviceId, FlightDataId};
 
 use super::errors::Error;
+use super::transaction::Transaction;
 
+#[async_trait]
 pub trait DeviceStorage {
-    fn new_device(&self, device: &Device) -> Result<(), Error>; 
-    fn set_device(&self, device: &Device) -> Result<bool, Error>; 
-    fn get_device(&self, id: &DeviceId) -> Result<Option<Device>, Error>;
+    async fn new_device(&self, device: &Device) -> Result<(), Error>; 
+    async fn set_device(&self, device: &Device) -> Result<bool, Error>; 
+    async fn get_device(&self, id: &DeviceId) -> Result<Option<Device>, Error>;
+    /// Replaces the key of the Device keeping the current one among its previous keys. Fails once the
+    /// Device is revoked.
+    async fn rotate_device_key(&self, device_id: &DeviceId, key: &DeviceKey) -> Result<(), Error>;
+    /// Revokes the Device from `at` (milliseconds since the Unix epoch). Fails if it is already revoked.
+    async fn revoke_device(&self, device_id: &DeviceId, at: u64) -> Result<(), Error>;
 }
 
+#[async_trait]
 pub trait FlightDataStorage {
-    fn set_flight_data(&self, fd: &FlightData) -> Result<bool, Error>;
-    fn get_flight_data(&self, id: &FlightDataId) -> Result<Option<FlightData>, Error>;
+    async fn set_flight_data(&self, fd: &FlightData) -> Result<bool, Error>;
+    async fn get_flight_data(&self, id: &FlightDataId) -> Result<Option<FlightData>, Error>;
 }
 
+#[async_trait]
 pub trait DatasetStorage {
-    fn set_dataset(&self, ds: &Dataset) -> Result<bool, Error>;
-    fn add_dataset(&self, ds: &Dataset, device_id: &DeviceId) -> Result<(), Error>;
-    fn get_dataset(&self, id: &DatasetId) -> Result<Option<Dataset>, Error>;
-    fn get_latest_dataset(&self, device_id: &DeviceId) -> Result<Option<Dataset>, Error>;
-    fn add_flight_data(&self, ds_id: &DatasetId, fd: &FlightData) -> Result<(), Error>;
-    fn get_dataset_flight_data(&self, ds_id: &DatasetId) -> Result<Vec<FlightData>, Error>;
-    fn new_dataset_id(&self) -> Result<DatasetId, Error>;
+    async fn set_dataset(&self, ds: &Dataset) -> Result<bool, Error>;
+    async fn add_dataset(&self, ds: &Dataset, device_id: &DeviceId) -> Result<(), Error>;
+    async fn get_dataset(&self, id: &DatasetId) -> Result<Option<Dataset>, Error>;
+    async fn get_latest_dataset(&self, device_id: &DeviceId, kind: DatasetKind) -> Result<Option<Dataset>, Error>;
+    async fn get_datasets_by_status(&self, status: DatasetStatus) -> Result<Vec<Dataset>, Error>;
+    async fn add_flight_data(&self, ds_id: &DatasetId, fd: &FlightData) -> Result<(), Error>;
+    async fn get_dataset_flight_data(&self, ds_id: &DatasetId) -> Result<Vec<FlightData>, Error>;
+    /// Id of the Dataset containing the FlightData, if it was added to one.
+    async fn get_flight_data_dataset(&self, fd_id: &FlightDataId) -> Result<Option<DatasetId>, Error>;
+    /// Id of the Device the Dataset belongs to.
+    async fn get_dataset_device(&self, ds_id: &DatasetId) -> Result<Option<DeviceId>, Error>;
+    /// Merkle accumulator of the FlightData of the Dataset, if any was stored.
+    async fn get_dataset_accumulator(&self, ds_id: &DatasetId) -> Result<Option<DatasetAccumulator>, Error>;
+    /// Leaves of the sparse Merkle tree of the Dataset with the id of their FlightData, in the order they were added.
+    async fn get_dataset_sparse_leaves(&self, ds_id: &DatasetId) -> Result<Vec<(FlightDataId, MerkleRoot)>, Error>;
+    async fn new_dataset_id(&self) -> Result<DatasetId, Error>;
+}
+
+#[async_trait]
+pub trait TransactionStorage {
+    /// Applies all the operations of the transaction, or none of them if any fails.
+    async fn commit(&self, transaction: Transaction) -> Result<(), Error>;
 }
 
-pub trait FullStorage: DatasetStorage + DeviceStorage + FlightDataStorage {}
+pub trait FullStorage: DatasetStorage + DeviceStorage + FlightDataStorage + TransactionStorage + Send + Sync {}
+
+pub fn random_dataset_id() -> DatasetId {
+    let mut hasher = Sha256::new();
+    hasher.update(rand::random::<u64>().to_be_bytes());
+    hasher.update(rand::random::<u64>().to_be_bytes());
+    bs58::encode(hasher.finalize()).into_string()
+}
 
 // pub type ThreadSafeStorageWrapper<S> = Arc<RwLock<S>>;
 
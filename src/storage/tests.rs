@@ -0,0 +1,396 @@
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{fs::{self, OpenOptions}, io::Write, path::PathBuf};
+
+    use crate::{common::{hash_algorithm::HashAlgorithm, merkle::TreeFormat, signature::KeyType}, state::entities::{Dataset, DatasetAccumulator, DatasetId, DatasetKind, DatasetStatus, Device, FlightData, FlightDataId, LocalizationPoint, PublicKey}, storage::{append_log::AppendLogStorage, errors::Error, in_memory::InMemoryStorage, sqlite::SqliteStorage, storage::{DatasetStorage, DeviceStorage, FlightDataStorage, FullStorage}, transaction::Transaction}};
+
+    fn new_log_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("bitacora-log-test-{}", rand::random::<u64>()))
+    }
+
+    fn segments(dir: &PathBuf) -> Vec<PathBuf> {
+        let mut segments: Vec<PathBuf> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        segments.sort();
+        segments
+    }
+
+    /// An empty live Dataset, to be stored by the tests.
+    pub(crate) fn test_dataset(id: DatasetId, limit: u32) -> Dataset {
+        Dataset {
+            id,
+            kind: DatasetKind::Live,
+            hash_algorithm: HashAlgorithm::Keccak256,
+            tree_format: TreeFormat::V0,
+            limit,
+            count: 0,
+            status: DatasetStatus::Initialized,
+            first_flight_data_at: None,
+            last_flight_data_at: None,
+            merkle_root: None,
+            sparse_merkle_root: None,
+            web3: None
+        }
+    }
+
+    /// Runs a storage-generic test against every backend, as `<test>::in_memory`, `<test>::sqlite` and
+    /// `<test>::append_log`. The optional `replay` function is then given the log reopened from scratch and
+    /// what the test returned, to check that it survives a restart.
+    macro_rules! on_every_backend {
+        ($test:ident) => {
+            on_every_backend!($test, replay = |_, _| async {});
+        };
+        ($test:ident, replay = $replay:expr) => {
+            mod $test {
+                use super::*;
+
+                #[tokio::test]
+                async fn in_memory() {
+                    super::$test(&InMemoryStorage::default()).await;
+                }
+
+                #[tokio::test]
+                async fn sqlite() {
+                    super::$test(&SqliteStorage::open_in_memory().unwrap()).await;
+                }
+
+                #[tokio::test]
+                async fn append_log() {
+                    let dir = new_log_dir();
+                    let result = super::$test(&AppendLogStorage::open(&dir).await.unwrap()).await;
+                    ($replay)(AppendLogStorage::open(&dir).await.unwrap(), result).await;
+                    let _ = fs::remove_dir_all(&dir);
+                }
+            }
+        };
+    }
+
+    async fn populate(storage: &AppendLogStorage, n: u64) -> (Device, Dataset, Vec<FlightData>) {
+        let device_pk: PublicKey = "0x1234567890123456789012345678901234567890123456789012345678901234".try_into().unwrap();
+        let device = Device::from(device_pk);
+        storage.new_device(&device).await.unwrap();
+        let dataset = test_dataset(storage.new_dataset_id().await.unwrap(), n as u32);
+        storage.add_dataset(&dataset, &device.id).await.unwrap();
+        let mut fds = Vec::new();
+        for i in 0..n {
+            let fd = FlightData {
+                id: FlightDataId::new(1701305636123 + i, &device.id),
+                signature: String::new(),
+                timestamp: 1701305636123 + i,
+                localization: LocalizationPoint { longitude: 14.425681, latitude: 40.820948 },
+                payload: vec![i as u8; 16],
+                signature_full: None
+            };
+            storage.set_flight_data(&fd).await.unwrap();
+            storage.add_flight_data(&dataset.id, &fd).await.unwrap();
+            fds.push(fd);
+        }
+        (device, dataset, fds)
+    }
+
+    #[tokio::test]
+    async fn test_append_log_replay() {
+        let dir = new_log_dir();
+        let (device, dataset, fds) = populate(&AppendLogStorage::open(&dir).await.unwrap(), 5).await;
+
+        let storage = AppendLogStorage::open(&dir).await.unwrap();
+        assert!(storage.get_device(&device.id).await.unwrap().is_some(), "Device lost after replay");
+        let replayed_dataset = storage.get_latest_dataset(&device.id, DatasetKind::Live).await.unwrap().expect("Dataset lost after replay");
+        assert_eq!(replayed_dataset.id, dataset.id);
+        assert_eq!(replayed_dataset.count, fds.len() as u32, "Dataset count differs after replay");
+        let replayed_fds = storage.get_dataset_flight_data(&dataset.id).await.unwrap();
+        for (replayed, original) in replayed_fds.iter().zip(fds.iter()) {
+            assert_eq!(replayed.to_bytes(), original.to_bytes(), "FlightData differs after replay");
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_append_log_truncates_torn_tail() {
+        let dir = new_log_dir();
+        let (device, dataset, _) = populate(&AppendLogStorage::open(&dir).await.unwrap(), 3).await;
+        let segment = segments(&dir).pop().unwrap();
+        let valid_length = fs::metadata(&segment).unwrap().len();
+
+        // Simulate a crash in the middle of a record write: a header announcing more bytes than available
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&[0, 0, 1, 0, 0xde, 0xad, 0xbe, 0xef, b'{']).unwrap();
+        drop(file);
+
+        let storage = AppendLogStorage::open(&dir).await.unwrap();
+        assert_eq!(fs::metadata(&segment).unwrap().len(), valid_length, "Torn record was not truncated");
+        assert_eq!(storage.get_dataset(&dataset.id).await.unwrap().unwrap().count, 3);
+
+        // The log keeps being usable after recovery
+        let mut updated = storage.get_dataset(&dataset.id).await.unwrap().unwrap();
+        updated.limit = 10;
+        assert!(storage.set_dataset(&updated).await.unwrap());
+        let storage = AppendLogStorage::open(&dir).await.unwrap();
+        assert_eq!(storage.get_dataset(&dataset.id).await.unwrap().unwrap().limit, 10);
+        assert!(storage.get_device(&device.id).await.unwrap().is_some());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_append_log_drops_tail_with_bad_checksum() {
+        let dir = new_log_dir();
+        let (_, dataset, _) = populate(&AppendLogStorage::open(&dir).await.unwrap(), 3).await;
+        let segment = segments(&dir).pop().unwrap();
+
+        // Flip the last byte of the last record so that its checksum does not match anymore
+        let mut content = fs::read(&segment).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 0xff;
+        fs::write(&segment, &content).unwrap();
+
+        let storage = AppendLogStorage::open(&dir).await.unwrap();
+        assert_eq!(storage.get_dataset(&dataset.id).await.unwrap().unwrap().count, 2, "Corrupted tail record was replayed");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_append_log_rejects_corruption_before_tail() {
+        let dir = new_log_dir();
+        populate(&AppendLogStorage::open(&dir).await.unwrap(), 3).await;
+        let segment = segments(&dir).pop().unwrap();
+
+        // Flip a byte of the payload of the first record, which is followed by valid ones
+        let mut content = fs::read(&segment).unwrap();
+        content[8] ^= 0xff;
+        fs::write(&segment, &content).unwrap();
+
+        assert!(matches!(AppendLogStorage::open(&dir).await, Err(Error::BackendFailure(_))), "Corrupted record in the middle of the log was tolerated");
+        assert_eq!(fs::read(&segment).unwrap(), content, "Valid records after the corrupted one were truncated");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_append_log_rejects_damaged_length_before_tail() {
+        let dir = new_log_dir();
+        populate(&AppendLogStorage::open(&dir).await.unwrap(), 3).await;
+        let segment = segments(&dir).pop().unwrap();
+
+        // Make the length of the second record reach past the end of the segment, over the valid ones after it
+        let mut content = fs::read(&segment).unwrap();
+        let second = 8 + u32::from_be_bytes(content[0..4].try_into().unwrap()) as usize;
+        content[second..second + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        fs::write(&segment, &content).unwrap();
+
+        assert!(matches!(AppendLogStorage::open(&dir).await, Err(Error::BackendFailure(_))), "Record with a damaged length was taken for a torn one");
+        assert_eq!(fs::read(&segment).unwrap(), content, "Valid records after the damaged one were truncated");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_append_log_rotates_segments() {
+        let dir = new_log_dir();
+        let (_, dataset, fds) = populate(&AppendLogStorage::open_with_segment_size(&dir, 512).await.unwrap(), 10).await;
+        assert!(segments(&dir).len() > 1, "Log was not split in several segments");
+
+        let storage = AppendLogStorage::open_with_segment_size(&dir, 512).await.unwrap();
+        assert_eq!(storage.get_dataset_flight_data(&dataset.id).await.unwrap().len(), fds.len());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Commits a transaction whose last operation fails and checks that none of the previous ones was kept.
+    async fn transaction_rollback<S: FullStorage>(storage: &S) {
+        let device_pk: PublicKey = "0x1234567890123456789012345678901234567890123456789012345678901234".try_into().unwrap();
+        let device = Device::from(device_pk);
+        storage.new_device(&device).await.unwrap();
+        let dataset = test_dataset(storage.new_dataset_id().await.unwrap(), 10);
+        let fd = FlightData {
+            id: FlightDataId::new(1701305636123, &device.id),
+            signature: String::new(),
+            timestamp: 1701305636123,
+            localization: LocalizationPoint { longitude: 14.425681, latitude: 40.820948 },
+            payload: vec![0u8; 16],
+            signature_full: None
+        };
+
+        let mut transaction = Transaction::new();
+        transaction
+            .new_flight_data(&fd)
+            .add_dataset(&dataset, &device.id)
+            .add_flight_data(&storage.new_dataset_id().await.unwrap(), &fd);
+        match storage.commit(transaction).await {
+            Err(Error::InconsistentRelatedData(_, _)) => (),
+            other => panic!("Transaction was expected to fail, got {:?}", other)
+        }
+        assert!(storage.get_flight_data(&fd.id).await.unwrap().is_none(), "FlightData survived the rollback");
+        assert!(storage.get_dataset(&dataset.id).await.unwrap().is_none(), "Dataset survived the rollback");
+        assert!(storage.get_latest_dataset(&device.id, DatasetKind::Live).await.unwrap().is_none(), "Dataset still related to the Device after the rollback");
+
+        let mut accumulator = DatasetAccumulator::new(HashAlgorithm::Keccak256, TreeFormat::V0);
+        accumulator.append(&fd.to_bytes());
+        let leaf = HashAlgorithm::Keccak256.leaf_hash(TreeFormat::V0, fd.to_bytes());
+        let mut transaction = Transaction::new();
+        transaction
+            .new_flight_data(&fd)
+            .add_dataset(&dataset, &device.id)
+            .add_flight_data(&dataset.id, &fd)
+            .set_dataset_accumulator(&dataset.id, &accumulator)
+            .add_dataset_sparse_leaf(&dataset.id, &fd.id, &leaf);
+        storage.commit(transaction).await.unwrap();
+        assert_eq!(storage.get_latest_dataset(&device.id, DatasetKind::Live).await.unwrap().unwrap().count, 1);
+        assert_eq!(storage.get_dataset_flight_data(&dataset.id).await.unwrap().len(), 1);
+        assert_eq!(storage.get_dataset_accumulator(&dataset.id).await.unwrap().unwrap().root(), accumulator.root());
+        assert_eq!(storage.get_dataset_sparse_leaves(&dataset.id).await.unwrap(), vec![(fd.id.clone(), leaf.clone())]);
+
+        // A duplicated FlightData makes the whole transaction fail
+        let mut next_accumulator = accumulator.clone();
+        next_accumulator.append(&fd.to_bytes());
+        let mut transaction = Transaction::new();
+        transaction
+            .set_dataset_accumulator(&dataset.id, &next_accumulator)
+            .add_dataset_sparse_leaf(&dataset.id, &fd.id, &leaf)
+            .new_flight_data(&fd)
+            .add_flight_data(&dataset.id, &fd);
+        assert!(matches!(storage.commit(transaction).await, Err(Error::AlreadyExists)));
+        assert_eq!(storage.get_dataset(&dataset.id).await.unwrap().unwrap().count, 1);
+        assert_eq!(storage.get_dataset_accumulator(&dataset.id).await.unwrap().unwrap().len(), 1, "Accumulator survived the rollback");
+        assert_eq!(storage.get_dataset_sparse_leaves(&dataset.id).await.unwrap().len(), 1, "Sparse leaf survived the rollback");
+    }
+
+    on_every_backend!(transaction_rollback, replay = transaction_rollback_replayed);
+
+    /// Failed transactions never reach the log
+    async fn transaction_rollback_replayed(storage: AppendLogStorage, _: ()) {
+        let device = Device::from(PublicKey::try_from("0x1234567890123456789012345678901234567890123456789012345678901234").unwrap());
+        let dataset = storage.get_latest_dataset(&device.id, DatasetKind::Live).await.unwrap().unwrap();
+        assert_eq!(dataset.count, 1);
+        assert_eq!(storage.get_dataset_flight_data(&dataset.id).await.unwrap().len(), 1);
+        assert_eq!(storage.get_dataset_accumulator(&dataset.id).await.unwrap().unwrap().len(), 1);
+        assert_eq!(storage.get_dataset_sparse_leaves(&dataset.id).await.unwrap().len(), 1);
+    }
+
+    async fn dataset_limit_guard<S: FullStorage>(storage: &S) {
+        let device_pk: PublicKey = "0x1234567890123456789012345678901234567890123456789012345678901234".try_into().unwrap();
+        let device = Device::from(device_pk);
+        storage.new_device(&device).await.unwrap();
+        let dataset = test_dataset(storage.new_dataset_id().await.unwrap(), 1);
+        storage.add_dataset(&dataset, &device.id).await.unwrap();
+        let mut fds = (0..2u64).map(|i| FlightData {
+            id: FlightDataId::new(1701305636123 + i, &device.id),
+            signature: String::new(),
+            timestamp: 1701305636123 + i,
+            localization: LocalizationPoint { longitude: 14.425681, latitude: 40.820948 },
+            payload: vec![i as u8; 16],
+            signature_full: None
+        });
+
+        let mut transaction = Transaction::new();
+        let fd = fds.next().unwrap();
+        transaction.new_flight_data(&fd).add_flight_data(&dataset.id, &fd);
+        storage.commit(transaction).await.unwrap();
+
+        let mut transaction = Transaction::new();
+        let fd = fds.next().unwrap();
+        transaction.new_flight_data(&fd).add_flight_data(&dataset.id, &fd);
+        assert!(matches!(storage.commit(transaction).await, Err(Error::DatasetFull)), "FlightData added beyond the Dataset limit");
+        assert!(storage.get_flight_data(&fd.id).await.unwrap().is_none());
+        let mut stored_dataset = storage.get_dataset(&dataset.id).await.unwrap().unwrap();
+        assert_eq!(stored_dataset.count, 1);
+        assert_eq!(stored_dataset.status, DatasetStatus::Active, "Dataset not activated by its first FlightData");
+
+        // A sealed Dataset rejects FlightData even below its limit
+        stored_dataset.limit = 10;
+        stored_dataset.status = DatasetStatus::Sealed;
+        storage.set_dataset(&stored_dataset).await.unwrap();
+        let mut transaction = Transaction::new();
+        transaction.new_flight_data(&fd).add_flight_data(&dataset.id, &fd);
+        assert!(matches!(storage.commit(transaction).await, Err(Error::DatasetSealed)), "FlightData added to a sealed Dataset");
+        assert_eq!(storage.get_dataset(&dataset.id).await.unwrap().unwrap().status, DatasetStatus::Sealed);
+    }
+
+    on_every_backend!(dataset_limit_guard);
+
+    async fn device_key_types<S: FullStorage>(storage: &S) -> Vec<Device> {
+        let secp256k1_pk: PublicKey = "0x0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798".try_into().unwrap();
+        let p256_pk: PublicKey = "0x036b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296".try_into().unwrap();
+        let devices = [
+            Device::from(PublicKey::try_from("0x1234567890123456789012345678901234567890123456789012345678901234").unwrap()),
+            Device::new(KeyType::Secp256k1, secp256k1_pk).unwrap(),
+            Device::new(KeyType::P256, p256_pk).unwrap()
+        ];
+        for device in devices.iter() {
+            storage.new_device(device).await.unwrap();
+        }
+        for device in devices.iter() {
+            let stored_device = storage.get_device(&device.id).await.unwrap().unwrap();
+            assert_eq!(stored_device.key_type, device.key_type);
+            assert_eq!(stored_device.pk, device.pk, "Wrong {} public key stored", device.key_type);
+        }
+        devices.to_vec()
+    }
+
+    on_every_backend!(device_key_types, replay = device_key_types_replayed);
+
+    async fn device_key_types_replayed(storage: AppendLogStorage, devices: Vec<Device>) {
+        for device in devices {
+            assert_eq!(storage.get_device(&device.id).await.unwrap().unwrap().key_type, device.key_type, "Key type not replayed");
+        }
+    }
+
+    async fn device_lifecycle<S: FullStorage>(storage: &S) -> Device {
+        let device = Device::from(PublicKey::try_from("0x1234567890123456789012345678901234567890123456789012345678901234").unwrap());
+        let p256_pk: PublicKey = "0x036b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296".try_into().unwrap();
+        let mut new_key = Device::new(KeyType::P256, p256_pk).unwrap().current_key();
+        new_key.valid_from = 2000;
+        assert!(matches!(storage.rotate_device_key(&device.id, &new_key).await, Err(Error::NotFound(_))));
+        storage.new_device(&device).await.unwrap();
+        storage.rotate_device_key(&device.id, &new_key).await.unwrap();
+        storage.revoke_device(&device.id, 3000).await.unwrap();
+        assert!(matches!(storage.revoke_device(&device.id, 4000).await, Err(Error::DeviceRevoked)), "Device revoked twice");
+        assert!(matches!(storage.rotate_device_key(&device.id, &new_key).await, Err(Error::DeviceRevoked)), "Key of a revoked Device rotated");
+        let stored_device = storage.get_device(&device.id).await.unwrap().unwrap();
+        assert_lifecycle(&device, &stored_device);
+        device
+    }
+
+    fn assert_lifecycle(device: &Device, stored_device: &Device) {
+        assert_eq!(stored_device.id, device.id);
+        assert_eq!((stored_device.key_type, stored_device.key_valid_from), (KeyType::P256, 2000));
+        assert_eq!(stored_device.previous_keys.len(), 1);
+        assert_eq!(stored_device.previous_keys[0].pk, device.pk);
+        assert_eq!((stored_device.previous_keys[0].valid_from, stored_device.previous_keys[0].valid_until), (0, Some(2000)));
+        assert_eq!(stored_device.revocation.as_ref().map(|revocation| revocation.revoked_at), Some(3000));
+        assert_eq!(stored_device.key_at(1999).unwrap().pk, device.pk);
+        assert_eq!(stored_device.key_at(2999).unwrap().key_type, KeyType::P256);
+        assert!(stored_device.key_at(3000).is_none(), "Key valid after the revocation");
+    }
+
+    on_every_backend!(device_lifecycle, replay = device_lifecycle_replayed);
+
+    async fn device_lifecycle_replayed(storage: AppendLogStorage, device: Device) {
+        let replayed_device = storage.get_device(&device.id).await.unwrap().unwrap();
+        assert_lifecycle(&device, &replayed_device);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_migrates_dataset_status() {
+        let db_path = std::env::temp_dir().join(format!("bitacora-test-{}.db", rand::random::<u64>()));
+        {
+            // Schema version 1, before Datasets had a status
+            let connection = rusqlite::Connection::open(&db_path).unwrap();
+            connection.execute_batch("
+                CREATE TABLE datasets (id TEXT PRIMARY KEY, ds_limit INTEGER NOT NULL, count INTEGER NOT NULL, merkle_root BLOB, web3 TEXT);
+                INSERT INTO datasets VALUES ('initialized', 10, 0, NULL, NULL);
+                INSERT INTO datasets VALUES ('active', 10, 3, NULL, NULL);
+                INSERT INTO datasets VALUES ('sealed', 10, 10, zeroblob(32), NULL);
+                PRAGMA user_version = 1;
+            ").unwrap();
+        }
+
+        let storage = SqliteStorage::open(&db_path).unwrap();
+        for (id, status) in [("initialized", DatasetStatus::Initialized), ("active", DatasetStatus::Active), ("sealed", DatasetStatus::Sealed)] {
+            assert_eq!(storage.get_dataset(&String::from(id)).await.unwrap().unwrap().status, status, "Wrong status migrated for {}", id);
+        }
+
+        let _ = fs::remove_file(&db_path);
+    }
+}
@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+
+use crate::common::prelude::MerkleRoot;
+use crate::state::entities::{Device, DeviceKey, FlightData, Dataset, DatasetAccumulator, DatasetId, DeviceId, FlightDataId};
+
+/// A single storage mutation.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Operation {
+    NewDevice(Device),
+    SetDevice(Device),
+    /// Makes the key the current one of the Device, see `Device::rotate_key`
+    RotateDeviceKey(DeviceId, DeviceKey),
+    /// Revokes the Device from the given time (milliseconds since the Unix epoch)
+    RevokeDevice(DeviceId, u64),
+    NewFlightData(FlightData),
+    SetFlightData(FlightData),
+    SetDataset(Dataset),
+    AddDataset(Dataset, DeviceId),
+    AddFlightData(DatasetId, FlightData),
+    /// Records that the Dataset received a FlightData at the given time (milliseconds since the Unix epoch)
+    TouchDataset(DatasetId, u64),
+    /// Replaces the Merkle accumulator of the Dataset with the one including its latest FlightData
+    SetDatasetAccumulator(DatasetId, DatasetAccumulator),
+    /// Appends the leaf of a FlightData, keyed by its id, to those of the sparse Merkle tree of the Dataset
+    AddDatasetSparseLeaf(DatasetId, FlightDataId, MerkleRoot)
+}
+
+/// An ordered list of mutations that a storage applies either completely or not at all.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Transaction {
+    operations: Vec<Operation>
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn new_device(&mut self, device: &Device) -> &mut Self {
+        self.push(Operation::NewDevice(device.clone()))
+    }
+
+    pub fn set_device(&mut self, device: &Device) -> &mut Self {
+        self.push(Operation::SetDevice(device.clone()))
+    }
+
+    pub fn rotate_device_key(&mut self, device_id: &DeviceId, key: &DeviceKey) -> &mut Self {
+        self.push(Operation::RotateDeviceKey(device_id.clone(), key.clone()))
+    }
+
+    pub fn revoke_device(&mut self, device_id: &DeviceId, at: u64) -> &mut Self {
+        self.push(Operation::RevokeDevice(device_id.clone(), at))
+    }
+
+    /// Stores a FlightData failing the whole transaction if it already exists.
+    pub fn new_flight_data(&mut self, fd: &FlightData) -> &mut Self {
+        self.push(Operation::NewFlightData(fd.clone()))
+    }
+
+    pub fn set_flight_data(&mut self, fd: &FlightData) -> &mut Self {
+        self.push(Operation::SetFlightData(fd.clone()))
+    }
+
+    pub fn set_dataset(&mut self, ds: &Dataset) -> &mut Self {
+        self.push(Operation::SetDataset(ds.clone()))
+    }
+
+    pub fn add_dataset(&mut self, ds: &Dataset, device_id: &DeviceId) -> &mut Self {
+        self.push(Operation::AddDataset(ds.clone(), device_id.clone()))
+    }
+
+    pub fn add_flight_data(&mut self, ds_id: &DatasetId, fd: &FlightData) -> &mut Self {
+        self.push(Operation::AddFlightData(ds_id.clone(), fd.clone()))
+    }
+
+    pub fn touch_dataset(&mut self, ds_id: &DatasetId, at: u64) -> &mut Self {
+        self.push(Operation::TouchDataset(ds_id.clone(), at))
+    }
+
+    pub fn set_dataset_accumulator(&mut self, ds_id: &DatasetId, accumulator: &DatasetAccumulator) -> &mut Self {
+        self.push(Operation::SetDatasetAccumulator(ds_id.clone(), accumulator.clone()))
+    }
+
+    pub fn add_dataset_sparse_leaf(&mut self, ds_id: &DatasetId, fd_id: &FlightDataId, leaf: &MerkleRoot) -> &mut Self {
+        self.push(Operation::AddDatasetSparseLeaf(ds_id.clone(), fd_id.clone(), leaf.clone()))
+    }
+
+    pub fn push(&mut self, operation: Operation) -> &mut Self {
+        self.operations.push(operation);
+        self
+    }
+
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    pub fn into_operations(self) -> Vec<Operation> {
+        self.operations
+    }
+}
+
+impl From<Vec<Operation>> for Transaction {
+    fn from(operations: Vec<Operation>) -> Self {
+        Transaction { operations }
+    }
+}
+
+impl From<Operation> for Transaction {
+    fn from(operation: Operation) -> Self {
+        Transaction { operations: vec![operation] }
+    }
+}
@@ -0,0 +1,148 @@
+use std::fmt::Display;
+
+use serde::Deserialize;
+
+use crate::cli_args::VerifyArgs;
+use crate::common::bytes::Bytes32;
+use crate::common::merkle::{EncodingError, MerkleRoot, MultiProof, PortableMultiProof, PortableProof};
+use crate::common::sparse_merkle::SparseMerkleProof;
+use crate::state::entities::{DatasetId, DeviceId, FlightDataId, FlightDataMultiProof, FlightDataProof, FlightDataSparseProof};
+use crate::web3::ethereum::new_ethereum_timestamper_from_http;
+use crate::web3::traits::Web3Error;
+
+#[derive(Debug)]
+pub enum VerifyError {
+    ReadFailed(String),
+    BadProofFormat(String),
+    Web3Error(Web3Error),
+    ContractCallFailed(String),
+    NotRegistered(String),
+    /// Portable proofs do not tell the Dataset they belong to, so its root can't be read from the contract
+    UnknownDataset
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::ReadFailed(reason) => write!(f, "can't read the proof file: {}", reason),
+            VerifyError::BadProofFormat(reason) => write!(f, "can't decode the proof: {}", reason),
+            VerifyError::Web3Error(error) => write!(f, "can't connect to the contract: {:?}", error),
+            VerifyError::ContractCallFailed(reason) => write!(f, "can't read the Dataset from the contract: {}", reason),
+            VerifyError::NotRegistered(dataset_id) => write!(f, "Dataset {} is not registered on the contract", dataset_id),
+            VerifyError::UnknownDataset => write!(f, "the proof does not tell its Dataset, it can only be checked against the root it contains")
+        }
+    }
+}
+
+/// Proofs the verify command checks, told apart by their fields: the ones returned by the service and the
+/// portable ones, which can also be in their binary encoding.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProofFile {
+    FlightData(FlightDataProof),
+    FlightDataMulti(FlightDataMultiProof),
+    FlightDataSparse(FlightDataSparseProof),
+    Portable(PortableProof),
+    PortableMulti(PortableMultiProof)
+}
+
+impl ProofFile {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, VerifyError> {
+        // JSON proofs are objects, binary ones start with their encoding version
+        if bytes.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'{') {
+            return match serde_json::from_slice(bytes) {
+                Ok(proof_file) => Ok(proof_file),
+                Err(error) => Err(VerifyError::BadProofFormat(error.to_string()))
+            };
+        }
+        let decoded = match PortableProof::from_bytes(bytes) {
+            Err(EncodingError::UnexpectedKind(_)) => PortableMultiProof::from_bytes(bytes).map(ProofFile::PortableMulti),
+            decoded => decoded.map(ProofFile::Portable)
+        };
+        decoded.map_err(|error| VerifyError::BadProofFormat(error.to_string()))
+    }
+
+    /// Root the proof claims, the sparse Merkle root for sparse proofs.
+    fn root(&self) -> &MerkleRoot {
+        match self {
+            ProofFile::FlightData(fd_proof) => &fd_proof.merkle_root,
+            ProofFile::FlightDataMulti(fd_multiproof) => &fd_multiproof.merkle_root,
+            ProofFile::FlightDataSparse(sparse_proof) => &sparse_proof.sparse_merkle_root,
+            ProofFile::Portable(proof) => &proof.root,
+            ProofFile::PortableMulti(multiproof) => &multiproof.root
+        }
+    }
+
+    fn dataset(&self) -> Option<(&DatasetId, &DeviceId)> {
+        match self {
+            ProofFile::FlightData(fd_proof) => Some((&fd_proof.dataset_id, &fd_proof.device_id)),
+            ProofFile::FlightDataMulti(fd_multiproof) => Some((&fd_multiproof.dataset_id, &fd_multiproof.device_id)),
+            ProofFile::FlightDataSparse(sparse_proof) => Some((&sparse_proof.dataset_id, &sparse_proof.device_id)),
+            ProofFile::Portable(_) | ProofFile::PortableMulti(_) => None
+        }
+    }
+
+    fn verify(self, root: MerkleRoot) -> bool {
+        match self {
+            ProofFile::FlightData(fd_proof) => fd_proof.hash_algorithm.verify_proof(fd_proof.tree_format, &root, &fd_proof.leaf, &fd_proof.proof),
+            ProofFile::FlightDataMulti(fd_multiproof) => {
+                let multiproof = MultiProof { n_leaves: fd_multiproof.n_leaves, leaf_indices: fd_multiproof.leaf_indices, proof: fd_multiproof.proof };
+                fd_multiproof.hash_algorithm.verify_multiproof(fd_multiproof.tree_format, &root, &fd_multiproof.leaves, &multiproof)
+            },
+            ProofFile::FlightDataSparse(sparse_proof) => {
+                let key = match FlightDataId::try_from(sparse_proof.flight_data_id) {
+                    Ok(fd_id) => Bytes32::from(&fd_id),
+                    Err(_) => return false
+                };
+                let proof = SparseMerkleProof { sibling_depths: sparse_proof.sibling_depths, siblings: sparse_proof.siblings };
+                sparse_proof.hash_algorithm.verify_sparse_proof(&root, &key, sparse_proof.value.as_ref(), &proof)
+            },
+            ProofFile::Portable(mut proof) => {
+                proof.root = root;
+                proof.verify()
+            },
+            ProofFile::PortableMulti(mut multiproof) => {
+                multiproof.root = root;
+                multiproof.verify()
+            }
+        }
+    }
+}
+
+/// Checks a proof file against the root it contains or, when a contract address is given, the one
+/// registered on chain for its Dataset.
+pub async fn verify(args: &VerifyArgs) -> Result<bool, VerifyError> {
+    let proof_bytes = match std::fs::read(&args.proof) {
+        Ok(proof_bytes) => proof_bytes,
+        Err(error) => return Err(VerifyError::ReadFailed(error.to_string()))
+    };
+    let proof_file = ProofFile::from_bytes(&proof_bytes)?;
+    let root = match &args.contract {
+        Some(address) => contract_root(&args.web3, address, &proof_file).await?,
+        None => proof_file.root().clone()
+    };
+    Ok(proof_file.verify(root))
+}
+
+async fn contract_root(url: &str, address: &str, proof_file: &ProofFile) -> Result<MerkleRoot, VerifyError> {
+    let (dataset_id, device_id) = match proof_file.dataset() {
+        Some(dataset) => dataset,
+        None => return Err(VerifyError::UnknownDataset)
+    };
+    let timestamper = match new_ethereum_timestamper_from_http(url, address) {
+        Ok(timestamper) => timestamper,
+        Err(error) => return Err(VerifyError::Web3Error(error))
+    };
+    let root = match proof_file {
+        ProofFile::FlightDataSparse(_) => timestamper.get_dataset_sparse_merkle_root(dataset_id.clone(), device_id.clone()).await,
+        _ => timestamper.get_dataset(dataset_id.clone(), device_id.clone()).await
+    };
+    let root = match root {
+        Ok(root) => root,
+        Err(error) => return Err(VerifyError::ContractCallFailed(error.to_string()))
+    };
+    if root == MerkleRoot::default() {
+        return Err(VerifyError::NotRegistered(dataset_id.clone()));
+    }
+    Ok(root)
+}
@@ -1,10 +1,11 @@
 use std::{path::Path, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
+use tracing::warn;
 use ethers::signers::Signer;
 use ethers::prelude::JsonRpcClient;
 use ethers::{
-    contract::{ abigen, ContractFactory },
+    contract::{ abigen, ContractCall, ContractFactory },
     core::{
         rand::thread_rng,
         types::Address,
@@ -18,6 +19,7 @@ use ethers::{
     utils::AnvilInstance
 };
 
+use crate::common::signature::KeyType;
 use crate::state::entities::{Dataset, Device, PublicKey};
 use crate::configuration::BitacoraConfiguration;
 use crate::web3::traits::TxStatus;
@@ -163,7 +165,11 @@ impl <M: ethers::providers::Middleware + 'static, P: JsonRpcClient> EthereumTime
     pub async fn get_device(&self, id: String) -> Result<Device, Box<dyn std::error::Error>> {
         let device_response = self.contract.devices(String::from(id));
         let result = device_response.call().await?;
-        Ok(Device { id: result.0, pk: PublicKey::from(result.1), web3: Option::None })
+        let key_type = match KeyType::from_id(result.1) {
+            Some(key_type) => key_type,
+            None => return Err(format!("Unknown key type {}", result.1).into())
+        };
+        Ok(Device { id: result.0, key_type, pk: PublicKey::from(result.2.to_vec()), attestation: None, key_valid_from: 0, previous_keys: Vec::new(), revocation: None, web3: Option::None })
     }
 
     pub async fn get_dataset(&self, id: String, device_id: String) -> Result<MerkleRoot, Box<dyn std::error::Error>> {
@@ -171,65 +177,59 @@ impl <M: ethers::providers::Middleware + 'static, P: JsonRpcClient> EthereumTime
         let result = dataset_response.call().await?;
         Ok(Bytes32(result))
     }
+
+    /// Sparse Merkle root registered along with the Dataset, zero when it was registered without one.
+    pub async fn get_dataset_sparse_merkle_root(&self, id: String, device_id: String) -> Result<MerkleRoot, Box<dyn std::error::Error>> {
+        let result = self.contract.get_dataset_sparse_merkle_root(id, device_id).call().await?;
+        Ok(Bytes32(result))
+    }
+
+    async fn submit(&self, call: ContractCall<M, ()>) -> Result<Web3Info, Web3Error> {
+        let pending_tx = match call.send().await {
+            Ok(pending_tx) => pending_tx,
+            Err(error) => {
+                warn!(error = ?error, "Transaction submission failed");
+                return Err(Web3Error::SubmissionFailed);
+            }
+        };
+        match pending_tx.await {
+            Ok(Some(receipt)) => Ok(Web3Info {
+                blockchain: Blockchain::devnet(),
+                tx: Tx {
+                    hash: receipt.transaction_hash.into(),
+                    status: TxStatus::Confirmed
+                }
+            }),
+            _ => Err(Web3Error::SubmissionFailed)
+        }
+    }
 }
 
 #[async_trait]
 impl <M: ethers::providers::Middleware + 'static, P: JsonRpcClient> Timestamper for EthereumTimestamper<M, P> {
     async fn register_device(&self, device: &Device) -> Result<Web3Info, Web3Error>  {
-        let device_response = self.contract.register_device(device.id.clone(), device.pk.0);
-        
-        let x = match device_response.send().await {
-            Ok(pending_tx) => {
-                let maybe_receipt = pending_tx.await;
-                match maybe_receipt {
-                    Ok(Some(receipt)) => Ok(Web3Info {
-                        blockchain: Blockchain::devnet(),
-                        tx: Tx {
-                            hash: receipt.transaction_hash.try_into().unwrap(),
-                            status: TxStatus::Confirmed
-                        }
-                    }),
-                    Ok(None) => unimplemented!(),
-                    Err(_) => unimplemented!()
-                }
-                
-            },
-            Err(error) => {
-                println!("{:?}", error);
-                Err(Web3Error::SubmissionFailed)
-            }
-        };
-        x
+        self.submit(self.contract.register_device(device.id.clone(), device.key_type.id(), device.pk.0.clone().into())).await
+    }
+
+    async fn rotate_device_key(&self, device: &Device) -> Result<Web3Info, Web3Error> {
+        self.submit(self.contract.rotate_device_key(device.id.clone(), device.key_type.id(), device.pk.0.clone().into())).await
+    }
+
+    async fn revoke_device(&self, device: &Device) -> Result<Web3Info, Web3Error> {
+        self.submit(self.contract.revoke_device(device.id.clone())).await
     }
 
     async fn register_dataset(&self, dataset: &Dataset, device_id: &String) -> Result<Web3Info, Web3Error> {
-        if dataset.merkle_root.is_none() {
-            return Err(Web3Error::BadInputData(String::from("MerkleTree")));
-        }
-        let response = self.contract.register_dataset(dataset.id.clone(), device_id.clone(), dataset.merkle_root.clone().unwrap().into());
-        
-        let x = match response.send().await {
-            Ok(pending_tx) => {
-                let maybe_receipt = pending_tx.await;
-                match maybe_receipt {
-                    Ok(Some(receipt)) => Ok(Web3Info {
-                        blockchain: Blockchain::devnet(),
-                        tx: Tx {
-                            hash: receipt.transaction_hash.try_into().unwrap(),
-                            status: TxStatus::Confirmed
-                        }
-                    }),
-                    Ok(None) => unimplemented!(),
-                    Err(_) => unimplemented!()
-                }
-                
-            },
-            Err(error) => {
-                println!("{:?}", error);
-                Err(Web3Error::SubmissionFailed)
-            }
+        let merkle_root = match &dataset.merkle_root {
+            Some(merkle_root) => merkle_root.clone(),
+            None => return Err(Web3Error::BadInputData(String::from("MerkleTree")))
+        };
+        // The sparse Merkle root is registered apart, so that the Merkle root stays the one of the Dataset tree
+        let call = match &dataset.sparse_merkle_root {
+            Some(sparse_merkle_root) => self.contract.register_dataset_with_sparse_merkle_root(dataset.id.clone(), device_id.clone(), merkle_root.into(), sparse_merkle_root.clone().into()),
+            None => self.contract.register_dataset(dataset.id.clone(), device_id.clone(), merkle_root.into())
         };
-        x
+        self.submit(call).await
     }
 
     async fn update_web3(&self, web3info: &Web3Info) -> Result<Web3Info, Web3Error> {
@@ -1,3 +1,6 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use async_trait::async_trait;
 use hex;
 use sha2::{Digest, Sha256};
@@ -7,9 +10,29 @@ use crate::state::entities::{ Dataset, Device };
 use super::traits::{ Blockchain, Timestamper, Web3Info, TxStatus, Tx, Web3Error, TxHash };
 
 #[derive(Default)]
-pub struct EthereumStub {}
+pub struct EthereumStub {
+    failing: Arc<AtomicBool>
+}
 
 impl EthereumStub {
+    /// Switch making every submission fail while it is on, to exercise the web3 failures.
+    pub fn failure_switch(&self) -> Arc<AtomicBool> {
+        self.failing.clone()
+    }
+
+    fn submit(&self) -> Result<Web3Info, Web3Error> {
+        if self.failing.load(Ordering::SeqCst) {
+            return Err(Web3Error::SubmissionFailed);
+        }
+        Ok(Web3Info {
+            blockchain: Blockchain::ethereum(),
+            tx: Tx {
+                hash: EthereumStub::get_random_tx_hash(),
+                status: TxStatus::Confirmed
+            }
+        })
+    }
+
     pub fn get_random_tx_hash() -> TxHash {
         let mut hasher = Sha256::new();
         hasher.update(rand::random::<u64>().to_be_bytes());
@@ -21,23 +44,21 @@ impl EthereumStub {
 #[async_trait]
 impl Timestamper for EthereumStub {
     async fn register_dataset(&self, _dataset: &Dataset, _device_id: &String) -> Result<Web3Info, Web3Error> {
-        Ok(Web3Info {
-            blockchain: Blockchain::ethereum(),
-            tx: Tx {
-                hash: EthereumStub::get_random_tx_hash(),
-                status: TxStatus::Confirmed
-            }
-        })
+        // Waits like a real submission, so that concurrent callers interleave
+        tokio::task::yield_now().await;
+        self.submit()
     }
 
     async fn register_device(&self, _device: &Device) -> Result<Web3Info, Web3Error> {
-        Ok(Web3Info {
-            blockchain: Blockchain::ethereum(),
-            tx: Tx {
-                hash: EthereumStub::get_random_tx_hash(),
-                status: TxStatus::Confirmed
-            }
-        })
+        self.submit()
+    }
+
+    async fn rotate_device_key(&self, _device: &Device) -> Result<Web3Info, Web3Error> {
+        self.submit()
+    }
+
+    async fn revoke_device(&self, _device: &Device) -> Result<Web3Info, Web3Error> {
+        self.submit()
     }
 
     async fn update_web3(&self, web3info: &Web3Info) -> Result<Web3Info, Web3Error> {
@@ -19,7 +19,7 @@ mod tests {
         solc::Solc, utils::AnvilInstance
     };
 
-    use crate::{web3::{ethereum::{new_ethereum_timestamper_from_devnode, EthereumTimestamper}, traits::Timestamper, stub::EthereumStub}, state::entities::Device, state::entities::{PublicKey, Dataset}};
+    use crate::{common::signature::KeyType, web3::{ethereum::{new_ethereum_timestamper_from_devnode, EthereumTimestamper}, traits::Timestamper, stub::EthereumStub}, state::entities::Device, state::entities::{PublicKey, Dataset, DatasetStatus}, storage::tests::tests::test_dataset};
 
     use crate::common::prelude::*;
 
@@ -40,11 +40,32 @@ mod tests {
                 assert!(gotten_device.is_ok(), "Could not get Device after registration");
                 let gotten_device = gotten_device.unwrap();
                 assert_eq!(gotten_device.id, device.id, "Registered a different ID than supplied");
+                assert_eq!(gotten_device.key_type, device.key_type, "Registered a different key type than supplied");
                 assert_eq!(gotten_device.pk.0, device.pk.0, "Registered a different Public Key than supplied");
             }
         }
     }
 
+    #[tokio::test]
+    async fn test_rotate_and_revoke_device() {
+        let (timestamper, _anvil) = new_ethereum_timestamper_from_devnode().await;
+
+        let device_pk: PublicKey = "0x1234567890123456789012345678901234567890123456789012345678901234".try_into().unwrap();
+        let mut device = Device::from(device_pk);
+        timestamper.register_device(&device).await.unwrap();
+
+        let p256_pk: PublicKey = "0x036b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296".try_into().unwrap();
+        device.rotate_key(Device::new(KeyType::P256, p256_pk).unwrap().current_key());
+        assert!(timestamper.rotate_device_key(&device).await.is_ok(), "Device key rotation failed");
+        let gotten_device = timestamper.get_device(device.id.clone()).await.unwrap();
+        assert_eq!(gotten_device.id, device.id);
+        assert_eq!(gotten_device.key_type, KeyType::P256, "Key type not rotated");
+        assert_eq!(gotten_device.pk.0, device.pk.0, "Public Key not rotated");
+
+        assert!(timestamper.revoke_device(&device).await.is_ok(), "Device revocation failed");
+        assert!(timestamper.rotate_device_key(&device).await.is_err(), "Key of a revoked Device rotated");
+    }
+
     #[tokio::test]
     async fn test_register_dataset() {
         let (timestamper, _anvil) = new_ethereum_timestamper_from_devnode().await;
@@ -57,11 +78,10 @@ mod tests {
         // let dummy_merkle_root: MerkleRoot = EthereumStub::get_random_tx_hash().0;
 
         let dataset = Dataset {
-            id: String::from("Some Id"),
-            limit: 10,
             count: 10,
+            status: DatasetStatus::Sealed,
             merkle_root: Some(EthereumStub::get_random_tx_hash()),
-            web3: None
+            ..test_dataset(String::from("Some Id"), 10)
         };
 
         match timestamper.register_dataset(&dataset, &device.id).await {
@@ -70,10 +90,22 @@ mod tests {
                 panic!("Dataset registration failed");
             },
             Ok(_) => {
-                let gotten_merkle_root = timestamper.get_dataset(dataset.id, device.id).await.unwrap();
+                let gotten_merkle_root = timestamper.get_dataset(dataset.id, device.id.clone()).await.unwrap();
                 assert_eq!(gotten_merkle_root, dataset.merkle_root.unwrap())
             }
         }
+
+        // The sparse Merkle root is registered apart, the Merkle root stays the one of the tree
+        let dataset = Dataset {
+            count: 10,
+            status: DatasetStatus::Sealed,
+            merkle_root: Some(EthereumStub::get_random_tx_hash()),
+            sparse_merkle_root: Some(EthereumStub::get_random_tx_hash()),
+            ..test_dataset(String::from("Other Id"), 10)
+        };
+        assert!(timestamper.register_dataset(&dataset, &device.id).await.is_ok(), "Dataset registration failed");
+        assert_eq!(timestamper.get_dataset(dataset.id.clone(), device.id.clone()).await.unwrap(), dataset.merkle_root.unwrap());
+        assert_eq!(timestamper.get_dataset_sparse_merkle_root(dataset.id, device.id).await.unwrap(), dataset.sparse_merkle_root.unwrap());
     }
 
 }
\ No newline at end of file
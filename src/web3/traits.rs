@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 use ethers::types::H256;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::state::entities::{Device, Dataset};
 use crate::common::bytes::Bytes32;
@@ -13,13 +13,16 @@ pub enum Web3Error {
 } 
 
 #[async_trait]
-pub trait Timestamper {
+pub trait Timestamper: Send + Sync {
     async fn register_device(&self, device: &Device) -> Result<Web3Info, Web3Error> ;
+    /// Registers the current key of an already registered device.
+    async fn rotate_device_key(&self, device: &Device) -> Result<Web3Info, Web3Error>;
+    async fn revoke_device(&self, device: &Device) -> Result<Web3Info, Web3Error>;
     async fn register_dataset(&self, dataset: &Dataset, device_id: &String) -> Result<Web3Info, Web3Error>;
     async fn update_web3(&self, web3info: &Web3Info) -> Result<Web3Info, Web3Error>;
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum TxStatus {
     Submitted,
     Included,
@@ -40,14 +43,14 @@ impl From<TxHash> for H256 {
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Tx {
     #[serde(serialize_with = "Bytes32::serialize_as_hex")]
     pub hash: TxHash,
     pub status: TxStatus
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
 pub enum Blockchain {
     EVM { chain: String }
@@ -63,7 +66,7 @@ impl Blockchain {
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Web3Info {
     pub blockchain: Blockchain,
     pub tx: Tx